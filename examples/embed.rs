@@ -0,0 +1,76 @@
+//! Uses rlox as a library, compiling and running several scripts on the same Vm with a native function and globals of our own,
+//! then calling into a script's function the way a game would call an update hook.
+//! Try it with `cargo run --example embed`
+use rlox::{Arity, NativeError, Output, RloxError, Value, Vm, VmConfig};
+
+use std::error::Error;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // The errors are handled below, so they don't need printing as well
+    let (stderr, _) = Output::buffer();
+    let mut vm = Vm::new(VmConfig {
+        stderr,
+        max_memory: Some(1 << 20), // Scripts we don't trust shouldn't take the whole machine down with them
+        ..VmConfig::default()
+    });
+
+    vm.register_native("greet", Arity::Exactly(1), |context, args| match &args[0] {
+        Value::LoxString(name) => Ok(context.string(&format!("Hello, {}!", name))),
+        value => Err(NativeError::with_value("greet() expects a name", value)),
+    });
+    // Strings don't need making through the context, whatever a host function returns is interned on the way out
+    vm.register_native("hostName", Arity::Exactly(0), |_, _| {
+        Ok(Value::from("embedder"))
+    });
+    vm.set_global("name", Value::from("embedder"));
+    vm.set_global(
+        "numbers",
+        Value::from(vec![Value::from(1.0), Value::from(2.0)]),
+    );
+
+    let scripts = [
+        "var greeting = greet(name); print greeting;",
+        "assertTrue(hostName() == \"embedder\", \"host strings equal Lox ones\"); print \"hostName() == name\";",
+        "var total = 0; for (var x in numbers) total = total + x;",
+        "greet(3);",
+        "print 1 +;",
+        "var s = \"ab\"; while (true) s = s + s;",
+    ];
+    for source in scripts {
+        match vm.compile(source).and_then(|program| vm.run(program)) {
+            Ok(()) => {}
+            Err(RloxError::Runtime(error)) => {
+                println!("runtime error on line {}: {}", error.line, error.message)
+            }
+            Err(error) => print!("{}", error),
+        }
+        // The globals of a script can still be read after it ends
+        if let Some(total) = vm.get_global("total").and_then(|total| total.as_num()) {
+            println!("total = {}", total);
+        }
+    }
+
+    // Values can be passed from one Vm to another, the members of a set are interned again on the way in like the strings in arrays and maps
+    let mut settings = Vm::new(VmConfig::default());
+    let program = settings.compile("var tags = setNew(); add(tags, \"lox\");")?;
+    settings.run(program)?;
+    if let Some(tags) = settings.get_global("tags") {
+        vm.set_global("tags", tags);
+    }
+    let program = vm.compile(
+        "for (var tag in tags) assertTrue(tag == \"lox\", \"set members equal Lox strings\"); print \"tags has lox\";",
+    )?;
+    vm.run(program)?;
+
+    let program = vm.compile(
+        "var elapsed = 0; fun on_update(dt) { elapsed = elapsed + dt; return elapsed; }",
+    )?;
+    vm.run(program)?;
+    for _ in 0..3 {
+        match vm.call_function("on_update", &[Value::from(0.5)])? {
+            Value::Double(elapsed) => println!("elapsed = {}", elapsed),
+            _ => println!("on_update didn't return a number"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,34 @@
+//! Runs a script a slice at a time from a host loop, the way a game would between frames. Try it with `cargo run --example frame_loop`
+use rlox::{StepResult, Vm, VmConfig};
+
+const INSTRUCTIONS_PER_FRAME: u64 = 1000;
+
+fn main() {
+    let source = "
+        var total = 0;
+        for (var i = 0; i < 10000; i = i + 1) {
+            total = total + i;
+        }
+        print total;
+    ";
+
+    let mut vm = Vm::new(VmConfig::default());
+    let program = match vm.compile(source) {
+        Ok(program) => program,
+        Err(_) => std::process::exit(65),
+    };
+    vm.start(program);
+
+    let mut frames = 0;
+    loop {
+        frames += 1;
+        // The host would do its own per frame work here
+        match vm.run_for(INSTRUCTIONS_PER_FRAME) {
+            StepResult::Yielded => continue,
+            StepResult::Done(result) => {
+                println!("finished with {:?} after {} frames", result, frames);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! A syntax highlighter for the terminal, built on the tokens of rlox::Scanner. It reads Lox from stdin and prints it back with ANSI colors,
+//! with the scanner keeping the comments and whitespace. Try it with `cargo run --example highlight < test/precedence.lox`
+use rlox::{Scanner, TokenType};
+
+use std::io::{self, Read};
+
+fn color(token_type: TokenType) -> Option<&'static str> {
+    match token_type {
+        TokenType::TokenString => Some("32"),
+        TokenType::TokenNumber => Some("36"),
+        TokenType::TokenError => Some("41"),
+        TokenType::TokenComment => Some("2"),
+        TokenType::TokenIdentifier | TokenType::TokenEOF => None,
+        TokenType::TokenAnd
+        | TokenType::TokenClass
+        | TokenType::TokenElse
+        | TokenType::TokenFalse
+        | TokenType::TokenFor
+        | TokenType::TokenFun
+        | TokenType::TokenIf
+        | TokenType::TokenNil
+        | TokenType::TokenOr
+        | TokenType::TokenPrint
+        | TokenType::TokenReturn
+        | TokenType::TokenSuper
+        | TokenType::TokenThis
+        | TokenType::TokenTrue
+        | TokenType::TokenVar
+        | TokenType::TokenWhile
+        | TokenType::TokenAwait
+        | TokenType::TokenAsync
+        | TokenType::TokenUse
+        | TokenType::TokenExport
+        | TokenType::TokenDelete
+        | TokenType::TokenExtend
+        | TokenType::TokenStatic => Some("35"),
+        _ => None,
+    }
+}
+
+fn main() -> io::Result<()> {
+    let mut source = String::new();
+    io::stdin().read_to_string(&mut source)?;
+
+    let mut scanner = Scanner::new(&source);
+    scanner.set_trivia(true);
+    let mut out = String::new();
+    for token in scanner {
+        // Error tokens hold the message, so the text comes from the source
+        let text = &source[token.span.clone()];
+        match color(token.token_type) {
+            Some(color) => out.push_str(&format!("\x1b[{}m{}\x1b[0m", color, text)),
+            None => out.push_str(text),
+        }
+    }
+    print!("{}", out);
+    Ok(())
+}
@@ -0,0 +1,47 @@
+//! A call counter and line tracer living outside the VM, built on VmConfig::hooks. Try it with `cargo run --example hooks`
+use rlox::{Hook, Hooks, Vm, VmConfig};
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+fn main() {
+    let source = "
+        fun fib(n) {
+            if (n < 2) return n;
+            return fib(n - 1) + fib(n - 2);
+        }
+        print fib(10);
+    ";
+
+    let calls: Rc<RefCell<BTreeMap<String, usize>>> = Rc::default();
+    let lines: Rc<RefCell<Vec<usize>>> = Rc::default();
+    let hooks = Hooks {
+        on_call: Some(Hook::new({
+            let calls = calls.clone();
+            move |name: &str| *calls.borrow_mut().entry(name.to_string()).or_default() += 1
+        })),
+        on_line: Some(Hook::new({
+            let lines = lines.clone();
+            move |line: &usize| lines.borrow_mut().push(*line)
+        })),
+        ..Hooks::default()
+    };
+
+    let mut vm = Vm::new(VmConfig {
+        hooks,
+        ..VmConfig::default()
+    });
+    if vm
+        .compile(source)
+        .and_then(|program| vm.run(program))
+        .is_err()
+    {
+        std::process::exit(70);
+    }
+
+    for (name, count) in calls.borrow().iter() {
+        println!("{} was called {} times", name, count);
+    }
+    println!("the first lines run were {:?}", &lines.borrow()[..8]);
+}
@@ -0,0 +1,56 @@
+//! Checks what a script defines without running it, here that it has the `setup` and `update` entry points a game loop would call.
+//! Try it with `cargo run --example inspect`
+use rlox::{Program, Vm, VmConfig};
+
+/// Why the program can't be shipped, if it can't
+fn check_entry_points(program: &Program) -> Result<(), String> {
+    let globals = program.globals();
+    let functions = program.functions();
+    for (name, arity) in [("setup", 0), ("update", 1)] {
+        if !globals.contains(&name) {
+            return Err(format!("'{}' isn't defined", name));
+        }
+        let defined = functions
+            .iter()
+            .any(|f| f.name == name && f.arity == arity && !f.is_method && f.module.is_none());
+        if !defined {
+            return Err(format!(
+                "'{}' should be a function of {} arguments",
+                name, arity
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let vm = Vm::new(VmConfig::default());
+    let program = vm
+        .compile(
+            r#"
+            class Player {
+                init(name) { this.name = name; }
+                move(dt) { print this.name + " moved"; }
+            }
+            var player;
+            fun setup() { player = Player("ferris"); }
+            fun update(dt) { player.move(dt); }
+            "#,
+        )
+        .unwrap();
+
+    println!("globals: {:?}", program.globals());
+    for class in program.classes() {
+        println!("class {} with methods {:?}", class.name, class.methods);
+    }
+    println!("{} constants", program.constants().len());
+    match check_entry_points(&program) {
+        Ok(()) => println!("ok to ship"),
+        Err(reason) => println!("can't ship: {}", reason),
+    }
+
+    let program = vm.compile("fun setup() {}").unwrap();
+    if let Err(reason) = check_entry_points(&program) {
+        println!("as expected, can't ship: {}", reason);
+    }
+}
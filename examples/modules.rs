@@ -0,0 +1,45 @@
+//! Serves the modules a script imports from memory instead of from files, ie for a host that ships its scripts inside its own binary.
+//! Try it with `cargo run --example modules`
+use rlox::{Output, RloxError, Vm, VmConfig};
+
+use std::collections::HashMap;
+
+fn main() {
+    let mut modules = HashMap::new();
+    modules.insert(
+        String::from("math"),
+        String::from("export fun square(x) { return x * x; }"),
+    );
+    modules.insert(
+        String::from("greetings"),
+        String::from(r#"use "math"; export fun greet(name) { print "Hello, " + name + "! " + str(math::square(3)); }"#),
+    );
+
+    // The errors are handled below, so they don't need printing as well
+    let (stderr, _) = Output::buffer();
+    let mut vm = Vm::new(VmConfig {
+        stderr,
+        ..VmConfig::default()
+    });
+    vm.set_sources(modules);
+    let source = r#"
+        use "greetings"::{greet};
+        greet("modules");
+        use "missing";
+    "#;
+    if let Err(RloxError::Compile(diagnostics)) = vm.compile(source) {
+        println!(
+            "as expected, the last import failed: {}",
+            diagnostics[0].message
+        );
+    }
+
+    let source = r#"use "greetings"::{greet}; greet("modules");"#;
+    if vm
+        .compile(source)
+        .and_then(|program| vm.run(program))
+        .is_err()
+    {
+        std::process::exit(70);
+    }
+}
@@ -0,0 +1,34 @@
+//! A minimal native module. Build it with `cargo build --example native_module`, copy the library next to your script and load it with
+//!
+//! ```lox
+//! use "native:native_module";
+//! print native_module::hypot(3, 4);
+//! ```
+use rlox::plugin::{RloxRegisterFn, RloxValue, RLOX_ABI_VERSION, RLOX_NUMBER};
+
+use std::os::raw::{c_char, c_void};
+
+#[no_mangle]
+pub extern "C" fn rlox_module_abi_version() -> u32 {
+    RLOX_ABI_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn rlox_register_module(registry: *mut c_void, register: RloxRegisterFn) {
+    register(registry, b"hypot\0".as_ptr() as *const c_char, hypot);
+    register(registry, b"greeting\0".as_ptr() as *const c_char, greeting);
+}
+
+extern "C" fn hypot(arg_count: usize, args: *const RloxValue) -> RloxValue {
+    let args = unsafe { std::slice::from_raw_parts(args, arg_count) };
+    match args {
+        [x, y] if x.tag == RLOX_NUMBER && y.tag == RLOX_NUMBER => {
+            RloxValue::number(x.number.hypot(y.number))
+        }
+        _ => RloxValue::nil(),
+    }
+}
+
+extern "C" fn greeting(_arg_count: usize, _args: *const RloxValue) -> RloxValue {
+    RloxValue::string("hello from a native module")
+}
@@ -0,0 +1,23 @@
+//! A line at a time REPL built on Vm::eval, where every line can use the variables, functions and classes the earlier ones defined.
+//! Try it with `cargo run --example repl`, or pipe a script into it
+use rlox::{Vm, VmConfig};
+
+use std::io::{self, BufRead, Write};
+
+fn main() -> io::Result<()> {
+    // Errors are already printed to stderr by the Vm, and a line that fails doesn't undo what the lines before it did
+    let mut vm = Vm::new(VmConfig::default());
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+        if !line.trim().is_empty() {
+            let _ = vm.eval(&line);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Keeps a script's state across restarts of the host by saving its globals with Vm::snapshot and putting them back with Vm::restore.
+//! Try it with `cargo run --example snapshot`
+use rlox::{Value, Vm, VmConfig};
+
+use std::error::Error;
+
+const SOURCE: &str = "
+    class User {
+        init(name) {
+            this.name = name;
+            this.visits = 0;
+        }
+    }
+    var users = mapNew();
+    var seen = __array();
+
+    fun visit(name) {
+        var user = mapGet(users, name);
+        if (user == nil) {
+            user = User(name);
+            mapSet(users, name, user);
+            push(seen, user);
+        }
+        user.visits = user.visits + 1;
+        return user.visits;
+    }
+";
+
+/// Starts the bot afresh, the way it would be after the host restarts
+fn start() -> Result<Vm, Box<dyn Error>> {
+    let mut vm = Vm::new(VmConfig::default());
+    let program = vm.compile(SOURCE)?;
+    vm.run(program)?;
+    Ok(vm)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut vm = start()?;
+    for name in ["ada", "grace", "ada"] {
+        vm.call_function("visit", &[Value::from(name)])?;
+    }
+    let saved = vm.snapshot()?;
+    println!("saved {} bytes", saved.len());
+
+    let mut vm = start()?;
+    vm.restore(&saved)?;
+    // The instance in seen is the same one as in users, so both see the visit
+    if let Some(visits) = vm.call_function("visit", &[Value::from("ada")])?.as_num() {
+        println!("ada has visited {} times", visits);
+    }
+    Ok(())
+}
@@ -0,0 +1,66 @@
+//! Hands scripts handles to Rust objects with UserData, here counters standing in for something like a database connection, and gets them
+//! back in later calls. The finalizer shows when a handle is dropped.
+//! Try it with `cargo run --example userdata`
+use rlox::{Arity, NativeError, Output, RloxError, UserData, Value, Vm, VmConfig};
+
+use std::error::Error;
+
+struct Counter {
+    name: String,
+    count: u32,
+}
+
+/// The Counter behind a handle given to a host function
+fn counter_arg(value: &Value) -> Result<&UserData, NativeError> {
+    match value {
+        Value::LoxUserData(data) if data.get::<Counter>().is_some() => Ok(data),
+        value => Err(NativeError::with_value("Expected a counter", value)),
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // The error at the end is printed below instead
+    let (stderr, _) = Output::buffer();
+    let mut vm = Vm::new(VmConfig {
+        stderr,
+        ..VmConfig::default()
+    });
+    vm.register_native("counterOpen", Arity::Exactly(1), |context, args| {
+        let name = context.display(&args[0]);
+        let counter = Counter { name, count: 0 };
+        let data =
+            UserData::new(counter)
+                .with_tag("counter")
+                .with_finalizer(|counter: &mut Counter| {
+                    println!("closing {} after {} ticks", counter.name, counter.count)
+                });
+        Ok(Value::from(data))
+    });
+    vm.register_native("counterTick", Arity::Exactly(1), |_, args| {
+        let mut counter = counter_arg(&args[0])?.get_mut::<Counter>().unwrap();
+        counter.count += 1;
+        Ok(Value::from(counter.count as i64))
+    });
+
+    let program = vm.compile(
+        "
+        var a = counterOpen(\"a\");
+        print type(a);
+        print a;
+        counterTick(a);
+        print counterTick(a);
+
+        fun scratch() {
+            var b = counterOpen(\"b\");
+            counterTick(b);
+        }
+        scratch();
+        print \"after scratch\";
+        counterTick(42);
+        ",
+    )?;
+    if let Err(RloxError::Runtime(error)) = vm.run(program) {
+        println!("runtime error on line {}: {}", error.line, error.message);
+    }
+    Ok(())
+}
@@ -4,6 +4,6 @@ use libfuzzer_sys::fuzz_target;
 extern crate rlox;
 
 fuzz_target!(|data: String| {
-    // fuzzed code goes here
-    rlox::interpret(&data, false, true);
+    // Only compiles, since running the program could loop forever
+    let _ = rlox::compile_str(&data);
 });
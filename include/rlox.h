@@ -0,0 +1,90 @@
+/*
+ * The C API for embedding rlox, see src/ffi.rs. Build the library with
+ *
+ *     cargo rustc --release --lib --features ffi --crate-type cdylib
+ *
+ * and link against target/release/librlox.so (or the platform's equivalent).
+ *
+ *     RloxVm *vm = rlox_vm_new();
+ *     rlox_register_native(vm, "twice", 1, twice, NULL);
+ *     if (rlox_eval(vm, "print twice(21);") != RLOX_RESULT_OK)
+ *         fprintf(stderr, "%s\n", rlox_last_error(vm));
+ *     rlox_vm_free(vm);
+ */
+#ifndef RLOX_H
+#define RLOX_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* What rlox_eval returns */
+#define RLOX_RESULT_OK 0
+#define RLOX_RESULT_COMPILE_ERROR 1
+#define RLOX_RESULT_RUNTIME_ERROR 2
+#define RLOX_RESULT_BUDGET_EXCEEDED 3
+
+/* RloxValue tags */
+#define RLOX_NIL 0
+#define RLOX_BOOL 1
+#define RLOX_NUMBER 2
+#define RLOX_STRING 3
+#define RLOX_ERROR 4 /* Only returned by natives, raises a runtime error with the string as its message */
+
+/*
+ * A value passed to or returned from a native. Only nil, bools, numbers and strings can be passed.
+ * Strings are utf8 and not nul terminated. Argument strings are only valid for the duration of the call,
+ * returned strings are copied as soon as the native returns.
+ * The same layout native modules use, so it only changes along with RLOX_ABI_VERSION.
+ */
+typedef struct RloxValue {
+    uint32_t tag;
+    bool boolean;
+    double number;
+    const char *string;
+    size_t string_len;
+} RloxValue;
+
+typedef struct RloxVm RloxVm;
+
+typedef RloxValue (*RloxNativeFn)(void *userdata, size_t arg_count, const RloxValue *args);
+
+/* Prints and errors go to stdout and stderr */
+RloxVm *rlox_vm_new(void);
+void rlox_vm_free(RloxVm *vm);
+
+/*
+ * Compiles and runs source, returning one of the RLOX_RESULT_* codes. Every call adds to the same program, so the globals, functions
+ * and classes an earlier call defined can be used by the later ones, even if the earlier call ended with an error
+ */
+int rlox_eval(RloxVm *vm, const char *source);
+
+/* The message of the error the last rlox_eval ended with, or NULL. Valid until the next rlox_eval or rlox_vm_free */
+const char *rlox_last_error(const RloxVm *vm);
+
+/* Binds function to the global name in all the code evaluated from now on. arity is the exact number of arguments, or -1 for any */
+void rlox_register_native(RloxVm *vm, const char *name, int arity, RloxNativeFn function, void *userdata);
+
+RloxValue rlox_value_nil(void);
+RloxValue rlox_value_bool(bool x);
+RloxValue rlox_value_number(double x);
+/* Borrows s, which has to stay valid until the value has been returned to rlox */
+RloxValue rlox_value_string(const char *s);
+/* Borrows message the same way as rlox_value_string */
+RloxValue rlox_value_error(const char *message);
+
+uint32_t rlox_value_type(RloxValue value);
+bool rlox_value_as_bool(RloxValue value);     /* false if it isn't a bool */
+double rlox_value_as_number(RloxValue value); /* 0 if it isn't a number */
+/* The bytes of a string or error, which aren't nul terminated, with their length written to len unless it's NULL. NULL for every other value */
+const char *rlox_value_as_string(RloxValue value, size_t *len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
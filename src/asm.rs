@@ -0,0 +1,531 @@
+use crate::chunk::{ClassChunk, FunctionChunk, FunctionType, Instr, OpCode};
+use crate::compiler::CompilationResult;
+use crate::interner::Interner;
+use crate::resolver::UpValue;
+use crate::value::Value;
+
+use std::collections::HashMap;
+
+// Assembler for the textual bytecode format (.loxasm), run with `rlox asm file.loxasm`
+//
+// Meant for VM tests that need an exact instruction sequence the compiler would never produce. A file is a list of functions and classes:
+//
+//     // Comments run to the end of the line like in Lox
+//     fn script                // The first function has to be the top level script
+//         OpConstant fn add
+//         OpDefineGlobal add
+//         OpConstant 1
+//         OpConstant 2
+//         OpCallGlobal add 2
+//         OpPrint
+//         OpNil
+//         OpReturn
+//     end
+//
+//     fn add 2                 // fn <name> [arity] [async]
+//         OpGetLocal 1
+//         OpGetLocal 2
+//         OpAdd
+//         OpReturn
+//     end
+//
+//     class Point < Base       // class <name> [< <superclass>], the superclass has to come first
+//         field x                  // The fields it declares, after the superclass's
+//         method init point_init
+//     end
+//
+// Operands are written the way the disassembler shows them rather than as raw indices:
+// - Globals, properties and method names are bare identifiers
+// - OpConstant and OpLoadNative take a number, a "string", nil, true, false, `fn <name>` or `class <name>`
+// - OpClass and OpExtend take a class name, OpExtend's being the class whose methods get added
+// - Jumps and loops take a label, defined with `<label>:` on its own line in the same function
+// - `upvalue local <slot>` and `upvalue outer <index>` give a function its closure information, in capture order
+//
+// Every instruction gets the line it's on in the .loxasm file as its line number, so runtime errors point back into it
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+}
+
+/// Splits a line into words and string literals, dropping the comment at the end
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c == '/' && chars.peek() == Some(&'/') {
+            break;
+        }
+        if c == '"' {
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => s.push(c),
+                    None => return Err(String::from("Unterminated string")),
+                }
+            }
+            tokens.push(Token::Str(s));
+            continue;
+        }
+
+        let mut word = String::from(c);
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() || c == '"' {
+                break;
+            }
+            word.push(c);
+            chars.next();
+        }
+        tokens.push(Token::Word(word));
+    }
+    Ok(tokens)
+}
+
+/// The operands of one line, consumed left to right
+struct Operands<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl Operands<'_> {
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    fn word(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Word(word)) => Ok(word.clone()),
+            Some(Token::Str(s)) => Err(format!("Expected a name, got \"{}\"", s)),
+            None => Err(String::from("Missing operand")),
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, String> {
+        let word = self.word()?;
+        word.parse()
+            .map_err(|_| format!("Expected a number, got '{}'", word))
+    }
+
+    fn optional_word(&mut self) -> Option<String> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Word(word)) => {
+                self.pos += 1;
+                Some(word.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Word(word)) => Err(format!("Unexpected operand '{}'", word)),
+            Some(Token::Str(s)) => Err(format!("Unexpected operand \"{}\"", s)),
+            None => Ok(()),
+        }
+    }
+}
+
+enum Block {
+    Top,
+    Function {
+        index: usize,
+        upvalues: Vec<UpValue>,
+    },
+    Class(usize),
+}
+
+struct Assembler {
+    functions: Vec<FunctionChunk>,
+    classes: Vec<ClassChunk>,
+    constants: Vec<Value>,
+    identifiers: Vec<String>,
+    strings: Interner,
+    function_names: HashMap<String, usize>,
+    class_names: HashMap<String, usize>,
+    labels: Vec<HashMap<String, usize>>, // The instruction offset of every label, indexed the same as functions
+    finished_classes: Vec<bool>, // So a subclass can tell whether its superclass already has all of its methods
+}
+
+/// Assembles the .loxasm source into the same form the compiler produces
+///
+/// Returns the first error along with the line it's on
+pub fn assemble(source: &str) -> Result<CompilationResult, String> {
+    let mut assembler = Assembler {
+        functions: Vec::new(),
+        classes: Vec::new(),
+        constants: Vec::new(),
+        identifiers: Vec::new(),
+        strings: Interner::new(),
+        function_names: HashMap::new(),
+        class_names: HashMap::new(),
+        labels: Vec::new(),
+        finished_classes: Vec::new(),
+    };
+
+    let mut lines = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let tokens = tokenize(line).map_err(|msg| format!("[Line {}] Error: {}", i + 1, msg))?;
+        if !tokens.is_empty() {
+            lines.push((i + 1, tokens));
+        }
+    }
+
+    // Name every function, class and label up front so they can be referred to before they're defined
+    let mut function = None;
+    for (line, tokens) in lines.iter() {
+        assembler
+            .declare(tokens, &mut function)
+            .map_err(|msg| format!("[Line {}] Error: {}", line, msg))?;
+    }
+    if assembler.functions.is_empty() {
+        return Err(String::from("Error: Missing 'fn script'"));
+    }
+
+    let mut block = Block::Top;
+    for (line, tokens) in lines.iter() {
+        block = assembler
+            .line(block, tokens, *line)
+            .map_err(|msg| format!("[Line {}] Error: {}", line, msg))?;
+    }
+    if !matches!(block, Block::Top) {
+        return Err(String::from(
+            "Error: Expected 'end' before the end of the file",
+        ));
+    }
+
+    Ok(CompilationResult {
+        classes: assembler.classes,
+        functions: assembler.functions,
+        constants: assembler.constants,
+        identifier_constants: assembler.identifiers,
+        globals: HashMap::new(),
+        module_functions: Vec::new(),
+        modules: Vec::new(),
+        source_map: None,
+        strings: assembler.strings,
+    })
+}
+
+impl Assembler {
+    /// function is the (index, instruction count so far) of the function the line is in
+    fn declare(
+        &mut self,
+        tokens: &[Token],
+        function: &mut Option<(usize, usize)>,
+    ) -> Result<(), String> {
+        let mut operands = Operands { tokens, pos: 1 };
+        if let Some((index, offset)) = function {
+            match &tokens[0] {
+                Token::Word(word) if word == "end" => *function = None,
+                Token::Word(word) if word == "upvalue" => {}
+                Token::Word(label) if label.ends_with(':') && tokens.len() == 1 => {
+                    let label = &label[..label.len() - 1];
+                    if self.labels[*index]
+                        .insert(label.to_string(), *offset)
+                        .is_some()
+                    {
+                        return Err(format!("Label '{}' is defined twice", label));
+                    }
+                }
+                _ => *offset += 1,
+            }
+            return Ok(());
+        }
+
+        match &tokens[0] {
+            Token::Word(word) if word == "fn" => {
+                let name = operands.word()?;
+                if self.function_names.contains_key(&name) {
+                    return Err(format!("Function '{}' is defined twice", name));
+                }
+                let chunk = if self.functions.is_empty() {
+                    if name != "script" {
+                        return Err(String::from("The first function has to be 'fn script'"));
+                    }
+                    FunctionChunk::new(None, 0, FunctionType::Script)
+                } else {
+                    FunctionChunk::new(Some(name.clone()), 0, FunctionType::Function)
+                };
+                *function = Some((self.functions.len(), 0));
+                self.function_names.insert(name, self.functions.len());
+                self.functions.push(chunk);
+                self.labels.push(HashMap::new());
+            }
+            Token::Word(word) if word == "class" => {
+                let name = operands.word()?;
+                if self.class_names.contains_key(&name) {
+                    return Err(format!("Class '{}' is defined twice", name));
+                }
+                self.class_names.insert(name.clone(), self.classes.len());
+                self.classes.push(ClassChunk::new(name));
+                self.finished_classes.push(false);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Handles one line, returning the block that the next line belongs to
+    fn line(&mut self, block: Block, tokens: &[Token], line: usize) -> Result<Block, String> {
+        let word = match &tokens[0] {
+            Token::Word(word) => word.as_str(),
+            Token::Str(s) => return Err(format!("Unexpected string \"{}\"", s)),
+        };
+        let mut operands = Operands { tokens, pos: 1 };
+
+        match block {
+            Block::Top => match word {
+                "fn" => {
+                    let index = self.function_names[&operands.word()?];
+                    while let Some(word) = operands.optional_word() {
+                        match word.as_str() {
+                            "async" => self.functions[index].is_async = true,
+                            arity => {
+                                self.functions[index].arity = arity
+                                    .parse()
+                                    .map_err(|_| format!("Expected an arity, got '{}'", arity))?
+                            }
+                        }
+                    }
+                    operands.finish()?;
+                    Ok(Block::Function {
+                        index,
+                        upvalues: Vec::new(),
+                    })
+                }
+                "class" => {
+                    let index = self.class_names[&operands.word()?];
+                    if operands.optional_word().as_deref() == Some("<") {
+                        let superclass = operands.word()?;
+                        let super_index = match self.class_names.get(&superclass) {
+                            Some(i) if self.finished_classes[*i] => *i,
+                            Some(_) => {
+                                return Err(format!(
+                                    "Superclass '{}' has to be defined before its subclasses",
+                                    superclass
+                                ))
+                            }
+                            None => return Err(format!("Undefined class '{}'", superclass)),
+                        };
+                        // Inherited methods are copied at assembly time, the same as the compiler does
+                        self.classes[index].methods = self.classes[super_index].methods.clone();
+                        self.classes[index].fields = self.classes[super_index].fields.clone();
+                        self.classes[index].superclass = Some(super_index);
+                    }
+                    operands.finish()?;
+                    Ok(Block::Class(index))
+                }
+                _ => Err(format!("Expected 'fn' or 'class', got '{}'", word)),
+            },
+
+            Block::Function {
+                index,
+                mut upvalues,
+            } => {
+                match word {
+                    "end" => {
+                        operands.finish()?;
+                        if !upvalues.is_empty() {
+                            self.functions[index].set_upvalues(upvalues);
+                        }
+                        return Ok(Block::Top);
+                    }
+                    "upvalue" => {
+                        let is_local = match operands.word()?.as_str() {
+                            "local" => true,
+                            "outer" => false,
+                            other => {
+                                return Err(format!("Expected 'local' or 'outer', got '{}'", other))
+                            }
+                        };
+                        let index = operands.number()?;
+                        operands.finish()?;
+                        upvalues.push(UpValue { is_local, index });
+                    }
+                    label if label.ends_with(':') && tokens.len() == 1 => {} // Already recorded by declare
+                    _ => {
+                        let op_code = self.instruction(index, word, &mut operands)?;
+                        operands.finish()?;
+                        self.functions[index].chunk.write_instruction(Instr {
+                            op_code,
+                            line_num: line,
+                        });
+                    }
+                }
+                Ok(Block::Function { index, upvalues })
+            }
+
+            Block::Class(index) => match word {
+                "end" => {
+                    operands.finish()?;
+                    let init = self.identifier("init");
+                    self.classes[index].has_init = self.classes[index].methods.contains_key(&init);
+                    self.finished_classes[index] = true;
+                    Ok(Block::Top)
+                }
+                "method" => {
+                    let name = operands.word()?;
+                    let function = self.function(&operands.word()?)?;
+                    operands.finish()?;
+                    self.functions[function].class = Some(index);
+                    self.functions[function].fn_type = if name == "init" {
+                        FunctionType::Initializer
+                    } else {
+                        FunctionType::Method
+                    };
+                    let name = self.identifier(&name);
+                    self.classes[index].methods.insert(name, function);
+                    Ok(Block::Class(index))
+                }
+                "field" => {
+                    let name = self.identifier(&operands.word()?);
+                    operands.finish()?;
+                    if !self.classes[index].fields.contains(&name) {
+                        self.classes[index].fields.push(name);
+                    }
+                    Ok(Block::Class(index))
+                }
+                _ => Err(format!(
+                    "Expected 'method', 'field' or 'end', got '{}'",
+                    word
+                )),
+            },
+        }
+    }
+
+    /// Parses the next instruction of function
+    fn instruction(
+        &mut self,
+        function: usize,
+        name: &str,
+        operands: &mut Operands,
+    ) -> Result<OpCode, String> {
+        let op_code = match name {
+            "OpReturn" => OpCode::OpReturn,
+            "OpPop" => OpCode::OpPop,
+            "OpDefineGlobal" => OpCode::OpDefineGlobal(self.identifier(&operands.word()?)),
+            "OpGetGlobal" => OpCode::OpGetGlobal(self.identifier(&operands.word()?)),
+            "OpSetGlobal" => OpCode::OpSetGlobal(self.identifier(&operands.word()?)),
+            "OpGetSuper" => OpCode::OpGetSuper(self.identifier(&operands.word()?)),
+            "OpCallGlobal" => {
+                OpCode::OpCallGlobal(self.identifier(&operands.word()?), operands.number()?)
+            }
+            "OpGetLocal" => OpCode::OpGetLocal(operands.number()?),
+            "OpSetLocal" => OpCode::OpSetLocal(operands.number()?),
+            "OpInvoke" => OpCode::OpInvoke(self.identifier(&operands.word()?), operands.number()?),
+            "OpGetProperty" => OpCode::OpGetProperty(self.identifier(&operands.word()?)),
+            "OpGetField" => {
+                OpCode::OpGetField(self.identifier(&operands.word()?), operands.number()?)
+            }
+            "OpSetField" => {
+                OpCode::OpSetField(self.identifier(&operands.word()?), operands.number()?)
+            }
+            "OpSetProperty" => OpCode::OpSetProperty(self.identifier(&operands.word()?)),
+            "OpDeleteProperty" => OpCode::OpDeleteProperty(self.identifier(&operands.word()?)),
+            "OpGetUpvalue" => OpCode::OpGetUpvalue(operands.number()?),
+            "OpSetUpvalue" => OpCode::OpSetUpvalue(operands.number()?),
+            "OpClosure" => OpCode::OpClosure,
+            "OpJump" | "OpJumpIfFalse" | "OpLoop" => {
+                let label = operands.word()?;
+                let target = match self.labels[function].get(&label) {
+                    Some(target) => *target,
+                    None => return Err(format!("Undefined label '{}'", label)),
+                };
+                // Offsets are relative to the jump itself, the same as the disassembler shows them
+                let offset = self.functions[function].chunk.code.len();
+                match name {
+                    "OpJump" if target > offset => OpCode::OpJump(target - offset),
+                    "OpJumpIfFalse" if target > offset => OpCode::OpJumpIfFalse(target - offset),
+                    "OpLoop" if target <= offset => OpCode::OpLoop(offset - target),
+                    "OpLoop" => {
+                        return Err(format!("Loops can only jump backwards, to '{}'", label))
+                    }
+                    _ => return Err(format!("Jumps can only jump forwards, to '{}'", label)),
+                }
+            }
+            "OpCall" => OpCode::OpCall(operands.number()?),
+            "OpClass" | "OpExtend" => {
+                let class = operands.word()?;
+                match self.class_names.get(&class) {
+                    Some(index) if name == "OpClass" => OpCode::OpClass(*index),
+                    Some(index) => OpCode::OpExtend(*index),
+                    None => return Err(format!("Undefined class '{}'", class)),
+                }
+            }
+            "OpConstant" => OpCode::OpConstant(self.constant(operands)?),
+            "OpLoadNative" => OpCode::OpLoadNative(self.constant(operands)?),
+            "OpNil" => OpCode::OpNil,
+            "OpTrue" => OpCode::OpTrue,
+            "OpFalse" => OpCode::OpFalse,
+            "OpNegate" => OpCode::OpNegate,
+            "OpNot" => OpCode::OpNot,
+            "OpAdd" => OpCode::OpAdd,
+            "OpSubtract" => OpCode::OpSubtract,
+            "OpMultiply" => OpCode::OpMultiply,
+            "OpDivide" => OpCode::OpDivide,
+            "OpEqual" => OpCode::OpEqual,
+            "OpGreater" => OpCode::OpGreater,
+            "OpLess" => OpCode::OpLess,
+            "OpGreaterEqual" => OpCode::OpGreaterEqual,
+            "OpLessEqual" => OpCode::OpLessEqual,
+            "OpPrint" => OpCode::OpPrint,
+            "OpAwait" => OpCode::OpAwait,
+            "OpTuple" => OpCode::OpTuple(operands.number()?),
+            "OpUnpack" => OpCode::OpUnpack(operands.number()?),
+            _ => return Err(format!("Unknown instruction '{}'", name)),
+        };
+        Ok(op_code)
+    }
+
+    fn constant(&mut self, operands: &mut Operands) -> Result<usize, String> {
+        let value = match operands.next() {
+            Some(Token::Str(s)) => Value::LoxString(self.strings.intern(s)),
+            Some(Token::Word(word)) => match word.as_str() {
+                "nil" => Value::Nil,
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "fn" => Value::LoxFunction(self.function(&operands.word()?)?),
+                "class" => {
+                    let name = operands.word()?;
+                    match self.class_names.get(&name) {
+                        Some(index) => Value::LoxClass(*index),
+                        None => return Err(format!("Undefined class '{}'", name)),
+                    }
+                }
+                number => match (number.parse::<i64>(), number.parse::<f64>()) {
+                    (Ok(x), _) => Value::Int(x),
+                    (_, Ok(x)) => Value::Double(x),
+                    _ => return Err(format!("Expected a constant, got '{}'", number)),
+                },
+            },
+            None => return Err(String::from("Missing operand")),
+        };
+        self.constants.push(value);
+        Ok(self.constants.len() - 1)
+    }
+
+    fn function(&self, name: &str) -> Result<usize, String> {
+        match self.function_names.get(name) {
+            Some(index) => Ok(*index),
+            None => Err(format!("Undefined function '{}'", name)),
+        }
+    }
+
+    /// Index of the name in the identifiers, which the VM also uses as the global's slot, so every name only gets one entry
+    fn identifier(&mut self, name: &str) -> usize {
+        match self.identifiers.iter().position(|x| x == name) {
+            Some(index) => index,
+            None => {
+                self.identifiers.push(name.to_string());
+                self.identifiers.len() - 1
+            }
+        }
+    }
+}
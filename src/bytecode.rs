@@ -0,0 +1,839 @@
+use crate::chunk::{
+    Chunk, ClassChunk, FunctionChunk, FunctionType, Instr, OpCode, SourceMap, Visibility,
+};
+use crate::compiler::CompilationResult;
+use crate::interner::Interner;
+use crate::resolver::UpValue;
+use crate::value::Value;
+
+use std::collections::HashMap;
+
+// Binary format for precompiled programs and modules (.loxb)
+//
+// Everything is written in the same order as the fields of CompilationResult, after a header of a 4 byte magic number and a 2 byte little endian format version.
+// Integers are LEB128 varints since almost every operand is a small index, strings are a length followed by utf8 bytes
+
+const MAGIC: &[u8; 4] = b"LOXB";
+const FORMAT_VERSION: u16 = 5; // Bump whenever the layout changes, files of any other version are rejected instead of misread
+const HEADER_LEN: usize = MAGIC.len() + 2;
+const MAX_STACK: usize = 1 << 16; // More values than any function's frame needs, which keeps validate from looping for long on a corrupt one
+
+/// Serializes a CompilationResult into the .loxb format
+pub fn serialize(result: &CompilationResult) -> Vec<u8> {
+    let mut writer = Writer { bytes: Vec::new() };
+    writer.bytes.extend_from_slice(MAGIC);
+    writer
+        .bytes
+        .extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+
+    writer.usize(result.functions.len());
+    for function in result.functions.iter() {
+        writer.function(function);
+    }
+
+    writer.usize(result.classes.len());
+    for class in result.classes.iter() {
+        writer.class(class);
+    }
+
+    writer.usize(result.constants.len());
+    for constant in result.constants.iter() {
+        writer.value(constant);
+    }
+
+    writer.usize(result.identifier_constants.len());
+    for identifier in result.identifier_constants.iter() {
+        writer.string(identifier);
+    }
+
+    // Sorted, since the same program has to give the same bytes every time it's compiled
+    let mut globals: Vec<(&usize, &Visibility)> = result.globals.iter().collect();
+    globals.sort_unstable_by_key(|(index, _)| **index);
+    writer.usize(globals.len());
+    for (index, visibility) in globals {
+        writer.usize(*index);
+        writer.byte(match visibility {
+            Visibility::Public => 0,
+            Visibility::Private => 1,
+        });
+    }
+
+    writer.usize(result.module_functions.len());
+    for (path, range) in result.module_functions.iter() {
+        writer.string(path);
+        writer.usize(range.start);
+        writer.usize(range.end);
+    }
+
+    writer.bool(result.source_map.is_some());
+    if let Some(source_map) = &result.source_map {
+        writer.usize(source_map.columns.len());
+        for columns in source_map.columns.iter() {
+            writer.usize(columns.len());
+            for column in columns.iter() {
+                writer.usize(*column);
+            }
+        }
+    }
+
+    writer.bytes
+}
+
+/// Reads a CompilationResult back out of the .loxb format
+///
+/// Returns a message describing the problem if the bytes are not a valid .loxb file
+pub fn deserialize(bytes: &[u8]) -> Result<CompilationResult, String> {
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(String::from("Not a compiled lox file"));
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(String::from("Unexpected end of file"));
+    }
+    let version = u16::from_le_bytes([bytes[MAGIC.len()], bytes[MAGIC.len() + 1]]);
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "Compiled for bytecode format version {}, but this rlox reads version {}. Recompile it from source",
+            version, FORMAT_VERSION
+        ));
+    }
+
+    let mut reader = Reader::new(bytes, HEADER_LEN);
+
+    let mut functions = Vec::new();
+    for _ in 0..reader.usize()? {
+        functions.push(reader.function()?);
+    }
+
+    let mut classes = Vec::new();
+    for _ in 0..reader.usize()? {
+        classes.push(reader.class()?);
+    }
+
+    let mut constants = Vec::new();
+    for _ in 0..reader.usize()? {
+        constants.push(reader.value()?);
+    }
+
+    let mut identifier_constants = Vec::new();
+    for _ in 0..reader.usize()? {
+        identifier_constants.push(reader.string()?);
+    }
+
+    let mut globals = HashMap::new();
+    for _ in 0..reader.usize()? {
+        let index = reader.usize()?;
+        let visibility = match reader.byte()? {
+            0 => Visibility::Public,
+            1 => Visibility::Private,
+            x => return Err(format!("Invalid visibility tag {}", x)),
+        };
+        globals.insert(index, visibility);
+    }
+
+    let mut module_functions = Vec::new();
+    for _ in 0..reader.usize()? {
+        let path = reader.string()?;
+        let range = reader.usize()?..reader.usize()?;
+        if range.end > functions.len() {
+            return Err(format!("Invalid function range {:?}", range));
+        }
+        module_functions.push((path, range));
+    }
+
+    let source_map = if reader.bool()? {
+        let mut source_map = SourceMap::default();
+        for _ in 0..reader.usize()? {
+            let mut columns = Vec::new();
+            for _ in 0..reader.usize()? {
+                columns.push(reader.usize()?);
+            }
+            source_map.columns.push(columns);
+        }
+        Some(source_map)
+    } else {
+        None
+    };
+
+    if functions.is_empty() || functions[0].fn_type != FunctionType::Script {
+        return Err(String::from("Missing the script function"));
+    }
+
+    if reader.pos != bytes.len() {
+        return Err(String::from("Unexpected trailing bytes"));
+    }
+
+    let result = CompilationResult {
+        classes,
+        functions,
+        constants,
+        identifier_constants,
+        globals,
+        module_functions,
+        modules: Vec::new(),
+        source_map,
+        strings: reader.strings,
+    };
+    validate(&result).map_err(|why| format!("Malformed bytecode: {}", why))?;
+    Ok(result)
+}
+
+/// Checks everything the VM takes for granted about code that came out of the compiler, so that a corrupt file is an error instead of a crash:
+/// every index an instruction, constant or class holds is in range, jumps land inside their function, and running a function never pops
+/// more than it pushed or reads a local below what's on the stack
+fn validate(result: &CompilationResult) -> Result<(), String> {
+    let identifiers = result.identifier_constants.len();
+    let identifier = |i: usize| match i < identifiers {
+        true => Ok(()),
+        false => Err(format!("identifier {} out of range", i)),
+    };
+    let function = |i: usize| match result.functions.get(i) {
+        Some(function) => Ok(function),
+        None => Err(format!("function {} out of range", i)),
+    };
+    let class = |i: usize| match i < result.classes.len() {
+        true => Ok(()),
+        false => Err(format!("class {} out of range", i)),
+    };
+
+    for index in result.globals.keys() {
+        identifier(*index)?;
+    }
+    if result.functions[0].upvalues.is_some() || result.functions[0].arity != 0 {
+        return Err(String::from("the script can't take arguments or upvalues"));
+    }
+    for constant in result.constants.iter() {
+        match constant {
+            Value::LoxFunction(i) => {
+                function(*i)?;
+            }
+            Value::LoxClass(i) => class(*i)?,
+            _ => {}
+        }
+    }
+
+    let init = result.identifier_constants.iter().position(|x| x == "init"); // The same one VM::new looks methods up with
+    for (index, chunk) in result.classes.iter().enumerate() {
+        for (name, method) in chunk.methods.iter() {
+            identifier(*name)?;
+            if function(*method)?.upvalues.is_some() {
+                return Err(format!("method {} has upvalues", method));
+            }
+        }
+        for name in chunk.fields.iter() {
+            identifier(*name)?;
+        }
+        if chunk.has_init && !init.is_some_and(|init| chunk.methods.contains_key(&init)) {
+            return Err(format!("class {} has no init method", index));
+        }
+        // find_method walks up the superclasses, so they can't go round in a circle
+        let mut next = chunk.superclass;
+        for _ in 0..=result.classes.len() {
+            match next {
+                Some(superclass) => {
+                    class(superclass)?;
+                    next = result.classes[superclass].superclass;
+                }
+                None => break,
+            }
+        }
+        if next.is_some() {
+            return Err(format!("class {} inherits from itself", index));
+        }
+    }
+
+    for (index, chunk) in result.functions.iter().enumerate() {
+        validate_function(result, chunk).map_err(|why| format!("{} in function {}", why, index))?;
+        if let Some(class) = chunk.class {
+            if class >= result.classes.len() {
+                return Err(format!("class {} out of range", class));
+            }
+        }
+    }
+    for (_, range) in result.module_functions.iter() {
+        if range.start > range.end {
+            return Err(format!("invalid function range {:?}", range));
+        }
+    }
+    Ok(())
+}
+
+/// The indices and stack heights of one function, see validate
+fn validate_function(result: &CompilationResult, function: &FunctionChunk) -> Result<(), String> {
+    let code = &function.chunk.code;
+    let upvalues = function
+        .upvalues
+        .as_ref()
+        .map_or(0, |upvalues| upvalues.len());
+    let constant = |i: usize| match result.constants.get(i) {
+        Some(constant) => Ok(constant),
+        None => Err(format!("constant {} out of range", i)),
+    };
+    let target = |instr: &Instr, i: usize| {
+        let target = match instr.op_code {
+            OpCode::OpJump(offset) | OpCode::OpJumpIfFalse(offset) if offset > 0 => {
+                i.checked_add(offset)
+            }
+            OpCode::OpLoop(offset) => i.checked_sub(offset),
+            _ => None,
+        };
+        match target {
+            Some(target) if target < code.len() => Ok(target),
+            _ => Err(format!("jump out of the function at instruction {}", i)),
+        }
+    };
+
+    // How many values each instruction finds on the stack of the frame, slot 0 and the arguments included
+    let mut heights: Vec<Option<usize>> = vec![None; code.len()];
+    let mut pending = vec![(0, function.arity + 1)];
+    while let Some((i, height)) = pending.pop() {
+        let Some(instr) = code.get(i) else {
+            return Err(String::from("runs past the end of its code"));
+        };
+        // An if without an else leaves its condition on the stack on one path only, so paths can meet with different heights. The lowest is
+        // the one that has to be enough
+        if heights[i].is_some_and(|seen| seen <= height) {
+            continue;
+        }
+        heights[i] = Some(height);
+
+        let (pops, pushes) = stack_effect(instr.op_code);
+        if pops > height {
+            return Err(format!("stack underflow at instruction {}", i));
+        }
+        match instr.op_code {
+            OpCode::OpGetLocal(slot) | OpCode::OpSetLocal(slot) if slot >= height - pops => {
+                return Err(format!("local {} out of range at instruction {}", slot, i));
+            }
+            OpCode::OpGetUpvalue(index) | OpCode::OpSetUpvalue(index) if index >= upvalues => {
+                return Err(format!(
+                    "upvalue {} out of range at instruction {}",
+                    index, i
+                ));
+            }
+            OpCode::OpClosure => {
+                // Only the function constant right before it, see Compiler::function
+                let closed = match i.checked_sub(1).map(|before| code[before].op_code) {
+                    Some(OpCode::OpConstant(c)) => match constant(c)? {
+                        Value::LoxFunction(f) => &result.functions[*f],
+                        _ => return Err(format!("closure of a non function at instruction {}", i)),
+                    },
+                    _ => return Err(format!("closure of a non function at instruction {}", i)),
+                };
+                let Some(captured) = closed.upvalues.as_ref() else {
+                    return Err(format!("closure without upvalues at instruction {}", i));
+                };
+                for upvalue in captured {
+                    let limit = if upvalue.is_local {
+                        height - 1
+                    } else {
+                        upvalues
+                    };
+                    if upvalue.index >= limit {
+                        return Err(format!(
+                            "captured variable out of range at instruction {}",
+                            i
+                        ));
+                    }
+                }
+            }
+            _ => {}
+        }
+        let height = height - pops + pushes;
+        if height > MAX_STACK {
+            return Err(format!("stack overflow at instruction {}", i));
+        }
+
+        match instr.op_code {
+            OpCode::OpReturn => {}
+            OpCode::OpJump(_) | OpCode::OpLoop(_) => pending.push((target(instr, i)?, height)),
+            OpCode::OpJumpIfFalse(_) => {
+                pending.push((target(instr, i)?, height));
+                pending.push((i + 1, height));
+            }
+            _ => pending.push((i + 1, height)),
+        }
+    }
+
+    // The rest is only checked for its indices, since it never runs
+    for (i, instr) in code.iter().enumerate() {
+        match instr.op_code {
+            OpCode::OpDefineGlobal(index)
+            | OpCode::OpGetGlobal(index)
+            | OpCode::OpSetGlobal(index)
+            | OpCode::OpCallGlobal(index, _)
+            | OpCode::OpGetSuper(index)
+            | OpCode::OpInvoke(index, _)
+            | OpCode::OpGetProperty(index)
+            | OpCode::OpSetProperty(index)
+            | OpCode::OpGetField(index, _)
+            | OpCode::OpSetField(index, _)
+            | OpCode::OpDeleteProperty(index)
+                if index >= result.identifier_constants.len() =>
+            {
+                return Err(format!("identifier {} out of range", index));
+            }
+            OpCode::OpClass(index) | OpCode::OpExtend(index) if index >= result.classes.len() => {
+                return Err(format!("class {} out of range", index));
+            }
+            OpCode::OpConstant(index) => {
+                if let Value::LoxFunction(f) = constant(index)? {
+                    // A function with upvalues reads them from the closure in slot 0, so it can only be called as one
+                    let closed =
+                        matches!(code.get(i + 1), Some(next) if next.op_code == OpCode::OpClosure);
+                    if result.functions[*f].upvalues.is_some() && !closed {
+                        return Err(format!("function {} used without its closure", f));
+                    }
+                }
+            }
+            OpCode::OpLoadNative(index) if !matches!(constant(index)?, Value::LoxString(_)) => {
+                return Err(format!("native module path {} isn't a string", index));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// How many values the instruction pops off the stack and then pushes, as far as its own frame sees it. A call replaces the callee and its
+/// arguments with the result, and a return leaves the frame
+fn stack_effect(op_code: OpCode) -> (usize, usize) {
+    match op_code {
+        OpCode::OpReturn
+        | OpCode::OpPop
+        | OpCode::OpDefineGlobal(_)
+        | OpCode::OpDeleteProperty(_) => (1, 0),
+        OpCode::OpExtend(_) | OpCode::OpPrint => (1, 0),
+        OpCode::OpGetGlobal(_) | OpCode::OpGetLocal(_) | OpCode::OpGetUpvalue(_) => (0, 1),
+        OpCode::OpClass(_)
+        | OpCode::OpConstant(_)
+        | OpCode::OpNil
+        | OpCode::OpTrue
+        | OpCode::OpFalse => (0, 1),
+        OpCode::OpSetGlobal(_) | OpCode::OpSetLocal(_) | OpCode::OpSetUpvalue(_) => (1, 1),
+        OpCode::OpGetProperty(_)
+        | OpCode::OpGetField(..)
+        | OpCode::OpClosure
+        | OpCode::OpJumpIfFalse(_) => (1, 1),
+        OpCode::OpNegate | OpCode::OpNot | OpCode::OpAwait => (1, 1),
+        OpCode::OpSetProperty(_) | OpCode::OpSetField(..) | OpCode::OpGetSuper(_) => (2, 1),
+        OpCode::OpAdd
+        | OpCode::OpSubtract
+        | OpCode::OpMultiply
+        | OpCode::OpDivide
+        | OpCode::OpEqual
+        | OpCode::OpGreater
+        | OpCode::OpLess
+        | OpCode::OpGreaterEqual
+        | OpCode::OpLessEqual => (2, 1),
+        OpCode::OpCallGlobal(_, arity) | OpCode::OpTuple(arity) => (arity, 1),
+        OpCode::OpInvoke(_, arity) | OpCode::OpCall(arity) => (arity.saturating_add(1), 1),
+        OpCode::OpUnpack(count) => (1, count.min(MAX_STACK + 1)),
+        OpCode::OpJump(_) | OpCode::OpLoop(_) | OpCode::OpLoadNative(_) => (0, 0),
+    }
+}
+
+/// Also used for VM snapshots, see snapshot.rs
+pub(crate) struct Writer {
+    pub(crate) bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub(crate) fn byte(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    pub(crate) fn usize(&mut self, mut x: usize) {
+        loop {
+            let byte = (x & 0x7f) as u8;
+            x >>= 7;
+            if x == 0 {
+                self.byte(byte);
+                return;
+            }
+            self.byte(byte | 0x80);
+        }
+    }
+
+    pub(crate) fn bool(&mut self, x: bool) {
+        self.byte(x as u8);
+    }
+
+    pub(crate) fn f64(&mut self, x: f64) {
+        self.bytes.extend_from_slice(&x.to_le_bytes());
+    }
+
+    pub(crate) fn i64(&mut self, x: i64) {
+        self.bytes.extend_from_slice(&x.to_le_bytes());
+    }
+
+    pub(crate) fn string(&mut self, s: &str) {
+        self.usize(s.len());
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    fn option_usize(&mut self, x: Option<usize>) {
+        match x {
+            Some(x) => {
+                self.byte(1);
+                self.usize(x);
+            }
+            None => self.byte(0),
+        }
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Nil => self.byte(0),
+            Value::Bool(x) => {
+                self.byte(1);
+                self.bool(*x);
+            }
+            Value::Double(x) => {
+                self.byte(2);
+                self.f64(*x);
+            }
+            Value::LoxString(x) => {
+                self.byte(3);
+                self.string(x);
+            }
+            Value::LoxFunction(x) => {
+                self.byte(4);
+                self.usize(*x);
+            }
+            Value::LoxClass(x) => {
+                self.byte(5);
+                self.usize(*x);
+            }
+            Value::Int(x) => {
+                self.byte(6);
+                self.i64(*x);
+            }
+            _ => panic!(
+                "Compiler panic! Found a runtime only value in the constants table: {:?}",
+                value
+            ),
+        }
+    }
+
+    fn function(&mut self, function: &FunctionChunk) {
+        match &function.name {
+            Some(name) => {
+                self.byte(1);
+                self.string(name);
+            }
+            None => self.byte(0),
+        }
+        self.usize(function.arity);
+        self.byte(match function.fn_type {
+            FunctionType::Function => 0,
+            FunctionType::Script => 1,
+            FunctionType::Method => 2,
+            FunctionType::Initializer => 3,
+        });
+        self.bool(function.is_async);
+        self.option_usize(function.class);
+
+        match &function.upvalues {
+            Some(upvalues) => {
+                self.byte(1);
+                self.usize(upvalues.len());
+                for upvalue in upvalues.iter() {
+                    self.bool(upvalue.is_local);
+                    self.usize(upvalue.index);
+                }
+            }
+            None => self.byte(0),
+        }
+
+        self.usize(function.chunk.code.len());
+        for instr in function.chunk.code.iter() {
+            self.op_code(instr.op_code);
+            self.usize(instr.line_num);
+        }
+    }
+
+    fn class(&mut self, class: &ClassChunk) {
+        self.string(&class.name);
+        let mut methods: Vec<(&usize, &usize)> = class.methods.iter().collect();
+        methods.sort_unstable(); // Like the globals in serialize
+        self.usize(methods.len());
+        for (name, fn_index) in methods {
+            self.usize(*name);
+            self.usize(*fn_index);
+        }
+        self.option_usize(class.superclass);
+        self.bool(class.has_init);
+        self.usize(class.fields.len());
+        for name in class.fields.iter() {
+            self.usize(*name);
+        }
+    }
+
+    fn op_code(&mut self, op_code: OpCode) {
+        macro_rules! op {
+            ($tag: expr) => {{
+                self.byte($tag)
+            }};
+            ($tag: expr, $($operand: expr),+) => {{
+                self.byte($tag);
+                $(self.usize($operand);)+
+            }};
+        }
+
+        match op_code {
+            OpCode::OpReturn => op!(0),
+            OpCode::OpPop => op!(1),
+            OpCode::OpDefineGlobal(i) => op!(2, i),
+            OpCode::OpGetGlobal(i) => op!(3, i),
+            OpCode::OpSetGlobal(i) => op!(4, i),
+            OpCode::OpGetSuper(i) => op!(5, i),
+            OpCode::OpCallGlobal(i, arity) => op!(6, i, arity),
+            OpCode::OpGetLocal(i) => op!(7, i),
+            OpCode::OpSetLocal(i) => op!(8, i),
+            OpCode::OpInvoke(i, arity) => op!(9, i, arity),
+            OpCode::OpGetProperty(i) => op!(10, i),
+            OpCode::OpSetProperty(i) => op!(11, i),
+            OpCode::OpGetUpvalue(i) => op!(12, i),
+            OpCode::OpSetUpvalue(i) => op!(13, i),
+            OpCode::OpClosure => op!(14),
+            OpCode::OpJump(offset) => op!(15, offset),
+            OpCode::OpJumpIfFalse(offset) => op!(16, offset),
+            OpCode::OpLoop(offset) => op!(17, offset),
+            OpCode::OpCall(arity) => op!(18, arity),
+            OpCode::OpClass(i) => op!(19, i),
+            OpCode::OpConstant(i) => op!(20, i),
+            OpCode::OpNil => op!(21),
+            OpCode::OpTrue => op!(22),
+            OpCode::OpFalse => op!(23),
+            OpCode::OpNegate => op!(24),
+            OpCode::OpNot => op!(25),
+            OpCode::OpAdd => op!(26),
+            OpCode::OpSubtract => op!(27),
+            OpCode::OpMultiply => op!(28),
+            OpCode::OpDivide => op!(29),
+            OpCode::OpEqual => op!(30),
+            OpCode::OpGreater => op!(31),
+            OpCode::OpLess => op!(32),
+            OpCode::OpPrint => op!(33),
+            OpCode::OpAwait => op!(34),
+            OpCode::OpLoadNative(i) => op!(35, i),
+            OpCode::OpTuple(n) => op!(36, n),
+            OpCode::OpUnpack(n) => op!(37, n),
+            OpCode::OpGreaterEqual => op!(38),
+            OpCode::OpLessEqual => op!(39),
+            OpCode::OpDeleteProperty(i) => op!(40, i),
+            OpCode::OpExtend(i) => op!(41, i),
+            OpCode::OpGetField(i, slot) => op!(42, i, slot),
+            OpCode::OpSetField(i, slot) => op!(43, i, slot),
+        }
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) pos: usize,
+    strings: Interner, // String constants are interned as they're read, same as the compiler would have done
+}
+
+impl Reader<'_> {
+    pub(crate) fn new(bytes: &[u8], pos: usize) -> Reader<'_> {
+        Reader {
+            bytes,
+            pos,
+            strings: Interner::new(),
+        }
+    }
+
+    pub(crate) fn byte(&mut self) -> Result<u8, String> {
+        match self.bytes.get(self.pos) {
+            Some(byte) => {
+                self.pos += 1;
+                Ok(*byte)
+            }
+            None => Err(String::from("Unexpected end of file")),
+        }
+    }
+
+    pub(crate) fn usize(&mut self) -> Result<usize, String> {
+        let mut x: usize = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift >= usize::BITS {
+                return Err(String::from("Integer too large"));
+            }
+            x |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(x);
+            }
+            shift += 7;
+        }
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool, String> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            x => Err(format!("Invalid bool {}", x)),
+        }
+    }
+
+    pub(crate) fn f64(&mut self) -> Result<f64, String> {
+        let mut bytes = [0; 8];
+        for byte in bytes.iter_mut() {
+            *byte = self.byte()?;
+        }
+        Ok(f64::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn i64(&mut self) -> Result<i64, String> {
+        let mut bytes = [0; 8];
+        for byte in bytes.iter_mut() {
+            *byte = self.byte()?;
+        }
+        Ok(i64::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn string(&mut self) -> Result<String, String> {
+        let len = self.usize()?;
+        if self.bytes.len() - self.pos < len {
+            return Err(String::from("Unexpected end of file"));
+        }
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        String::from_utf8(slice.to_vec()).map_err(|_| String::from("Invalid utf8 in string"))
+    }
+
+    fn option_usize(&mut self) -> Result<Option<usize>, String> {
+        match self.byte()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.usize()?)),
+            x => Err(format!("Invalid option tag {}", x)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.byte()? {
+            0 => Ok(Value::Nil),
+            1 => Ok(Value::Bool(self.bool()?)),
+            2 => Ok(Value::Double(self.f64()?)),
+            3 => {
+                let string = self.string()?;
+                Ok(Value::LoxString(self.strings.intern(&string)))
+            }
+            4 => Ok(Value::LoxFunction(self.usize()?)),
+            5 => Ok(Value::LoxClass(self.usize()?)),
+            6 => Ok(Value::Int(self.i64()?)),
+            x => Err(format!("Invalid constant tag {}", x)),
+        }
+    }
+
+    fn function(&mut self) -> Result<FunctionChunk, String> {
+        let name = match self.byte()? {
+            0 => None,
+            1 => Some(self.string()?),
+            x => return Err(format!("Invalid option tag {}", x)),
+        };
+        let arity = self.usize()?;
+        let fn_type = match self.byte()? {
+            0 => FunctionType::Function,
+            1 => FunctionType::Script,
+            2 => FunctionType::Method,
+            3 => FunctionType::Initializer,
+            x => return Err(format!("Invalid function type {}", x)),
+        };
+
+        let mut function = FunctionChunk::new(name, arity, fn_type);
+        function.is_async = self.bool()?;
+        function.class = self.option_usize()?;
+        match self.byte()? {
+            0 => (),
+            1 => {
+                let mut upvalues = Vec::new();
+                for _ in 0..self.usize()? {
+                    let is_local = self.bool()?;
+                    let index = self.usize()?;
+                    upvalues.push(UpValue { is_local, index });
+                }
+                function.set_upvalues(upvalues);
+            }
+            x => return Err(format!("Invalid option tag {}", x)),
+        }
+
+        let mut chunk = Chunk::new();
+        for _ in 0..self.usize()? {
+            let op_code = self.op_code()?;
+            let line_num = self.usize()?;
+            chunk.write_instruction(Instr { op_code, line_num });
+        }
+        function.chunk = chunk;
+
+        Ok(function)
+    }
+
+    fn class(&mut self) -> Result<ClassChunk, String> {
+        let mut class = ClassChunk::new(self.string()?);
+        for _ in 0..self.usize()? {
+            let name = self.usize()?;
+            let fn_index = self.usize()?;
+            class.methods.insert(name, fn_index);
+        }
+        class.superclass = self.option_usize()?;
+        class.has_init = self.bool()?;
+        for _ in 0..self.usize()? {
+            class.fields.push(self.usize()?);
+        }
+        Ok(class)
+    }
+
+    fn op_code(&mut self) -> Result<OpCode, String> {
+        let op_code = match self.byte()? {
+            0 => OpCode::OpReturn,
+            1 => OpCode::OpPop,
+            2 => OpCode::OpDefineGlobal(self.usize()?),
+            3 => OpCode::OpGetGlobal(self.usize()?),
+            4 => OpCode::OpSetGlobal(self.usize()?),
+            5 => OpCode::OpGetSuper(self.usize()?),
+            6 => OpCode::OpCallGlobal(self.usize()?, self.usize()?),
+            7 => OpCode::OpGetLocal(self.usize()?),
+            8 => OpCode::OpSetLocal(self.usize()?),
+            9 => OpCode::OpInvoke(self.usize()?, self.usize()?),
+            10 => OpCode::OpGetProperty(self.usize()?),
+            11 => OpCode::OpSetProperty(self.usize()?),
+            12 => OpCode::OpGetUpvalue(self.usize()?),
+            13 => OpCode::OpSetUpvalue(self.usize()?),
+            14 => OpCode::OpClosure,
+            15 => OpCode::OpJump(self.usize()?),
+            16 => OpCode::OpJumpIfFalse(self.usize()?),
+            17 => OpCode::OpLoop(self.usize()?),
+            18 => OpCode::OpCall(self.usize()?),
+            19 => OpCode::OpClass(self.usize()?),
+            20 => OpCode::OpConstant(self.usize()?),
+            21 => OpCode::OpNil,
+            22 => OpCode::OpTrue,
+            23 => OpCode::OpFalse,
+            24 => OpCode::OpNegate,
+            25 => OpCode::OpNot,
+            26 => OpCode::OpAdd,
+            27 => OpCode::OpSubtract,
+            28 => OpCode::OpMultiply,
+            29 => OpCode::OpDivide,
+            30 => OpCode::OpEqual,
+            31 => OpCode::OpGreater,
+            32 => OpCode::OpLess,
+            33 => OpCode::OpPrint,
+            34 => OpCode::OpAwait,
+            35 => OpCode::OpLoadNative(self.usize()?),
+            36 => OpCode::OpTuple(self.usize()?),
+            37 => OpCode::OpUnpack(self.usize()?),
+            38 => OpCode::OpGreaterEqual,
+            39 => OpCode::OpLessEqual,
+            40 => OpCode::OpDeleteProperty(self.usize()?),
+            41 => OpCode::OpExtend(self.usize()?),
+            42 => OpCode::OpGetField(self.usize()?, self.usize()?),
+            43 => OpCode::OpSetField(self.usize()?, self.usize()?),
+            x => return Err(format!("Invalid opcode {}", x)),
+        };
+        Ok(op_code)
+    }
+}
@@ -0,0 +1,115 @@
+//! The control flow graph of every function, as Graphviz DOT for `rlox path --dump-cfg`. Render it with `dot -Tsvg`
+use crate::chunk::{FunctionChunk, FunctionType, OpCode};
+use crate::compiler::CompilationResult;
+use crate::debug::{describe_instruction, jump_target};
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// A run of instructions that's only ever entered at its first and left after its last
+struct BasicBlock {
+    start: usize,
+    end: usize,                             // Exclusive
+    successors: Vec<(usize, &'static str)>, // The first instruction of each block it can continue in, with a label for the edge
+}
+
+/// Splits the function's code into basic blocks. A block starts at the first instruction, at every jump target and after every jump, loop
+/// and return
+fn basic_blocks(function: &FunctionChunk) -> Vec<BasicBlock> {
+    let code = &function.chunk.code;
+    let mut leaders = BTreeSet::new();
+    if !code.is_empty() {
+        leaders.insert(0);
+    }
+    for (i, instr) in code.iter().enumerate() {
+        if let Some(target) = jump_target(instr, i) {
+            leaders.insert(target);
+        }
+        if jump_target(instr, i).is_some() || instr.op_code == OpCode::OpReturn {
+            leaders.insert(i + 1);
+        }
+    }
+    let leaders: Vec<usize> = leaders.into_iter().filter(|i| *i < code.len()).collect();
+
+    let mut blocks = Vec::new();
+    for (n, start) in leaders.iter().enumerate() {
+        let end = leaders.get(n + 1).copied().unwrap_or(code.len());
+        let last = &code[end - 1];
+        let successors = match last.op_code {
+            OpCode::OpReturn => Vec::new(),
+            OpCode::OpJump(_) | OpCode::OpLoop(_) => {
+                vec![(jump_target(last, end - 1).unwrap(), "")]
+            }
+            // The condition is left on the stack, so falling through is the true branch
+            OpCode::OpJumpIfFalse(_) => {
+                vec![
+                    (end, "true"),
+                    (jump_target(last, end - 1).unwrap(), "false"),
+                ]
+            }
+            _ => vec![(end, "")],
+        };
+        blocks.push(BasicBlock {
+            start: *start,
+            end,
+            successors: successors
+                .into_iter()
+                .filter(|(target, _)| *target < code.len())
+                .collect(),
+        });
+    }
+    blocks
+}
+
+/// One cluster per function, with a node per basic block listing its instructions
+pub fn control_flow_graph(result: &CompilationResult) -> String {
+    let mut dot = String::from("digraph cfg {\n  node [shape=box, fontname=monospace];\n");
+    for (index, function) in result.functions.iter().enumerate() {
+        let name = match (&function.name, function.fn_type) {
+            (None, _) => String::from("<script>"),
+            (Some(name), FunctionType::Method | FunctionType::Initializer) => {
+                format!("<method {} | #{}>", name, index)
+            }
+            (Some(name), _) => format!("<fn {} | #{}>", name, index),
+        };
+        let _ = writeln!(dot, "  subgraph cluster_{} {{", index);
+        let _ = writeln!(dot, "    label=\"{}\";", escape(&name));
+
+        let blocks = basic_blocks(function);
+        for block in blocks.iter() {
+            let mut label = String::new();
+            for i in block.start..block.end {
+                let instr = &function.chunk.code[i];
+                let line =
+                    describe_instruction(instr, i, &result.constants, &result.identifier_constants);
+                let _ = write!(label, "{}: {}\\l", i, escape(&line));
+            }
+            let _ = writeln!(
+                dot,
+                "    f{}_L{} [label=\"L{}:\\l{}\"];",
+                index, block.start, block.start, label
+            );
+        }
+        for block in blocks.iter() {
+            for (target, edge) in block.successors.iter() {
+                let _ = write!(
+                    dot,
+                    "    f{}_L{} -> f{}_L{}",
+                    index, block.start, index, target
+                );
+                if !edge.is_empty() {
+                    let _ = write!(dot, " [label=\"{}\"]", edge);
+                }
+                dot.push_str(";\n");
+            }
+        }
+        dot.push_str("  }\n");
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// For the inside of a quoted DOT string
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
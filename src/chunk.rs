@@ -1,6 +1,7 @@
 use crate::resolver::UpValue;
 
 use std::collections::HashMap;
+use std::ops::Range;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpCode {
@@ -19,6 +20,9 @@ pub enum OpCode {
     OpInvoke(usize, usize), // Combines a GetProperty and a Call. Contains the exact same information. First usize is the index for the property name, second is for the arity
     OpGetProperty(usize), // Index of the String name for this variable name in the identifiers vec corresponding with the property name
     OpSetProperty(usize), // ^
+    OpGetField(usize, usize), // Name and slot, for `this.name` in a method of a class that declares the field. Looked up like OpGetProperty when the slot has some other field
+    OpSetField(usize, usize), // ^
+    OpDeleteProperty(usize),  // ^, removes the field from the instance on top of the stack
     // Optimization note: Is there any way to resolve properties at compile time? Lox allows arbitrary properties to be added at any time, so I don't believe it's possible
     OpGetUpvalue(usize), // upvalue index for a closure
     OpSetUpvalue(usize), // ^
@@ -30,7 +34,8 @@ pub enum OpCode {
 
     OpCall(usize), // Arity
 
-    OpClass(usize), // Index into the classes vec for the ClassChunk object
+    OpClass(usize),  // Index into the classes vec for the ClassChunk object
+    OpExtend(usize), // Index into the classes vec for the ClassChunk holding the methods of an extend, which get added to the class on top of the stack
 
     OpConstant(usize), // Index of the constant we want to retrieve
     OpNil,
@@ -47,9 +52,16 @@ pub enum OpCode {
     OpEqual,
     OpGreater,
     OpLess,
+    OpGreaterEqual, // Not just OpLess and OpNot, which would make NaN >= x true
+    OpLessEqual,
 
     OpPrint,
     OpAwait,
+
+    OpTuple(usize),  // Element count, pops that many values into a tuple
+    OpUnpack(usize), // Element count, replaces a tuple of exactly that many elements with its elements
+
+    OpLoadNative(usize), // Index of the LoxString constant holding the path of the native module to load
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -58,7 +70,7 @@ pub struct Instr {
     pub line_num: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Chunk {
     pub code: Vec<Instr>,
 }
@@ -73,6 +85,45 @@ impl Chunk {
     }
 }
 
+/// The column every instruction was compiled from, indexed by function and then like the function's code. Only kept when it's asked for,
+/// see Compiler::set_source_map. The lines are already on the instructions and the files are in module_functions
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMap {
+    pub columns: Vec<Vec<usize>>, // Empty for functions of modules that were compiled without a source map
+}
+
+impl SourceMap {
+    /// Records the column of the instruction just written to function
+    pub fn push(&mut self, function: usize, column: usize) {
+        if self.columns.len() <= function {
+            self.columns.resize(function + 1, Vec::new());
+        }
+        self.columns[function].push(column);
+    }
+
+    pub fn column(&self, function: usize, instr: usize) -> Option<usize> {
+        self.columns.get(function)?.get(instr).copied()
+    }
+
+    /// Appends the columns of count functions that were merged in after the first offset functions
+    pub fn append(&mut self, offset: usize, count: usize, other: Option<SourceMap>) {
+        self.columns.resize(offset, Vec::new());
+        self.columns
+            .extend(other.unwrap_or_default().columns.into_iter().take(count));
+        self.columns.resize(offset + count, Vec::new());
+    }
+}
+
+/// The module a function was written in according to module_functions (see CompilationResult), None if it's part of the main script
+pub fn module_file(module_functions: &[(String, Range<usize>)], function: usize) -> Option<&str> {
+    // The innermost (ie shortest) range is the file the function was actually written in
+    module_functions
+        .iter()
+        .filter(|(_, range)| range.contains(&function))
+        .min_by_key(|(_, range)| range.len())
+        .map(|(path, _)| path.as_str())
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FunctionType {
     Function,
@@ -82,12 +133,14 @@ pub enum FunctionType {
 }
 
 /// Compile time representation of a function, ie its code, name, resolved closure information
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FunctionChunk {
     pub chunk: Chunk,
     pub name: Option<String>, // None for the top level script
     pub arity: usize,
     pub fn_type: FunctionType,
+    pub is_async: bool, // Calling an async function spawns a task running it and returns a future for the result
+    pub class: Option<usize>, // The class it's a method of, or that the method it's nested in belongs to. Only these can use private properties
     pub upvalues: Option<Vec<UpValue>>, // None while the function is being defined and for functions without upvalues. If the function does have upvalues, this field must be set and must be binded with an OpClosure
 }
 
@@ -98,6 +151,8 @@ impl FunctionChunk {
             name,
             arity,
             fn_type,
+            is_async: false,
+            class: None,
             upvalues: None,
         }
     }
@@ -108,12 +163,13 @@ impl FunctionChunk {
 }
 
 /// Compile time repr of a class
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ClassChunk {
     pub name: String,
     pub methods: HashMap<usize, usize>,
     pub superclass: Option<usize>,
     pub has_init: bool,
+    pub fields: Vec<usize>, // The names of the fields it declares in slot order, starting with the ones its superclass declares
 }
 
 impl ClassChunk {
@@ -123,10 +179,18 @@ impl ClassChunk {
             methods: HashMap::new(),
             superclass: None,
             has_init: false,
+            fields: Vec::new(),
         }
     }
 }
 
+/// Whether a top level definition can be imported by other modules
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Visibility {
+    Public,
+    Private,
+}
+
 #[derive(Debug)]
 pub struct ModuleChunk {
     pub name: String,
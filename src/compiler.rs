@@ -1,25 +1,205 @@
-use crate::chunk::{Chunk, ClassChunk, FunctionChunk, FunctionType, Instr, ModuleChunk, OpCode};
-use crate::debug::{disassemble_class_chunk, disassemble_fn_chunk};
+use crate::bytecode;
+use crate::chunk::{
+    Chunk, ClassChunk, FunctionChunk, FunctionType, Instr, ModuleChunk, OpCode, SourceMap,
+    Visibility,
+};
+use crate::debug::{disassemble_class_chunk, disassemble_fn_chunk, jump_target, Sources};
+use crate::interner::Interner;
 use crate::interpret;
+use crate::native::{STD_CONSTANTS, STD_LIB};
 use crate::prec::{get_rule, ParseFn, Precedence};
-use crate::resolver::{Resolver, Local};
+use crate::resolver::{Local, Resolver};
 use crate::scanner::{Scanner, Token, TokenType};
+use crate::source::{default_sources, ModuleSource, SourceProvider};
+use crate::symbols::{Symbol, SymbolKind, Symbols, Target};
 use crate::value::Value;
-use std::fs::File;
-use std::io::Read;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
 use std::path::Path;
-use std::process::exit;
+use std::rc::Rc;
+
+const MAX_NESTING: usize = 1000; // How deeply expressions and statements can nest. The parser recurses on each level, so this keeps it from overflowing the stack
+const MAX_ERRORS: usize = 100; // The rest of the source is skipped after this many, since each error keeps a copy of its line
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error, // The source doesn't compile
+    Warning,
+}
+
+/// How diagnostics are shown to whoever ran the compiler
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    Human, // The Display of Diagnostic, with the source line underneath
+    Json,  // One JSON object per line, see Diagnostic::to_json
+}
+
+/// Something the compiler found wrong with the source. The compiler never prints these itself, that's up to whoever asked for the compilation
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub line: usize,
+    pub column: Option<usize>, // Where the token it's about starts on its line, counting characters from 1
+    pub span: Option<Range<usize>>, // Byte offsets of that token in the source. Only the line is known for warnings about the bytecode
+    pub message: String,
+    pub at: Option<String>, // The token as the message shows it, ie 'x' or end of file. None for errors from the scanner
+    pub file: Option<String>, // The module it was found in, None for the source given to the compiler
+    pub source_line: Option<String>, // The line the token is on, which gets printed with the token underlined
+    pub code: Option<&'static str>, // Names the kind of warning, ie unused_local, so tools can tell them apart. None for errors
+}
+
+/// Rendered the way the CLI prints it, ie [Line 3] Error at 'x': Expected ';' after value
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file)?;
+        }
+        let severity = match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        match self.column {
+            Some(column) => write!(f, "[Line {}, col {}] {}", self.line, column, severity)?,
+            None => write!(f, "[Line {}] {}", self.line, severity)?,
+        }
+        match &self.at {
+            Some(at) => write!(f, " at {}: {}", at, self.message)?,
+            None => write!(f, ": {}", self.message)?,
+        }
+        if let Some(source_line) = &self.source_line {
+            write!(f, "\n{}", self.snippet(source_line))?;
+        }
+        Ok(())
+    }
+}
+
+impl Diagnostic {
+    /// The source line with the token underlined by carets, ie
+    ///
+    /// ```text
+    ///   3 | print x y;
+    ///     |         ^
+    /// ```
+    fn snippet(&self, source_line: &str) -> String {
+        let gutter = self.line.to_string();
+        let column = self
+            .column
+            .unwrap_or(1)
+            .min(source_line.chars().count() + 1);
+        // Tabs are kept so the carets line up however wide the terminal shows them
+        let padding: String = source_line
+            .chars()
+            .take(column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        // One caret for every character of the span, which counts bytes. A token running past the end of its line (a string spanning lines)
+        // is only underlined up to it. End of file still gets a caret
+        let len = match &self.span {
+            Some(span) => {
+                let mut bytes = 0;
+                source_line
+                    .chars()
+                    .skip(column - 1)
+                    .take_while(|c| {
+                        bytes += c.len_utf8();
+                        bytes <= span.len()
+                    })
+                    .count()
+            }
+            None => 1,
+        }
+        .max(1);
+        format!(
+            "{:w$} | {}\n{:w$} | {}{}",
+            gutter,
+            source_line,
+            "",
+            padding,
+            "^".repeat(len),
+            w = gutter.len()
+        )
+    }
+}
+
+impl Error for Diagnostic {}
+
+impl Diagnostic {
+    /// A single line JSON object for editors and CI, ie
+    ///
+    /// ```text
+    /// {"file":"main.lox","line":3,"column":7,"severity":"warning","code":"unused_local","message":"Local variable 'x' is never read"}
+    /// ```
+    ///
+    /// file is the module the diagnostic was found in, or script_path for the source given to the compiler. Unknown fields are null
+    pub fn to_json(&self, script_path: Option<&str>) -> String {
+        let string = |s: Option<&str>| s.map_or(String::from("null"), json_string);
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        format!(
+            "{{\"file\":{},\"line\":{},\"column\":{},\"severity\":\"{}\",\"code\":{},\"message\":{}}}",
+            string(self.file.as_deref().or(script_path)),
+            self.line,
+            self.column.map_or(String::from("null"), |x| x.to_string()),
+            severity,
+            string(self.code),
+            json_string(&self.message)
+        )
+    }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A class inheriting from a class that isn't defined yet, which is looked for again once the whole file has been compiled
+struct PendingSuperclass {
+    class: usize,
+    name: Token, // The superclass name, for the error if there's no such class after all
+    constant: Option<usize>, // The placeholder constant that `super` in the class loads the superclass from
+}
+
+/// The functions and classes of a module merged in by `use`, wherever they ended up after its first function and class. Only kept while
+/// compiling, so that importing the same module again can share them instead of appending another copy, see Compiler::merge_module
+#[derive(Debug, Clone)]
+pub struct MergedModule {
+    pub path: String,
+    pub functions: Vec<usize>,
+    pub classes: Vec<usize>,
+}
 
-#[derive(Debug)]
 pub struct Compiler<'a> {
     scanner: Scanner<'a>,
-    tokens: Vec<Token>,
+    previous: Token, // The parser never looks further back or ahead than these two, so the tokens before them are dropped
+    current: Token,
 
     constants: Vec<Value>,
     identifier_constants: Vec<String>,
+    module_functions: Vec<(String, Range<usize>)>, // Which source file each block of functions merged in by `use` came from
+    modules: Vec<MergedModule>, // Every module merged in by `use`, the ones they import included
+    source_map: Option<SourceMap>,
+    symbols: Option<Symbols>, // Only kept for editors, and only for the source given to the compiler
+    strings: Interner, // Every LoxString constant is interned here, the VM keeps using this table at runtime
 
     classes: Vec<ClassChunk>,
     current_class: Option<usize>,
+    pending_superclasses: Vec<PendingSuperclass>,
 
     functions: Vec<FunctionChunk>,
     current_function: usize,      // The current FunctionChunk
@@ -27,9 +207,23 @@ pub struct Compiler<'a> {
 
     resolver: Resolver, // Manages the slots for the local variables and upvalues, represented as a Vec of individal ResolverNodes
 
+    globals: HashMap<usize, Visibility>, // Every top level definition, keyed by the index of its name in identifier_constants
+    global_uses: HashMap<usize, Token>, // Where this source first uses each global, for check_undefined_globals
+    exporting: bool,                    // Set while compiling the declaration following an 'export'
+
+    warn_undefined_globals: bool,
+    strict: bool,                    // Undefined globals are errors instead of warnings
+    warnings: bool, // Warn about unused locals, unreachable code and shadowed locals
+    deny_warnings: bool, // Fail the compilation if there are any warnings at all
+    diagnostics: Vec<Diagnostic>, // Every error and warning so far, with the errors found while in panic_mode left out
+    host_globals: Vec<String>, // Defined by the host before the script runs, so they aren't undefined
+    sources: Rc<dyn SourceProvider>, // Where `use` finds its modules, which the modules' own compilers share
+    importing: Vec<String>, // The paths of the modules whose `use` led to compiling this one, to catch a module importing itself
+    resolve_imports: bool,  // Whether `use` loads the module, see set_resolve_imports
+    unexported: HashSet<String>, // `module::name` for the private globals of every module imported whole, which can't be accessed that way
+    nesting: usize, // How many expressions and statements the one being compiled is inside of, see MAX_NESTING
     had_error: bool,
     panic_mode: bool,
-    quiet_mode: bool,
 }
 
 impl Compiler<'_> {
@@ -55,25 +249,30 @@ impl Compiler<'_> {
     }
 
     fn advance(&mut self) {
-        self.tokens.push(self.scanner.scan_token()); // Fixme: Wastes memory by not just dropping the older tokens, make advance() drop older tokens after i finish the code?
+        self.previous = std::mem::replace(&mut self.current, self.scanner.scan_token());
         if self.current().token_type == TokenType::TokenError {
-            self.error(self.current().lexemme.clone().as_str());
+            let token = self.current().clone();
+            self.error_at(&token, &token.lexemme);
             self.advance();
         }
     }
 
     fn previous(&self) -> &Token {
-        &self.tokens[self.tokens.len() - 2]
+        &self.previous
     }
 
     fn current(&self) -> &Token {
-        &self.tokens[self.tokens.len() - 1]
+        &self.current
     }
 
+    /// Reports msg at the current token if it isn't a token_type. It's only skipped over if it matches, so that a missing token doesn't swallow the
+    /// start of whatever comes after it
     fn consume(&mut self, token_type: TokenType, msg: &str) {
-        self.advance();
-        if !(self.previous().token_type == token_type) {
-            self.error(msg);
+        if self.check(token_type) {
+            self.advance();
+        } else {
+            let token = self.current().clone();
+            self.error_at(&token, msg);
         }
     }
 
@@ -91,6 +290,11 @@ impl Compiler<'_> {
     }
 
     fn error(&mut self, message: &str) {
+        let token = self.previous().clone();
+        self.error_at(&token, message);
+    }
+
+    fn error_at(&mut self, token: &Token, message: &str) {
         if self.panic_mode {
             return;
         } // Ignore other errors while in panic_mode
@@ -98,29 +302,82 @@ impl Compiler<'_> {
         self.had_error = true;
         self.panic_mode = true;
 
-        if self.quiet_mode {
-            return;
+        let error = Diagnostic {
+            severity: Severity::Error,
+            line: token.line_num,
+            column: Some(token.column),
+            span: Some(token.span.clone()),
+            message: message.to_string(),
+            at: match token.token_type {
+                TokenType::TokenEOF => Some(String::from("end of file")),
+                TokenType::TokenError => None,
+                _ => Some(format!("'{}'", token.lexemme)),
+            },
+            file: None,
+            source_line: Some(self.scanner.line(token.line_num).to_string()),
+            code: None,
+        };
+        self.diagnostics.push(error);
+
+        let errors = self.diagnostics.iter();
+        if errors.filter(|d| d.severity == Severity::Error).count() >= MAX_ERRORS {
+            self.skip_to_end();
         }
+    }
 
-        let token = self.previous();
-        eprint!("[Line {}] Error", token.line_num);
-        match token.token_type {
-            TokenType::TokenEOF => eprint!(" at end of file"),
-            TokenType::TokenError => (), // nothing
-            _ => eprint!(" at '{}'", token.lexemme),
+    /// Throws away the rest of the tokens. Only for errors that mean none of what follows could be compiled anyway, since it won't be checked
+    fn skip_to_end(&mut self) {
+        while !self.check(TokenType::TokenEOF) {
+            self.advance();
         }
+    }
 
-        eprintln!(": {}", message);
+    /// Skips to the next statement boundary after an error, so that the statements after it are checked as well. Inside a block that includes
+    /// the '}' closing it, otherwise the rest of the file would be read as part of the block
+    /// Warnings are reported even in panic_mode and never stop the compilation, unless the warnings are denied
+    fn warn_at(&mut self, token: &Token, code: &'static str, message: &str) {
+        if !self.warnings {
+            return;
+        }
+        let warning = Diagnostic {
+            severity: Severity::Warning,
+            line: token.line_num,
+            column: Some(token.column),
+            span: Some(token.span.clone()),
+            message: message.to_string(),
+            at: Some(format!("'{}'", token.lexemme)),
+            file: None,
+            source_line: Some(self.scanner.line(token.line_num).to_string()),
+            code: Some(code),
+        };
+        self.diagnostics.push(warning);
+    }
+
+    /// Warns about the locals that went out of scope without ever being read. Names starting with an underscore are left alone
+    fn warn_unused(&mut self, locals: Vec<Local>) {
+        for local in locals {
+            if let Some(token) = local.declared_at {
+                if !local.read && !local.name.starts_with('_') {
+                    self.warn_at(
+                        &token,
+                        "unused_local",
+                        &format!("Local variable '{}' is never read", local.name),
+                    );
+                }
+            }
+        }
     }
 
     fn synchronize(&mut self) {
         self.panic_mode = false;
+        let in_block = !self.resolver.is_global();
 
         while !self.check(TokenType::TokenEOF) {
             if self.previous().token_type == TokenType::TokenSemicolon {
                 return;
             }
             match self.current().token_type {
+                TokenType::TokenRightBrace if in_block => return,
                 TokenType::TokenClass
                 | TokenType::TokenFun
                 | TokenType::TokenVar
@@ -128,6 +385,11 @@ impl Compiler<'_> {
                 | TokenType::TokenIf
                 | TokenType::TokenWhile
                 | TokenType::TokenPrint
+                | TokenType::TokenExport
+                | TokenType::TokenAsync
+                | TokenType::TokenUse
+                | TokenType::TokenDelete
+                | TokenType::TokenExtend
                 | TokenType::TokenReturn => return,
                 _ => (),
             }
@@ -141,7 +403,10 @@ impl Compiler<'_> {
             op_code,
             line_num: self.previous().line_num,
         };
-        self.current_chunk().write_instruction(instr)
+        self.current_chunk().write_instruction(instr);
+        if let Some(source_map) = &mut self.source_map {
+            source_map.push(self.current_function, self.previous.column);
+        }
     }
 
     fn emit_instrs(&mut self, op_codes: &[OpCode]) {
@@ -197,21 +462,21 @@ impl Compiler<'_> {
             self.error("Too much code to jump over");
         }
 
-        let jump_instr = self.current_chunk().code.get_mut(index).unwrap();
-        macro_rules! replace_jump {
-            ($jump_type: path) => {{
-                jump_instr.op_code = $jump_type(jump_amount)
-            }};
-        }
-
-        match jump_instr.op_code {
-            OpCode::OpJump(_) => replace_jump!(OpCode::OpJump),
-            OpCode::OpJumpIfFalse(_) => replace_jump!(OpCode::OpJumpIfFalse),
-            _ => panic!(
-                "Compiler panic: Attempted to patch a non_jump op code instruction: {:?}",
-                jump_instr
-            ),
-        }
+        let patched = match self
+            .current_chunk()
+            .code
+            .get(index)
+            .map(|instr| instr.op_code)
+        {
+            Some(OpCode::OpJump(_)) => OpCode::OpJump(jump_amount),
+            Some(OpCode::OpJumpIfFalse(_)) => OpCode::OpJumpIfFalse(jump_amount),
+            _ => {
+                // A bug in the compiler rather than in the source, but it's still better reported than panicked on
+                self.error("Attempted to patch an instruction that isn't a jump");
+                return;
+            }
+        };
+        self.current_chunk().code[index].op_code = patched;
     }
 
     /// loop_start: Index of the instruction to jump back to
@@ -233,21 +498,91 @@ impl Compiler<'_> {
 
     /// End scope by emitting pop instructions and cleaning the resolver
     fn end_scope(&mut self) {
-        for _ in 0..self.resolver.end_scope() {
+        let locals = self.resolver.end_scope();
+        for _ in 0..locals.len() {
             self.emit_instr(OpCode::OpPop); // Remove old local variables
         }
+        self.warn_unused(locals);
     }
 
     /// Calls Resolver::declare_variable() with the previous Token's lexemme (TokenIdentifier)
     fn declare_variable(&mut self) {
-        let str_val = self.previous().lexemme.clone();
-        let success = self.resolver.declare_variable(str_val);
+        let token = self.previous().clone();
+        self.declare_local(token);
+    }
+
+    /// Declares a local named by token, which does nothing in the global scope
+    fn declare_local(&mut self, token: Token) {
+        if self.resolver.is_global() {
+            return;
+        }
+        if self.resolver.is_shadowing(&token.lexemme) {
+            self.warn_at(
+                &token,
+                "shadowed_local",
+                &format!(
+                    "Local variable '{}' shadows a local of an outer scope",
+                    token.lexemme
+                ),
+            );
+        }
+        let success = self.resolver.declare_variable(token.lexemme.clone());
         if !success {
             self.error("Variable with this name already declared in this scope");
         }
+        self.resolver.set_declared_at(token);
+    }
+
+    /// Records the previous token as declaring a symbol, if symbols are being kept. Functions and variables only count at the top level,
+    /// the locals are found through the resolver instead
+    fn add_symbol(&mut self, kind: SymbolKind) {
+        let container = match kind {
+            SymbolKind::Method => Some(
+                // Methods of an extend belong to the class it extends
+                self.current_class()
+                    .name
+                    .trim_start_matches("extend ")
+                    .to_string(),
+            ),
+            _ if self.resolver.is_global() => None,
+            _ => return,
+        };
+        let name = self.previous().clone();
+        if let (Some(symbols), TokenType::TokenIdentifier) = (&mut self.symbols, name.token_type) {
+            symbols.symbols.push(Symbol {
+                kind,
+                name,
+                container,
+            });
+        }
+    }
+
+    /// Records what the previous token refers to when symbols are being kept. Without a target it's a variable, which is looked up like
+    /// named_variable() does
+    fn add_reference(&mut self, target: Option<Target>) {
+        if self.symbols.is_none() || self.previous().token_type != TokenType::TokenIdentifier {
+            return;
+        }
+        let name = self.previous().clone();
+        let target = match target {
+            Some(target) => target,
+            None => match self.resolver.local_named(&name.lexemme) {
+                Some(local) => match &local.declared_at {
+                    Some(declaration) => Target::Local(declaration.clone()),
+                    None => return, // Declared by the compiler itself
+                },
+                None => Target::Global(name.lexemme.clone()),
+            },
+        };
+        if let Some(symbols) = &mut self.symbols {
+            symbols.references.push((name, target));
+        }
     }
 
     fn parse_precedence(&mut self, prec: Precedence) {
+        if !self.nest() {
+            return;
+        }
         self.advance();
 
         // Parse the start of the prefix expression
@@ -268,6 +603,19 @@ impl Compiler<'_> {
         if can_assign && self.previous().token_type == TokenType::TokenEqual {
             self.error("Invalid assignment target");
         }
+        self.nesting -= 1;
+    }
+
+    /// Counts going one level deeper, for parse_precedence, statement and function bodies. Past MAX_NESTING it reports the error and skips the rest of the
+    /// source instead, since none of it could be compiled anyway, and returns false
+    fn nest(&mut self) -> bool {
+        if self.nesting >= MAX_NESTING {
+            self.error_at(&self.current().clone(), "Too deeply nested");
+            self.skip_to_end();
+            return false;
+        }
+        self.nesting += 1;
+        true
     }
 
     fn call_parse_fn(&mut self, parse_fn: ParseFn, can_assign: bool) {
@@ -287,21 +635,27 @@ impl Compiler<'_> {
             ParseFn::This => self.this(),
             ParseFn::Super => self.super_(),
             // ParseFn:: ModuleAccess=> {self.module_access();},
-            _ => {},
+            _ => {}
         }
     }
 
-    fn module_access(&mut self)->Option<String>{
+    fn module_access(&mut self) -> Option<String> {
         // println!("in");
         self.consume(TokenType::TokenIdentifier, "Expected identifier after '::'");
         Some(self.previous().lexemme.clone())
     }
 
     fn declaration(&mut self) {
-        if self.match_cur(TokenType::TokenFun) {
-            self.fun_declaration();
+        if self.match_cur(TokenType::TokenExport) {
+            self.export_declaration();
+        } else if self.match_cur(TokenType::TokenFun) {
+            self.fun_declaration(false);
+        } else if self.match_cur(TokenType::TokenAsync) {
+            self.async_fun_declaration();
         } else if self.match_cur(TokenType::TokenClass) {
             self.class_declaration();
+        } else if self.match_cur(TokenType::TokenExtend) {
+            self.extend_declaration();
         } else if self.match_cur(TokenType::TokenVar) {
             self.var_declaration();
         } else {
@@ -312,13 +666,41 @@ impl Compiler<'_> {
         }
     }
 
-    fn fun_declaration(&mut self) {
+    /// Compiles the fun/class/var declaration after an 'export' keyword, marking the defined global as importable
+    fn export_declaration(&mut self) {
+        if !self.resolver.is_global() || self.current_fn_type() != FunctionType::Script {
+            self.error("Can only export top level declarations");
+        }
+
+        self.exporting = true;
+        if self.match_cur(TokenType::TokenFun) {
+            self.fun_declaration(false);
+        } else if self.match_cur(TokenType::TokenAsync) {
+            self.async_fun_declaration();
+        } else if self.match_cur(TokenType::TokenClass) {
+            self.class_declaration();
+        } else if self.match_cur(TokenType::TokenVar) {
+            self.var_declaration();
+        } else {
+            self.error("Expected 'fun', 'class' or 'var' after 'export'");
+        }
+        self.exporting = false;
+    }
+
+    fn fun_declaration(&mut self, is_async: bool) {
         let global = self.parse_variable("Expected function name");
+        self.add_symbol(SymbolKind::Function);
         self.resolver.mark_initialized(); // Initialize the function object if we are in a local scope
-        self.function(FunctionType::Function);
+        let index = self.function(FunctionType::Function);
+        self.functions[index].is_async = is_async;
         self.define_variable(global); // Emit the define instr if we are in the global scope
     }
 
+    fn async_fun_declaration(&mut self) {
+        self.consume(TokenType::TokenFun, "Expected 'fun' after 'async'");
+        self.fun_declaration(true);
+    }
+
     fn class_declaration(&mut self) {
         self.consume(
             TokenType::TokenIdentifier,
@@ -326,6 +708,7 @@ impl Compiler<'_> {
         );
         let name = self.previous().lexemme.clone();
         let name_index = self.identifier_constant(&name);
+        self.add_symbol(SymbolKind::Class);
         self.declare_variable();
 
         let class = ClassChunk::new(name);
@@ -341,8 +724,9 @@ impl Compiler<'_> {
         // Check for superclass
         if self.match_cur(TokenType::TokenLess) {
             self.consume(TokenType::TokenIdentifier, "Expected superclass name");
+            self.add_reference(None);
             // Resolve the superclass methods entierly at compile time instead of runtime because it fits how everything else works
-            // However because the compiler is single pass, you can only inherit a class that has already been defined
+            // A superclass that isn't defined yet is looked for again at the end of compile(), after which its methods get copied in the same way
             // Note: we know that all the methods the superclass will ever own must already be defined, since it will have had the same superclass resolution at compile time < Lox classes are closed
             // Note: I like this bit of code, it is a really nice shiny implementaiton of superclasses that doesnt require any new opcodes and does not require any copying of the FunctionChunks. Fucking sick
             let superclass_name = &self.previous().lexemme.clone();
@@ -353,46 +737,238 @@ impl Compiler<'_> {
                 }
             }
 
-            if superclass_index == self.current_class {
-                self.error("A class cannot inherit from itself");
-            }
-
             match superclass_index {
+                Some(i) if i == class_index => self.error("A class cannot inherit from itself"),
                 Some(i) => {
-                    let superclass = &self.classes[i];
-                    for (name_index, fn_index) in superclass.methods.clone().iter() {
-                        self.current_class()
-                            .methods
-                            .insert(name_index.clone(), *fn_index);
-                        // Inherit all the methods by just copying in all the fn_indices, nicely handles multiple levels of inheritence
-                        let name = self.identifier_constants[*name_index].clone();
-                        if name.as_str().eq("init") {
-                            self.current_class().has_init = true;
-                        }
-                    }
-                    self.current_class().superclass = superclass_index;
+                    self.inherit(class_index, i);
                 }
-                None => {
-                    self.error(format!("'{}' is not a valid superclass", superclass_name).as_str())
+                None if self.previous().token_type == TokenType::TokenIdentifier => {
+                    self.pending_superclasses.push(PendingSuperclass {
+                        class: class_index,
+                        name: self.previous().clone(),
+                        constant: None,
+                    })
                 }
+                None => {} // There wasn't a name to look for, which consume() already reported
             }
         }
 
         self.consume(TokenType::TokenLeftBrace, "Expected '{' before class body");
         while !self.check(TokenType::TokenRightBrace) && !self.check(TokenType::TokenEOF) {
-            self.method();
+            if self.match_cur(TokenType::TokenStatic) {
+                self.static_field(class_index);
+            } else {
+                self.method();
+            }
         }
         self.consume(TokenType::TokenRightBrace, "Expected '}' after class body");
 
         self.current_class = old_class;
     }
 
+    /// `static count = 0;` in a class body sets the field on the class itself when the declaration runs, nil if there's no value. The value is
+    /// worked out in a function of its own that belongs to the class, so that it can use the private properties of the class
+    fn static_field(&mut self, class_index: usize) {
+        self.consume(TokenType::TokenIdentifier, "Expected static field name");
+        let name = self.previous().lexemme.clone();
+        let name_index = self.identifier_constant(&name);
+
+        let index = self.start_child(FunctionType::Function);
+        self.resolver.begin_scope();
+        self.emit_instr(OpCode::OpClass(class_index));
+        if self.match_cur(TokenType::TokenEqual) {
+            self.expression();
+        } else {
+            self.emit_instr(OpCode::OpNil);
+        }
+        self.consume(TokenType::TokenSemicolon, "Expected ';' after static field");
+        self.emit_instrs(&[OpCode::OpSetProperty(name_index), OpCode::OpReturn]);
+
+        let (upvalues, _) = self.resolver.pop();
+        let has_upvalues = !upvalues.is_empty();
+        if has_upvalues {
+            self.current_fn().set_upvalues(upvalues);
+        }
+        self.end_child();
+
+        self.emit_constant(Value::LoxFunction(index));
+        if has_upvalues {
+            self.emit_instr(OpCode::OpClosure);
+        }
+        self.emit_instrs(&[OpCode::OpCall(0), OpCode::OpPop]);
+    }
+
+    /// `extend Point { ... }` compiles the methods into a class of their own, then emits an OpExtend that adds them to the class the name holds
+    /// when the statement runs. So it works on classes from other modules, and instances that already exist get the methods too
+    fn extend_declaration(&mut self) {
+        self.consume(
+            TokenType::TokenIdentifier,
+            "Expected class name after keyword 'extend'",
+        );
+        self.variable(false);
+        let name = self.previous().lexemme.clone(); // The class of `extend module::Class` is named after the part after the '::'
+
+        // Named so that it can't be found as a superclass
+        self.classes
+            .push(ClassChunk::new(format!("extend {}", name)));
+        let class_index = self.classes.len() - 1;
+        self.emit_instr(OpCode::OpExtend(class_index)); // The methods are only looked at once it runs, so it can go before them
+        let old_class = self.current_class;
+        self.current_class = Some(class_index);
+
+        self.consume(TokenType::TokenLeftBrace, "Expected '{' before extend body");
+        while !self.check(TokenType::TokenRightBrace) && !self.check(TokenType::TokenEOF) {
+            if self.check(TokenType::TokenIdentifier) && self.current().lexemme == "init" {
+                let token = self.current().clone();
+                self.error_at(&token, "Cannot add an initializer to a class with extend");
+            }
+            self.method();
+        }
+        self.consume(TokenType::TokenRightBrace, "Expected '}' after extend body");
+
+        self.current_class = old_class;
+    }
+
+    fn inherit(&mut self, class: usize, superclass: usize) {
+        for (name_index, fn_index) in self.classes[superclass].methods.clone().iter() {
+            // Inherit all the methods by just copying in all the fn_indices, nicely handles multiple levels of inheritence
+            // Methods the class already has are its own overrides, or were copied in from the superclass before
+            self.classes[class]
+                .methods
+                .entry(*name_index)
+                .or_insert(*fn_index);
+            let name = self.identifier_constants[*name_index].clone();
+            if name.as_str().eq("init") {
+                self.classes[class].has_init = true;
+            }
+        }
+        self.classes[class].superclass = Some(superclass);
+
+        // The superclass's fields keep their slots, so that its methods find them in the same place
+        let mut fields = self.classes[superclass].fields.clone();
+        for name in std::mem::take(&mut self.classes[class].fields) {
+            if !fields.contains(&name) {
+                fields.push(name);
+            }
+        }
+        self.classes[class].fields = fields;
+    }
+
+    /// Finds the superclasses that weren't defined yet when the classes inheriting them were, then copies the methods down every chain of
+    /// superclasses starting from the top, since a class that was inherited straight away may have only just got its own superclass
+    fn resolve_superclasses(&mut self) {
+        if self.pending_superclasses.is_empty() {
+            return;
+        }
+
+        let pending_superclasses = std::mem::take(&mut self.pending_superclasses);
+        for pending in pending_superclasses.iter() {
+            // The last class with the name, like when it's found straight away
+            match self
+                .classes
+                .iter()
+                .rposition(|class| class.name == pending.name.lexemme)
+            {
+                Some(i) => {
+                    self.classes[pending.class].superclass = Some(i);
+                    if let Some(constant) = pending.constant {
+                        self.constants[constant] = Value::LoxClass(i);
+                    }
+                }
+                None => {
+                    self.panic_mode = false;
+                    self.error_at(
+                        &pending.name,
+                        format!("'{}' is not a valid superclass", pending.name.lexemme).as_str(),
+                    );
+                }
+            }
+        }
+
+        let mut done = vec![false; self.classes.len()];
+        for class in 0..self.classes.len() {
+            let mut chain = Vec::new();
+            let mut next = Some(class);
+            while let Some(i) = next.filter(|i| !done[*i]) {
+                if let Some(start) = chain.iter().position(|c| *c == i) {
+                    // Every cycle goes through a class whose superclass was defined after it
+                    let cycle = &chain[start..];
+                    let pending = pending_superclasses
+                        .iter()
+                        .find(|pending| cycle.contains(&pending.class))
+                        .unwrap();
+                    self.panic_mode = false;
+                    self.error_at(&pending.name, "A class cannot inherit from itself");
+                    return;
+                }
+                chain.push(i);
+                next = self.classes[i].superclass;
+            }
+            // From the class nearest the top, so its superclass already has every method it's going to
+            for &i in chain.iter().rev() {
+                if let Some(superclass) = self.classes[i].superclass {
+                    self.inherit(i, superclass);
+                }
+                done[i] = true;
+            }
+        }
+    }
+
     // Note: Since this constantly confuses me, I'm gonna keep a note here so that I don't forget how variables work in rlox
     // Globals: The opcodes GetGlobal and SetGlobal take a LoxString from the constants vec and map it into a HashMap in the VM, no resolving/checking is done before runtime
     // Locals: Local variables live on the stack and since they are the ONLY values that do not get popped after statements, we know that they must live at the very bottom of the stack,
     // and thus we can just raw index from the bottom of the stack to the index of the variable by looking at how many locals have been defined in this scope
     fn var_declaration(&mut self) {
+        if self.match_cur(TokenType::TokenLeftParen) {
+            self.destructuring_declaration();
+            return;
+        }
         let global = self.parse_variable("Expected variable name");
+        self.add_symbol(SymbolKind::Variable);
+        self.var_initializer(global);
+    }
+
+    /// `var (a, b) = value;` after the '(', which unpacks a tuple of exactly that many elements into the variables
+    ///
+    /// The names are only declared once the value has been compiled, so the value can't refer to them
+    fn destructuring_declaration(&mut self) {
+        let mut names = Vec::new();
+        loop {
+            self.consume(TokenType::TokenIdentifier, "Expected variable name");
+            self.add_symbol(SymbolKind::Variable);
+            names.push(self.previous().clone());
+            if !self.match_cur(TokenType::TokenComma) || self.check(TokenType::TokenRightParen) {
+                break;
+            }
+        }
+        self.consume(
+            TokenType::TokenRightParen,
+            "Expected ')' after variable names",
+        );
+        self.consume(TokenType::TokenEqual, "Expected '=' after variable names");
+        self.expression();
+        self.consume(
+            TokenType::TokenSemicolon,
+            "Expected ';' after variable declaration",
+        );
+        self.emit_instr(OpCode::OpUnpack(names.len()));
+
+        if self.resolver.is_global() {
+            // The last element is on top of the stack
+            for name in names.iter().rev() {
+                let global = self.identifier_constant(&name.lexemme);
+                self.define_variable(global);
+            }
+        } else {
+            for name in names {
+                self.declare_local(name);
+                self.resolver.mark_initialized();
+            }
+        }
+    }
+
+    /// The rest of a var declaration after the name
+    fn var_initializer(&mut self, global: usize) {
         if self.match_cur(TokenType::TokenEqual) {
             self.expression();
         } else {
@@ -410,6 +986,11 @@ impl Compiler<'_> {
     /// Calls declare_variable() if the current scope is local
     fn parse_variable(&mut self, error_msg: &str) -> usize {
         self.consume(TokenType::TokenIdentifier, error_msg);
+        self.declare_parsed_variable()
+    }
+
+    /// parse_variable() for when the identifier has already been consumed
+    fn declare_parsed_variable(&mut self) -> usize {
         self.declare_variable();
 
         if self.resolver.is_global() {
@@ -438,6 +1019,12 @@ impl Compiler<'_> {
     /// or to set the local variable as initialized
     fn define_variable(&mut self, global: usize) {
         if self.resolver.is_global() {
+            let visibility = if self.exporting {
+                Visibility::Public
+            } else {
+                Visibility::Private
+            };
+            self.globals.insert(global, visibility);
             self.emit_instr(OpCode::OpDefineGlobal(global));
         } else {
             self.resolver.mark_initialized();
@@ -445,6 +1032,9 @@ impl Compiler<'_> {
     }
 
     fn statement(&mut self) {
+        if !self.nest() {
+            return;
+        }
         if self.match_cur(TokenType::TokenPrint) {
             self.print_statement();
         } else if self.match_cur(TokenType::TokenReturn) {
@@ -459,89 +1049,408 @@ impl Compiler<'_> {
             self.resolver.begin_scope();
             self.block();
             self.end_scope();
-        } else if self.match_cur(TokenType::TokenAwait) {
-            self.await_statement();
         } else if self.match_cur(TokenType::TokenUse) {
             self.import_statement();
+        } else if self.match_cur(TokenType::TokenDelete) {
+            self.delete_statement();
         } else {
             self.expression_statement();
         }
+        self.nesting -= 1;
     }
 
+    /// Compiles the module file, merges it into this compilation and emits a call to the module's top level code
+    ///
+    /// Globals exported by the module are bound as `module::name`, unless it's a selective import (`use "math"::{sin_deg, Vec2};`).
+    /// That binds the requested names under their own name and nothing else, the rest of the module is bound privately
+    ///
+    /// A module that doesn't `export` anything exports all of its globals, so that modules written before `export` existed keep working.
+    /// Once it exports one of them, accessing any of the others, as `module::name` or selectively, is a compile error
     fn import_statement(&mut self) {
         self.consume(
             TokenType::TokenString,
             "Expected module path after keyword 'use'",
         );
-        // println!("curr {:#?}", self.current());
         let name = self.previous().lexemme.clone();
         let name = name[1..name.len() - 1].to_string();
-        let binding = name.clone() + ".lox";
-        let path = Path::new(&binding);
-        let mut file = match File::open(path) {
-            Ok(file) => file,
-            Err(why) => {
-                eprintln!("Failed to open {}: {}", path.display(), why);
-                exit(1);
+        if let Some(path) = name.strip_prefix("native:") {
+            self.native_import_statement(path.to_string());
+            return;
+        }
+
+        if !self.resolve_imports {
+            if self.match_cur(TokenType::TokenModuleAccess) {
+                self.consume(
+                    TokenType::TokenLeftBrace,
+                    "Expected '{' after '::' in import",
+                );
+                loop {
+                    self.consume(TokenType::TokenIdentifier, "Expected name to import");
+                    if !self.match_cur(TokenType::TokenComma) {
+                        break;
+                    }
+                }
+                self.consume(
+                    TokenType::TokenRightBrace,
+                    "Expected '}' after imported names",
+                );
             }
+            self.consume(TokenType::TokenSemicolon, "Expected ';' after import");
+            return;
+        }
+
+        let module_name = match Path::new(&name).file_stem() {
+            Some(stem) => stem.to_string_lossy().to_string(),
+            None => name.clone(),
+        };
+        let compile_result = match self.load_module(&name, &module_name) {
+            Some(result) => result,
+            None => return,
         };
 
-        let mut s = String::new();
-        match file.read_to_string(&mut s) {
-            Ok(_) => {
-                let mut compiler = Compiler::new(&s, self.quiet_mode);
-                let mut compile_result = compiler.compile(self.quiet_mode).unwrap();
-                // constants: Vec<Value>,
-                // identifier_constants: Vec<String>,
-
-                // classes: Vec<ClassChunk>,
-                // current_class: Option<usize>,
-
-                // functions: Vec<FunctionChunk>,
-                // current_function: usize,      // The current FunctionChunk
-                // parent_functions: Vec<usize>
-                let mut identifier_constants: Vec<String> = Vec::new();
-                compile_result
-                    .identifier_constants
-                    .clone()
-                    .into_iter()
-                    .map(|c| {
-                        // println!("{:#?}", (name.clone() + "::" + &c).to_string());
-                        identifier_constants.push((name.clone() + "::" + &c).to_string());
-                        self.resolver.stack[0].add_local((name.clone() + "::" + &c).to_string());
-                        // println!("{:#?}", self.resolver.clone());
-                        // self.emit_instr(OpCode::OpDefineGlobal(global))
-                    })
-                    .collect::<Vec<_>>();
-                // println!("ids {:#?}", identifier_constants.clone());
-                // println!("Added name: {:#?}", name);
-                // println!(
-                //     "Compile res: {:#?} ",
-                //     compile_result.identifier_constants.clone()
-                // );
-                self.constants.append(&mut compile_result.constants);
-                self.identifier_constants.append(&mut identifier_constants);
-                compile_result
+        // Private globals are renamed to something that can't be written in Lox source, so that they can neither be accessed nor collide with the importer's globals
+        let selective = self.check(TokenType::TokenModuleAccess);
+        let mut bindings: HashMap<String, String> = HashMap::new();
+        for (index, visibility) in compile_result.globals.iter() {
+            let global = compile_result.identifier_constants[*index].clone();
+            let bound = match visibility {
+                Visibility::Public if !selective => format!("{}::{}", module_name, global),
+                _ => format!("{}#{}", module_name, global),
+            };
+            if *visibility == Visibility::Private && !selective {
+                self.unexported
+                    .insert(format!("{}::{}", module_name, global));
+            }
+            bindings.insert(global, bound);
+        }
+
+        if self.match_cur(TokenType::TokenModuleAccess) {
+            self.consume(
+                TokenType::TokenLeftBrace,
+                "Expected '{' after '::' in import",
+            );
+            loop {
+                self.consume(TokenType::TokenIdentifier, "Expected name to import");
+                let imported = self.previous().lexemme.clone();
+                let index = compile_result
                     .identifier_constants
-                    .clone()
-                    .into_iter()
-                    .map(|c| {
-                        // println!("{:#?}", (name.clone() + "::" + &c).to_string());
-                        let iconst = self.identifier_constant(&(name.clone() + "::" + &c).to_string());
-                        self.emit_instr(OpCode::OpDefineGlobal(
-                            iconst,
-                        ))
-                    })
-                    .collect::<Vec<_>>();
-                self.classes.append(&mut compile_result.classes);
-                self.functions.append(&mut compile_result.functions);
-                // println!("self res: {:#?} ", self.identifier_constants.clone());
+                    .iter()
+                    .position(|x| x == &imported);
+                match index.and_then(|i| compile_result.globals.get(&i)) {
+                    Some(Visibility::Public) => {
+                        bindings.insert(imported.clone(), imported);
+                    }
+                    Some(Visibility::Private) => self.error(
+                        format!("'{}' is not exported by module '{}'", imported, module_name)
+                            .as_str(),
+                    ),
+                    None => self.error(
+                        format!("Module '{}' has no member '{}'", module_name, imported).as_str(),
+                    ),
+                }
+
+                if !self.match_cur(TokenType::TokenComma) {
+                    break;
+                }
+            }
+            self.consume(
+                TokenType::TokenRightBrace,
+                "Expected '}' after imported names",
+            );
+        }
+        self.consume(TokenType::TokenSemicolon, "Expected ';' after import");
+
+        let script = self.merge_module(compile_result, &bindings, &format!("{}.lox", name));
+        self.emit_constant(Value::LoxFunction(script));
+        self.emit_instrs(&[OpCode::OpCall(0), OpCode::OpPop]);
+    }
+
+    /// Emits an OpLoadNative for `use "native:path";`, the native module's functions only get bound once the VM has loaded the library
+    fn native_import_statement(&mut self, path: String) {
+        if self.match_cur(TokenType::TokenModuleAccess) {
+            self.error("Selective imports are not supported for native modules");
+            return;
+        }
+        self.consume(TokenType::TokenSemicolon, "Expected ';' after import");
+
+        let path = self.strings.intern(&path);
+        let index = self.add_constant(Value::LoxString(path));
+        self.emit_instr(OpCode::OpLoadNative(index));
+    }
+
+    /// Loads the module at path from the SourceProvider, compiling it unless it's already compiled
+    ///
+    /// Reports the error and returns None if the module can't be loaded or compiled
+    fn load_module(&mut self, path: &str, module_name: &str) -> Option<CompilationResult> {
+        let source = match self.sources.load(path) {
+            Ok(ModuleSource::Source(source)) => source,
+            Ok(ModuleSource::Compiled(bytes)) => {
+                return match bytecode::deserialize(&bytes) {
+                    Ok(result) => Some(result),
+                    Err(why) => {
+                        self.error(
+                            format!("Failed to load compiled module {}: {}", path, why).as_str(),
+                        );
+                        None
+                    }
+                };
             }
             Err(why) => {
-                eprintln!("Failed to read {}: {}", path.display(), why);
-                exit(1);
+                self.error(&why);
+                return None;
             }
         };
+
+        if self.importing.iter().any(|importing| importing == path) {
+            self.error(format!("Module '{}' imports itself", module_name).as_str());
+            return None;
+        }
+
+        let source_path = format!("{}.lox", path);
+        let mut compiler = Compiler::new(&source);
+        compiler.importing = self.importing.clone();
+        compiler.importing.push(path.to_string());
+        compiler.set_warnings(self.warnings);
+        compiler.set_sources(self.sources.clone());
+        compiler.set_source_map(self.source_map.is_some());
+        let (result, diagnostics) = compiler.compile(false);
+        for mut diagnostic in diagnostics {
+            diagnostic.file.get_or_insert_with(|| source_path.clone());
+            self.diagnostics.push(diagnostic);
+        }
+        if result.is_none() {
+            self.error(format!("Failed to compile module '{}'", module_name).as_str());
+        }
+        result
+    }
+
+    /// Appends a compiled module onto this compilation, rebasing every function, class, constant and identifier index it uses
+    ///
+    /// Global names found in bindings are renamed, everything else (properties, methods, natives) keeps its name
+    ///
+    /// A module that's imported again with the same bindings, directly or by another module, compiles to the same chunks as the first time. Those
+    /// aren't appended again, the code of the new import uses the ones already there instead
+    ///
+    /// Returns the index of the FunctionChunk holding the module's top level code
+    fn merge_module(
+        &mut self,
+        module: CompilationResult,
+        bindings: &HashMap<String, String>,
+        source_path: &str,
+    ) -> usize {
+        let names: Vec<usize> = module
+            .identifier_constants
+            .iter()
+            .map(|name| self.identifier_constant(name))
+            .collect();
+        let globals: Vec<usize> = module
+            .identifier_constants
+            .iter()
+            .map(|name| match bindings.get(name) {
+                Some(bound) => self.identifier_constant(bound),
+                None => self.identifier_constant(name),
+            })
+            .collect();
+
+        // The whole module first, so that the modules it imports only get looked at when it can't be shared as a whole
+        let whole = MergedModule {
+            path: source_path.to_string(),
+            functions: (0..module.functions.len()).collect(),
+            classes: (0..module.classes.len()).collect(),
+        };
+        let mut shared_fns: Vec<Option<usize>> = vec![None; module.functions.len()];
+        let mut shared_classes: Vec<Option<usize>> = vec![None; module.classes.len()];
+        for candidate in std::iter::once(&whole).chain(module.modules.iter()) {
+            if candidate.functions.iter().all(|i| shared_fns[*i].is_some()) {
+                continue;
+            }
+            let Some(existing) = self
+                .modules
+                .iter()
+                .find(|existing| self.same_module(&module, candidate, existing, &names, &globals))
+            else {
+                continue;
+            };
+            for (i, j) in candidate.functions.iter().zip(existing.functions.iter()) {
+                shared_fns[*i].get_or_insert(*j);
+            }
+            for (i, j) in candidate.classes.iter().zip(existing.classes.iter()) {
+                shared_classes[*i].get_or_insert(*j);
+            }
+        }
+        if let Some(script) = shared_fns[0] {
+            return script;
+        }
+
+        // What isn't shared is appended in the same order as before
+        let fn_offset = self.functions.len();
+        let class_offset = self.classes.len();
+        let fn_map = appended_indices(&shared_fns, fn_offset);
+        let class_map = appended_indices(&shared_classes, class_offset);
+        let appended = |i: &usize| fn_map[*i] >= fn_offset;
+
+        if let Some(source_map) = &mut self.source_map {
+            let columns = module.source_map.map(|other| SourceMap {
+                columns: (other.columns.into_iter().enumerate())
+                    .filter(|(i, _)| appended(i))
+                    .map(|(_, columns)| columns)
+                    .collect(),
+            });
+            let count = (0..fn_map.len()).filter(appended).count();
+            source_map.append(fn_offset, count, columns);
+        }
+
+        let constants: Vec<usize> = module
+            .constants
+            .into_iter()
+            .map(|value| match value {
+                Value::LoxFunction(i) => self.add_constant(Value::LoxFunction(fn_map[i])),
+                Value::LoxClass(i) => self.add_constant(Value::LoxClass(class_map[i])),
+                Value::LoxString(s) => {
+                    let s = self.strings.intern(&s); // The module was interned into its own table
+                    self.add_constant(Value::LoxString(s))
+                }
+                value => self.add_constant(value),
+            })
+            .collect();
+
+        for (i, mut function) in module.functions.into_iter().enumerate() {
+            if !appended(&i) {
+                continue;
+            }
+            function.class = function.class.map(|class| class_map[class]);
+            for instr in function.chunk.code.iter_mut() {
+                instr.op_code = rebase(
+                    instr.op_code,
+                    &names,
+                    &globals,
+                    |i| Some(constants[i]),
+                    |i| Some(class_map[i]),
+                )
+                .unwrap();
+            }
+            self.functions.push(function);
+        }
+
+        self.module_functions
+            .push((source_path.to_string(), fn_offset..self.functions.len()));
+        for (path, range) in module.module_functions {
+            // The functions of a nested import that are still appended stay together, the shared ones are already in a range of their own
+            let mut kept = range.map(|i| fn_map[i]).filter(|i| *i >= fn_offset);
+            if let Some(start) = kept.next() {
+                let end = kept.next_back().unwrap_or(start) + 1;
+                self.module_functions.push((path, start..end));
+            }
+        }
+
+        for (i, mut class) in module.classes.into_iter().enumerate() {
+            if class_map[i] < class_offset {
+                continue;
+            }
+            class.methods = class
+                .methods
+                .into_iter()
+                .map(|(name, fn_index)| (names[name], fn_map[fn_index]))
+                .collect();
+            class.superclass = class.superclass.map(|i| class_map[i]);
+            class.fields = class.fields.into_iter().map(|name| names[name]).collect();
+            self.classes.push(class);
+        }
+
+        for merged in std::iter::once(whole).chain(module.modules) {
+            self.modules.push(MergedModule {
+                path: merged.path,
+                functions: merged.functions.into_iter().map(|i| fn_map[i]).collect(),
+                classes: merged.classes.into_iter().map(|i| class_map[i]).collect(),
+            });
+        }
+
+        fn_offset
+    }
+
+    /// Whether candidate, one of the modules merged into module, would compile to exactly the functions and classes of the existing module
+    /// once it's rebased. References to its own functions and classes have to point at the corresponding ones of existing
+    fn same_module(
+        &self,
+        module: &CompilationResult,
+        candidate: &MergedModule,
+        existing: &MergedModule,
+        names: &[usize],
+        globals: &[usize],
+    ) -> bool {
+        if candidate.path != existing.path
+            || candidate.functions.len() != existing.functions.len()
+            || candidate.classes.len() != existing.classes.len()
+        {
+            return false;
+        }
+        let function_of = |i: usize| {
+            let position = candidate.functions.iter().position(|x| *x == i)?;
+            Some(existing.functions[position])
+        };
+        let class_of = |i: usize| {
+            let position = candidate.classes.iter().position(|x| *x == i)?;
+            Some(existing.classes[position])
+        };
+        let same_constant = |i: usize, j: usize| {
+            let value = match &module.constants[i] {
+                Value::LoxFunction(f) => function_of(*f).map(Value::LoxFunction),
+                Value::LoxClass(c) => class_of(*c).map(Value::LoxClass),
+                value => Some(value.clone()),
+            };
+            value.as_ref() == Some(&self.constants[j])
+        };
+
+        let same_functions = candidate.functions.iter().zip(existing.functions.iter());
+        for (new, old) in same_functions.map(|(i, j)| (&module.functions[*i], &self.functions[*j]))
+        {
+            if new.name != old.name
+                || new.arity != old.arity
+                || new.fn_type != old.fn_type
+                || new.is_async != old.is_async
+                || new.upvalues != old.upvalues
+                || new.class.map(class_of) != old.class.map(Some)
+                || new.chunk.code.len() != old.chunk.code.len()
+            {
+                return false;
+            }
+            let same_code = new
+                .chunk
+                .code
+                .iter()
+                .zip(old.chunk.code.iter())
+                .all(|(a, b)| {
+                    a.line_num == b.line_num
+                        && match (a.op_code, b.op_code) {
+                            (OpCode::OpConstant(i), OpCode::OpConstant(j)) => same_constant(i, j),
+                            (op_code, other) => {
+                                rebase(op_code, names, globals, |_| None, class_of) == Some(other)
+                            }
+                        }
+                });
+            if !same_code {
+                return false;
+            }
+        }
+
+        let same_classes = candidate.classes.iter().zip(existing.classes.iter());
+        same_classes
+            .map(|(i, j)| (&module.classes[*i], &self.classes[*j]))
+            .all(|(new, old)| {
+                new.name == old.name
+                    && new.has_init == old.has_init
+                    && new.superclass.map(class_of) == old.superclass.map(Some)
+                    && new
+                        .fields
+                        .iter()
+                        .map(|name| names[*name])
+                        .eq(old.fields.iter().copied())
+                    && new.methods.len() == old.methods.len()
+                    && new.methods.iter().all(|(name, function)| {
+                        function_of(*function).is_some()
+                            && old.methods.get(&names[*name]).copied() == function_of(*function)
+                    })
+            })
     }
 
     fn print_statement(&mut self) {
@@ -553,6 +1462,27 @@ impl Compiler<'_> {
         self.emit_instr(OpCode::OpPrint);
     }
 
+    /// `delete x.field;`, which is compiled like the property access and then has its last instruction swapped for an OpDeleteProperty
+    fn delete_statement(&mut self) {
+        self.parse_precedence(Precedence::PrecCall);
+        let last = self.current_chunk().code.last_mut();
+        match last {
+            Some(Instr {
+                op_code: op_code @ (OpCode::OpGetProperty(_) | OpCode::OpGetField(..)),
+                ..
+            }) => {
+                if let OpCode::OpGetProperty(name) | OpCode::OpGetField(name, _) = *op_code {
+                    *op_code = OpCode::OpDeleteProperty(name);
+                }
+            }
+            _ => self.error("Can only delete a property"),
+        }
+        self.consume(
+            TokenType::TokenSemicolon,
+            "Expected ';' after property in delete statement",
+        );
+    }
+
     fn return_statement(&mut self) {
         if self.current_fn_type() == FunctionType::Script {
             self.error("Cannot return from top-level code");
@@ -572,15 +1502,6 @@ impl Compiler<'_> {
         }
     }
 
-    fn await_statement(&mut self) {
-        self.expression();
-        self.consume(
-            TokenType::TokenSemicolon,
-            "Expected ';' after value in await statement",
-        );
-        self.emit_instr(OpCode::OpAwait);
-    }
-
     fn if_statement(&mut self) {
         self.consume(TokenType::TokenLeftParen, "Expected '(' after 'if'");
         self.expression();
@@ -632,7 +1553,14 @@ impl Compiler<'_> {
         if self.match_cur(TokenType::TokenSemicolon) {
             // Do nothing
         } else if self.match_cur(TokenType::TokenVar) {
-            self.var_declaration();
+            self.consume(TokenType::TokenIdentifier, "Expected variable name");
+            // `in` isn't a keyword, so it's only special right here
+            if self.check(TokenType::TokenIdentifier) && self.current().lexemme == "in" {
+                self.for_in_statement();
+                return;
+            }
+            let global = self.declare_parsed_variable();
+            self.var_initializer(global);
         } else {
             self.expression_statement(); //
         }
@@ -680,8 +1608,88 @@ impl Compiler<'_> {
         self.end_scope();
     }
 
+    /// Compiles `for (var x in value) body` after the `x`, as a loop over a copy of value made by the __iterate native
+    ///
+    /// The copy and the loop counter live in two locals that can't be named from Lox, followed by x which is reassigned every iteration
+    fn for_in_statement(&mut self) {
+        let name = self.previous().clone();
+        self.advance(); // in
+
+        let iterate = self.identifier_constant(&String::from("__iterate"));
+        self.emit_instr(OpCode::OpGetGlobal(iterate));
+        self.expression();
+        self.emit_instr(OpCode::OpCall(1));
+        self.consume(
+            TokenType::TokenRightParen,
+            "Expected ')' after for-in value",
+        );
+        self.resolver
+            .declare_variable(String::from("(for-in values)"));
+        self.resolver.mark_initialized();
+        let values = self.resolver.last_local_slot();
+
+        let zero = self.add_constant(Value::Int(0));
+        self.emit_instr(OpCode::OpConstant(zero));
+        self.resolver
+            .declare_variable(String::from("(for-in index)"));
+        self.resolver.mark_initialized();
+        let index = self.resolver.last_local_slot();
+
+        self.emit_instr(OpCode::OpNil);
+        self.declare_local(name);
+        self.resolver.mark_initialized();
+        let variable = self.resolver.last_local_slot();
+
+        // while (index < __array_len(values))
+        let loop_start = self.current_chunk().code.len();
+        let array_len = self.identifier_constant(&String::from("__array_len"));
+        self.emit_instrs(&[
+            OpCode::OpGetLocal(index),
+            OpCode::OpGetGlobal(array_len),
+            OpCode::OpGetLocal(values),
+            OpCode::OpCall(1),
+            OpCode::OpLess,
+        ]);
+        let exit_jump = self.emit_jif();
+        self.emit_instr(OpCode::OpPop);
+
+        // x = __array_index_get(index, values)
+        let index_get = self.identifier_constant(&String::from("__array_index_get"));
+        self.emit_instrs(&[
+            OpCode::OpGetGlobal(index_get),
+            OpCode::OpGetLocal(index),
+            OpCode::OpGetLocal(values),
+            OpCode::OpCall(2),
+            OpCode::OpSetLocal(variable),
+            OpCode::OpPop,
+        ]);
+
+        self.statement();
+
+        let one = self.add_constant(Value::Int(1));
+        self.emit_instrs(&[
+            OpCode::OpGetLocal(index),
+            OpCode::OpConstant(one),
+            OpCode::OpAdd,
+            OpCode::OpSetLocal(index),
+            OpCode::OpPop,
+        ]);
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_instr(OpCode::OpPop);
+        self.end_scope();
+    }
+
     fn block(&mut self) {
+        let mut returned = false;
         while !self.check(TokenType::TokenRightBrace) && !self.check(TokenType::TokenEOF) {
+            if returned {
+                // Once, at the first statement after the return
+                let token = self.current().clone();
+                self.warn_at(&token, "unreachable_code", "Unreachable code after return");
+            }
+            returned = self.check(TokenType::TokenReturn);
             self.declaration();
         }
         self.consume(TokenType::TokenRightBrace, "Expected '}' after block"); // Fails if we hit EOF instead
@@ -703,11 +1711,26 @@ impl Compiler<'_> {
             return; // Ideally we would attempt to compile the rest of the expression, but trying to continue will cause a panic
         }
 
-        let superclass_index = if self.current_class().superclass == None {
-            self.error("Cannot use keyword 'super' in a class which does not inherit a class");
-            0 // Random value, we don't care that this value is wrong because we're going to exit because of the error anyway
-        } else {
-            self.current_class().superclass.unwrap()
+        let class = self.current_class.unwrap();
+        let pending = self
+            .pending_superclasses
+            .iter()
+            .position(|pending| pending.class == class);
+        let superclass_constant = match (self.current_class().superclass, pending) {
+            (Some(i), _) => self.add_constant(Value::LoxClass(i)),
+            // Not deduplicated like other constants, since it gets patched to the superclass once that's defined
+            (None, Some(pending)) => match self.pending_superclasses[pending].constant {
+                Some(constant) => constant,
+                None => {
+                    self.constants.push(Value::LoxClass(usize::MAX));
+                    self.pending_superclasses[pending].constant = Some(self.constants.len() - 1);
+                    self.constants.len() - 1
+                }
+            },
+            (None, None) => {
+                self.error("Cannot use keyword 'super' in a class which does not inherit a class");
+                0 // Random value, we don't care that this value is wrong because we're going to exit because of the error anyway
+            }
         };
 
         self.consume(TokenType::TokenDot, "Expected '.' after 'super'");
@@ -715,6 +1738,7 @@ impl Compiler<'_> {
             TokenType::TokenIdentifier,
             "Expected superclass method name",
         );
+        self.add_reference(Some(Target::Property(self.previous().lexemme.clone())));
         let name = self.previous().lexemme.clone();
         let name_index = self.identifier_constant(&name);
 
@@ -722,8 +1746,7 @@ impl Compiler<'_> {
         // 1. The superclass we're going to be looking for values in
         // 2. A pointer to the instance we want to bind the method to
 
-        let superclass_val = Value::LoxClass(superclass_index);
-        self.emit_constant(superclass_val);
+        self.emit_instr(OpCode::OpConstant(superclass_constant));
         self.named_variable(&String::from("this"), false); // Slightly better?
         self.emit_instr(OpCode::OpGetSuper(name_index));
     }
@@ -732,6 +1755,11 @@ impl Compiler<'_> {
         self.consume(TokenType::TokenIdentifier, "Expected method name");
         let name = self.previous().lexemme.clone();
         let name_index = self.identifier_constant(&name);
+        if self.match_cur(TokenType::TokenSemicolon) {
+            self.field_declaration(name_index);
+            return;
+        }
+        self.add_symbol(SymbolKind::Method);
 
         let index = if name.eq("init") {
             self.current_class().has_init = true;
@@ -751,6 +1779,18 @@ impl Compiler<'_> {
         // I swear
     }
 
+    /// `name;` in a class body declares a field, which every instance starts out with as nil. The methods of the class can then get to it
+    /// by slot instead of by name, see this_field_slot
+    fn field_declaration(&mut self, name_index: usize) {
+        if self.current_class().name.starts_with("extend ") {
+            self.error("Cannot declare fields in an extend");
+        }
+        let class = self.current_class();
+        if !class.fields.contains(&name_index) {
+            class.fields.push(name_index);
+        }
+    }
+
     /// Compiles the function into a new FunctionChunk, adds it to the current parser, adds the LoxFunction object to the constants stack, emits a OpConstant pointing to it and a OpClosure to wrap it
     fn function(&mut self, fun_type: FunctionType) -> usize {
         //let mut function_parser = self.from_old(fun_type);
@@ -787,9 +1827,14 @@ impl Compiler<'_> {
             TokenType::TokenLeftBrace,
             "Expected '{' before function body",
         );
-        self.block();
+        // Declarations don't go through statement(), so functions nested in functions have to be counted here
+        if self.nest() {
+            self.block();
+            self.nesting -= 1;
+        }
 
-        let upvalues = self.resolver.pop();
+        let (upvalues, locals) = self.resolver.pop();
+        self.warn_unused(locals);
         let has_upvalues = !upvalues.is_empty();
         if !upvalues.is_empty() {
             self.current_fn().set_upvalues(upvalues); // Gotta set this before end_child() switches what the current_fn is
@@ -845,8 +1890,12 @@ impl Compiler<'_> {
     fn number(&mut self) {
         // We trust that the scanner has given us something that looks like a number (124214.52)
         // BUT the scanner does NOT check the size, so this parse to f64 can still fail due to overflow
+        // Without a fraction it's an Int, unless it's too large for one
 
-        if let Ok(value) = self.previous().lexemme.parse::<f64>() {
+        let lexemme = &self.previous().lexemme;
+        if let Ok(value) = lexemme.parse::<i64>() {
+            self.emit_constant(Value::Int(value));
+        } else if let Ok(value) = lexemme.parse::<f64>() {
             self.emit_constant(Value::Double(value));
         } else {
             self.error(format!("Invalid number").as_str())
@@ -858,15 +1907,13 @@ impl Compiler<'_> {
             TokenType::TokenFalse => self.emit_instr(OpCode::OpFalse),
             TokenType::TokenTrue => self.emit_instr(OpCode::OpTrue),
             TokenType::TokenNil => self.emit_instr(OpCode::OpNil),
-            _ => panic!(
-                "Unreachable state reached, attempted to make a literal out of a non-literal type?"
-            ),
+            _ => self.error("Expected expression"), // Only reachable if the parse rules are wrong
         }
     }
 
     fn string(&mut self) {
         let str_val = self.previous().lexemme.clone();
-        let cleaned = str_val[1..str_val.len() - 1].to_string();
+        let cleaned = self.strings.intern(&str_val[1..str_val.len() - 1]);
 
         self.emit_constant(Value::LoxString(cleaned));
     }
@@ -878,6 +1925,9 @@ impl Compiler<'_> {
     /// Note: Uses named_variable to do all the heavy lifting
     fn variable(&mut self, can_assign: bool) {
         let name = &self.previous().lexemme.clone();
+        if !self.check(TokenType::TokenModuleAccess) {
+            self.add_reference(None); // Names in other modules are left out, since they aren't in this source
+        }
         self.named_variable(name, can_assign)
     }
 
@@ -901,18 +1951,23 @@ impl Compiler<'_> {
                     // println!("more in");
                     if let Some(param) = self.module_access() {
                         param_name = name.clone() + "::" + &param.clone();
+                        if self.unexported.contains(&param_name) {
+                            self.error(
+                                format!("'{}' is not exported by module '{}'", param, name)
+                                    .as_str(),
+                            );
+                        }
                         // println!("name {}", param_name);
-                        if let Some(upvalue_index) = self.resolver.resolve_upvalue(&param_name.clone()) {
+                        if let Some(upvalue_index) =
+                            self.resolver.resolve_upvalue(&param_name.clone())
+                        {
                             // println!("upin");
                             local_arg = Some(upvalue_index)
                         }
-
                     }
                 }
             }
-            Ok(opt) => {
-                local_arg = opt
-            }
+            Ok(opt) => local_arg = opt,
             // Err(e) if opt
             Err(e) => {
                 self.error("Cannot read local variable in its own initializer");
@@ -934,6 +1989,8 @@ impl Compiler<'_> {
             )
         } else {
             let global_arg = self.identifier_constant(&param_name.clone()); // Does NOT check at compile time if this variable can be resolved
+            let token = self.previous().clone();
+            self.global_uses.entry(global_arg).or_insert(token);
 
             if self.match_cur(TokenType::TokenLeftParen) {
                 let arg_count = self.argument_list();
@@ -954,13 +2011,37 @@ impl Compiler<'_> {
             self.expression();
             self.emit_instr(set_op);
         } else {
+            if let OpCode::OpGetLocal(slot) = get_op {
+                self.resolver.mark_read(slot);
+            }
             self.emit_instr(get_op);
         }
     }
 
+    /// A parenthesized expression, or a tuple if there's a comma in it. `()` is the empty tuple and `(x,)` a tuple of one
     fn grouping(&mut self) {
+        if self.match_cur(TokenType::TokenRightParen) {
+            self.emit_instr(OpCode::OpTuple(0));
+            return;
+        }
         self.expression();
-        self.consume(TokenType::TokenRightParen, "Expected ')' after expression");
+        if !self.match_cur(TokenType::TokenComma) {
+            self.consume(TokenType::TokenRightParen, "Expected ')' after expression");
+            return;
+        }
+        let mut count = 1;
+        while !self.check(TokenType::TokenRightParen) && !self.check(TokenType::TokenEOF) {
+            self.expression();
+            count += 1;
+            if !self.match_cur(TokenType::TokenComma) {
+                break;
+            }
+        }
+        self.consume(
+            TokenType::TokenRightParen,
+            "Expected ')' after tuple elements",
+        );
+        self.emit_instr(OpCode::OpTuple(count));
     }
 
     fn unary(&mut self) {
@@ -969,6 +2050,7 @@ impl Compiler<'_> {
         match operator_type {
             TokenType::TokenMinus => self.emit_instr(OpCode::OpNegate),
             TokenType::TokenBang => self.emit_instr(OpCode::OpNot),
+            TokenType::TokenAwait => self.emit_instr(OpCode::OpAwait),
             _ => (), // Error?
         }
     }
@@ -988,9 +2070,9 @@ impl Compiler<'_> {
             TokenType::TokenBangEqual => self.emit_instrs(&[OpCode::OpEqual, OpCode::OpNot]),
             TokenType::TokenEqualEqual => self.emit_instr(OpCode::OpEqual),
             TokenType::TokenGreater => self.emit_instr(OpCode::OpGreater),
-            TokenType::TokenGreaterEqual => self.emit_instrs(&[OpCode::OpLess, OpCode::OpNot]),
+            TokenType::TokenGreaterEqual => self.emit_instr(OpCode::OpGreaterEqual),
             TokenType::TokenLess => self.emit_instr(OpCode::OpLess),
-            TokenType::TokenLessEqual => self.emit_instrs(&[OpCode::OpGreater, OpCode::OpNot]),
+            TokenType::TokenLessEqual => self.emit_instr(OpCode::OpLessEqual),
             _ => (), // error?
         }
     }
@@ -1030,30 +2112,64 @@ impl Compiler<'_> {
             TokenType::TokenIdentifier,
             "Expected property name after '.'",
         );
+        self.add_reference(Some(Target::Property(self.previous().lexemme.clone())));
         let name_index = self.identifier_constant(&self.previous().lexemme.clone());
 
+        let slot = self.this_field_slot(name_index);
+
         if can_assign && self.match_cur(TokenType::TokenEqual) {
             // We check can_assign so that a + b.c = 3 does not invalidly emit a set op
             // Setter
             self.expression();
-            self.emit_instr(OpCode::OpSetProperty(name_index));
+            match slot {
+                Some(slot) => self.emit_instr(OpCode::OpSetField(name_index, slot)),
+                None => self.emit_instr(OpCode::OpSetProperty(name_index)),
+            }
         } else if self.match_cur(TokenType::TokenLeftParen) {
             // A left paren after the initializer will usually mean a method invocation, so compress that into a single OpCode here
             let arg_count = self.argument_list();
             self.emit_instr(OpCode::OpInvoke(name_index, arg_count));
         } else {
-            self.emit_instr(OpCode::OpGetProperty(name_index));
+            match slot {
+                Some(slot) => self.emit_instr(OpCode::OpGetField(name_index, slot)),
+                None => self.emit_instr(OpCode::OpGetProperty(name_index)),
+            }
         }
         // } else {
         //     self.emit_instr(OpCode::OpGetProperty(name_index));
         // }
     }
 
+    /// The slot of the field, when the '.' is right after a `this` in a method of a class that declares it. Instances of subclasses, and
+    /// instances that had fields deleted, can have it somewhere else, which the VM checks for
+    fn this_field_slot(&mut self, name_index: usize) -> Option<usize> {
+        let is_method = matches!(
+            self.current_fn_type(),
+            FunctionType::Method | FunctionType::Initializer
+        );
+        let after_this = matches!(
+            self.current_chunk_ref().code.last(),
+            Some(Instr {
+                op_code: OpCode::OpGetLocal(0),
+                ..
+            })
+        ); // Slot 0 of a method is always this
+        if !is_method || !after_this {
+            return None;
+        }
+        let class = self.current_class?;
+        self.classes[class]
+            .fields
+            .iter()
+            .position(|name| *name == name_index)
+    }
+
     /// Sets the compiler to generate a new function chunk for the next segment of code
     fn start_child(&mut self, function_type: FunctionType) -> usize {
         let function_name = self.previous().lexemme.clone();
         self.functions
             .push(FunctionChunk::new(Some(function_name), 0, function_type));
+        self.functions.last_mut().unwrap().class = self.current_class;
         self.resolver.push(function_type);
         self.parent_functions.push(self.current_function);
         self.current_function = self.functions.len() - 1;
@@ -1063,39 +2179,65 @@ impl Compiler<'_> {
 
     /// Switches the current chunk out of the new function def
     fn end_child(&mut self) {
-        // Emit an implicit nil return if not specified explicity
-        let last_instr = self.current_chunk_ref().code.last();
-        if (last_instr == None) || last_instr.unwrap().op_code != OpCode::OpReturn {
+        // Emit an implicit nil return if not specified explicity, or if a jump skips over the explicit one (ie `if (x) y; else return z;`)
+        let code = &self.current_chunk_ref().code;
+        let jumps_to_end = code
+            .iter()
+            .enumerate()
+            .any(|(i, instr)| jump_target(instr, i) == Some(code.len()));
+        if code
+            .last()
+            .is_none_or(|instr| instr.op_code != OpCode::OpReturn)
+            || jumps_to_end
+        {
             self.emit_return();
         }
         self.current_function = self.parent_functions.pop().unwrap();
     }
 
-    pub fn new<'a>(code: &'a String, quiet: bool) -> Compiler<'a> {
+    pub fn new(code: &str) -> Compiler<'_> {
         let mut scanner = Scanner::new(code);
 
-        let mut tokens = Vec::new();
-        let first_token = scanner.scan_token();
-        tokens.push(first_token.clone()); // Load up the first token
+        let first_token = scanner.scan_token(); // Load up the first token
 
         let mut functions = Vec::new();
         functions.push(FunctionChunk::new(None, 0, FunctionType::Script)); // Start the compilation with a top level function
 
         let mut compiler = Compiler {
             scanner,
-            tokens,
+            previous: first_token.clone(), // Nothing reads it before the first advance()
+            current: first_token.clone(),
             constants: Vec::new(),
             identifier_constants: Vec::new(),
+            module_functions: Vec::new(),
+            modules: Vec::new(),
+            source_map: None,
+            symbols: None,
+            strings: Interner::new(),
 
             classes: Vec::new(),
             current_class: None,
+            pending_superclasses: Vec::new(),
             functions,
             current_function: 0,
             parent_functions: Vec::new(),
             resolver: Resolver::new(),
+            globals: HashMap::new(),
+            global_uses: HashMap::new(),
+            exporting: false,
+            warn_undefined_globals: false,
+            strict: false,
+            warnings: false,
+            deny_warnings: false,
+            diagnostics: Vec::new(),
+            host_globals: Vec::new(),
+            sources: default_sources(),
+            importing: Vec::new(),
+            resolve_imports: true,
+            unexported: HashSet::new(),
+            nesting: 0,
             had_error: false,
             panic_mode: false,
-            quiet_mode: quiet,
         };
 
         // Hack to account for the case where the first token is a TokenError
@@ -1107,14 +2249,201 @@ impl Compiler<'_> {
         compiler
     }
 
+    /// Compiles code as more of a program that has already started, for Vm::eval. The tables are the program's, so every index it refers to
+    /// stays valid and new entries go on the end, with the top level code becoming a new script function after the program's functions
+    pub fn continuing(
+        code: &str,
+        functions: Vec<FunctionChunk>,
+        classes: Vec<ClassChunk>,
+        constants: Vec<Value>,
+        identifiers: Vec<String>,
+    ) -> Compiler<'_> {
+        let mut compiler = Compiler::new(code);
+        compiler.functions = functions;
+        compiler
+            .functions
+            .push(FunctionChunk::new(None, 0, FunctionType::Script));
+        compiler.current_function = compiler.functions.len() - 1;
+        compiler.classes = classes;
+        compiler.constants = constants;
+        compiler.identifier_constants = identifiers;
+        compiler
+    }
+
+    /// Makes compile() warn about globals that are used by the script but never defined anywhere
+    pub fn set_warn_undefined_globals(&mut self, warn: bool) {
+        self.warn_undefined_globals = warn;
+    }
+
+    /// Makes compile() fail if the script uses a global that nothing defines, which would otherwise only be an error once the code using it runs
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Makes compile() warn about locals that are never read, code after a return and locals that shadow the locals of an outer scope
+    pub fn set_warnings(&mut self, warn: bool) {
+        self.warnings = warn;
+    }
+
+    /// Makes every warning an error, so the source doesn't compile while it has any
+    pub fn set_deny_warnings(&mut self, deny: bool) {
+        self.deny_warnings = deny;
+    }
+
+    /// Makes compile() keep the column of every instruction, for runtime errors and the disassembler
+    pub fn set_source_map(&mut self, keep: bool) {
+        self.source_map = keep.then(SourceMap::default);
+    }
+
+    /// Makes `use` load modules from sources instead of from files
+    pub fn set_sources(&mut self, sources: Rc<dyn SourceProvider>) {
+        self.sources = sources;
+    }
+
+    /// Without resolving imports, `use` is only checked for its syntax, for when the source is compiled to see whether it parses rather than to run it
+    pub fn set_resolve_imports(&mut self, resolve: bool) {
+        self.resolve_imports = resolve;
+    }
+
+    pub fn set_host_globals(&mut self, names: Vec<String>) {
+        self.host_globals = names;
+    }
+
+    /// Warns once for every global that the main script uses without anything defining it, or reports it as an error in strict mode
+    ///
+    /// Code from imported modules isn't checked since we don't know which file its line numbers came from
+    fn check_undefined_globals(&mut self) {
+        let mut defined: HashSet<usize> = HashSet::new();
+        for function in self.functions.iter() {
+            for instr in function.chunk.code.iter() {
+                if let OpCode::OpDefineGlobal(index) = instr.op_code {
+                    defined.insert(index);
+                }
+            }
+        }
+
+        let mut warned: HashSet<usize> = HashSet::new();
+        let mut warnings: Vec<(usize, usize, &String)> = Vec::new();
+        for (fn_index, function) in self.functions.iter().enumerate() {
+            if self
+                .module_functions
+                .iter()
+                .any(|(_, range)| range.contains(&fn_index))
+            {
+                continue;
+            }
+
+            for instr in function.chunk.code.iter() {
+                let index = match instr.op_code {
+                    OpCode::OpGetGlobal(index)
+                    | OpCode::OpSetGlobal(index)
+                    | OpCode::OpCallGlobal(index, _) => index,
+                    _ => continue,
+                };
+                let name = &self.identifier_constants[index];
+                // Natives are bound by the VM, and functions of native modules only once the library is loaded
+                if defined.contains(&index)
+                    || name.contains("::")
+                    || STD_LIB.iter().any(|native| native.name == name)
+                    || STD_CONSTANTS.iter().any(|(constant, _)| constant == name)
+                    || self.host_globals.contains(name)
+                {
+                    continue;
+                }
+                if warned.insert(index) {
+                    warnings.push((instr.line_num, index, name));
+                }
+            }
+        }
+
+        warnings.sort(); // Functions are stored in the order they finished compiling, not in source order
+        let strict = self.strict;
+        let warnings: Vec<Diagnostic> = warnings
+            .into_iter()
+            .map(|(line, index, name)| {
+                let token = self.global_uses.get(&index);
+                Diagnostic {
+                    severity: if strict {
+                        Severity::Error
+                    } else {
+                        Severity::Warning
+                    },
+                    line: token.map_or(line, |token| token.line_num),
+                    column: token.map(|token| token.column),
+                    span: token.map(|token| token.span.clone()),
+                    message: if strict {
+                        format!("Possibly undefined variable '{}'", name)
+                    } else {
+                        format!("Undefined global '{}'", name)
+                    },
+                    at: None,
+                    file: None,
+                    source_line: token.map(|token| self.scanner.line(token.line_num).to_string()),
+                    code: Some("undefined_global"),
+                }
+            })
+            .collect();
+        self.had_error |= strict && !warnings.is_empty();
+        self.diagnostics.extend(warnings);
+    }
+
     // Note: is this an expensive move (moving self into this function) ? Is it less expensive to just move/copy the FunctionChunks afterwards?
-    pub fn compile(mut self, debug: bool) -> Option<CompilationResult> {
+    /// Returns None if the source doesn't compile, along with every error and warning found either way
+    pub fn compile(mut self, debug: bool) -> (Option<CompilationResult>, Vec<Diagnostic>) {
+        self.compile_source(debug);
+        if !self.had_error {
+            let result = CompilationResult {
+                classes: self.classes,
+                functions: self.functions,
+                constants: self.constants,
+                identifier_constants: self.identifier_constants,
+                globals: self.globals,
+                module_functions: self.module_functions,
+                modules: self.modules,
+                source_map: self.source_map,
+                strings: self.strings,
+            };
+            (Some(result), self.diagnostics)
+        } else {
+            (None, self.diagnostics)
+        }
+    }
+
+    /// Compiles the source only for what it declares and what each name in it refers to, along with every error and warning. Errors don't stop
+    /// the symbols from being collected, so an editor still has them while the source is being typed
+    pub fn symbols(mut self) -> (Symbols, Vec<Diagnostic>) {
+        self.symbols = Some(Symbols::default());
+        self.compile_source(false);
+        (self.symbols.unwrap_or_default(), self.diagnostics)
+    }
+
+    fn compile_source(&mut self, debug: bool) {
         while !self.match_cur(TokenType::TokenEOF) {
             self.declaration();
         }
         self.end_compilation();
+        self.resolve_superclasses();
+
+        if (self.warn_undefined_globals || self.strict) && !self.had_error {
+            self.check_undefined_globals();
+        }
+        // Locals are only warned about once their scope ends, after the code inside it
+        self.diagnostics
+            .sort_by(|a, b| (&a.file, a.line, a.column).cmp(&(&b.file, b.line, b.column)));
+        if self.deny_warnings {
+            for diagnostic in self.diagnostics.iter_mut() {
+                if diagnostic.severity == Severity::Warning {
+                    diagnostic.severity = Severity::Error;
+                    self.had_error = true;
+                }
+            }
+        }
 
         if debug {
+            let sources = Sources {
+                module_functions: &self.module_functions,
+                source_map: self.source_map.as_ref(),
+            };
             for (index, fn_chunk) in self.functions.iter().enumerate() {
                 if fn_chunk.fn_type != FunctionType::Method
                     && fn_chunk.fn_type != FunctionType::Initializer
@@ -1124,6 +2453,7 @@ impl Compiler<'_> {
                         &fn_chunk,
                         &self.constants,
                         &self.identifier_constants,
+                        &sources,
                     );
                 }
             }
@@ -1135,26 +2465,70 @@ impl Compiler<'_> {
                     &self.classes,
                     &self.constants,
                     &self.identifier_constants,
+                    &sources,
                 );
             }
         }
 
-        if !self.had_error {
-            Some(CompilationResult {
-                classes: self.classes,
-                functions: self.functions,
-                constants: self.constants,
-                identifier_constants: self.identifier_constants,
-            })
-        } else {
-            None
+        // A module without any 'export' keeps the old behaviour of making everything importable
+        if !self.globals.values().any(|v| *v == Visibility::Public) {
+            for visibility in self.globals.values_mut() {
+                *visibility = Visibility::Public;
+            }
         }
     }
 }
 
+/// Where each function or class of a merged module ends up: the one it's shared with, or the next free index from offset on
+fn appended_indices(shared: &[Option<usize>], offset: usize) -> Vec<usize> {
+    let mut next = offset;
+    shared
+        .iter()
+        .map(|shared| {
+            shared.unwrap_or_else(|| {
+                next += 1;
+                next - 1
+            })
+        })
+        .collect()
+}
+
+/// The instruction of a merged module with its indices moved to where they are in the importer. None if constant or class has no index for it
+fn rebase(
+    op_code: OpCode,
+    names: &[usize],
+    globals: &[usize],
+    constant: impl Fn(usize) -> Option<usize>,
+    class: impl Fn(usize) -> Option<usize>,
+) -> Option<OpCode> {
+    Some(match op_code {
+        OpCode::OpDefineGlobal(i) => OpCode::OpDefineGlobal(globals[i]),
+        OpCode::OpGetGlobal(i) => OpCode::OpGetGlobal(globals[i]),
+        OpCode::OpSetGlobal(i) => OpCode::OpSetGlobal(globals[i]),
+        OpCode::OpCallGlobal(i, arity) => OpCode::OpCallGlobal(globals[i], arity),
+        OpCode::OpGetSuper(i) => OpCode::OpGetSuper(names[i]),
+        OpCode::OpInvoke(i, arity) => OpCode::OpInvoke(names[i], arity),
+        OpCode::OpGetProperty(i) => OpCode::OpGetProperty(names[i]),
+        OpCode::OpGetField(i, slot) => OpCode::OpGetField(names[i], slot),
+        OpCode::OpSetField(i, slot) => OpCode::OpSetField(names[i], slot),
+        OpCode::OpSetProperty(i) => OpCode::OpSetProperty(names[i]),
+        OpCode::OpDeleteProperty(i) => OpCode::OpDeleteProperty(names[i]),
+        OpCode::OpConstant(i) => OpCode::OpConstant(constant(i)?),
+        OpCode::OpClass(i) => OpCode::OpClass(class(i)?),
+        OpCode::OpExtend(i) => OpCode::OpExtend(class(i)?),
+        op_code => op_code,
+    })
+}
+
+#[derive(Clone)]
 pub struct CompilationResult {
     pub classes: Vec<ClassChunk>,
     pub functions: Vec<FunctionChunk>,
     pub constants: Vec<Value>,
     pub identifier_constants: Vec<String>,
+    pub globals: HashMap<usize, Visibility>,
+    pub module_functions: Vec<(String, Range<usize>)>, // Functions outside of every range came from the main script. Ranges of nested imports sit inside their importer's range, unless they're shared with an earlier import
+    pub modules: Vec<MergedModule>, // Empty for modules loaded from bytecode, which isn't a problem since they only end up not being shared
+    pub source_map: Option<SourceMap>,
+    pub strings: Interner,
 }
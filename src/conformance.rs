@@ -0,0 +1,189 @@
+//! What `rlox conformance dir` checks a run against: the expectations written in the comments of a test in the layout of the crafting
+//! interpreters test suite, the same ones tool/bin/test.dart reads. The reference interpreters word their errors slightly differently, so
+//! both sides are normalized before they're compared, and only real differences in behaviour are reported
+use regex::Regex;
+
+use std::collections::HashSet;
+
+/// The output, errors and exit code a test expects
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestExpectations {
+    output: Vec<(String, usize)>, // Each line and the line of the comment expecting it
+    errors: HashSet<String>,      // Compile errors as "[line] Error...", normalized
+    runtime_error: Option<(String, usize)>,
+    exit_code: i32,
+}
+
+/// Patterns for the comments, in the order a line is tried against them
+struct Patterns {
+    non_test: Regex,
+    output: Regex,
+    error: Regex,
+    error_line: Regex,
+    runtime_error: Regex,
+    syntax_error: Regex, // What rlox prints for a compile error
+    snippet: Regex,      // The source line and caret under a compile error
+    stack_trace: Regex,
+}
+
+impl Patterns {
+    fn new() -> Patterns {
+        let re = |pattern: &str| Regex::new(pattern).unwrap();
+        Patterns {
+            non_test: re(r"// nontest"),
+            output: re(r"// expect: ?(.*)"),
+            error: re(r"// (Error.*)"),
+            error_line: re(r"// \[((java|c) )?line (\d+)\] (Error.*)"),
+            runtime_error: re(r"// expect runtime error: (.+)"),
+            syntax_error: re(r"\[.*Line (\d+)(, col \d+)?\] (Error.*)"),
+            snippet: re(r"^ *\d* \| "),
+            stack_trace: re(r"\[line (\d+)\]"),
+        }
+    }
+}
+
+/// "Expect ';' after value." and "Error at end: ..." are how clox says what rlox words as "Expected ';' after value" and
+/// "Error at end of file: ...". Applied to both what's expected and what rlox printed
+fn normalize(error: &str) -> String {
+    let error = error.trim_end().trim_end_matches('.');
+    let error = error.replacen("Error at end: ", "Error at end of file: ", 1);
+    match error.strip_prefix("Expect ") {
+        Some(rest) => format!("Expected {}", rest),
+        None => error.replacen(": Expect ", ": Expected ", 1),
+    }
+}
+
+impl TestExpectations {
+    /// Reads the expectations from the comments of the test. None if it's marked as not being a test, or it expects both a compile and a
+    /// runtime error, which can't happen. Errors marked as only coming from jlox (`// [java line 3] Error...`) aren't expected
+    pub fn parse(source: &str) -> Option<TestExpectations> {
+        let patterns = Patterns::new();
+        let mut expectations = TestExpectations {
+            output: Vec::new(),
+            errors: HashSet::new(),
+            runtime_error: None,
+            exit_code: 0,
+        };
+
+        for (i, line) in source.lines().enumerate() {
+            let line_num = i + 1;
+            if patterns.non_test.is_match(line) {
+                return None;
+            } else if let Some(captures) = patterns.output.captures(line) {
+                expectations
+                    .output
+                    .push((captures[1].to_string(), line_num));
+            } else if let Some(captures) = patterns.error.captures(line) {
+                let error = format!("[{}] {}", line_num, normalize(&captures[1]));
+                expectations.errors.insert(error);
+                expectations.exit_code = 65;
+            } else if let Some(captures) = patterns.error_line.captures(line) {
+                if captures
+                    .get(2)
+                    .is_some_and(|language| language.as_str() == "java")
+                {
+                    continue;
+                }
+                let error = format!("[{}] {}", &captures[3], normalize(&captures[4]));
+                expectations.errors.insert(error);
+                expectations.exit_code = 65;
+            } else if let Some(captures) = patterns.runtime_error.captures(line) {
+                expectations.runtime_error = Some((normalize(&captures[1]), line_num));
+                expectations.exit_code = 70;
+            }
+        }
+
+        if !expectations.errors.is_empty() && expectations.runtime_error.is_some() {
+            return None;
+        }
+        Some(expectations)
+    }
+
+    /// Every way a run of the test differed from what it expects, worded like tool/bin/test.dart. A run that passed has none
+    pub fn check(&self, stdout: &str, stderr: &str, exit_code: Option<i32>) -> Vec<String> {
+        let patterns = Patterns::new();
+        let mut failures = Vec::new();
+        let error_lines: Vec<&str> = stderr.lines().collect();
+
+        match &self.runtime_error {
+            Some((error, line)) => match error_lines.split_first() {
+                None => failures.push(format!("Expected runtime error '{}' and got none.", error)),
+                Some((first, stack)) => {
+                    if normalize(first) != *error {
+                        failures.push(format!("Expected runtime error '{}' and got:", error));
+                        failures.push(first.to_string());
+                    }
+                    let stack_line = stack
+                        .iter()
+                        .find_map(|line| patterns.stack_trace.captures(line));
+                    match stack_line {
+                        None => {
+                            failures.push(String::from("Expected stack trace and got:"));
+                            failures.extend(stack.iter().map(|line| line.to_string()));
+                        }
+                        Some(captures) if captures[1] != line.to_string() => {
+                            failures.push(format!(
+                                "Expected runtime error on line {} but was on line {}.",
+                                line, &captures[1]
+                            ))
+                        }
+                        Some(_) => {}
+                    }
+                }
+            },
+            None => {
+                let mut found = HashSet::new();
+                for line in error_lines.iter() {
+                    if let Some(captures) = patterns.syntax_error.captures(line) {
+                        let error = format!("[{}] {}", &captures[1], normalize(&captures[3]));
+                        if self.errors.contains(&error) {
+                            found.insert(error);
+                        } else {
+                            failures.push(String::from("Unexpected error:"));
+                            failures.push(error);
+                        }
+                    } else if !line.is_empty() && !patterns.snippet.is_match(line) {
+                        failures.push(String::from("Unexpected output on stderr:"));
+                        failures.push(line.to_string());
+                    }
+                }
+                let mut missing: Vec<&String> = self.errors.difference(&found).collect();
+                missing.sort();
+                for error in missing {
+                    failures.push(format!("Missing expected error: {}", error));
+                }
+            }
+        }
+
+        if exit_code != Some(self.exit_code) {
+            let got = match exit_code {
+                Some(code) => code.to_string(),
+                None => String::from("killed by a signal"),
+            };
+            failures.push(format!(
+                "Expected return code {} and got {}. Stderr:",
+                self.exit_code, got
+            ));
+            failures.extend(error_lines.iter().take(10).map(|line| line.to_string()));
+        }
+
+        let output_lines: Vec<&str> = stdout.lines().collect();
+        for (i, line) in output_lines.iter().enumerate() {
+            match self.output.get(i) {
+                None => failures.push(format!("Got output '{}' when none was expected.", line)),
+                Some((expected, line_num)) if expected != line => failures.push(format!(
+                    "Expected output '{}' on line {} and got '{}'.",
+                    expected, line_num, line
+                )),
+                Some(_) => {}
+            }
+        }
+        for (expected, line_num) in self.output.iter().skip(output_lines.len()) {
+            failures.push(format!(
+                "Missing expected output '{}' on line {}.",
+                expected, line_num
+            ));
+        }
+        failures
+    }
+}
@@ -0,0 +1,107 @@
+use crate::chunk::{Instr, OpCode};
+use crate::vm::{Output, VM};
+
+use std::collections::BTreeMap;
+use std::fs;
+
+// Line coverage for `--coverage`
+//
+// Every executed instruction is counted, and a line counts as covered if any of the instructions compiled from it ran.
+// Functions are attributed to files using the ranges the compiler records when merging modules, anything else belongs to the main script
+
+pub struct Coverage {
+    hits: Vec<Vec<u64>>, // hits[function][instruction offset]
+}
+
+impl Coverage {
+    pub fn new(vm: &VM) -> Coverage {
+        Coverage {
+            hits: vm
+                .functions
+                .iter()
+                .map(|f| vec![0; f.chunk.code.len()])
+                .collect(),
+        }
+    }
+
+    /// For functions added by Vm::eval while the program is running
+    pub fn grow(&mut self, vm: &VM) {
+        let known = self.hits.len();
+        self.hits.extend(
+            vm.functions[known..]
+                .iter()
+                .map(|f| vec![0; f.chunk.code.len()]),
+        );
+    }
+
+    pub fn hit(&mut self, function: usize, offset: usize) {
+        self.hits[function][offset] += 1;
+    }
+
+    /// Returns the hit count of every executable line, keyed by file and then by line
+    fn lines(&self, vm: &VM, script_path: &str) -> BTreeMap<String, BTreeMap<usize, u64>> {
+        let mut files: BTreeMap<String, BTreeMap<usize, u64>> = BTreeMap::new();
+        for (function, hits) in self.hits.iter().enumerate() {
+            let file = vm.file_of(function).unwrap_or(script_path);
+
+            let lines = files.entry(file.to_string()).or_default();
+            let code = &vm.functions[function].chunk.code;
+            let code = &code[..code.len() - implicit_return_len(code)];
+            for (instr, count) in code.iter().zip(hits) {
+                let line = lines.entry(instr.line_num).or_insert(0);
+                *line = (*line).max(*count);
+            }
+        }
+        files
+    }
+
+    /// Writes an lcov tracefile to output and prints a per file summary to stderr
+    pub fn report(&self, vm: &VM, script_path: &str, output: &str, stderr: &Output) {
+        let mut lcov = String::from("TN:\n");
+        stderr.write_line("== Coverage ==");
+        for (file, lines) in self.lines(vm, script_path) {
+            let hit = lines.values().filter(|count| **count > 0).count();
+            let percent = if lines.is_empty() {
+                100.0
+            } else {
+                hit as f64 * 100.0 / lines.len() as f64
+            };
+            stderr.write_line(&format!(
+                "{}: {}/{} lines ({:.1}%)",
+                file,
+                hit,
+                lines.len(),
+                percent
+            ));
+
+            lcov.push_str(&format!("SF:{}\n", file));
+            for (line, count) in lines.iter() {
+                lcov.push_str(&format!("DA:{},{}\n", line, count));
+            }
+            lcov.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), hit));
+        }
+
+        if let Err(why) = fs::write(output, lcov) {
+            stderr.write_line(&format!(
+                "Failed to write coverage report {}: {}",
+                output, why
+            ));
+        }
+    }
+}
+
+/// How many instructions at the end of code are the return the compiler adds to every function, which are left out since they sit on the
+/// closing brace or past the end of the file. It's only told apart from an explicit `return;` by having a line to itself
+fn implicit_return_len(code: &[Instr]) -> usize {
+    match code {
+        [.., before, value, ret]
+            if matches!(value.op_code, OpCode::OpNil | OpCode::OpGetLocal(0))
+                && ret.op_code == OpCode::OpReturn
+                && value.line_num == ret.line_num
+                && before.line_num != ret.line_num =>
+        {
+            2
+        }
+        _ => 0,
+    }
+}
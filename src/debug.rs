@@ -1,12 +1,36 @@
-use crate::chunk::{Chunk, ClassChunk, FunctionChunk, Instr, OpCode};
+use crate::chunk::{module_file, Chunk, ClassChunk, FunctionChunk, Instr, OpCode, SourceMap};
 use crate::value::Value;
 
+use std::collections::HashSet;
+use std::ops::Range;
+
+/// Where the disassembled functions came from, which is shown along with their code
+pub struct Sources<'a> {
+    pub module_functions: &'a [(String, Range<usize>)],
+    pub source_map: Option<&'a SourceMap>, // Adds the column to the line of every instruction
+}
+
+impl Sources<'_> {
+    fn file_suffix(&self, function: usize) -> String {
+        module_file(self.module_functions, function)
+            .map_or(String::new(), |file| format!(" ({})", file))
+    }
+
+    fn columns(&self, function: usize) -> Option<&[usize]> {
+        self.source_map
+            .and_then(|source_map| source_map.columns.get(function))
+            .map(|columns| columns.as_slice())
+            .filter(|columns| !columns.is_empty())
+    }
+}
+
 pub fn disassemble_class_chunk(
     class_chunk: &ClassChunk,
     function_defs: &Vec<FunctionChunk>,
     class_defs: &Vec<ClassChunk>,
     constants: &Vec<Value>,
     identifiers: &Vec<String>,
+    sources: &Sources,
 ) {
     match class_chunk.superclass {
         Some(i) => eprintln!(
@@ -15,13 +39,22 @@ pub fn disassemble_class_chunk(
         ),
         None => eprintln!("== <class {}> ===============", &class_chunk.name),
     }
-    for (name, fn_index) in class_chunk.methods.iter() {
+    let mut methods: Vec<(&usize, &usize)> = class_chunk.methods.iter().collect();
+    methods.sort_by_key(|(_, fn_index)| **fn_index); // In the order they were declared, rather than the HashMap's
+    for (name, fn_index) in methods {
         eprintln!(
-            "== <method {} | #{}> ============",
+            "== <method {} | #{}>{} ============",
             identifiers.get(*name).unwrap(),
-            fn_index
+            fn_index,
+            sources.file_suffix(*fn_index)
+        );
+        disassemble_upvalues(&function_defs[*fn_index]);
+        disassemble_chunk(
+            &function_defs[*fn_index].chunk,
+            constants,
+            identifiers,
+            sources.columns(*fn_index),
         );
-        disassemble_chunk(&function_defs[*fn_index].chunk, constants, identifiers);
     }
 }
 
@@ -30,28 +63,76 @@ pub fn disassemble_fn_chunk(
     fn_chunk: &FunctionChunk,
     constants: &Vec<Value>,
     identifiers: &Vec<String>,
+    sources: &Sources,
 ) {
+    let file = sources.file_suffix(index);
     match &fn_chunk.name {
-        Some(name) => eprintln!("== <fn {} | #{}> ==============", name, index),
-        None => eprintln!("== <script> =============="),
+        Some(name) => eprintln!("== <fn {} | #{}>{} ==============", name, index, file),
+        None => eprintln!("== <script>{} ==============", file),
     }
-    disassemble_chunk(&fn_chunk.chunk, constants, identifiers);
+    disassemble_upvalues(fn_chunk);
+    disassemble_chunk(
+        &fn_chunk.chunk,
+        constants,
+        identifiers,
+        sources.columns(index),
+    );
 }
 
-fn disassemble_chunk(chunk: &Chunk, constants: &Vec<Value>, identifiers: &Vec<String>) {
+/// What the closure of the function captures, in the same terms as the assembler: a local slot of the enclosing function, or an upvalue the
+/// enclosing function captured itself
+fn disassemble_upvalues(fn_chunk: &FunctionChunk) {
+    for (i, upvalue) in fn_chunk.upvalues.iter().flatten().enumerate() {
+        let captures = if upvalue.is_local { "local" } else { "outer" };
+        eprintln!("upvalue {} => {} {}", i, captures, upvalue.index);
+    }
+}
+
+/// With columns, the line of every instruction is followed by its column, ie 3:12, or just :12 while the line stays the same
+///
+/// Every instruction a jump or loop lands on gets a label, ie L12 for the instruction at byte 12, which the jumps show as their target
+fn disassemble_chunk(
+    chunk: &Chunk,
+    constants: &Vec<Value>,
+    identifiers: &Vec<String>,
+    columns: Option<&[usize]>,
+) {
     eprintln!("---");
-    eprintln!("byte\tline\tOpCode");
+    eprintln!(
+        "byte\t{}\tOpCode",
+        if columns.is_some() {
+            "line:col"
+        } else {
+            "line"
+        }
+    );
+    let labels: HashSet<usize> = chunk
+        .code
+        .iter()
+        .enumerate()
+        .filter_map(|(i, instr)| jump_target(instr, i))
+        .collect();
     let mut last_line_num = 0;
     for (i, instr) in chunk.code.iter().enumerate() {
-        let line_marker = if last_line_num == instr.line_num {
-            "|".to_string()
-        } else {
-            instr.line_num.to_string()
+        if labels.contains(&i) {
+            eprintln!("L{}:", i);
+        }
+        let line_marker = match (
+            last_line_num == instr.line_num,
+            columns.and_then(|c| c.get(i)),
+        ) {
+            (true, None) => "|".to_string(),
+            (false, None) => instr.line_num.to_string(),
+            (true, Some(column)) => format!(":{}", column),
+            (false, Some(column)) => format!("{}:{}", instr.line_num, column),
         };
         last_line_num = instr.line_num;
         eprint!("{}\t{}", i, line_marker);
         disassemble_instruction(instr, i, constants, identifiers)
     }
+    if labels.contains(&chunk.code.len()) {
+        eprintln!("L{}:", chunk.code.len()); // A jump past the last instruction
+    }
 
     eprintln!("======================\n");
 }
@@ -62,33 +143,69 @@ pub fn disassemble_instruction(
     constants: &Vec<Value>,
     identifiers: &Vec<String>,
 ) {
+    eprintln!(
+        "\t{}",
+        describe_instruction(instr, instr_offset, constants, identifiers)
+    );
+}
+
+/// An instruction with its operands resolved, ie OpGetGlobal(0) => name: "x"
+pub fn describe_instruction(
+    instr: &Instr,
+    instr_offset: usize,
+    constants: &[Value],
+    identifiers: &[String],
+) -> String {
+    if let Some(target) = jump_target(instr, instr_offset) {
+        return format!("{:?} -> L{}", instr.op_code, target);
+    }
     match instr.op_code {
-        OpCode::OpConstant(index) => eprintln!(
-            "\t{:?} => {:?}",
+        OpCode::OpConstant(index) | OpCode::OpLoadNative(index) => format!(
+            "{:?} => {}",
             instr.op_code,
-            constants.get(index).unwrap()
+            describe_constant(constants.get(index).unwrap())
         ),
         OpCode::OpDefineGlobal(index)
         | OpCode::OpGetSuper(index)
         | OpCode::OpSetGlobal(index)
         | OpCode::OpGetGlobal(index)
         | OpCode::OpCallGlobal(index, _)
+        | OpCode::OpInvoke(index, _)
         | OpCode::OpGetProperty(index)
-        | OpCode::OpSetProperty(index) => eprintln!(
-            "\t{:?} => name: {:?}",
+        | OpCode::OpGetField(index, _)
+        | OpCode::OpSetField(index, _)
+        | OpCode::OpSetProperty(index)
+        | OpCode::OpDeleteProperty(index) => format!(
+            "{:?} => name: {:?}",
             instr.op_code,
             identifiers.get(index).unwrap()
         ),
-        OpCode::OpJump(jump_offset) | OpCode::OpJumpIfFalse(jump_offset) => eprintln!(
-            "\t{:?} | jump -> {}",
-            instr.op_code,
-            instr_offset + jump_offset
-        ),
-        OpCode::OpLoop(neg_offset) => eprintln!(
-            "\t{:?} | loop back -> {}",
-            instr.op_code,
-            instr_offset - neg_offset
-        ),
-        _ => eprintln!("\t{:?}", instr.op_code),
+        _ => format!("{:?}", instr.op_code),
+    }
+}
+
+/// Where the jump or loop at instr_offset lands, None for every other instruction
+pub fn jump_target(instr: &Instr, instr_offset: usize) -> Option<usize> {
+    match instr.op_code {
+        OpCode::OpJump(jump_offset) | OpCode::OpJumpIfFalse(jump_offset) => {
+            Some(instr_offset + jump_offset)
+        }
+        OpCode::OpLoop(neg_offset) => Some(instr_offset - neg_offset),
+        _ => None,
+    }
+}
+
+/// A constant the way the assembler takes it, ie 1.5, "hi" or fn #2. The rest have no literal syntax and keep their Debug form
+fn describe_constant(value: &Value) -> String {
+    match value {
+        Value::Double(x) if x.fract() == 0.0 => format!("{:.1}", x), // So that it doesn't read back as an Int
+        Value::Double(x) => x.to_string(),
+        Value::Int(x) => x.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Nil => String::from("nil"),
+        Value::LoxString(s) => format!("{:?}", s),
+        Value::LoxFunction(i) => format!("fn #{}", i),
+        Value::LoxClass(i) => format!("class #{}", i),
+        value => format!("{:?}", value),
     }
 }
@@ -0,0 +1,184 @@
+use crate::chunk::Instr;
+use crate::debug::disassemble_instruction;
+use crate::vm::{Global, VMState, VM};
+
+use std::collections::HashSet;
+use std::io::{self, BufRead, Write};
+
+// The interactive debugger for `rlox debug script.lox`
+//
+// The VM calls on_instruction before executing every instruction, and we decide from the step mode and the breakpoints whether to stop and read commands.
+// Local variable names are erased by the resolver, so locals can only be shown as slots in the current call frame
+
+const HELP: &str = "Commands:
+  s, step          Execute one instruction
+  n, next          Run until the next line
+  c, continue      Run until the next breakpoint
+  b, break <line>  Set a breakpoint
+  d, delete <line> Remove a breakpoint
+  breakpoints      List the breakpoints
+  stack            Print the whole value stack
+  locals           Print the stack slots of the current call frame
+  globals          Print every defined global
+  p, print <name>  Print a global
+  bt, backtrace    Print the call frames
+  q, quit          Stop the program
+An empty line repeats the last command";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StepMode {
+    Instruction,
+    Line,
+    Continue,
+}
+
+pub struct Debugger {
+    breakpoints: HashSet<usize>,
+    mode: StepMode,
+    last_position: Option<(usize, usize)>, // The (function, line) of the previously executed instruction, so breakpoints only trigger when a line is entered
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: HashSet::new(),
+            mode: StepMode::Instruction, // Start paused so that breakpoints can be set before anything runs
+            last_position: None,
+            last_command: String::new(),
+        }
+    }
+
+    /// Called by the VM before it executes instr. Returns false if the user asked to stop the program
+    pub fn on_instruction(&mut self, vm: &VM, instr: &Instr, state: &VMState) -> bool {
+        let (function, ip, _) = state.current_frame();
+        let position = (function, instr.line_num);
+        let entered_line = self.last_position != Some(position);
+        self.last_position = Some(position);
+
+        let pause = match self.mode {
+            StepMode::Instruction => true,
+            StepMode::Line => entered_line,
+            StepMode::Continue => false,
+        } || (entered_line && self.breakpoints.contains(&instr.line_num));
+
+        if !pause {
+            return true;
+        }
+
+        let name = match &vm.functions[function].name {
+            Some(name) => name.as_str(),
+            None => "script",
+        };
+        eprintln!("[line {}] in {}", instr.line_num, name);
+        eprint!("{:04}\t{}", ip - 1, instr.line_num); // The ip has already been incremented past instr
+        disassemble_instruction(instr, ip - 1, &vm.constants, &vm.identifiers);
+
+        self.prompt(vm, state)
+    }
+
+    /// Reads and runs commands until one of them resumes execution
+    fn prompt(&mut self, vm: &VM, state: &VMState) -> bool {
+        let stdin = io::stdin();
+        loop {
+            eprint!("(rlox) ");
+            io::stderr().flush().ok();
+
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => return false, // Nobody left to give us commands
+                Ok(_) => {}
+            }
+
+            let line = line.trim();
+            let command = if line.is_empty() {
+                self.last_command.clone()
+            } else {
+                line.to_string()
+            };
+            self.last_command = command.clone();
+
+            let mut words = command.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("s" | "step"), _) => {
+                    self.mode = StepMode::Instruction;
+                    return true;
+                }
+                (Some("n" | "next"), _) => {
+                    self.mode = StepMode::Line;
+                    return true;
+                }
+                (Some("c" | "continue"), _) => {
+                    self.mode = StepMode::Continue;
+                    return true;
+                }
+                (Some("q" | "quit"), _) => return false,
+                (Some("b" | "break"), Some(arg)) => match arg.parse::<usize>() {
+                    Ok(line) => {
+                        self.breakpoints.insert(line);
+                        eprintln!("Breakpoint set at line {}", line);
+                    }
+                    Err(_) => eprintln!("Expected a line number, got '{}'", arg),
+                },
+                (Some("d" | "delete"), Some(arg)) => match arg.parse::<usize>() {
+                    Ok(line) => {
+                        if self.breakpoints.remove(&line) {
+                            eprintln!("Removed breakpoint at line {}", line);
+                        } else {
+                            eprintln!("No breakpoint at line {}", line);
+                        }
+                    }
+                    Err(_) => eprintln!("Expected a line number, got '{}'", arg),
+                },
+                (Some("breakpoints"), _) => {
+                    let mut lines: Vec<&usize> = self.breakpoints.iter().collect();
+                    lines.sort();
+                    for line in lines {
+                        eprintln!("line {}", line);
+                    }
+                }
+                (Some("stack"), _) => {
+                    for (i, value) in state.stack().iter().enumerate() {
+                        eprintln!("[{}] {}", i, value.to_string(vm, state));
+                    }
+                }
+                (Some("locals"), _) => {
+                    let (_, _, frame_start) = state.current_frame();
+                    for (slot, value) in state.stack()[frame_start..].iter().enumerate() {
+                        eprintln!("slot {} = {}", slot, value.to_string(vm, state));
+                    }
+                }
+                (Some("globals"), _) => {
+                    for (index, global) in state.globals().iter().enumerate() {
+                        if let Global::Init(value) = global {
+                            eprintln!(
+                                "{} = {}",
+                                vm.get_variable_name(index),
+                                value.to_string(vm, state)
+                            );
+                        }
+                    }
+                }
+                (Some("p" | "print"), Some(name)) => {
+                    match vm.identifiers.iter().position(|x| x == name) {
+                        Some(index) => match &state.globals()[index] {
+                            Global::Init(value) => eprintln!("{}", value.to_string(vm, state)),
+                            Global::Uninit => eprintln!("'{}' is not defined yet", name),
+                        },
+                        None => eprintln!("No global named '{}'", name),
+                    }
+                }
+                (Some("bt" | "backtrace"), _) => vm.print_backtrace(state),
+                (Some("h" | "help"), _) => eprintln!("{}", HELP),
+                (Some(other), _) => eprintln!("Unknown command '{}', try 'help'", other),
+                (None, _) => {}
+            }
+        }
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
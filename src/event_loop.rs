@@ -0,0 +1,400 @@
+use crate::native::Arity;
+use crate::value::Value;
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Futures, timers and async io for `async fun` and `await`
+//
+// The VM owns the tasks (suspended call stacks) and decides what runs next, this only tracks the futures and the operations that will resolve them.
+// Timers are kept in a min-heap on their deadline, and io runs on a background thread per operation that reports back over a channel
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30); // How long httpGet waits for the server to send anything before giving up
+
+/// Natives that start an async operation instead of producing a value directly. Calling one returns a Value::LoxFuture
+pub type AsyncNativeFn = fn(&[Value]) -> Result<AsyncOp, String>;
+
+/// An async native along with the global name it is bound to, the arity is checked by the VM the same way as for STD_LIB
+#[derive(Debug)]
+pub struct AsyncNative {
+    pub name: &'static str,
+    pub function: AsyncNativeFn,
+    pub arity: Arity,
+}
+
+impl AsyncNative {
+    const fn new(name: &'static str, function: AsyncNativeFn, arity: Arity) -> AsyncNative {
+        AsyncNative {
+            name,
+            function,
+            arity,
+        }
+    }
+}
+
+/// Same as Native, two are the same if they're the same entry of ASYNC_STD_LIB
+impl PartialEq for AsyncNative {
+    fn eq(&self, other: &AsyncNative) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+pub enum AsyncOp {
+    Timer(Duration),           // Resolves to nil once the duration has passed
+    ReadFile(String),          // Resolves to the contents of the file, or nil if it can't be read
+    WriteFile(String, String), // Replaces the file with the string, resolves to whether it could be written
+    HttpGet(String), // Resolves to the body of a 2xx response, or nil if the request fails
+    Spawn(Value), // Runs the function in a new fiber, resolves to its return value. Handled by the VM since it needs a new task
+    Timeout(Value, Duration), // Spawn, but the fiber only starts once the duration has passed
+    Delay(Duration), // Parks the calling task for the duration without returning a future, like the channels below
+
+    // Combinators, handled by the VM since they watch other futures. Values that aren't futures count as already resolved, like with await
+    All(Vec<Value>), // Resolves to an array of what each one resolved to, once they all have
+    Race(Vec<Value>), // Resolves to whatever resolves first
+    Then(Value, Value), // Calls the function with what the future resolves to in a new fiber, resolves to its return value
+
+    // Channels are handled by the VM too, since send and recv suspend the calling task directly instead of returning a future
+    Channel(usize),     // Creates a channel that buffers up to this many values
+    Send(usize, Value), // Waits until the channel has room for the value
+    Recv(usize),        // Waits until the channel has a value
+}
+
+/// The result of a finished operation, converted into a Value by the VM since strings have to be interned
+pub enum Completion {
+    Nil,
+    Bool(bool),
+    String(String),
+}
+
+/// A static for the same reason as STD_LIB
+pub static ASYNC_STD_LIB: &[AsyncNative] = &[
+    AsyncNative::new("sleep", sleep, Arity::Exactly(1)),
+    AsyncNative::new("delay", delay, Arity::Exactly(1)),
+    AsyncNative::new("setTimeout", set_timeout, Arity::Exactly(2)),
+    AsyncNative::new("readFile", read_file, Arity::Exactly(1)),
+    AsyncNative::new("writeFile", write_file, Arity::Exactly(2)),
+    AsyncNative::new("httpGet", http_get, Arity::Exactly(1)),
+    AsyncNative::new("spawn", spawn, Arity::Exactly(1)),
+    AsyncNative::new("all", all, Arity::Exactly(1)),
+    AsyncNative::new("race", race, Arity::Exactly(1)),
+    AsyncNative::new("then", then, Arity::Exactly(2)),
+    AsyncNative::new("channel", channel, Arity::Between(0, 1)),
+    AsyncNative::new("send", send, Arity::Exactly(2)),
+    AsyncNative::new("recv", recv, Arity::Exactly(1)),
+];
+
+/// call this like `var fiber = spawn(fn);`, fn must take no arguments
+fn spawn(args: &[Value]) -> Result<AsyncOp, String> {
+    Ok(AsyncOp::Spawn(args[0].clone()))
+}
+
+/// The duration for a number of milliseconds, None if it isn't one
+fn millis(value: &Value) -> Option<Duration> {
+    match value.as_num() {
+        Some(ms) if ms >= 0.0 => Some(Duration::from_secs_f64(ms / 1000.0)),
+        _ => None,
+    }
+}
+
+/// call this like `await sleep(100);` with the time in milliseconds
+fn sleep(args: &[Value]) -> Result<AsyncOp, String> {
+    match millis(&args[0]) {
+        Some(duration) => Ok(AsyncOp::Timer(duration)),
+        None => Err(String::from("sleep() expects a number of milliseconds")),
+    }
+}
+
+/// call this like `delay(100);`, it only stops the calling task, the others keep running in the meantime
+fn delay(args: &[Value]) -> Result<AsyncOp, String> {
+    match millis(&args[0]) {
+        Some(duration) => Ok(AsyncOp::Delay(duration)),
+        None => Err(String::from("delay() expects a number of milliseconds")),
+    }
+}
+
+/// call this like `var result = setTimeout(fn, 100);`, fn must take no arguments
+fn set_timeout(args: &[Value]) -> Result<AsyncOp, String> {
+    match millis(&args[1]) {
+        Some(duration) => Ok(AsyncOp::Timeout(args[0].clone(), duration)),
+        None => Err(String::from(
+            "setTimeout() expects a function and a number of milliseconds",
+        )),
+    }
+}
+
+/// call this like `var contents = await readFile(path);`
+fn read_file(args: &[Value]) -> Result<AsyncOp, String> {
+    match &args[0] {
+        Value::LoxString(path) => Ok(AsyncOp::ReadFile(path.to_string())),
+        _ => Err(String::from("readFile() expects a path")),
+    }
+}
+
+/// call this like `await writeFile(path, contents);`
+fn write_file(args: &[Value]) -> Result<AsyncOp, String> {
+    match (&args[0], &args[1]) {
+        (Value::LoxString(path), Value::LoxString(contents)) => {
+            Ok(AsyncOp::WriteFile(path.to_string(), contents.to_string()))
+        }
+        _ => Err(String::from("writeFile() expects a path and a string")),
+    }
+}
+
+/// call this like `var body = await httpGet("http://example.com/");`. Only plain http is supported, there's no tls
+fn http_get(args: &[Value]) -> Result<AsyncOp, String> {
+    match &args[0] {
+        Value::LoxString(url) if url.starts_with("http://") => {
+            Ok(AsyncOp::HttpGet(url.to_string()))
+        }
+        Value::LoxString(_) => Err(String::from("httpGet() only supports http:// urls")),
+        _ => Err(String::from("httpGet() expects a url")),
+    }
+}
+/// An HTTP/1.0 GET, so the server closes the connection after the body and there's no chunked encoding to decode
+fn fetch(url: &str) -> Option<String> {
+    let rest = url.strip_prefix("http://")?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+
+    let mut stream = TcpStream::connect(address).ok()?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT)).ok()?;
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    )
+    .ok()?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).ok()?;
+
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n")?;
+    let status = head.split_whitespace().nth(1)?;
+    if status.starts_with('2') {
+        Some(body.to_string())
+    } else {
+        None
+    }
+}
+
+/// call this like `var results = await all(futures);` with an array, the results are in the same order as the futures
+fn all(args: &[Value]) -> Result<AsyncOp, String> {
+    match &args[0] {
+        Value::LoxArray(futures) => Ok(AsyncOp::All(futures.borrow().clone())),
+        _ => Err(String::from("all() expects an array of futures")),
+    }
+}
+
+/// call this like `var first = await race(futures);`
+fn race(args: &[Value]) -> Result<AsyncOp, String> {
+    match &args[0] {
+        Value::LoxArray(futures) => Ok(AsyncOp::Race(futures.borrow().clone())),
+        _ => Err(String::from("race() expects an array of futures")),
+    }
+}
+
+/// call this like `var length = then(readFile(path), len);`, fn must take one argument
+fn then(args: &[Value]) -> Result<AsyncOp, String> {
+    Ok(AsyncOp::Then(args[0].clone(), args[1].clone()))
+}
+
+/// call this like `var ch = channel(0);`, a capacity of 0 makes every send wait for a matching recv
+fn channel(args: &[Value]) -> Result<AsyncOp, String> {
+    match args.first().map(Value::as_num) {
+        None => Ok(AsyncOp::Channel(0)),
+        Some(Some(capacity)) if capacity >= 0.0 => Ok(AsyncOp::Channel(capacity as usize)),
+        _ => Err(String::from("channel() expects a capacity")),
+    }
+}
+
+/// call this like `send(ch, value);`
+fn send(args: &[Value]) -> Result<AsyncOp, String> {
+    match &args[0] {
+        Value::LoxChannel(channel) => Ok(AsyncOp::Send(*channel, args[1].clone())),
+        _ => Err(String::from("send() expects a channel and a value")),
+    }
+}
+
+/// call this like `var value = recv(ch);`
+fn recv(args: &[Value]) -> Result<AsyncOp, String> {
+    match &args[0] {
+        Value::LoxChannel(channel) => Ok(AsyncOp::Recv(*channel)),
+        _ => Err(String::from("recv() expects a channel")),
+    }
+}
+
+/// A queue of values between tasks. The tasks blocked on it are parked in the VM, waiting on the futures stored here
+#[derive(Debug, Default)]
+pub struct Channel {
+    pub capacity: usize,
+    pub buffer: VecDeque<Value>,
+    pub receivers: VecDeque<usize>, // Futures of the tasks blocked in recv, resolved with the value they receive
+    pub senders: VecDeque<(usize, Value)>, // Futures of the tasks blocked in send and what they're sending, resolved with nil once it's taken
+}
+
+impl Channel {
+    pub fn new(capacity: usize) -> Channel {
+        Channel {
+            capacity,
+            ..Channel::default()
+        }
+    }
+
+    /// Values held by the channel, which the GC has to treat as roots
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.buffer
+            .iter()
+            .chain(self.senders.iter().map(|(_, value)| value))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FutureState {
+    Pending,
+    Resolved(Value),
+}
+
+pub struct EventLoop {
+    pub futures: Vec<FutureState>, // Indexed by Value::LoxFuture. Never shrinks, so a future stays valid for the whole run
+    timers: BinaryHeap<Reverse<(Instant, usize)>>, // (deadline, future)
+    io_sender: Sender<(usize, Completion)>,
+    io_receiver: Receiver<(usize, Completion)>,
+    pending_io: usize,
+}
+
+impl EventLoop {
+    pub fn new() -> EventLoop {
+        let (io_sender, io_receiver) = mpsc::channel();
+        EventLoop {
+            futures: Vec::new(),
+            timers: BinaryHeap::new(),
+            io_sender,
+            io_receiver,
+            pending_io: 0,
+        }
+    }
+
+    pub fn new_future(&mut self) -> usize {
+        self.futures.push(FutureState::Pending);
+        self.futures.len() - 1
+    }
+
+    /// Starts the operation and returns the future it will resolve
+    pub fn start(&mut self, op: AsyncOp) -> usize {
+        let future = self.new_future();
+        match op {
+            AsyncOp::Timer(duration) => self
+                .timers
+                .push(Reverse((Instant::now() + duration, future))),
+            AsyncOp::ReadFile(path) => {
+                self.in_background(future, move || match fs::read_to_string(&path) {
+                    Ok(contents) => Completion::String(contents),
+                    Err(_) => Completion::Nil,
+                })
+            }
+            AsyncOp::WriteFile(path, contents) => self.in_background(future, move || {
+                Completion::Bool(fs::write(&path, contents).is_ok())
+            }),
+            AsyncOp::HttpGet(url) => self.in_background(future, move || match fetch(&url) {
+                Some(body) => Completion::String(body),
+                None => Completion::Nil,
+            }),
+            AsyncOp::Spawn(_)
+            | AsyncOp::Timeout(..)
+            | AsyncOp::Delay(_)
+            | AsyncOp::All(_)
+            | AsyncOp::Race(_)
+            | AsyncOp::Then(..)
+            | AsyncOp::Channel(_)
+            | AsyncOp::Send(..)
+            | AsyncOp::Recv(_) => {
+                panic!("VM panic! Fibers, combinators and channels have to be handled by the VM")
+            }
+        }
+        future
+    }
+
+    /// Runs the io on its own thread, which resolves future once it's done
+    fn in_background(&mut self, future: usize, io: impl FnOnce() -> Completion + Send + 'static) {
+        let sender = self.io_sender.clone();
+        self.pending_io += 1;
+        thread::spawn(move || {
+            sender.send((future, io())).ok(); // The VM might have already exited
+        });
+    }
+
+    /// True when nothing is in flight, ie waiting would never return anything
+    pub fn is_idle(&self) -> bool {
+        self.timers.is_empty() && self.pending_io == 0
+    }
+
+    /// Blocks until at least one operation finishes and returns everything that has finished. Returns nothing if the loop is idle, or once
+    /// until has passed
+    pub fn wait(&mut self, until: Option<Instant>) -> Vec<(usize, Completion)> {
+        let mut done = Vec::new();
+        loop {
+            while let Ok(completion) = self.io_receiver.try_recv() {
+                self.pending_io -= 1;
+                done.push(completion);
+            }
+
+            let now = Instant::now();
+            while let Some(&Reverse((deadline, future))) = self.timers.peek() {
+                if deadline > now {
+                    break;
+                }
+                self.timers.pop();
+                done.push((future, Completion::Nil));
+            }
+
+            if !done.is_empty() || self.is_idle() || until.is_some_and(|until| until <= now) {
+                return done;
+            }
+
+            // Sleep on the io channel until either some io finishes, the next timer is due or until passes
+            let next_timer = self.timers.peek().map(|Reverse((deadline, _))| *deadline);
+            let wake = match (next_timer, until) {
+                (Some(timer), Some(until)) => Some(timer.min(until)),
+                (timer, until) => timer.or(until),
+            };
+            let received = match wake {
+                Some(wake) => self
+                    .io_receiver
+                    .recv_timeout(wake.saturating_duration_since(now))
+                    .ok(),
+                None => self.io_receiver.recv().ok(),
+            };
+            if let Some(completion) = received {
+                self.pending_io -= 1;
+                done.push(completion);
+            }
+        }
+    }
+
+    /// Values held by resolved futures, which the GC has to treat as roots
+    pub fn resolved_values(&self) -> impl Iterator<Item = &Value> {
+        self.futures.iter().filter_map(|future| match future {
+            FutureState::Resolved(value) => Some(value),
+            FutureState::Pending => None,
+        })
+    }
+}
+
+impl Default for EventLoop {
+    fn default() -> EventLoop {
+        EventLoop::new()
+    }
+}
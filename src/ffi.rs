@@ -0,0 +1,199 @@
+//! A C API for embedding rlox in programs that aren't written in rust, declared in include/rlox.h. Only built with the ffi feature:
+//!
+//! ```sh
+//! cargo rustc --release --lib --features ffi --crate-type cdylib
+//! ```
+//!
+//! Values cross the boundary as the same RloxValue native modules use, see plugin.rs
+use crate::plugin::{RloxValue, RLOX_BOOL, RLOX_NUMBER};
+use crate::{Arity, InterpretResult, NativeError, RloxError, Vm, VmConfig};
+
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+
+/// A function registered with rlox_register_native. userdata is whatever pointer was registered along with it
+pub type RloxNativeFn =
+    extern "C" fn(userdata: *mut c_void, arg_count: usize, args: *const RloxValue) -> RloxValue;
+
+/// What C gets a pointer to. The Vm is the same one rust embedders use, the message is kept so rlox_last_error can hand out a pointer to it
+pub struct RloxVm {
+    vm: Vm,
+    last_error: Option<CString>,
+}
+
+/// Prints and errors go to stdout and stderr, the same as running the script with the rlox binary
+#[no_mangle]
+pub extern "C" fn rlox_vm_new() -> *mut RloxVm {
+    let vm = RloxVm {
+        vm: Vm::new(VmConfig::default()),
+        last_error: None,
+    };
+    Box::into_raw(Box::new(vm))
+}
+
+/// # Safety
+/// vm has to come from rlox_vm_new, and can't be used again afterwards
+#[no_mangle]
+pub unsafe extern "C" fn rlox_vm_free(vm: *mut RloxVm) {
+    if !vm.is_null() {
+        drop(Box::from_raw(vm));
+    }
+}
+
+/// Compiles and runs source, a nul terminated utf8 string, as more of the program the earlier calls ran, see Vm::eval. Returns one of the
+/// RLOX_RESULT_* codes, which are InterpretResult in order
+///
+/// # Safety
+/// vm has to come from rlox_vm_new and source has to be nul terminated
+#[no_mangle]
+pub unsafe extern "C" fn rlox_eval(vm: *mut RloxVm, source: *const c_char) -> c_int {
+    let vm = &mut *vm;
+    let source = CStr::from_ptr(source).to_string_lossy();
+    let result = vm.vm.eval(&source);
+    vm.last_error = result
+        .as_ref()
+        .err()
+        .map(|error| CString::new(error.to_string().trim_end().replace('\0', "")).unwrap());
+
+    let result = match result {
+        Ok(()) => InterpretResult::InterpretOK,
+        Err(RloxError::Compile(_)) => InterpretResult::InterpretCompileError,
+        Err(RloxError::BudgetExceeded(_)) => InterpretResult::InterpretBudgetExceeded,
+        Err(_) => InterpretResult::InterpretRuntimeError,
+    };
+    result as c_int
+}
+
+/// The message of the error the last rlox_eval ended with, or NULL if it succeeded. Valid until the next rlox_eval or rlox_vm_free
+///
+/// # Safety
+/// vm has to come from rlox_vm_new
+#[no_mangle]
+pub unsafe extern "C" fn rlox_last_error(vm: *const RloxVm) -> *const c_char {
+    match &(*vm).last_error {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Binds function to the global name in all the code evaluated from now on. arity is the exact number of arguments it takes, or -1 for any
+/// number. Arrays, instances and functions can't be passed to it, calling it with one is a runtime error
+///
+/// # Safety
+/// vm has to come from rlox_vm_new, name has to be nul terminated, and userdata has to stay valid for as long as the function can be called
+#[no_mangle]
+pub unsafe extern "C" fn rlox_register_native(
+    vm: *mut RloxVm,
+    name: *const c_char,
+    arity: c_int,
+    function: RloxNativeFn,
+    userdata: *mut c_void,
+) {
+    let vm = &mut *vm;
+    let name = CStr::from_ptr(name).to_string_lossy();
+    let arity = match usize::try_from(arity) {
+        Ok(n) => Arity::Exactly(n),
+        Err(_) => Arity::AtLeast(0),
+    };
+    vm.vm.register_native(&name, arity, move |context, args| {
+        let mut c_args = Vec::with_capacity(args.len());
+        for arg in args {
+            match RloxValue::from_value(arg) {
+                Some(arg) => c_args.push(arg),
+                None => {
+                    return Err(NativeError::with_value(
+                        "C functions only accept nil, bools, numbers and strings",
+                        arg,
+                    ))
+                }
+            }
+        }
+        let result = function(userdata, c_args.len(), c_args.as_ptr());
+        unsafe { result.to_value(|s| context.string(s)) }
+            .map_err(|message| NativeError::new(&message))
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn rlox_value_nil() -> RloxValue {
+    RloxValue::nil()
+}
+
+#[no_mangle]
+pub extern "C" fn rlox_value_bool(x: bool) -> RloxValue {
+    RloxValue::bool(x)
+}
+
+#[no_mangle]
+pub extern "C" fn rlox_value_number(x: f64) -> RloxValue {
+    RloxValue::number(x)
+}
+
+/// Borrows s, which has to stay valid until the value has been returned to rlox, which copies it
+///
+/// # Safety
+/// s has to be nul terminated
+#[no_mangle]
+pub unsafe extern "C" fn rlox_value_string(s: *const c_char) -> RloxValue {
+    string_value(s, RloxValue::string)
+}
+
+/// Returning this from a native raises a runtime error with message at the call site. Borrows message the same way as rlox_value_string
+///
+/// # Safety
+/// message has to be nul terminated
+#[no_mangle]
+pub unsafe extern "C" fn rlox_value_error(message: *const c_char) -> RloxValue {
+    string_value(message, RloxValue::error)
+}
+
+/// One of the RLOX_* tags
+#[no_mangle]
+pub extern "C" fn rlox_value_type(value: RloxValue) -> u32 {
+    value.tag
+}
+
+/// False if it isn't a bool
+#[no_mangle]
+pub extern "C" fn rlox_value_as_bool(value: RloxValue) -> bool {
+    value.tag == RLOX_BOOL && value.boolean
+}
+
+/// 0 if it isn't a number
+#[no_mangle]
+pub extern "C" fn rlox_value_as_number(value: RloxValue) -> f64 {
+    if value.tag == RLOX_NUMBER {
+        value.number
+    } else {
+        0.0
+    }
+}
+
+/// The bytes of a string or error, which aren't nul terminated, with their length written to len. NULL for every other value
+///
+/// # Safety
+/// len has to be a valid pointer, or NULL if the length isn't needed
+#[no_mangle]
+pub unsafe extern "C" fn rlox_value_as_string(value: RloxValue, len: *mut usize) -> *const c_char {
+    let Some(s) = value.as_str() else {
+        return ptr::null();
+    };
+    if !len.is_null() {
+        *len = s.len();
+    }
+    s.as_ptr() as *const c_char
+}
+
+/// RloxValue::string and RloxValue::error want a &str, which can't be made without checking the string is utf8. An invalid one becomes an
+/// RLOX_STRING with a NULL string, which the VM reports as an invalid string
+unsafe fn string_value(s: *const c_char, make: fn(&str) -> RloxValue) -> RloxValue {
+    match CStr::from_ptr(s).to_str() {
+        Ok(s) => make(s),
+        Err(_) => RloxValue {
+            string: ptr::null(),
+            ..RloxValue::string("")
+        },
+    }
+}
@@ -0,0 +1,230 @@
+//! `rlox fmt`, which prints Lox source back with canonical indentation, spacing and line breaks. It works on the tokens of the trivia keeping
+//! scanner instead of a syntax tree, since the compiler goes straight from tokens to bytecode. Comments are kept, and so is a single blank line
+//! wherever the source had at least one
+use crate::compiler::{Compiler, Diagnostic, Severity};
+use crate::scanner::{Scanner, Token, TokenType};
+
+const INDENT: &str = "  ";
+
+/// A token or a comment, with the line breaks the source had before it
+struct Piece {
+    token: Token,
+    line_breaks: usize,
+}
+
+/// Formats the source, which has to compile, although the modules it imports aren't looked at. Formatting source that doesn't parse could change what it means,
+/// ie `/* x */` isn't a comment and would come back as `/ * x * /`
+///
+/// Returns the errors otherwise, ie of an invalid token like an unterminated string
+pub fn format_source(source: &str) -> Result<String, Vec<Diagnostic>> {
+    let mut scanner = Scanner::new(source);
+    scanner.set_trivia(true);
+
+    let mut pieces = Vec::new();
+    let mut errors = Vec::new();
+    let mut line_breaks = 0;
+    for token in scanner {
+        match token.token_type {
+            TokenType::TokenWhitespace => line_breaks += token.lexemme.matches('\n').count(),
+            TokenType::TokenEOF => break,
+            TokenType::TokenError => {
+                errors.push(Diagnostic {
+                    severity: Severity::Error,
+                    line: token.line_num,
+                    column: Some(token.column),
+                    span: Some(token.span.clone()),
+                    message: token.lexemme,
+                    at: None,
+                    file: None,
+                    source_line: Some(scanner.line(token.line_num).to_string()),
+                    code: None,
+                });
+            }
+            _ => {
+                pieces.push(Piece { token, line_breaks });
+                line_breaks = 0;
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut compiler = Compiler::new(source);
+    compiler.set_resolve_imports(false);
+    if let (None, diagnostics) = compiler.compile(false) {
+        return Err(diagnostics
+            .into_iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Error)
+            .collect());
+    }
+
+    let mut formatter = Formatter {
+        out: String::new(),
+        indent: 0,
+        parens: 0,
+        braces: Vec::new(),
+        previous: None,
+        previous_unary: false,
+        closed_block: false,
+        break_next: false,
+    };
+    for piece in pieces {
+        formatter.piece(piece);
+    }
+    let mut out = formatter.out.trim_end().to_string();
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+struct Formatter {
+    out: String,
+    indent: usize,
+    parens: usize, // How deep inside parentheses we are, where a ';' (in a for) doesn't end the line
+    braces: Vec<bool>, // For every '{' we're inside, whether it opens a block. The braces of a selective import don't
+    previous: Option<TokenType>, // The last token written, comments aside
+    previous_unary: bool, // Whether that token was a unary '-' or '!'
+    closed_block: bool, // Whether that token was a '}' closing a block
+    break_next: bool,  // The next piece goes on a new line, ie after a comment
+}
+
+impl Formatter {
+    fn piece(&mut self, piece: Piece) {
+        let token_type = piece.token.token_type;
+        if token_type == TokenType::TokenComment {
+            if piece.line_breaks == 0 && self.previous.is_some() {
+                self.out.push(' '); // Stays at the end of the line it was on
+            } else {
+                self.new_line(piece.line_breaks > 1);
+            }
+            self.out.push_str(piece.token.lexemme.trim_end());
+            self.break_next = true;
+            return;
+        }
+
+        let closes_block =
+            token_type == TokenType::TokenRightBrace && self.braces.last() == Some(&true);
+        if closes_block {
+            self.indent = self.indent.saturating_sub(1);
+        }
+
+        if self.break_next || self.breaks_before(token_type) {
+            // No blank lines right inside the braces of a block
+            let opened_block = self.previous == Some(TokenType::TokenLeftBrace)
+                && self.braces.last() == Some(&true);
+            self.new_line(piece.line_breaks > 1 && !opened_block && !closes_block);
+        } else if self.space_before(token_type) {
+            self.out.push(' ');
+        }
+        self.out.push_str(&piece.token.lexemme);
+
+        match token_type {
+            TokenType::TokenLeftParen => self.parens += 1,
+            TokenType::TokenRightParen => self.parens = self.parens.saturating_sub(1),
+            TokenType::TokenLeftBrace => {
+                let block = self.previous != Some(TokenType::TokenModuleAccess);
+                if block {
+                    self.indent += 1;
+                }
+                self.braces.push(block);
+            }
+            TokenType::TokenRightBrace => {
+                self.braces.pop();
+            }
+            _ => (),
+        }
+        self.previous_unary = matches!(token_type, TokenType::TokenMinus | TokenType::TokenBang)
+            && !self.previous.is_some_and(ends_value);
+        self.closed_block = closes_block;
+        self.previous = Some(token_type);
+        self.break_next = false;
+    }
+
+    /// Ends the line, with a blank line after it if blank is set, and indents the next one. At the start of the output it does nothing
+    fn new_line(&mut self, blank: bool) {
+        if self.out.is_empty() {
+            return;
+        }
+        self.out.truncate(self.out.trim_end_matches(' ').len());
+        self.out.push('\n');
+        if blank {
+            self.out.push('\n');
+        }
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    fn breaks_before(&self, token_type: TokenType) -> bool {
+        let in_block = self.braces.last() == Some(&true);
+        match self.previous {
+            None => false,
+            Some(TokenType::TokenSemicolon) => self.parens == 0,
+            Some(TokenType::TokenLeftBrace) => in_block && token_type != TokenType::TokenRightBrace,
+            Some(TokenType::TokenRightBrace) => {
+                !matches!(
+                    token_type,
+                    TokenType::TokenElse
+                        | TokenType::TokenSemicolon
+                        | TokenType::TokenRightParen
+                        | TokenType::TokenComma
+                        | TokenType::TokenModuleAccess
+                ) && self.closed_block
+            }
+            Some(_) => token_type == TokenType::TokenRightBrace && in_block,
+        }
+    }
+
+    fn space_before(&self, token_type: TokenType) -> bool {
+        let Some(previous) = self.previous else {
+            return false;
+        };
+        if self.previous_unary {
+            return false;
+        }
+        match (previous, token_type) {
+            (
+                _,
+                TokenType::TokenSemicolon
+                | TokenType::TokenComma
+                | TokenType::TokenDot
+                | TokenType::TokenRightParen
+                | TokenType::TokenModuleAccess,
+            ) => false,
+            (TokenType::TokenLeftParen | TokenType::TokenDot | TokenType::TokenModuleAccess, _) => {
+                false
+            }
+            // Calls, and the parameters of a function declaration
+            (
+                TokenType::TokenIdentifier
+                | TokenType::TokenRightParen
+                | TokenType::TokenThis
+                | TokenType::TokenFun,
+                TokenType::TokenLeftParen,
+            ) => false,
+            (TokenType::TokenLeftBrace, TokenType::TokenRightBrace) => false,
+            // Inside the braces of a selective import
+            (TokenType::TokenLeftBrace, _) | (_, TokenType::TokenRightBrace) => {
+                self.braces.last() != Some(&false)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Whether a '-' or '!' after a token of this type is a binary operator, since the token finished an operand
+fn ends_value(token_type: TokenType) -> bool {
+    matches!(
+        token_type,
+        TokenType::TokenIdentifier
+            | TokenType::TokenNumber
+            | TokenType::TokenString
+            | TokenType::TokenRightParen
+            | TokenType::TokenTrue
+            | TokenType::TokenFalse
+            | TokenType::TokenNil
+            | TokenType::TokenThis
+    )
+}
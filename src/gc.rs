@@ -2,10 +2,8 @@ use crate::value::{HeapObj, HeapObjVal, Value};
 use crate::vm::Global;
 
 use std::cmp::Reverse;
-use std::collections::BinaryHeap;
-
-const DEBUG_GC: bool = false;
-const DEBUG_STRESS_GC: bool = false;
+use std::collections::{BinaryHeap, HashSet};
+use std::rc::Rc;
 
 const INIT_GC_THRESHOLD: usize = 200;
 const MIN_SCALING_FACTOR: f64 = 0.5;
@@ -40,21 +38,27 @@ pub struct GC {
 
     allocations: usize,       // The number of live allocations
     next_gc_threshold: usize, // The number of allocations allowed until we GC
+    collections: usize,       // The number of times collect_garbage has run
 
     grey_worklist: Vec<usize>, // Each worklist task is an index into the instances vec for the HeapObj
+    visited_containers: HashSet<usize>, // Addresses of the arrays and maps already searched for pointers this collection, since they can contain themselves
     free_slots: BinaryHeap<Reverse<usize>>, // A priority queue for which slots to allocate. A min-heap because we want to allocate the front slots of the instances vec first,
-                                            // so that the later slots (which are still filled but just with placeholders) can be truncated in the cases where a users program allocates a large amount, drops them all, and then leavesthe instances vec full of placeholders
+    // so that the later slots (which are still filled but just with placeholders) can be truncated in the cases where a users program allocates a large amount, drops them all, and then leavesthe instances vec full of placeholders
 
-                                            // unmarked: bool, // Which bool type represents an "unmarked" node
-                                            // Annoying to implement because new variables will get instantiated with the wrong value, possibly allowing them to live one extra round of GC
+    // unmarked: bool, // Which bool type represents an "unmarked" node
+    // Annoying to implement because new variables will get instantiated with the wrong value, possibly allowing them to live one extra round of GC
+    stress: bool, // Collect on every allocation instead of waiting for the threshold
+    log: bool,    // Print every allocation, mark, free and collection to stderr
 }
 
 impl GC {
-    pub fn alloc(&mut self, val: HeapObj, stack: &Vec<Value>, globals: &Vec<Global>) -> Value {
-        if DEBUG_STRESS_GC || self.allocations >= self.next_gc_threshold {
-            self.collect_garbage(stack, globals);
-        }
+    /// Whether the next allocation should collect first, see collect
+    pub fn collection_due(&self) -> bool {
+        self.stress || self.allocations >= self.next_gc_threshold
+    }
 
+    /// Doesn't collect on its own, the caller checks collection_due beforehand
+    pub fn alloc(&mut self, val: HeapObj) -> Value {
         self.instances.push(val); // Either way we need to put on the new instance
         let index = if self.free_slots.is_empty() {
             self.instances.len() - 1
@@ -73,7 +77,7 @@ impl GC {
         };
 
         self.allocations += 1;
-        if DEBUG_GC {
+        if self.log {
             eprintln!(
                 "allocated slot {} | # of allocations = {}",
                 index, self.allocations
@@ -82,6 +86,85 @@ impl GC {
         Value::LoxPointer(index)
     }
 
+    /// Collect right away instead of waiting for the threshold. Returns the number of objects freed
+    ///
+    /// Stack is every value that is alive outside of the heap and the globals, ie the running stack and the stacks of suspended tasks
+    pub fn collect<'a>(
+        &mut self,
+        stack: impl Iterator<Item = &'a Value>,
+        globals: &Vec<Global>,
+    ) -> usize {
+        let before = self.allocations;
+        self.collect_garbage(stack, globals);
+        before - self.allocations
+    }
+
+    pub fn live_objects(&self) -> usize {
+        self.allocations
+    }
+
+    /// Approximate, since it only counts the heap slots of the live objects and not what they own, like the field maps of instances
+    pub fn bytes_allocated(&self) -> usize {
+        self.allocations * std::mem::size_of::<HeapObj>()
+    }
+
+    /// Bytes owned by the live heap objects and by every array, map, set, bytes and tuple reachable from them, the stack or the globals. Only accurate right after a collection, since it counts garbage too
+    pub fn memory_in_use<'a>(
+        &self,
+        stack: impl Iterator<Item = &'a Value>,
+        globals: &[Global],
+    ) -> usize {
+        let mut bytes = 0;
+        let mut pending: Vec<Value> = stack.cloned().collect();
+        pending.extend(globals.iter().filter_map(|global| match global {
+            Global::Init(value) => Some(value.clone()),
+            Global::Uninit => None,
+        }));
+        for instance in self.instances.iter() {
+            match &instance.obj {
+                HeapObjVal::LoxInstance(instance) => {
+                    bytes += instance.fields.len() * size_of::<(usize, Value)>();
+                    pending.extend(instance.fields.values().cloned());
+                }
+                HeapObjVal::LoxClosure(closure) => {
+                    bytes += closure.values.len() * size_of::<Value>();
+                    pending.extend(closure.values.iter().cloned());
+                }
+                HeapObjVal::HeapPlaceholder => continue,
+            }
+            bytes += size_of::<HeapObj>();
+        }
+
+        let mut visited = HashSet::new();
+        while let Some(val) = pending.pop() {
+            let address = match &val {
+                Value::LoxTuple(values) => Rc::as_ptr(values) as *const () as usize,
+                Value::LoxArray(values) => Rc::as_ptr(values) as *const () as usize,
+                Value::LoxMap(map) => Rc::as_ptr(map) as *const () as usize,
+                Value::LoxSet(set) => Rc::as_ptr(set) as *const () as usize,
+                Value::LoxBytes(bytes) => Rc::as_ptr(bytes) as *const () as usize,
+                _ => continue,
+            };
+            if !visited.insert(address) {
+                continue;
+            }
+            bytes += val.container_size();
+            match &val {
+                Value::LoxArray(values) => pending.extend(values.borrow().iter().cloned()),
+                Value::LoxTuple(values) => pending.extend(values.iter().cloned()),
+                Value::LoxMap(map) => {
+                    pending.extend(map.borrow().iter().map(|(_, val)| val.clone()))
+                }
+                _ => (),
+            }
+        }
+        bytes
+    }
+
+    pub fn collections(&self) -> usize {
+        self.collections
+    }
+
     fn mark_heap_obj(&mut self, index: usize) {
         let obj_opt = self.instances.get_mut(index);
         match obj_opt {
@@ -89,7 +172,7 @@ impl GC {
                 if obj.is_marked == false {
                     // obj.is_marked = !self.unmarked;
                     obj.is_marked = true;
-                    if DEBUG_GC {
+                    if self.log {
                         eprintln!("marked {:?} at {}", obj.obj_type, index)
                     }
                     self.grey_worklist.push(index); // Only the values that are pointed to by LoxPointers (instances and closures) can contain values that might be garbage collected
@@ -100,13 +183,15 @@ impl GC {
     }
 
     fn mark_value(&mut self, val: &Value) {
-        if let Value::LoxPointer(ptr) = val {
-            self.mark_heap_obj(*ptr);
+        let mut pointers = Vec::new();
+        collect_pointers(val, &mut pointers, &mut self.visited_containers);
+        for ptr in pointers {
+            self.mark_heap_obj(ptr);
         }
     }
 
-    fn mark_roots(&mut self, stack: &Vec<Value>, globals: &Vec<Global>) {
-        for val in stack.iter() {
+    fn mark_roots<'a>(&mut self, stack: impl Iterator<Item = &'a Value>, globals: &Vec<Global>) {
+        for val in stack {
             self.mark_value(val);
         }
 
@@ -126,22 +211,18 @@ impl GC {
             match obj_opt {
                 Some(obj) => {
                     // Blacken -> Look for LoxPointers that might be stored in these HeapObjs
-                    if DEBUG_GC {
+                    if self.log {
                         eprintln!("blackening {:?} at {}", obj.obj_type, index)
                     }
                     match &obj.obj {
                         HeapObjVal::LoxClosure(closure) => {
                             for val in &closure.values {
-                                if let Value::LoxPointer(ptr) = val {
-                                    to_mark.push(*ptr);
-                                }
+                                collect_pointers(val, &mut to_mark, &mut self.visited_containers);
                             }
                         }
                         HeapObjVal::LoxInstance(instance) => {
                             for val in instance.fields.values() {
-                                if let Value::LoxPointer(ptr) = val {
-                                    to_mark.push(*ptr);
-                                }
+                                collect_pointers(val, &mut to_mark, &mut self.visited_containers);
                             }
                         }
                         HeapObjVal::HeapPlaceholder => {
@@ -173,7 +254,7 @@ impl GC {
 
                 // Now check if we need to free this obj
                 if !obj.is_marked {
-                    if DEBUG_GC {
+                    if self.log {
                         eprintln!(
                             "freed slot {} | # of allocations = {} | value to free = {:?}",
                             index, self.allocations, obj,
@@ -198,7 +279,7 @@ impl GC {
     /// Shrink the instances vec as much as possible, but only if we will be removing above a given threshold # of placeholders
     fn shrink(&mut self, new_size: usize) {
         if (new_size as f64) < SHRINK_THRESHOLD * (self.instances.len() as f64) {
-            if DEBUG_GC {
+            if self.log {
                 eprintln!("shrinking from {} to {}", self.instances.len(), new_size)
             }
             self.instances.truncate(new_size);
@@ -216,7 +297,7 @@ impl GC {
             }
             self.free_slots = new_slots;
         } else {
-            if DEBUG_GC {
+            if self.log {
                 eprintln!(
                     "not shrinking from {} to {}",
                     self.instances.len(),
@@ -230,7 +311,7 @@ impl GC {
     fn rescale_threshold(&mut self) {
         // Now we know we went from self.next_gc_threshold # of instances down to self.allocations # of instances
         // Use that difference to determine the next_gc_threshold
-        let diff = self.next_gc_threshold - self.allocations;
+        let diff = self.next_gc_threshold.saturating_sub(self.allocations); // Stress mode collects before reaching the threshold, so there can be more live allocations than the threshold
 
         // 0 <= diff <= old_threshold
         // If this number is small, then we have mostly live values, and we should let the heap grow quite a bit before we try to GC again
@@ -243,9 +324,10 @@ impl GC {
         let slope: f64 = (MIN_SCALING_FACTOR - MAX_SCALING_FACTOR) / old_threshold;
         let scaling_factor = slope * (diff as f64) + MAX_SCALING_FACTOR;
         let new_threshold = old_threshold * scaling_factor;
-        self.next_gc_threshold = 1 + new_threshold as usize;
+        // Never below where it started, or a program that makes nothing but garbage ends up collecting on every other allocation
+        self.next_gc_threshold = INIT_GC_THRESHOLD.max(1 + new_threshold as usize);
 
-        if DEBUG_GC {
+        if self.log {
             eprintln!(
                 "Scaled GC threshold from {} to {}",
                 old_threshold, self.next_gc_threshold
@@ -253,44 +335,76 @@ impl GC {
         }
     }
 
-    fn collect_garbage(&mut self, stack: &Vec<Value>, globals: &Vec<Global>) {
-        if DEBUG_GC {
+    fn collect_garbage<'a>(
+        &mut self,
+        stack: impl Iterator<Item = &'a Value>,
+        globals: &Vec<Global>,
+    ) {
+        if self.log {
             eprintln!("--- gc begin")
         }
 
         self.mark_roots(stack, globals);
         self.mark_grey();
+        self.visited_containers.clear();
         let shrinkable_to = self.sweep();
 
         if let Some(new_size) = shrinkable_to {
             self.shrink(new_size);
         }
 
-        if DEBUG_GC {
-            // # of collections this round is inaccurate if we have DEBUG_GC_STRESS turned on, since we don't use the threshold
+        if self.log {
+            // # of collections this round is inaccurate in stress mode, since we don't use the threshold
             eprintln!(
                 "After collection | vec_size = {} | allocations = {} | collected = {}",
                 self.instances.len(),
                 self.allocations,
-                self.next_gc_threshold - self.allocations
+                self.next_gc_threshold.saturating_sub(self.allocations)
             );
         }
 
         self.rescale_threshold();
+        self.collections += 1;
 
         //self.unmarked = !self.unmarked; // Flip for the next gc run
-        if DEBUG_GC {
+        if self.log {
             eprintln!("--- gc end")
         }
     }
 
-    pub fn new() -> GC {
+    pub fn new(stress: bool, log: bool) -> GC {
         GC {
+            stress,
+            log,
             grey_worklist: Vec::new(),
+            visited_containers: HashSet::new(),
             instances: Vec::new(),
             free_slots: BinaryHeap::new(),
             allocations: 0,
+            collections: 0,
             next_gc_threshold: INIT_GC_THRESHOLD,
         }
     }
 }
+
+/// Pushes every heap pointer reachable from this value without going through the heap, ie the pointer itself, the instance a bound method is bound to, or pointers stored inside an array, map or tuple.
+/// Works through a list rather than recursing, and skips the containers in visited, so deeply nested or cyclic arrays are fine
+fn collect_pointers(val: &Value, pointers: &mut Vec<usize>, visited: &mut HashSet<usize>) {
+    let mut pending = vec![val.clone()];
+    while let Some(val) = pending.pop() {
+        match val {
+            Value::LoxPointer(ptr) => pointers.push(ptr),
+            Value::LoxBoundMethod(method) => pointers.push(method.pointer),
+            Value::LoxArray(values)
+                if visited.insert(Rc::as_ptr(&values) as *const () as usize) =>
+            {
+                pending.extend(values.borrow().iter().cloned());
+            }
+            Value::LoxMap(map) if visited.insert(Rc::as_ptr(&map) as *const () as usize) => {
+                pending.extend(map.borrow().iter().map(|(_, val)| val.clone()));
+            }
+            Value::LoxTuple(values) => pending.extend(values.iter().cloned()),
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+
+const INIT_PRUNE_THRESHOLD: usize = 1024;
+
+/// Deduplicates strings so that every distinct string only exists once, which lets string equality be a pointer comparison
+///
+/// The compiler interns every string constant, and the table is then handed over to the VM so that strings created at runtime (ie concatenation) point at the same allocations
+#[derive(Debug, Clone)]
+pub struct Interner {
+    strings: HashSet<Rc<str>>,
+    next_prune_threshold: usize, // The size the table can grow to before we drop the strings that nothing else refers to anymore
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner {
+            strings: HashSet::new(),
+            next_prune_threshold: INIT_PRUNE_THRESHOLD,
+        }
+    }
+
+    /// Returns the shared copy of s, adding it to the table if this is the first time we've seen it
+    pub fn intern(&mut self, s: &str) -> Rc<str> {
+        if let Some(interned) = self.strings.get(s) {
+            return interned.clone();
+        }
+
+        if self.strings.len() >= self.next_prune_threshold {
+            self.prune();
+        }
+
+        let interned: Rc<str> = Rc::from(s);
+        self.strings.insert(interned.clone());
+        interned
+    }
+
+    /// The total length of the strings that are still in use, see VmConfig::max_memory
+    pub fn bytes_in_use(&mut self) -> usize {
+        self.prune();
+        self.strings.iter().map(|s| s.len()).sum()
+    }
+
+    /// Drops every string that is only kept alive by the table itself
+    ///
+    /// Safe to do at any point since a string that nobody holds can't be compared against. If it shows up again it just gets a new allocation
+    fn prune(&mut self) {
+        self.strings.retain(|s| Rc::strong_count(s) > 1);
+        self.next_prune_threshold = (self.strings.len() * 2).max(INIT_PRUNE_THRESHOLD);
+    }
+}
+
+impl Default for Interner {
+    fn default() -> Interner {
+        Interner::new()
+    }
+}
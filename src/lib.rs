@@ -1,36 +1,595 @@
+mod asm;
+mod bytecode;
+mod cfg;
 mod chunk;
 mod compiler;
+mod conformance;
+mod coverage;
 mod debug;
+mod debugger;
+mod event_loop;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod formatter;
 mod gc;
+mod interner;
+mod lint;
+mod lsp;
 mod native;
+pub mod plugin;
 mod prec;
+mod profiler;
 mod resolver;
 mod scanner;
+mod snapshot;
+mod source;
+mod symbols;
 mod value;
 mod vm;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+mod wasm;
 
-use crate::compiler::Compiler;
-use crate::vm::{ExecutionMode, VM};
+use crate::chunk::{module_file, FunctionType};
+use crate::compiler::{CompilationResult, Compiler};
+use crate::native::HostNative;
+use crate::source::default_sources;
 
-#[derive(Debug, PartialEq)]
+use crate::vm::{ExecutionMode, VMState, VM};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+
+pub use crate::compiler::{Diagnostic, ErrorFormat, Severity};
+pub use crate::conformance::TestExpectations;
+pub use crate::formatter::format_source;
+pub use crate::lint::{lint, LINT_RULES};
+pub use crate::lsp::serve_lsp;
+pub use crate::native::{Arity, NativeContext, NativeError};
+pub use crate::scanner::{Scanner, Token, TokenType};
+#[cfg(feature = "fs")]
+pub use crate::source::FileSources;
+pub use crate::source::{ModuleSource, SourceProvider};
+pub use crate::value::{UserData, Value};
+pub use crate::vm::{BacktraceFrame, Hook, Hooks, Output, RuntimeError, VmConfig};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InterpretResult {
     InterpretOK,
     InterpretCompileError,
     InterpretRuntimeError,
+    InterpretBudgetExceeded, // Ran past VmConfig::max_instructions, VmConfig::max_time or VmConfig::max_memory
 }
 
-pub fn interpret(source: &String, debug: bool, quiet: bool) -> InterpretResult {
-    let compiler = Compiler::new(source, quiet);
-    let result = compiler.compile(debug);
-    if let None = result {
-        return InterpretResult::InterpretCompileError;
+/// What Vm::run_for stopped on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepResult {
+    Yielded, // Ran the requested number of instructions, the program continues with the next call to run_for
+    Done(InterpretResult),
+}
+
+/// Everything the Vm API can fail with
+#[derive(Debug, Clone, PartialEq)]
+pub enum RloxError {
+    Compile(Vec<Diagnostic>), // Every diagnostic of the source, warnings included
+    Runtime(RuntimeError),
+    BudgetExceeded(RuntimeError), // Ran past VmConfig::max_instructions, VmConfig::max_time or VmConfig::max_memory
+    NoProgram, // call_function, snapshot or restore was used before any program was started
+    UndefinedGlobal(String), // call_function was given a name the program doesn't define
+    Snapshot(String), // A global couldn't be saved by snapshot, or restore was given bytes it couldn't read
+}
+
+impl RloxError {
+    /// What the state ended with, after VM::resume or VM::call_from_host gave back anything other than InterpretOK
+    fn from_result(result: InterpretResult, state: &mut VMState) -> RloxError {
+        let error = state.error.take().unwrap_or_else(|| RuntimeError {
+            message: String::from("Runtime error"),
+            line: 0,
+            backtrace: Vec::new(),
+        });
+        match result {
+            InterpretResult::InterpretBudgetExceeded => RloxError::BudgetExceeded(error),
+            _ => RloxError::Runtime(error),
+        }
+    }
+}
+
+impl fmt::Display for RloxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RloxError::Compile(errors) => {
+                for error in errors {
+                    writeln!(f, "{}", error)?;
+                }
+                Ok(())
+            }
+            RloxError::Runtime(error) | RloxError::BudgetExceeded(error) => write!(f, "{}", error),
+            RloxError::NoProgram => write!(f, "No program was started"),
+            RloxError::UndefinedGlobal(name) => write!(f, "Undefined variable '{}'", name),
+            RloxError::Snapshot(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for RloxError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RloxError::Runtime(error) | RloxError::BudgetExceeded(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// A compiled program, ready to be run by a Vm. A clone runs the same program again without compiling it twice
+#[derive(Clone)]
+pub struct Program {
+    result: CompilationResult,
+    warnings: Vec<Diagnostic>,
+}
+
+impl Program {
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    /// Every function of the program and the modules it imports, in the order they were defined. The top level script isn't one of them
+    pub fn functions(&self) -> Vec<FunctionInfo<'_>> {
+        let result = &self.result;
+        result
+            .functions
+            .iter()
+            .enumerate()
+            .filter_map(|(index, function)| {
+                Some(FunctionInfo {
+                    name: function.name.as_deref()?,
+                    arity: function.arity,
+                    is_method: matches!(
+                        function.fn_type,
+                        FunctionType::Method | FunctionType::Initializer
+                    ),
+                    is_async: function.is_async,
+                    module: module_file(&result.module_functions, index),
+                })
+            })
+            .collect()
     }
 
-    let result = result.unwrap();
+    /// Every class of the program and the modules it imports, with its methods in the order they were defined
+    pub fn classes(&self) -> Vec<ClassInfo<'_>> {
+        let result = &self.result;
+        result
+            .classes
+            .iter()
+            .map(|class| {
+                let mut methods: Vec<(usize, usize)> = class
+                    .methods
+                    .iter()
+                    .map(|(name, function)| (*function, *name))
+                    .collect();
+                methods.sort();
+                ClassInfo {
+                    name: &class.name,
+                    superclass: class
+                        .superclass
+                        .map(|superclass| result.classes[superclass].name.as_str()),
+                    methods: methods
+                        .into_iter()
+                        .map(|(_, name)| result.identifier_constants[name].as_str())
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// The names of the globals the script defines at its top level, sorted. Globals that come from imports aren't included
+    pub fn globals(&self) -> Vec<&str> {
+        let mut globals: Vec<&str> = self
+            .result
+            .globals
+            .keys()
+            .map(|index| self.result.identifier_constants[*index].as_str())
+            .collect();
+        globals.sort_unstable();
+        globals
+    }
+
+    /// The constants the code loads, in the order OpConstant indexes them
+    pub fn constants(&self) -> &[Value] {
+        &self.result.constants
+    }
+}
+
+/// A function of a Program, see Program::functions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FunctionInfo<'a> {
+    pub name: &'a str,
+    pub arity: usize,
+    pub is_method: bool, // Initializers included
+    pub is_async: bool,
+    pub module: Option<&'a str>, // The file of the module it was written in, None for the main script
+}
+
+/// A class of a Program, see Program::classes
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassInfo<'a> {
+    pub name: &'a str,
+    pub superclass: Option<&'a str>,
+    pub methods: Vec<&'a str>,
+}
+
+/// The interpreter as a library. One Vm compiles and runs any number of programs, either to completion with run or a slice at a time with start
+/// and run_for, so that a host (ie a game's frame loop) can interleave it with its own work without needing a thread
+pub struct Vm {
+    config: VmConfig,
+    host_natives: Vec<HostNative>,
+    host_globals: Vec<(String, Value)>, // Set with set_global, defined in every program before it starts
+    sources: Rc<dyn SourceProvider>,    // Where `use` finds modules, see set_sources
+    program: Option<VM>,                // The program started last
+    state: Option<VMState>, // Kept once the program has ended, so that its globals can still be read
+    result: Option<InterpretResult>, // What the program ended with, None while it's still running
+    error: Option<RloxError>, // The details of what it ended with, if that was an error
+}
+
+impl Vm {
+    pub fn new(config: VmConfig) -> Vm {
+        Vm {
+            config,
+            host_natives: Vec::new(),
+            host_globals: Vec::new(),
+            sources: default_sources(),
+            program: None,
+            state: None,
+            result: None,
+            error: None,
+        }
+    }
+
+    /// The errors and warnings are written to VmConfig::stderr as well as returned, the warnings through Program::warnings
+    pub fn compile(&self, source: &str) -> Result<Program, RloxError> {
+        self.compile_with(Compiler::new(source))
+    }
+
+    /// Makes `use` load modules from sources in every program compiled from now on, instead of from files next to the working directory.
+    /// A HashMap<String, String> of module paths to source works as one
+    pub fn set_sources(&mut self, sources: impl SourceProvider + 'static) {
+        self.sources = Rc::new(sources);
+    }
+
+    fn compile_with(&self, mut compiler: Compiler) -> Result<Program, RloxError> {
+        compiler.set_warn_undefined_globals(self.config.warn_undefined_globals);
+        compiler.set_strict(self.config.strict);
+        compiler.set_warnings(self.config.warnings);
+        compiler.set_sources(self.sources.clone());
+        compiler.set_deny_warnings(self.config.deny_warnings);
+        compiler.set_source_map(self.config.source_map);
+        compiler.set_host_globals(
+            self.host_natives
+                .iter()
+                .map(|native| native.name.clone())
+                .chain(self.host_globals.iter().map(|(name, _)| name.clone()))
+                .collect(),
+        );
+        let (result, diagnostics) = compiler.compile(false);
+        for diagnostic in diagnostics.iter() {
+            self.config
+                .stderr
+                .write_line(&render(diagnostic, &self.config));
+        }
+        match result {
+            Some(result) => Ok(Program {
+                result,
+                warnings: diagnostics,
+            }),
+            None => Err(RloxError::Compile(diagnostics)),
+        }
+    }
+
+    /// Compiles source as more of the program started last and runs its top level code straight away, keeping every global, function and
+    /// class the program already has, ie for a REPL or for reloading code into a running host. Works both once the program has ended and
+    /// between two run_for slices. Without a program it's the same as compile followed by run
+    pub fn eval(&mut self, source: &str) -> Result<(), RloxError> {
+        let (Some(vm), Some(state)) = (self.program.as_ref(), self.state.as_ref()) else {
+            let program = self.compile(source)?;
+            return self.run(program);
+        };
+        let identifiers = vm
+            .identifiers
+            .iter()
+            .chain(state.extra_properties())
+            .cloned()
+            .collect();
+        let compiler = Compiler::continuing(
+            source,
+            vm.functions.clone(),
+            vm.classes.clone(),
+            vm.constants.clone(),
+            identifiers,
+        );
+        let program = self.compile_with(compiler)?;
+
+        let (Some(vm), Some(state)) = (self.program.as_mut(), self.state.as_mut()) else {
+            unreachable!()
+        };
+        vm.host_natives = self.host_natives.clone(); // register_native keeps the index of a native it replaces, so the ones already bound stay right
+        let script = vm.extend(program.result, state);
+        if self.result.is_some() {
+            state.clear_frames();
+        }
+        // Host globals are only defined for the names the program used, and the new code might use more of them
+        for (name, value) in self.host_globals.iter() {
+            if state.global(vm, name).is_none() {
+                state.set_global(vm, name, value.clone());
+            }
+        }
+        vm.call_from_host(state, Value::LoxFunction(script), &[])
+            .map(|_| ())
+            .map_err(|result| RloxError::from_result(result, state))
+    }
+
+    /// Binds function to the global name in every program started from now on and in the code eval adds to the current one, taking the place
+    /// of a native with the same name.
+    /// The VM checks the number of arguments against arity before calling it, and reports an Err as a runtime error at the call site
+    pub fn register_native(
+        &mut self,
+        name: &str,
+        arity: Arity,
+        function: impl Fn(&mut NativeContext, &[Value]) -> Result<Value, NativeError> + 'static,
+    ) {
+        let native = HostNative {
+            name: name.to_string(),
+            function: Rc::new(function),
+            arity,
+        };
+        match self.host_natives.iter().position(|x| x.name == name) {
+            Some(i) => self.host_natives[i] = native,
+            None => self.host_natives.push(native),
+        }
+    }
+
+    /// Defines the global name as value in every program started from now on, before any of its code runs, and in the current program if it uses
+    /// the name. Strings (including the ones in arrays, maps and sets) are interned again on the way in, so Value::from("text") is fine to pass
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        if let (Some(vm), Some(state)) = (self.program.as_ref(), self.state.as_mut()) {
+            state.set_global(vm, name, value.clone());
+        }
+        match self.host_globals.iter().position(|(x, _)| x == name) {
+            Some(i) => self.host_globals[i].1 = value,
+            None => self.host_globals.push((name.to_string(), value)),
+        }
+    }
+
+    /// The value of the global in the program started last, which can still be read once it has ended. None if the program never defined it
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.state.as_ref()?.global(self.program.as_ref()?, name)
+    }
+
+    /// Calls the function or closure in the global name of the program started last with args, and runs it to completion.
+    /// Works both once the program has ended and between two run_for slices, so a host can use it for event hooks
+    pub fn call_function(&mut self, name: &str, args: &[Value]) -> Result<Value, RloxError> {
+        let (Some(vm), Some(state)) = (self.program.as_ref(), self.state.as_mut()) else {
+            return Err(RloxError::NoProgram);
+        };
+        let Some(callee) = state.global(vm, name) else {
+            return Err(RloxError::UndefinedGlobal(name.to_string()));
+        };
+        let args: Vec<Value> = args.iter().map(|arg| state.adopt(arg.clone())).collect();
+        vm.call_from_host(state, callee, &args)
+            .map_err(|result| RloxError::from_result(result, state))
+    }
+
+    /// The globals of the program started last as bytes that restore can read back, ie so that a long running script keeps its state across restarts
+    /// of the host. Functions and classes are left out, since running the program defines them again. Only the globals are saved, a fiber that
+    /// is still running or waiting loses its place
+    pub fn snapshot(&self) -> Result<Vec<u8>, RloxError> {
+        let (Some(vm), Some(state)) = (self.program.as_ref(), self.state.as_ref()) else {
+            return Err(RloxError::NoProgram);
+        };
+        snapshot::snapshot(vm, state).map_err(RloxError::Snapshot)
+    }
+
+    /// Sets the globals saved by snapshot in the program started last, typically once it has run and defined its functions. Instances are
+    /// restored as instances of the class with the same name, so the program should be the one that was saved or a newer version of it
+    pub fn restore(&mut self, bytes: &[u8]) -> Result<(), RloxError> {
+        let (Some(vm), Some(state)) = (self.program.as_ref(), self.state.as_mut()) else {
+            return Err(RloxError::NoProgram);
+        };
+        snapshot::restore(vm, state, bytes).map_err(RloxError::Snapshot)
+    }
+
+    /// Runs the program to completion
+    pub fn run(&mut self, program: Program) -> Result<(), RloxError> {
+        self.start(program);
+        match self.resume(None) {
+            StepResult::Done(InterpretResult::InterpretOK) => Ok(()),
+            StepResult::Done(_) => Err(self.error.clone().unwrap()),
+            StepResult::Yielded => unreachable!("VM panic! Yielded without an instruction limit"),
+        }
+    }
+
+    /// Runs the program to completion and then calls the function in the global name with args, like `rlox --entry name` does with the script
+    /// arguments. Returns what the function returned
+    pub fn run_function(
+        &mut self,
+        program: Program,
+        name: &str,
+        args: &[Value],
+    ) -> Result<Value, RloxError> {
+        self.run(program)?;
+        self.call_function(name, args)
+    }
+
+    /// What the program started last ended with, once run_for has returned something other than Done(InterpretOK)
+    pub fn error(&self) -> Option<&RloxError> {
+        self.error.as_ref()
+    }
+
+    /// Gets the program ready to run with run_for, without executing anything yet. A program that was still running is dropped
+    pub fn start(&mut self, program: Program) {
+        let mut vm = VM::new(
+            ExecutionMode::Default,
+            program.result,
+            false,
+            self.config.clone(),
+        );
+        vm.host_natives = self.host_natives.clone();
+        let mut state = vm.start();
+        for (name, value) in self.host_globals.iter() {
+            state.set_global(&vm, name, value.clone());
+        }
+        self.state = Some(state);
+        self.program = Some(vm);
+        self.result = None;
+        self.error = None;
+    }
+
+    /// Executes at most n_instrs instructions of the started program. Once it has ended every call returns what it ended with
+    ///
+    /// Waiting on the event loop (ie every task is sleeping) still blocks, since that doesn't execute any instructions
+    pub fn run_for(&mut self, n_instrs: u64) -> StepResult {
+        self.resume(Some(n_instrs))
+    }
+
+    fn resume(&mut self, max_steps: Option<u64>) -> StepResult {
+        if let Some(result) = self.result {
+            return StepResult::Done(result);
+        }
+        let (Some(vm), Some(state)) = (self.program.as_ref(), self.state.as_mut()) else {
+            return StepResult::Done(InterpretResult::InterpretOK); // Nothing was started
+        };
+        match vm.resume(state, max_steps) {
+            StepResult::Yielded => StepResult::Yielded,
+            StepResult::Done(result) => {
+                vm.finish(state);
+                if result != InterpretResult::InterpretOK {
+                    self.error = Some(RloxError::from_result(result, state));
+                }
+                self.result = Some(result);
+                StepResult::Done(result)
+            }
+        }
+    }
+}
+
+pub fn interpret(source: &str, debug: bool, quiet: bool) -> InterpretResult {
+    interpret_with_config(source, debug, quiet, VmConfig::default())
+}
+
+pub fn interpret_with_config(
+    source: &str,
+    debug: bool,
+    quiet: bool,
+    config: VmConfig,
+) -> InterpretResult {
+    let mut compiler = Compiler::new(source);
+    compiler.set_warn_undefined_globals(config.warn_undefined_globals);
+    compiler.set_strict(config.strict);
+    compiler.set_warnings(config.warnings);
+    compiler.set_deny_warnings(config.deny_warnings);
+    compiler.set_source_map(config.source_map);
+    let (result, diagnostics) = compiler.compile(debug);
+    if !quiet {
+        for diagnostic in diagnostics.iter() {
+            eprintln!("{}", render(diagnostic, &config));
+        }
+    }
+    let Some(result) = result else {
+        return InterpretResult::InterpretCompileError;
+    };
+
     let vm = if debug {
-        VM::new(ExecutionMode::Trace, result, quiet)
+        VM::new(ExecutionMode::Trace, result, quiet, config)
     } else {
-        VM::new(ExecutionMode::Default, result, quiet)
+        VM::new(ExecutionMode::Default, result, quiet, config)
     };
     vm.run()
 }
+
+/// Runs a program that was compiled with compile_to_bytecode, skipping the compiler entirely
+pub fn interpret_bytecode(bytes: &[u8], config: VmConfig) -> InterpretResult {
+    match bytecode::deserialize(bytes) {
+        Ok(result) => VM::new(ExecutionMode::Default, result, false, config).run(),
+        Err(why) => {
+            eprintln!("Failed to load compiled program: {}", why);
+            InterpretResult::InterpretCompileError
+        }
+    }
+}
+
+/// Assembles the textual bytecode format (see asm.rs) and runs it
+pub fn interpret_assembly(source: &str, config: VmConfig) -> InterpretResult {
+    match asm::assemble(source) {
+        Ok(result) => VM::new(ExecutionMode::Default, result, false, config).run(),
+        Err(msg) => {
+            eprintln!("{}", msg);
+            InterpretResult::InterpretCompileError
+        }
+    }
+}
+
+/// Assembles the textual bytecode format into the precompiled .loxb format
+pub fn assemble_to_bytecode(source: &str) -> Option<Vec<u8>> {
+    match asm::assemble(source) {
+        Ok(result) => Some(bytecode::serialize(&result)),
+        Err(msg) => {
+            eprintln!("{}", msg);
+            None
+        }
+    }
+}
+
+/// Compiles the source into the precompiled .loxb format, which can be run directly or imported with `use` in place of the source file
+///
+/// With source_map the columns of the instructions are kept in the file as well, see VmConfig::source_map
+pub fn compile_to_bytecode(source: &str, quiet: bool, source_map: bool) -> Option<Vec<u8>> {
+    let mut compiler = Compiler::new(source);
+    compiler.set_source_map(source_map);
+    let (result, diagnostics) = compiler.compile(false);
+    if !quiet {
+        report(&diagnostics);
+    }
+    result.map(|result| bytecode::serialize(&result))
+}
+
+/// Compiles the source into the Graphviz DOT graph of the basic blocks of every function, for checking what the compiler made of the jumps
+pub fn compile_to_cfg(source: &str, quiet: bool) -> Option<String> {
+    let (result, diagnostics) = Compiler::new(source).compile(false);
+    if !quiet {
+        report(&diagnostics);
+    }
+    result.map(|result| cfg::control_flow_graph(&result))
+}
+
+/// Compiles the source and prints the disassembly of every function and class to stderr, the same listing --debug starts with, without running it
+///
+/// Returns false if it doesn't compile
+pub fn disassemble(source: &str) -> bool {
+    let (result, diagnostics) = Compiler::new(source).compile(true);
+    report(&diagnostics);
+    result.is_some()
+}
+
+/// Compiles the source without running it, for fuzzing the compiler: whatever the input, it comes back as Ok or as the diagnostics, never as a
+/// panic. Nothing is printed and `use` doesn't find any modules, so the outcome only depends on the source
+pub fn compile_str(source: &str) -> Result<(), Vec<Diagnostic>> {
+    let mut compiler = Compiler::new(source);
+    compiler.set_sources(Rc::new(HashMap::<String, String>::new()));
+    match compiler.compile(false) {
+        (Some(_), _) => Ok(()),
+        (None, diagnostics) => Err(diagnostics),
+    }
+}
+
+/// A diagnostic in the format the config asks for, see VmConfig::error_format
+fn render(diagnostic: &Diagnostic, config: &VmConfig) -> String {
+    match config.error_format {
+        ErrorFormat::Human => diagnostic.to_string(),
+        ErrorFormat::Json => diagnostic.to_json(config.script_path.as_deref()),
+    }
+}
+
+/// How the CLI shows compile errors and warnings
+fn report(diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+}
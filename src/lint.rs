@@ -0,0 +1,339 @@
+//! `rlox lint`, style checks over the tokens of a script that the compiler doesn't make, since none of them stop the script from running.
+//! Every rule reports warnings with its name as the code, and can be turned off by that name
+use crate::compiler::{Diagnostic, Severity};
+use crate::scanner::{Scanner, Token, TokenType};
+
+/// Every rule of the linter, by the code its warnings have
+pub const LINT_RULES: &[&str] = &[
+    "naming", // Classes start with an uppercase letter, functions, methods and variables don't
+    "empty_block", // A block with nothing in it, ie if (x) {}
+    "constant_condition", // An if or while whose condition is a literal, except for while (true)
+    "literal_on_left", // A comparison like 1 == x
+    "unused_parameter", // A parameter the function never mentions. Parameters starting with _ are left alone
+];
+
+/// Checks the source with every rule except the disabled ones, and returns the warnings in the order they appear in the source along with
+/// the errors for any tokens that couldn't be scanned
+pub fn lint(source: &str, disabled: &[&str]) -> Vec<Diagnostic> {
+    let scanner = Scanner::new(source);
+    let mut linter = Linter {
+        scanner,
+        tokens: Vec::new(),
+        disabled,
+        diagnostics: Vec::new(),
+    };
+    for token in scanner {
+        match token.token_type {
+            TokenType::TokenEOF => break,
+            TokenType::TokenError => linter.report(&token, Severity::Error, None, &token.lexemme),
+            _ => linter.tokens.push(token),
+        }
+    }
+
+    linter.check_tokens();
+    linter.check_functions();
+    linter
+        .diagnostics
+        .sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+    linter.diagnostics
+}
+
+struct Linter<'a> {
+    scanner: Scanner<'a>, // For the source lines of the diagnostics
+    tokens: Vec<Token>,
+    disabled: &'a [&'a str],
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Linter<'_> {
+    fn report(
+        &mut self,
+        token: &Token,
+        severity: Severity,
+        code: Option<&'static str>,
+        message: &str,
+    ) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            line: token.line_num,
+            column: Some(token.column),
+            span: Some(token.span.clone()),
+            message: message.to_string(),
+            at: match severity {
+                Severity::Warning => Some(format!("'{}'", token.lexemme)),
+                Severity::Error => None,
+            },
+            file: None,
+            source_line: Some(self.scanner.line(token.line_num).to_string()),
+            code,
+        });
+    }
+
+    fn warn(&mut self, index: usize, rule: &'static str, message: &str) {
+        if !self.disabled.contains(&rule) {
+            let token = self.tokens[index].clone();
+            self.report(&token, Severity::Warning, Some(rule), message);
+        }
+    }
+
+    fn token_type(&self, index: usize) -> Option<TokenType> {
+        self.tokens.get(index).map(|token| token.token_type)
+    }
+
+    /// The rules that only need to look at a token and the ones around it
+    fn check_tokens(&mut self) {
+        for i in 0..self.tokens.len() {
+            let before = i.checked_sub(1).and_then(|i| self.token_type(i));
+            match self.tokens[i].token_type {
+                TokenType::TokenClass => self.check_name(i + 1, true),
+                TokenType::TokenFun | TokenType::TokenVar => self.check_name(i + 1, false),
+                TokenType::TokenIf | TokenType::TokenWhile => self.check_condition(i),
+                TokenType::TokenEqualEqual | TokenType::TokenBangEqual => {
+                    // Only when the literal is all of the left operand, and not the right operand of something binding more tightly, ie x + 1 == y.
+                    // Comparing two literals is left alone as well, since there's nothing to swap
+                    let operand_start = i.checked_sub(2).and_then(|i| self.token_type(i));
+                    if before.is_some_and(is_literal)
+                        && operand_start.is_none_or(starts_operand)
+                        && !self.token_type(i + 1).is_some_and(is_literal)
+                    {
+                        let message = format!(
+                            "Literal on the left of '{}', which reads better on the right",
+                            self.tokens[i].lexemme
+                        );
+                        self.warn(i - 1, "literal_on_left", &message);
+                    }
+                }
+                TokenType::TokenLeftBrace
+                    if self.token_type(i + 1) == Some(TokenType::TokenRightBrace)
+                        && self.opens_statement_block(i) =>
+                {
+                    self.warn(i, "empty_block", "Empty block");
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn check_name(&mut self, index: usize, class: bool) {
+        let Some(token) = self.tokens.get(index) else {
+            return;
+        };
+        if token.token_type != TokenType::TokenIdentifier {
+            return; // An anonymous function
+        }
+        let Some(first) = token.lexemme.chars().next() else {
+            return;
+        };
+        let name = token.lexemme.clone();
+        if class && !first.is_uppercase() {
+            self.warn(
+                index,
+                "naming",
+                &format!(
+                    "Class name '{}' should start with an uppercase letter",
+                    name
+                ),
+            );
+        } else if !class && first.is_uppercase() {
+            self.warn(
+                index,
+                "naming",
+                &format!("Name '{}' should start with a lowercase letter", name),
+            );
+        }
+    }
+
+    /// Warns about if (literal), and about while (literal) unless it's the while (true) of a loop that's meant to be endless
+    fn check_condition(&mut self, index: usize) {
+        let i = index + 2;
+        if self.token_type(index + 1) != Some(TokenType::TokenLeftParen)
+            || self.token_type(i + 1) != Some(TokenType::TokenRightParen)
+        {
+            return;
+        }
+        let Some(literal) = self.token_type(i).filter(|t| is_literal(*t)) else {
+            return;
+        };
+        let truthy = !matches!(literal, TokenType::TokenFalse | TokenType::TokenNil);
+        if self.tokens[index].token_type == TokenType::TokenWhile && literal == TokenType::TokenTrue
+        {
+            return;
+        }
+        let message = if truthy {
+            "Condition is always true"
+        } else {
+            "Condition is always false"
+        };
+        self.warn(i, "constant_condition", message);
+    }
+
+    /// Whether the '{' at index starts a block statement, as opposed to the body of a function or a class which can be empty on purpose
+    fn opens_statement_block(&self, index: usize) -> bool {
+        let Some(before) = index.checked_sub(1) else {
+            return true;
+        };
+        match self.tokens[before].token_type {
+            TokenType::TokenElse
+            | TokenType::TokenSemicolon
+            | TokenType::TokenLeftBrace
+            | TokenType::TokenRightBrace => true,
+            // The condition of an if, while or for
+            TokenType::TokenRightParen => self
+                .matching_open(before)
+                .and_then(|open| open.checked_sub(1))
+                .and_then(|keyword| self.token_type(keyword))
+                .is_some_and(|keyword| {
+                    matches!(
+                        keyword,
+                        TokenType::TokenIf | TokenType::TokenWhile | TokenType::TokenFor
+                    )
+                }),
+            _ => false,
+        }
+    }
+
+    /// The index of the '(' or '{' that the ')' or '}' at index closes
+    fn matching_open(&self, index: usize) -> Option<usize> {
+        let (open, close) = match self.tokens[index].token_type {
+            TokenType::TokenRightParen => (TokenType::TokenLeftParen, TokenType::TokenRightParen),
+            _ => (TokenType::TokenLeftBrace, TokenType::TokenRightBrace),
+        };
+        let mut depth = 0;
+        for i in (0..=index).rev() {
+            let token_type = self.tokens[i].token_type;
+            if token_type == close {
+                depth += 1;
+            } else if token_type == open {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+        }
+        None
+    }
+
+    /// The index of the ')' or '}' closing the '(' or '{' at index
+    fn matching_close(&self, index: usize) -> Option<usize> {
+        let (open, close) = match self.tokens[index].token_type {
+            TokenType::TokenLeftParen => (TokenType::TokenLeftParen, TokenType::TokenRightParen),
+            _ => (TokenType::TokenLeftBrace, TokenType::TokenRightBrace),
+        };
+        let mut depth = 0;
+        for i in index..self.tokens.len() {
+            let token_type = self.tokens[i].token_type;
+            if token_type == open {
+                depth += 1;
+            } else if token_type == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+        }
+        None
+    }
+
+    /// The rules about functions and methods, whose parameter lists start at the '(' of every `fun` and of every method in a class body
+    fn check_functions(&mut self) {
+        let mut parameter_lists = Vec::new();
+        for i in 0..self.tokens.len() {
+            match self.tokens[i].token_type {
+                TokenType::TokenFun => match self.token_type(i + 1) {
+                    Some(TokenType::TokenIdentifier) => parameter_lists.push(i + 2),
+                    _ => parameter_lists.push(i + 1),
+                },
+                TokenType::TokenClass => {
+                    let Some(open) = (i..self.tokens.len())
+                        .find(|i| self.tokens[*i].token_type == TokenType::TokenLeftBrace)
+                    else {
+                        continue;
+                    };
+                    let close = self.matching_close(open).unwrap_or(self.tokens.len());
+                    // A method is a name and a parameter list right inside the class body, the methods' own bodies are skipped over
+                    let mut j = open + 1;
+                    while j < close {
+                        if self.token_type(j) == Some(TokenType::TokenIdentifier)
+                            && self.token_type(j + 1) == Some(TokenType::TokenLeftParen)
+                        {
+                            self.check_name(j, false);
+                            parameter_lists.push(j + 1);
+                        }
+                        j = match self.token_type(j) {
+                            Some(TokenType::TokenLeftBrace) => {
+                                self.matching_close(j).unwrap_or(close) + 1
+                            }
+                            _ => j + 1,
+                        };
+                    }
+                }
+                _ => (),
+            }
+        }
+        for open in parameter_lists {
+            self.check_parameters(open);
+        }
+    }
+
+    fn check_parameters(&mut self, open: usize) {
+        if self.token_type(open) != Some(TokenType::TokenLeftParen) {
+            return;
+        }
+        let Some(close) = self.matching_close(open) else {
+            return;
+        };
+        if self.token_type(close + 1) != Some(TokenType::TokenLeftBrace) {
+            return;
+        }
+        let Some(end) = self.matching_close(close + 1) else {
+            return;
+        };
+
+        let body = &self.tokens[close + 2..end];
+        let unused: Vec<usize> = (open + 1..close)
+            .filter(|i| self.tokens[*i].token_type == TokenType::TokenIdentifier)
+            .filter(|i| !self.tokens[*i].lexemme.starts_with('_'))
+            .filter(|i| {
+                let name = &self.tokens[*i].lexemme;
+                // A property of the same name after a '.' isn't the parameter
+                !body.iter().enumerate().any(|(j, token)| {
+                    token.token_type == TokenType::TokenIdentifier
+                        && &token.lexemme == name
+                        && (j == 0 || body[j - 1].token_type != TokenType::TokenDot)
+                })
+            })
+            .collect();
+        for i in unused {
+            let message = format!("Parameter '{}' is never used", self.tokens[i].lexemme);
+            self.warn(i, "unused_parameter", &message);
+        }
+    }
+}
+
+fn is_literal(token_type: TokenType) -> bool {
+    matches!(
+        token_type,
+        TokenType::TokenNumber
+            | TokenType::TokenString
+            | TokenType::TokenTrue
+            | TokenType::TokenFalse
+            | TokenType::TokenNil
+    )
+}
+
+/// Whether an operand can start after a token of this type, with nothing binding more tightly than == in between
+fn starts_operand(token_type: TokenType) -> bool {
+    matches!(
+        token_type,
+        TokenType::TokenLeftParen
+            | TokenType::TokenEqual
+            | TokenType::TokenComma
+            | TokenType::TokenReturn
+            | TokenType::TokenPrint
+            | TokenType::TokenAnd
+            | TokenType::TokenOr
+            | TokenType::TokenSemicolon
+            | TokenType::TokenLeftBrace
+            | TokenType::TokenRightBrace
+    )
+}
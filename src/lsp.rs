@@ -0,0 +1,516 @@
+//! `rlox lsp`, a Language Server Protocol server on stdin and stdout. Every change to a document recompiles it for its diagnostics and
+//! symbols, which answer go to definition and the outline of the document. Documents are synced in full, there's no incremental compilation
+use crate::compiler::{json_string, Compiler, Diagnostic, Severity};
+use crate::symbols::{SymbolKind, Symbols};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+/// Serves requests read from input until the client sends exit or closes input, writing the responses and notifications to output
+pub fn serve_lsp(mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    let mut server = Server {
+        documents: HashMap::new(),
+        output: &mut output,
+    };
+    while let Some(body) = read_message(&mut input)? {
+        let Some(message) = Parser::parse(&body) else {
+            server.send(&object(vec![
+                ("jsonrpc", Json::from("2.0")),
+                ("id", Json::Null),
+                ("error", error(-32700, "Parse error")),
+            ]))?;
+            continue;
+        };
+        if message.get("method").as_str() == Some("exit") {
+            break;
+        }
+        server.handle(&message)?;
+    }
+    Ok(())
+}
+
+/// Reads the headers and body of the next message, None at the end of input
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue; // Tolerates blank lines between messages
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0; length.unwrap()];
+    input.read_exact(&mut body)?;
+    Ok(Some(String::from_utf8_lossy(&body).to_string()))
+}
+
+/// An open document, along with what its last compilation found
+struct Document {
+    text: String,
+    symbols: Symbols,
+}
+
+struct Server<'a, W: Write> {
+    documents: HashMap<String, Document>, // Keyed by uri
+    output: &'a mut W,
+}
+
+impl<W: Write> Server<'_, W> {
+    fn send(&mut self, message: &Json) -> io::Result<()> {
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()
+    }
+
+    fn handle(&mut self, message: &Json) -> io::Result<()> {
+        let params = message.get("params");
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+        let result = match message.get("method").as_str().unwrap_or("") {
+            "initialize" => Ok(object(vec![
+                (
+                    "capabilities",
+                    object(vec![
+                        ("textDocumentSync", Json::Number(1.0)), // The whole document is sent on every change
+                        ("definitionProvider", Json::Bool(true)),
+                        ("documentSymbolProvider", Json::Bool(true)),
+                    ]),
+                ),
+                ("serverInfo", object(vec![("name", Json::from("rlox"))])),
+            ])),
+            "shutdown" => Ok(Json::Null),
+            "textDocument/didOpen" => {
+                let text = params
+                    .get("textDocument")
+                    .get("text")
+                    .as_str()
+                    .unwrap_or("");
+                return self.update(uri, text.to_string());
+            }
+            "textDocument/didChange" => {
+                let text = match params.get("contentChanges") {
+                    Json::Array(changes) => changes
+                        .last()
+                        .and_then(|change| change.get("text").as_str()),
+                    _ => None,
+                };
+                return self.update(uri, text.unwrap_or("").to_string());
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return self.publish_diagnostics(uri, Vec::new());
+            }
+            "textDocument/definition" => Ok(self.definition(uri, params.get("position"))),
+            "textDocument/documentSymbol" => Ok(self.document_symbols(uri)),
+            _ => Err(error(-32601, "Method not found")),
+        };
+
+        // Notifications don't have an id, and don't get a response even if they aren't understood
+        let id = message.get("id");
+        if *id == Json::Null {
+            return Ok(());
+        }
+        let (key, value) = match result {
+            Ok(result) => ("result", result),
+            Err(error) => ("error", error),
+        };
+        self.send(&object(vec![
+            ("jsonrpc", Json::from("2.0")),
+            ("id", id.clone()),
+            (key, value),
+        ]))
+    }
+
+    /// Recompiles the document and publishes its diagnostics
+    fn update(&mut self, uri: &str, text: String) -> io::Result<()> {
+        let mut compiler = Compiler::new(&text);
+        compiler.set_warnings(true);
+        let (symbols, diagnostics) = compiler.symbols();
+        let diagnostics = diagnostics
+            .iter()
+            .map(|diagnostic| lsp_diagnostic(&text, diagnostic))
+            .collect();
+        self.documents
+            .insert(uri.to_string(), Document { text, symbols });
+        self.publish_diagnostics(uri, diagnostics)
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str, diagnostics: Vec<Json>) -> io::Result<()> {
+        self.send(&object(vec![
+            ("jsonrpc", Json::from("2.0")),
+            ("method", Json::from("textDocument/publishDiagnostics")),
+            (
+                "params",
+                object(vec![
+                    ("uri", Json::from(uri)),
+                    ("diagnostics", Json::Array(diagnostics)),
+                ]),
+            ),
+        ]))
+    }
+
+    /// The Location of the declaration of the name at position, or null if it's not declared in the document
+    fn definition(&self, uri: &str, position: &Json) -> Json {
+        let Some(document) = self.documents.get(uri) else {
+            return Json::Null;
+        };
+        let line = position.get("line").as_usize().unwrap_or(0);
+        let character = position.get("character").as_usize().unwrap_or(0);
+        match document
+            .symbols
+            .definition(offset(&document.text, line, character))
+        {
+            Some(token) => object(vec![
+                ("uri", Json::from(uri)),
+                (
+                    "range",
+                    range(&document.text, token.span.start, token.span.end),
+                ),
+            ]),
+            None => Json::Null,
+        }
+    }
+
+    /// Every class, method, and top level function and variable, as SymbolInformation
+    fn document_symbols(&self, uri: &str) -> Json {
+        let Some(document) = self.documents.get(uri) else {
+            return Json::Null;
+        };
+        let symbols = document.symbols.symbols.iter().map(|symbol| {
+            let kind = match symbol.kind {
+                SymbolKind::Class => 5.0,
+                SymbolKind::Method => 6.0,
+                SymbolKind::Function => 12.0,
+                SymbolKind::Variable => 13.0,
+            };
+            let span = &symbol.name.span;
+            let mut fields = vec![
+                ("name", Json::from(symbol.name.lexemme.as_str())),
+                ("kind", Json::Number(kind)),
+                (
+                    "location",
+                    object(vec![
+                        ("uri", Json::from(uri)),
+                        ("range", range(&document.text, span.start, span.end)),
+                    ]),
+                ),
+            ];
+            if let Some(container) = &symbol.container {
+                fields.push(("containerName", Json::from(container.as_str())));
+            }
+            object(fields)
+        });
+        Json::Array(symbols.collect())
+    }
+}
+
+/// A Diagnostic as LSP has them. The ones from imported modules go at the start of the document, since that's all of it there is to show
+fn lsp_diagnostic(text: &str, diagnostic: &Diagnostic) -> Json {
+    let (range, message) = match (&diagnostic.file, &diagnostic.span) {
+        (Some(file), _) => (
+            range(text, 0, 0),
+            format!(
+                "{} (line {}): {}",
+                file, diagnostic.line, diagnostic.message
+            ),
+        ),
+        (None, Some(span)) => (
+            range(text, span.start, span.end),
+            diagnostic.message.clone(),
+        ),
+        // Only the line is known, so all of it is underlined
+        (None, None) => {
+            let start = offset(text, diagnostic.line.saturating_sub(1), 0);
+            let end = text[start..].find('\n').map_or(text.len(), |i| start + i);
+            (range(text, start, end), diagnostic.message.clone())
+        }
+    };
+    let severity = match diagnostic.severity {
+        Severity::Error => 1.0,
+        Severity::Warning => 2.0,
+    };
+    let mut fields = vec![
+        ("range", range),
+        ("severity", Json::Number(severity)),
+        ("source", Json::from("rlox")),
+        ("message", Json::from(message.as_str())),
+    ];
+    if let Some(code) = diagnostic.code {
+        fields.push(("code", Json::from(code)));
+    }
+    object(fields)
+}
+
+/// The LSP Range between two byte offsets
+fn range(text: &str, start: usize, end: usize) -> Json {
+    object(vec![
+        ("start", position(text, start)),
+        ("end", position(text, end)),
+    ])
+}
+
+/// The LSP Position of a byte offset, whose character counts UTF-16 code units from the start of the line
+fn position(text: &str, offset: usize) -> Json {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let character: usize = before[line_start..].chars().map(char::len_utf16).sum();
+    object(vec![
+        ("line", Json::Number(before.matches('\n').count() as f64)),
+        ("character", Json::Number(character as f64)),
+    ])
+}
+
+/// The byte offset of an LSP Position, clamped to the end of its line
+fn offset(text: &str, line: usize, character: usize) -> usize {
+    let line_start: usize = text.split_inclusive('\n').take(line).map(str::len).sum();
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(
+        fields
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+    )
+}
+
+fn error(code: i32, message: &str) -> Json {
+    object(vec![
+        ("code", Json::Number(code as f64)),
+        ("message", Json::from(message)),
+    ])
+}
+
+/// Just enough JSON for the protocol
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// The field called key, or Null if there isn't one or this isn't an object
+    fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(k, _)| k == key)
+                .map_or(&Json::Null, |(_, value)| value),
+            _ => &Json::Null,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(n) if *n >= 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write!(f, "{}", json_string(s)),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", json_string(key), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl Parser<'_> {
+    /// None unless the whole of s is a single JSON value
+    fn parse(s: &str) -> Option<Json> {
+        let mut parser = Parser {
+            chars: s.chars().peekable(),
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        match parser.chars.next() {
+            None => Some(value),
+            Some(_) => None,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, word: &str) -> Option<()> {
+        for c in word.chars() {
+            if self.chars.next()? != c {
+                return None;
+            }
+        }
+        Some(())
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.skip_whitespace();
+        match *self.chars.peek()? {
+            'n' => self.expect("null").map(|_| Json::Null),
+            't' => self.expect("true").map(|_| Json::Bool(true)),
+            'f' => self.expect("false").map(|_| Json::Bool(false)),
+            '"' => self.string().map(Json::String),
+            '[' => {
+                self.chars.next();
+                let mut values = Vec::new();
+                self.skip_whitespace();
+                if self.chars.peek() == Some(&']') {
+                    self.chars.next();
+                    return Some(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.chars.next()? {
+                        ',' => (),
+                        ']' => return Some(Json::Array(values)),
+                        _ => return None,
+                    }
+                }
+            }
+            '{' => {
+                self.chars.next();
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.chars.peek() == Some(&'}') {
+                    self.chars.next();
+                    return Some(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(":")?;
+                    fields.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.chars.next()? {
+                        ',' => (),
+                        '}' => return Some(Json::Object(fields)),
+                        _ => return None,
+                    }
+                }
+            }
+            _ => {
+                let mut number = String::new();
+                while let Some(c) = self
+                    .chars
+                    .peek()
+                    .filter(|c| c.is_ascii_digit() || "+-.eE".contains(**c))
+                {
+                    number.push(*c);
+                    self.chars.next();
+                }
+                number.parse().ok().map(Json::Number)
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.expect("\"")?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next()? {
+                '"' => return Some(s),
+                '\\' => match self.chars.next()? {
+                    'n' => s.push('\n'),
+                    't' => s.push('\t'),
+                    'r' => s.push('\r'),
+                    'b' => s.push('\u{8}'),
+                    'f' => s.push('\u{c}'),
+                    'u' => {
+                        let unit = self.hex()?;
+                        // Outside of the basic plane a character is written as a surrogate pair
+                        let c = if (0xD800..0xDC00).contains(&unit) {
+                            self.expect("\\u")?;
+                            let low = self.hex()?;
+                            char::from_u32(
+                                0x10000 + ((unit - 0xD800) << 10) + (low.checked_sub(0xDC00)?),
+                            )
+                        } else {
+                            char::from_u32(unit)
+                        };
+                        s.push(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    c => s.push(c), // ie \" \\ and \/
+                },
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn hex(&mut self) -> Option<u32> {
+        let digits: String = (0..4).filter_map(|_| self.chars.next()).collect();
+        u32::from_str_radix(&digits, 16).ok()
+    }
+}
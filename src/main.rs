@@ -1,55 +1,536 @@
-use rlox::InterpretResult;
+use rlox::{ErrorFormat, InterpretResult, Output, Severity, TestExpectations, Vm, VmConfig};
 
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io;
 use std::io::prelude::*;
-use std::path::Path;
-use std::process::exit;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+use std::time::{Duration, Instant};
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    // Everything after `--` belongs to the script, so that its arguments can't be mistaken for our flags
+    let script_args = match args.iter().position(|x| x == "--") {
+        Some(i) => args.split_off(i)[1..].to_vec(),
+        None => Vec::new(),
+    };
+    if args.len() >= 3 && args[1].eq("run") {
+        args.remove(1); // `rlox run path` is the same as `rlox path`, it just reads better for .loxb files
+    }
 
-    if args.len() >= 2 {
-        let debug = (args.len() == 3) && args[2].eq("--debug");
-        let stdlib = (args.len() == 3) && args[2].eq("--stdlib");
-        // println!("stdlib {}", stdlib);
-        let result = run_file(args.get(1).unwrap(), debug, stdlib);
+    if args.len() >= 3 && args[1].eq("compile") {
+        let output = match args.iter().position(|x| x == "-o") {
+            Some(i) => match args.get(i + 1) {
+                Some(output) => output.clone(),
+                None => {
+                    println!("Usage: rlox compile path [-o output] [--source-map]");
+                    exit(64);
+                }
+            },
+            None => Path::new(&args[2])
+                .with_extension("loxb")
+                .to_string_lossy()
+                .to_string(),
+        };
+        let source_map = args.iter().any(|x| x == "--source-map");
+        exit(compile_file(&args[2], &output, source_map))
+    } else if args.len() >= 3 && args[1].eq("asm") {
+        let source = match fs::read_to_string(&args[2]) {
+            Ok(source) => source,
+            Err(why) => {
+                eprintln!("Failed to read {}: {}", args[2], why);
+                exit(1);
+            }
+        };
+        // With -o the assembled program is written out as a .loxb instead of being run
+        if let Some(i) = args.iter().position(|x| x == "-o") {
+            let Some(output) = args.get(i + 1) else {
+                println!("Usage: rlox asm path [-o output]");
+                exit(64);
+            };
+            let Some(bytes) = rlox::assemble_to_bytecode(&source) else {
+                exit(65);
+            };
+            if let Err(why) = fs::write(output, bytes) {
+                eprintln!("Failed to write {}: {}", output, why);
+                exit(1);
+            }
+            exit(0);
+        }
+        let config = VmConfig {
+            script_path: Some(args[2].clone()),
+            ..VmConfig::default()
+        };
+        exit(match rlox::interpret_assembly(&source, config) {
+            InterpretResult::InterpretOK => 0,
+            InterpretResult::InterpretCompileError => 65,
+            _ => 70,
+        })
+    } else if args.len() >= 3 && args[1].eq("fmt") {
+        let check = args.iter().any(|x| x == "--check");
+        let paths: Vec<&String> = args[2..].iter().filter(|x| *x != "--check").collect();
+        exit(format_files(&paths, check))
+    } else if args.len() >= 3 && args[1].eq("lint") {
+        let json = args.iter().any(|x| x == "--error-format=json");
+        let disabled: Vec<&str> = args
+            .iter()
+            .filter_map(|x| x.strip_prefix("--disable="))
+            .flat_map(|rules| rules.split(','))
+            .collect();
+        if let Some(rule) = disabled
+            .iter()
+            .find(|rule| !rlox::LINT_RULES.contains(rule))
+        {
+            eprintln!(
+                "Unknown lint rule '{}', the rules are {}",
+                rule,
+                rlox::LINT_RULES.join(", ")
+            );
+            exit(64);
+        }
+        let paths: Vec<&String> = args[2..].iter().filter(|x| !x.starts_with("--")).collect();
+        exit(lint_files(&paths, &disabled, json))
+    } else if args.len() >= 3 && args[1].eq("bench") {
+        let iterations = match args.iter().position(|x| x == "--iterations") {
+            Some(i) => match args.get(i + 1).and_then(|x| x.parse().ok()) {
+                Some(n) if n > 0 => n,
+                _ => {
+                    eprintln!("Expected a number of iterations after --iterations");
+                    exit(64);
+                }
+            },
+            None => 10,
+        };
+        exit(bench_file(&args[2], iterations))
+    } else if args.len() >= 3 && args[1].eq("conformance") {
+        exit(conformance(&args[2]))
+    } else if args.len() == 2 && args[1].eq("lsp") {
+        let stdin = io::stdin();
+        if let Err(why) = rlox::serve_lsp(stdin.lock(), io::stdout()) {
+            eprintln!("Language server failed: {}", why);
+            exit(1);
+        }
+        exit(0);
+    } else if args.len() >= 3 && args[1].eq("debug") {
+        let config = VmConfig {
+            debugger: true,
+            script_path: Some(args[2].clone()),
+            script_args,
+            allow_exec: true,
+            ..VmConfig::default()
+        };
+        let result = run_file(&args[2], false, false, config);
+        exit(match result {
+            InterpretResult::InterpretOK => 0,
+            InterpretResult::InterpretCompileError => 65,
+            _ => 70,
+        })
+    } else if args.len() >= 2 {
+        let has_flag = |flag: &str| args[2..].iter().any(|x| x == flag);
+        if has_flag("--dump-cfg") {
+            exit(dump_cfg(&args[1]));
+        }
+        if has_flag("--disassemble") {
+            exit(disassemble_file(&args[1]));
+        }
+        let debug = has_flag("--debug");
+        let stdlib = has_flag("--stdlib");
+        let number_flag = |flag: &str| -> Option<u64> {
+            let i = args.iter().position(|x| x == flag)?;
+            match args.get(i + 1).and_then(|x| x.parse().ok()) {
+                Some(n) => Some(n),
+                None => {
+                    eprintln!("Expected a number after {}", flag);
+                    exit(64)
+                }
+            }
+        };
+        let entry = args
+            .iter()
+            .position(|x| x == "--entry")
+            .map(|i| match args.get(i + 1) {
+                Some(name) => name.clone(),
+                None => {
+                    eprintln!("Expected a function name after --entry");
+                    exit(64)
+                }
+            });
+        let mut config = VmConfig {
+            gc_stress: has_flag("--gc-stress"),
+            gc_log: has_flag("--gc-log"),
+            trace: has_flag("--trace"),
+            profile: has_flag("--profile"),
+            warn_undefined_globals: has_flag("--warn-undefined"),
+            strict: has_flag("--strict"),
+            warnings: has_flag("--warn") || has_flag("--deny-warnings"),
+            deny_warnings: has_flag("--deny-warnings"),
+            error_format: if has_flag("--error-format=json") {
+                ErrorFormat::Json
+            } else {
+                ErrorFormat::Human
+            },
+            source_map: has_flag("--source-map"),
+            coverage: if has_flag("--coverage") {
+                Some(String::from("lcov.info"))
+            } else {
+                None
+            },
+            script_path: Some(args[1].clone()),
+            max_instructions: number_flag("--max-instructions"),
+            max_time: number_flag("--max-time").map(Duration::from_millis),
+            max_memory: number_flag("--max-memory").map(|bytes| bytes as usize),
+            script_args,
+            entry,
+            allow_exec: !has_flag("--sandbox"),
+            ..VmConfig::default()
+        };
+        if let Some(max_frames) = number_flag("--max-frames") {
+            config.max_frames = max_frames as usize;
+        }
+        let path = args.get(1).unwrap();
+        let result = if path.ends_with(".loxb") {
+            run_bytecode_file(path, config)
+        } else {
+            run_file(path, debug, stdlib, config)
+        };
         exit(match result {
             InterpretResult::InterpretOK => 0,
             InterpretResult::InterpretCompileError => 65,
             InterpretResult::InterpretRuntimeError => 70,
+            InterpretResult::InterpretBudgetExceeded => 75,
         })
     } else {
-        println!("Usage: rlox path [--debug] | [--stdlib]");
+        println!("Usage: rlox path|- [--debug] [--trace] [--profile] [--coverage] [--warn] [--warn-undefined] [--strict] [--deny-warnings] [--error-format=json] [--source-map] [--dump-cfg] [--disassemble] [--stdlib] [--sandbox] [--gc-stress] [--gc-log] [--max-frames n]");
+        println!("           [--max-instructions n] [--max-time ms] [--max-memory bytes] [--entry function] [-- script args...]");
+        println!("       rlox compile path [-o output] [--source-map]");
+        println!("       rlox run path.loxb");
+        println!("       rlox asm path.loxasm [-o output]");
+        println!("       rlox fmt path... [--check]");
+        println!("       rlox lint path... [--disable=rule,...] [--error-format=json]");
+        println!("       rlox bench path [--iterations n]");
+        println!("       rlox conformance dir");
+        println!("       rlox debug path");
+        println!("       rlox lsp");
     }
 }
 
-fn run_file(filename: &String, debug: bool, stdlib: bool) -> InterpretResult {
-    let path = Path::new(&filename);
-    let path_display = path.display();
+/// Compiles the file into a .loxb module at output, returning the exit code
+fn compile_file(filename: &String, output: &String, source_map: bool) -> i32 {
+    let source = match fs::read_to_string(filename) {
+        Ok(source) => source,
+        Err(why) => {
+            eprintln!("Failed to read {}: {}", filename, why);
+            return 1;
+        }
+    };
+
+    match rlox::compile_to_bytecode(&source, false, source_map) {
+        Some(bytes) => match fs::write(output, bytes) {
+            Ok(_) => 0,
+            Err(why) => {
+                eprintln!("Failed to write {}: {}", output, why);
+                1
+            }
+        },
+        None => 65,
+    }
+}
+
+/// Formats the files in place, or with check only lists the ones that aren't formatted. A path of - formats stdin to stdout
+///
+/// Returns the exit code, which is 1 if check found a file to format and 65 if a file doesn't scan
+fn format_files(paths: &[&String], check: bool) -> i32 {
+    let mut code = 0;
+    for path in paths {
+        let source = if path.as_str() == "-" {
+            let mut source = String::new();
+            io::stdin().read_to_string(&mut source).map(|_| source)
+        } else {
+            fs::read_to_string(path)
+        };
+        let source = match source {
+            Ok(source) => source,
+            Err(why) => {
+                eprintln!("Failed to read {}: {}", path, why);
+                return 1;
+            }
+        };
+
+        let formatted = match rlox::format_source(&source) {
+            Ok(formatted) => formatted,
+            Err(diagnostics) => {
+                for mut diagnostic in diagnostics {
+                    diagnostic.file = Some(path.to_string());
+                    eprintln!("{}", diagnostic);
+                }
+                code = 65;
+                continue;
+            }
+        };
+        if check {
+            if formatted != source {
+                println!("{} is not formatted", path);
+                code = code.max(1);
+            }
+        } else if path.as_str() == "-" {
+            print!("{}", formatted);
+        } else if formatted != source {
+            if let Err(why) = fs::write(path, formatted) {
+                eprintln!("Failed to write {}: {}", path, why);
+                return 1;
+            }
+        }
+    }
+    code
+}
+
+/// Prints the lint warnings for the files, returning 1 if there were any and 65 if a file doesn't scan
+fn lint_files(paths: &[&String], disabled: &[&str], json: bool) -> i32 {
+    let mut code = 0;
+    for path in paths {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(why) => {
+                eprintln!("Failed to read {}: {}", path, why);
+                return 1;
+            }
+        };
+        for mut diagnostic in rlox::lint(&source, disabled) {
+            code = code.max(match diagnostic.severity {
+                Severity::Warning => 1,
+                Severity::Error => 65,
+            });
+            if json {
+                eprintln!("{}", diagnostic.to_json(Some(path)));
+            } else {
+                diagnostic.file = Some(path.to_string());
+                eprintln!("{}", diagnostic);
+            }
+        }
+    }
+    code
+}
 
-    let mut file = match File::open(&path) {
-        Ok(file) => file,
+/// Prints the control flow graph of the file as DOT instead of running it, returning the exit code
+fn dump_cfg(filename: &str) -> i32 {
+    let source = match fs::read_to_string(filename) {
+        Ok(source) => source,
         Err(why) => {
-            eprintln!("Failed to open {}: {}", path_display, why);
+            eprintln!("Failed to read {}: {}", filename, why);
+            return 1;
+        }
+    };
+    match rlox::compile_to_cfg(&source, false) {
+        Some(dot) => {
+            print!("{}", dot);
+            0
+        }
+        None => 65,
+    }
+}
+
+/// Prints the disassembly of the file instead of running it, returning the exit code
+fn disassemble_file(filename: &str) -> i32 {
+    let source = match fs::read_to_string(filename) {
+        Ok(source) => source,
+        Err(why) => {
+            eprintln!("Failed to read {}: {}", filename, why);
+            return 1;
+        }
+    };
+    if rlox::disassemble(&source) {
+        0
+    } else {
+        65
+    }
+}
+
+/// Compiles the file once and then times running it iterations times on the same Vm, after one untimed run to warm it up. What the script prints
+/// is thrown away so that the terminal doesn't get timed along with it
+///
+/// Returns the exit code, which is 65 if the file doesn't compile and 70 if a run fails
+fn bench_file(filename: &str, iterations: usize) -> i32 {
+    let source = match fs::read_to_string(filename) {
+        Ok(source) => source,
+        Err(why) => {
+            eprintln!("Failed to read {}: {}", filename, why);
+            return 1;
+        }
+    };
+    let mut vm = Vm::new(VmConfig {
+        stdout: Output::new(io::sink()),
+        script_path: Some(filename.to_string()),
+        ..VmConfig::default()
+    });
+    let Ok(program) = vm.compile(&source) else {
+        return 65;
+    };
+
+    let mut times = Vec::with_capacity(iterations);
+    for i in 0..=iterations {
+        let program = program.clone();
+        let start = Instant::now();
+        if vm.run(program).is_err() {
+            return 70; // The error has already been printed
+        }
+        if i > 0 {
+            times.push(start.elapsed().as_secs_f64() * 1000.0);
+        }
+    }
+
+    let mean = times.iter().sum::<f64>() / iterations as f64;
+    let variance = times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / iterations as f64;
+    times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median = if iterations % 2 == 0 {
+        (times[iterations / 2 - 1] + times[iterations / 2]) / 2.0
+    } else {
+        times[iterations / 2]
+    };
+    println!("{}: {} iterations", filename, iterations);
+    println!("  mean    {:.3} ms", mean);
+    println!("  median  {:.3} ms", median);
+    println!("  stddev  {:.3} ms", variance.sqrt());
+    println!("  min     {:.3} ms", times[0]);
+    println!("  max     {:.3} ms", times[iterations - 1]);
+    0
+}
+
+/// Runs every test under dir in its own rlox process, the way tool/bin/test.dart does, and reports the ones that didn't do what their comments
+/// expect. Benchmarks are left out, and so are the tests for the early chapters of the book (scanning and expressions) that only apply to an
+/// unfinished interpreter
+///
+/// Returns the exit code, which is 1 if any test failed
+fn conformance(dir: &str) -> i32 {
+    let interpreter = match env::current_exe() {
+        Ok(interpreter) => interpreter,
+        Err(why) => {
+            eprintln!("Failed to find the rlox executable: {}", why);
+            return 1;
+        }
+    };
+    let mut paths = Vec::new();
+    if let Err(why) = find_tests(Path::new(dir), &mut paths) {
+        eprintln!("Failed to read {}: {}", dir, why);
+        return 1;
+    }
+    paths.sort();
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for path in paths.iter() {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(why) => {
+                eprintln!("Failed to read {}: {}", path.display(), why);
+                return 1;
+            }
+        };
+        let Some(expectations) = TestExpectations::parse(&source) else {
+            skipped += 1;
+            continue;
+        };
+        // The time limit is only there so that a test that never ends can't hang the whole run. RLOX is for the tests under test/process
+        // that run the interpreter themselves
+        let output = match Command::new(&interpreter)
+            .arg(path)
+            .args(["--max-time", "10000"])
+            .env("RLOX", &interpreter)
+            .output()
+        {
+            Ok(output) => output,
+            Err(why) => {
+                eprintln!("Failed to run {}: {}", path.display(), why);
+                return 1;
+            }
+        };
+
+        let failures = expectations.check(
+            &String::from_utf8_lossy(&output.stdout),
+            &String::from_utf8_lossy(&output.stderr),
+            output.status.code(),
+        );
+        if failures.is_empty() {
+            passed += 1;
+        } else {
+            failed += 1;
+            println!("FAIL {}", path.display());
+            for failure in failures {
+                println!("     {}", failure);
+            }
+            println!();
+        }
+    }
+
+    println!(
+        "{} tests passed, {} tests failed, {} skipped",
+        passed, failed, skipped
+    );
+    if failed > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+/// Every .lox file under dir, except in the directories conformance leaves out
+fn find_tests(dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.contains("benchmark") || name == "scanning" || name == "expressions" {
+            continue;
+        }
+        if path.is_dir() {
+            find_tests(&path, paths)?;
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Runs a program compiled with `rlox compile`. The standard library has to have been compiled into it, so --stdlib doesn't apply
+fn run_bytecode_file(filename: &str, config: VmConfig) -> InterpretResult {
+    match fs::read(filename) {
+        Ok(bytes) => rlox::interpret_bytecode(&bytes, config),
+        Err(why) => {
+            eprintln!("Failed to open {}: {}", filename, why);
             exit(1);
         }
+    }
+}
+
+fn run_file(filename: &String, debug: bool, stdlib: bool, config: VmConfig) -> InterpretResult {
+    let path = Path::new(&filename);
+    let path_display = path.display();
+
+    // A path of - reads the script from stdin, ie for piping generated code straight in
+    let mut file: Box<dyn Read> = if filename == "-" {
+        Box::new(io::stdin())
+    } else {
+        match File::open(&path) {
+            Ok(file) => Box::new(file),
+            Err(why) => {
+                eprintln!("Failed to open {}: {}", path_display, why);
+                exit(1);
+            }
+        }
     };
 
     let mut s = String::new();
     match file.read_to_string(&mut s) {
         Ok(_) => {
             let mut std_src = String::new();
-            if stdlib{
+            if stdlib {
                 File::open(Path::new("loxstd.lox"))
-                .expect("NO STD LIB FOUND, BUT STDLIB WAS REQUESTED.")
-                .read_to_string(&mut std_src)
-                .expect("Cannot read file. FS Error.");
-            }else{
+                    .expect("NO STD LIB FOUND, BUT STDLIB WAS REQUESTED.")
+                    .read_to_string(&mut std_src)
+                    .expect("Cannot read file. FS Error.");
+            } else {
                 std_src = "".to_string()
             }
-            return rlox::interpret(&(std_src+&s), debug, false)
-        },
+            return rlox::interpret_with_config(&(std_src + &s), debug, false, config);
+        }
         Err(why) => {
             eprintln!("Failed to read {}: {}", path_display, why);
             exit(1);
@@ -1,102 +1,2080 @@
-use crate::value::Value;
+use crate::value::{
+    is_falsey, values_equal, Fields, LoxMap, LoxSet, MapKey, ObjBoundMethod, Value,
+};
+use crate::vm::{VMState, VM};
 
+use regex::Regex;
 
-pub type NativeFn = fn(usize, Vec<Value>) -> Value;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::process::Command;
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+/// The arguments are in the order they were passed, and the VM has already checked there's the right number of them
+pub type NativeFn = fn(&VM, &mut VMState, &[Value]) -> NativeResult;
 
-pub fn clock(_arg_count: usize, _args: Vec<Value>) -> Value {
-    Value::Double(1.0)
+pub type NativeResult = Result<Value, NativeError>;
+
+/// Returned by a native that was called the wrong way, the VM reports it as a runtime error at the call site
+#[derive(Debug, Clone)]
+pub struct NativeError {
+    pub message: String,
+    pub value: Option<Value>, // The value that was wrong, shown after the message
+}
+
+impl NativeError {
+    pub fn new(message: &str) -> NativeError {
+        NativeError {
+            message: message.to_string(),
+            value: None,
+        }
+    }
+
+    pub fn with_value(message: &str, value: &Value) -> NativeError {
+        NativeError {
+            message: message.to_string(),
+            value: Some(value.clone()),
+        }
+    }
+
+    /// The message for the runtime error
+    pub fn describe(&self, vm: &VM, state: &VMState) -> String {
+        match &self.value {
+            Some(value) => format!("{}, got {}", self.message, value.to_string(vm, state)),
+            None => self.message.clone(),
+        }
+    }
+}
+
+/// How many arguments a native takes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arity {
+    Exactly(usize),
+    Between(usize, usize), // For optional arguments, both ends are included
+    AtLeast(usize),        // Variadic
 }
 
-pub fn sin(_arg_count: usize, _args: Vec<Value>) -> Value {
-    match _args[0] {
-        Value::Double(d) => Value::Double(d.sin()),
-        _ => Value::Nil,
+impl Arity {
+    pub fn accepts(&self, arg_count: usize) -> bool {
+        match *self {
+            Arity::Exactly(n) => arg_count == n,
+            Arity::Between(min, max) => min <= arg_count && arg_count <= max,
+            Arity::AtLeast(min) => arg_count >= min,
+        }
     }
 }
 
-pub fn radians(_arg_count: usize, _args: Vec<Value>) -> Value {
-    match _args[0] {
-        Value::Double(d) => Value::Double(d * 3.14159265358979323846264338327950288f64 / 180.0),
-        _ => Value::Nil,
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arity::Exactly(n) => write!(f, "{}", n),
+            Arity::Between(min, max) => write!(f, "{} to {}", min, max),
+            Arity::AtLeast(min) => write!(f, "at least {}", min),
+        }
     }
 }
 
-pub fn __array(_arg_count: usize, _args: Vec<Value>) -> Value {
-    let v: Vec<Value> = Vec::new();
-    return Value::LoxArray(v);
+/// A function the host registered with Vm::register_native, which is given a NativeContext instead of the VM itself
+pub type HostFn = dyn Fn(&mut NativeContext, &[Value]) -> NativeResult;
+
+#[derive(Clone)]
+pub struct HostNative {
+    pub name: String,
+    pub function: Rc<HostFn>,
+    pub arity: Arity,
+}
+
+/// What a host function can do with the VM while it runs
+pub struct NativeContext<'a> {
+    vm: &'a VM,
+    state: &'a mut VMState,
+}
+
+impl<'a> NativeContext<'a> {
+    pub(crate) fn new(vm: &'a VM, state: &'a mut VMState) -> NativeContext<'a> {
+        NativeContext { vm, state }
+    }
+
+    /// A string interned by the VM. The strings a host function returns are interned on the way out as well, so Value::from works too
+    pub fn string(&mut self, s: &str) -> Value {
+        self.state.new_string(s)
+    }
+
+    /// The same text print would show for the value
+    pub fn display(&self, value: &Value) -> String {
+        value.to_string(self.vm, self.state)
+    }
+
+    /// Calls a Lox function that was passed in as an argument. Returns None if the call failed or the program ended inside it, in which case the
+    /// error has already been reported and the host function should return straight away
+    pub fn call(&mut self, callee: &Value, args: &[Value]) -> Option<Value> {
+        self.vm.call_function(self.state, callee.clone(), args)
+    }
+}
+
+/// A native function along with the global name it is bound to
+#[derive(Debug)]
+pub struct Native {
+    pub name: &'static str,
+    pub function: NativeFn,
+    pub arity: Arity,
+}
+
+impl Native {
+    const fn new(name: &'static str, function: NativeFn, arity: Arity) -> Native {
+        Native {
+            name,
+            function,
+            arity,
+        }
+    }
 }
 
-/// call this like `__array_index_get(1, arr)`
-pub fn __array_index_get(_arg_count: usize, _args: Vec<Value>) -> Value {
-    let mut index: usize;
-    match _args[1].clone() {
-        Value::Double(d) => index = d as usize,
-        _ => return Value::Nil,
+/// Natives are only ever referred to from STD_LIB, so two are the same native if they're the same entry
+impl PartialEq for Native {
+    fn eq(&self, other: &Native) -> bool {
+        std::ptr::eq(self, other)
     }
-    let mut arr: Vec<Value>;
-    // args[1]->array, _args[0]->index
-    match _args[0].clone() {
-        Value::LoxArray(v) => arr = v,
-        _ => return Value::Nil,
+}
+
+/// Every native function. A static rather than a const so that every reference to an entry points at the same place
+pub static STD_LIB: &[Native] = &[
+    Native::new("clock", clock, Arity::Exactly(0)),
+    Native::new("time_millis", time_millis, Arity::Exactly(0)),
+    Native::new("sin", sin, Arity::Exactly(1)),
+    Native::new("radians", radians, Arity::Exactly(1)),
+    Native::new("__array", __array, Arity::Exactly(0)),
+    Native::new("__array_index_get", __array_index_get, Arity::Exactly(2)),
+    Native::new("__array_index_set", __array_index_set, Arity::Exactly(3)),
+    Native::new("len", len, Arity::Exactly(1)),
+    Native::new("push", push, Arity::Exactly(2)),
+    Native::new("pop", pop, Arity::Exactly(1)),
+    Native::new("insert", insert, Arity::Exactly(3)),
+    Native::new("removeAt", remove_at, Arity::Exactly(2)),
+    Native::new("clear", clear, Arity::Exactly(1)),
+    Native::new("slice", slice, Arity::Exactly(3)),
+    Native::new("concat", concat, Arity::Exactly(2)),
+    Native::new("reverse", reverse, Arity::Exactly(1)),
+    Native::new("contains", contains, Arity::Exactly(2)),
+    Native::new("indexOf", index_of, Arity::Exactly(2)),
+    Native::new("join", join, Arity::Exactly(2)),
+    Native::new("map", map, Arity::Exactly(2)),
+    Native::new("filter", filter, Arity::Exactly(2)),
+    Native::new("reduce", reduce, Arity::Exactly(3)),
+    Native::new("sort", sort, Arity::Between(1, 2)),
+    Native::new("mapNew", map_new, Arity::Exactly(0)),
+    Native::new("mapGet", map_get, Arity::Exactly(2)),
+    Native::new("mapSet", map_set, Arity::Exactly(3)),
+    Native::new("mapHas", map_has, Arity::Exactly(2)),
+    Native::new("mapRemove", map_remove, Arity::Exactly(2)),
+    Native::new("mapKeys", map_keys, Arity::Exactly(1)),
+    Native::new("mapValues", map_values, Arity::Exactly(1)),
+    Native::new("setNew", set_new, Arity::Between(0, 1)),
+    Native::new("add", add, Arity::Exactly(2)),
+    Native::new("has", has, Arity::Exactly(2)),
+    Native::new("remove", remove, Arity::Exactly(2)),
+    Native::new("union", union, Arity::Exactly(2)),
+    Native::new("intersect", intersect, Arity::Exactly(2)),
+    Native::new("freeze", freeze, Arity::Exactly(1)),
+    Native::new("isFrozen", is_frozen, Arity::Exactly(1)),
+    Native::new("deepEqual", deep_equal, Arity::Exactly(2)),
+    Native::new("clone", clone, Arity::Exactly(1)),
+    Native::new("inspect", inspect, Arity::Exactly(1)),
+    Native::new("getattr", getattr, Arity::Exactly(2)),
+    Native::new("setattr", setattr, Arity::Exactly(3)),
+    Native::new("hasattr", hasattr, Arity::Exactly(2)),
+    Native::new("fields", fields, Arity::Exactly(1)),
+    Native::new("className", class_name, Arity::Exactly(1)),
+    Native::new("classOf", class_of, Arity::Exactly(1)),
+    Native::new("superOf", super_of, Arity::Exactly(1)),
+    Native::new("methods", methods, Arity::Exactly(1)),
+    Native::new("gcCollect", gc_collect, Arity::Exactly(0)),
+    Native::new("memoryStats", memory_stats, Arity::Exactly(0)),
+    Native::new("tupleGet", tuple_get, Arity::Exactly(2)),
+    Native::new("bytesNew", bytes_new, Arity::Exactly(1)),
+    Native::new("bytesGet", bytes_get, Arity::Exactly(2)),
+    Native::new("bytesSet", bytes_set, Arity::Exactly(3)),
+    Native::new("bytesSlice", bytes_slice, Arity::Exactly(3)),
+    Native::new("bytesFromString", bytes_from_string, Arity::Between(1, 2)),
+    Native::new("bytesToString", bytes_to_string, Arity::Between(1, 2)),
+    Native::new("readBytes", read_bytes, Arity::Exactly(1)),
+    Native::new("writeBytes", write_bytes, Arity::Exactly(2)),
+    Native::new("__iterate", __iterate, Arity::Exactly(1)),
+    Native::new("__array_len", len, Arity::Exactly(1)), // So that for-in still works when a program defines its own len
+    Native::new("random", random, Arity::Exactly(0)),
+    Native::new("randomInt", random_int, Arity::Exactly(2)),
+    Native::new("seedRandom", seed_random, Arity::Exactly(1)),
+    Native::new("ord", ord, Arity::Exactly(1)),
+    Native::new("chr", chr, Arity::Exactly(1)),
+    Native::new("charAt", char_at, Arity::Exactly(2)),
+    Native::new("type", type_of, Arity::Exactly(1)),
+    Native::new("str", str, Arity::Exactly(1)),
+    Native::new("num", num, Arity::Exactly(1)),
+    Native::new("format", format, Arity::AtLeast(1)),
+    Native::new("regexMatch", regex_match, Arity::Exactly(2)),
+    Native::new("regexFindAll", regex_find_all, Arity::Exactly(2)),
+    Native::new("regexReplace", regex_replace, Arity::Exactly(3)),
+    Native::new("readLine", read_line, Arity::Exactly(0)),
+    Native::new("readAll", read_all, Arity::Exactly(0)),
+    Native::new("getenv", getenv, Arity::Exactly(1)),
+    Native::new("setenv", setenv, Arity::Exactly(2)),
+    Native::new("args", args, Arity::Exactly(0)),
+    Native::new("cwd", cwd, Arity::Exactly(0)),
+    Native::new("platform", platform, Arity::Exactly(0)),
+    Native::new("exec", exec, Arity::Between(1, 2)),
+    Native::new("assertEq", assert_eq, Arity::Between(2, 3)),
+    Native::new("assertTrue", assert_true, Arity::Between(1, 2)),
+    Native::new("fail", fail, Arity::Exactly(1)),
+];
+
+/// The globals that aren't functions
+pub static STD_CONSTANTS: &[(&str, f64)] = &[("Infinity", f64::INFINITY), ("NaN", f64::NAN)];
+
+static START: OnceLock<Instant> = OnceLock::new(); // What clock() counts from, set when the first VM starts
+
+// wasm32-unknown-unknown has no clocks, asking it for the time panics. So clock() and time_millis() are errors there and random numbers start from the same seed every run
+
+pub fn start_clock() {
+    #[cfg(not(target_arch = "wasm32"))]
+    START.get_or_init(Instant::now);
+}
+
+/// Seconds since the program started. Monotonic, so the difference between two calls is safe to use for benchmarks
+#[cfg(not(target_arch = "wasm32"))]
+pub fn clock(_vm: &VM, _state: &mut VMState, _args: &[Value]) -> NativeResult {
+    Ok(Value::Double(
+        START.get_or_init(Instant::now).elapsed().as_secs_f64(),
+    ))
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn clock(_vm: &VM, _state: &mut VMState, _args: &[Value]) -> NativeResult {
+    Err(NativeError::new("clock() isn't available on this platform"))
+}
+
+/// Milliseconds since the Unix epoch from the wall clock, which can jump around so it shouldn't be used to time things
+pub fn time_millis(_vm: &VM, _state: &mut VMState, _args: &[Value]) -> NativeResult {
+    match since_epoch() {
+        Some(time) => Ok(Value::Int(time.as_millis() as i64)),
+        None => Err(NativeError::new(
+            "time_millis() isn't available on this platform",
+        )),
     }
-    // println!("Index {} of {:#?}", index, arr);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn since_epoch() -> Option<Duration> {
+    // Zero if the clock is set to before 1970
+    Some(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default(),
+    )
+}
+
+#[cfg(target_arch = "wasm32")]
+fn since_epoch() -> Option<Duration> {
+    None
+}
+
+pub fn sin(_vm: &VM, _state: &mut VMState, _args: &[Value]) -> NativeResult {
+    match _args {
+        [d] if d.as_num().is_some() => Ok(Value::Double(d.as_num().unwrap().sin())),
+        _ => Err(NativeError::new("sin() expects a number")),
+    }
+}
+
+pub fn radians(_vm: &VM, _state: &mut VMState, _args: &[Value]) -> NativeResult {
+    match _args {
+        [d] if d.as_num().is_some() => Ok(Value::Double(
+            d.as_num().unwrap() * 3.14159265358979323846264338327950288f64 / 180.0,
+        )),
+        _ => Err(NativeError::new("radians() expects a number")),
+    }
+}
+
+pub fn __array(_vm: &VM, _state: &mut VMState, _args: &[Value]) -> NativeResult {
+    let v: Vec<Value> = Vec::new();
+    return Ok(Value::LoxArray(Rc::new(RefCell::new(v))));
+}
+
+/// call this like `__array_index_get(1, arr)`, returns nil if the index is out of range
+pub fn __array_index_get(_vm: &VM, _state: &mut VMState, _args: &[Value]) -> NativeResult {
+    let (index, arr) = match _args {
+        [index, Value::LoxArray(arr)] => match as_index(index) {
+            Some(index) => (index, arr),
+            None => {
+                return Err(NativeError::with_value(
+                    "Array index must be a whole number",
+                    index,
+                ))
+            }
+        },
+        _ => {
+            return Err(NativeError::new(
+                "__array_index_get() expects an index and an array",
+            ))
+        }
+    };
+    let arr = arr.borrow();
     if index < arr.len() {
-        return arr[index].clone();
+        return Ok(arr[index].clone());
     } else {
-        // println!("exit else");
-        return Value::Nil;
+        return Ok(Value::Nil);
     }
 }
 
-pub fn __array_index_set(_arg_count: usize, mut _args: Vec<Value>) -> Value {
+/// call this like `__array_index_set(i, arr, value)`, where i can be at most the length of the array
+pub fn __array_index_set(_vm: &VM, state: &mut VMState, _args: &[Value]) -> NativeResult {
     // _args[1][_args[0]] = _args[2];
-    let mut index: usize;
-    // println!("0{:#?}", _args[0]);
-    // println!("1{:#?}", _args[1]);
-    // println!("2{:#?}", _args[2]);
-
-    match _args[2].clone() {
-        Value::Double(d) => {
-            index = d as usize;
-        }
-        _ => return Value::Nil,
-    }
-    let mut arr: Vec<Value>;
-    match _args[1].clone() {
-        Value::LoxArray(mut v) => {
-            arr = v.clone();
-            if arr.len() < index {
-                // println!("{}:{}", arr.len(), index);
-                return Value::Nil;
-            } else if arr.len() == index {
-                arr.insert(index, _args[0].clone());
-                // println!("Set value {:#?}",v);
-                return Value::LoxArray(arr);
-            } else {
-                arr[index] = _args[0].clone();
-                // println!("Set value {:#?}", v);
-                return Value::LoxArray(arr);
-            }
-        }
-        v => {
-            // println!("{}", v);
-            return Value::Nil;
-        }
-    };
-    Value::Double(21.0)
-}
-
-pub fn len(_arg_count: usize, mut _args: Vec<Value>) -> Value {
-    if _arg_count != 1 { // TODO: Return an error to the VM.
-        // println!("{}", _arg_count);
-        return Value::Nil;
-    }
-    match _args[0].clone() {
-        Value::LoxArray(v) => Value::Double(v.len() as f64),
-        v => {
-            // println!("type {:#?}", v);
-            Value::Nil
+    let (index, arr, value) = match _args {
+        [index, Value::LoxArray(arr), value] => match as_index(index) {
+            Some(index) => (index, arr, value),
+            None => {
+                return Err(NativeError::with_value(
+                    "Array index must be a whole number",
+                    index,
+                ))
+            }
+        },
+        _ => {
+            return Err(NativeError::new(
+                "__array_index_set() expects an index, an array and a value",
+            ))
+        }
+    };
+    unfrozen(state, &_args[1])?;
+    let mut elements = arr.borrow_mut(); // Changes in place, so every other copy of the array sees it too
+    if elements.len() < index {
+        return Err(NativeError::with_value(
+            "Array index out of range",
+            &_args[0],
+        ));
+    } else if elements.len() == index {
+        elements.insert(index, value.clone());
+    } else {
+        elements[index] = value.clone();
+    }
+    Ok(Value::LoxArray(arr.clone()))
+}
+
+pub fn len(_vm: &VM, _state: &mut VMState, _args: &[Value]) -> NativeResult {
+    match _args {
+        [Value::LoxArray(v)] => Ok(Value::Int(v.borrow().len() as i64)),
+        [Value::LoxMap(m)] => Ok(Value::Int(m.borrow().len() as i64)),
+        [Value::LoxSet(s)] => Ok(Value::Int(s.borrow().len() as i64)),
+        [Value::LoxBytes(b)] => Ok(Value::Int(b.borrow().len() as i64)),
+        [Value::LoxTuple(t)] => Ok(Value::Int(t.len() as i64)),
+        _ => Err(NativeError::new(
+            "len() expects an array, map, set, bytes or tuple",
+        )),
+    }
+}
+
+/// A splitmix64 generator. Small and fast, and good enough for games and simulations but not for anything that needs to be unpredictable
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Seeded from the wall clock, so every run is different until the script calls seedRandom
+    pub fn new() -> Rng {
+        let nanos = since_epoch().map_or(0, |time| time.as_nanos() as u64);
+        Rng { state: nanos }
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        self.state = seed;
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Rng {
+        Rng::new()
+    }
+}
+
+/// A number in [0, 1)
+pub fn random(_vm: &VM, state: &mut VMState, _args: &[Value]) -> NativeResult {
+    Ok(Value::Double(state.rng().next_f64()))
+}
+
+/// call this like `randomInt(lo, hi)`, both ends are included
+pub fn random_int(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        // Two Ints give an Int, the same as arithmetic on them would
+        [Value::Int(lo), Value::Int(hi)] if lo <= hi => {
+            let range = (*hi as i128 - *lo as i128 + 1) as f64;
+            Ok(Value::Int(lo + (state.rng().next_f64() * range) as i64))
+        }
+        [lo, hi]
+            if lo
+                .as_num()
+                .zip(hi.as_num())
+                .is_some_and(|(lo, hi)| lo <= hi) =>
+        {
+            let (lo, hi) = (lo.as_num().unwrap().ceil(), hi.as_num().unwrap().floor());
+            let range = (hi - lo + 1.0).max(1.0);
+            Ok(Value::Double(lo + (state.rng().next_f64() * range).floor()))
+        }
+        _ => Err(NativeError::new(
+            "randomInt() expects a low and a high number, low first",
+        )),
+    }
+}
+
+/// Makes every random number after this reproducible
+pub fn seed_random(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [seed] if seed.as_num().is_some() => {
+            state.rng().seed(match seed {
+                Value::Int(seed) => *seed as u64,
+                _ => seed.as_num().unwrap() as u64,
+            });
+            Ok(Value::Nil)
+        }
+        _ => Err(NativeError::new("seedRandom() expects a number")),
+    }
+}
+
+/// The codepoint of the first character of the string, or nil if it's empty
+pub fn ord(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxString(s)] => match s.chars().next() {
+            Some(c) => Ok(Value::Int(c as i64)),
+            None => Ok(Value::Nil),
+        },
+        _ => Err(NativeError::new("ord() expects a string")),
+    }
+}
+
+/// The one character string for a codepoint
+pub fn chr(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let n = &args[0];
+    match as_index(n).and_then(|n| char::from_u32(u32::try_from(n).ok()?)) {
+        Some(c) => Ok(state.new_string(c.encode_utf8(&mut [0; 4]))),
+        None => Err(NativeError::with_value("chr() expects a codepoint", n)),
+    }
+}
+
+/// call this like `charAt(s, i)`, indexes by character rather than by byte. Returns nil if i is out of range
+pub fn char_at(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxString(s), i] => match as_index(i) {
+            Some(i) => match s.chars().nth(i) {
+                Some(c) => Ok(state.new_string(c.encode_utf8(&mut [0; 4]))),
+                None => Ok(Value::Nil),
+            },
+            None => Err(NativeError::with_value(
+                "String index must be a whole number",
+                i,
+            )),
+        },
+        _ => Err(NativeError::new("charAt() expects a string and an index")),
+    }
+}
+
+/// The name of the value's type: "number", "string", "bool", "nil", "function", "class", "instance", "array", "map", "set",
+/// "bytes", "future" or "channel". Bound methods and natives are functions too
+pub fn type_of(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let name = args[0].type_name(state);
+    Ok(state.new_string(name))
+}
+
+/// Converts any value to a string, exactly the way print would show it
+pub fn str(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match &args[0] {
+        Value::LoxString(s) => Ok(Value::LoxString(s.clone())),
+        value => {
+            let s = value.to_string(vm, state);
+            Ok(state.new_string(&s))
+        }
+    }
+}
+
+/// Parses a number written the way it would be in Lox source (with an optional leading minus), returning nil if the string isn't one
+pub fn num(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    let s = match args {
+        [Value::LoxString(s)] => s.trim(),
+        _ => return Err(NativeError::new("num() expects a string")),
+    };
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    let (whole, fraction) = match digits.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (digits, None),
+    };
+    let all_digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    if !all_digits(whole) || !fraction.is_none_or(all_digits) {
+        return Ok(Value::Nil);
+    }
+    // Without a fraction it's an Int, like the same number in the source would be
+    match (fraction, s.parse::<i64>(), s.parse::<f64>()) {
+        (None, Ok(x), _) => Ok(Value::Int(x)),
+        (_, _, Ok(x)) => Ok(Value::Double(x)),
+        _ => Ok(Value::Nil),
+    }
+}
+
+/// call this like `format("x={}, y={}", x, y)`. `{}` takes the next argument, `{1}` takes the argument at that position and `{{` and `}}` are literal braces
+pub fn format(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let (template, args) = match args {
+        [Value::LoxString(template), args @ ..] => (template, args),
+        _ => return Err(NativeError::new("format() expects a format string")),
+    };
+
+    let mut result = String::new();
+    let mut next = 0;
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                result.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                result.push('}');
+            }
+            '{' => {
+                let mut position = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) if c.is_ascii_digit() => position.push(c),
+                        _ => {
+                            return Err(NativeError::new(
+                                "Placeholders in a format string have to be {} or {position}",
+                            ))
+                        }
+                    }
+                }
+                let index = if position.is_empty() {
+                    next += 1;
+                    Some(next - 1)
+                } else {
+                    position.parse().ok()
+                };
+                match index.and_then(|i| args.get(i)) {
+                    Some(value) => result.push_str(&value.to_string(vm, state)),
+                    None => {
+                        return Err(NativeError::new(
+                            "Not enough arguments for the format string",
+                        ))
+                    }
+                }
+            }
+            '}' => {
+                return Err(NativeError::new(
+                    "Unmatched '}' in format string, write '}}' for a literal one",
+                ))
+            }
+            c => result.push(c),
+        }
+    }
+    Ok(state.new_string(&result))
+}
+
+// Patterns use the syntax of the regex crate, and an invalid one is a runtime error
+
+/// Looks up the compiled pattern
+fn get_regex<'a>(state: &'a mut VMState, pattern: &Rc<str>) -> Result<&'a Regex, NativeError> {
+    state
+        .regex(pattern)
+        .ok_or_else(|| NativeError::with_value("Invalid regex", &Value::LoxString(pattern.clone())))
+}
+
+/// call this like `regexMatch(pattern, s)`. Returns an array of the first match followed by its capture groups (nil for groups that
+/// didn't take part), or nil if nothing matched
+pub fn regex_match(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let (pattern, s) = match args {
+        [Value::LoxString(pattern), Value::LoxString(s)] => (pattern, s),
+        _ => {
+            return Err(NativeError::new(
+                "regexMatch() expects a pattern and a string",
+            ))
+        }
+    };
+    let groups: Vec<Option<String>> = match get_regex(state, pattern)?.captures(s) {
+        Some(captures) => captures
+            .iter()
+            .map(|group| group.map(|m| m.as_str().to_string()))
+            .collect(),
+        None => return Ok(Value::Nil),
+    };
+    Ok(new_array(
+        groups
+            .iter()
+            .map(|group| match group {
+                Some(group) => state.new_string(group),
+                None => Value::Nil,
+            })
+            .collect(),
+    ))
+}
+
+/// call this like `regexFindAll(pattern, s)` for an array of every non-overlapping match
+pub fn regex_find_all(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let (pattern, s) = match args {
+        [Value::LoxString(pattern), Value::LoxString(s)] => (pattern, s),
+        _ => {
+            return Err(NativeError::new(
+                "regexFindAll() expects a pattern and a string",
+            ))
+        }
+    };
+    let matches: Vec<String> = get_regex(state, pattern)?
+        .find_iter(s)
+        .map(|m| m.as_str().to_string())
+        .collect();
+    Ok(new_array(
+        matches.iter().map(|m| state.new_string(m)).collect(),
+    ))
+}
+
+/// call this like `regexReplace(pattern, s, replacement)` to replace every match. `$1` or `${name}` in the replacement inserts that group
+pub fn regex_replace(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let (pattern, s, replacement) = match args {
+        [Value::LoxString(pattern), Value::LoxString(s), Value::LoxString(replacement)] => {
+            (pattern, s, replacement)
+        }
+        _ => {
+            return Err(NativeError::new(
+                "regexReplace() expects a pattern, a string and a replacement",
+            ))
+        }
+    };
+    let replaced = get_regex(state, pattern)?
+        .replace_all(s, &**replacement)
+        .into_owned();
+    Ok(state.new_string(&replaced))
+}
+
+/// The next line of stdin without its line ending, or nil once stdin is at its end
+pub fn read_line(_vm: &VM, state: &mut VMState, _args: &[Value]) -> NativeResult {
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => Ok(Value::Nil),
+        Ok(_) => {
+            let line = line.strip_suffix('\n').unwrap_or(&line);
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            Ok(state.new_string(line))
+        }
+    }
+}
+
+/// Everything left on stdin, blocking until it's closed. nil if it isn't valid UTF-8
+pub fn read_all(_vm: &VM, state: &mut VMState, _args: &[Value]) -> NativeResult {
+    let mut contents = String::new();
+    match io::stdin().read_to_string(&mut contents) {
+        Ok(_) => Ok(state.new_string(&contents)),
+        Err(_) => Ok(Value::Nil),
+    }
+}
+
+/// The value of an environment variable, or nil if it isn't set (or isn't valid UTF-8)
+pub fn getenv(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxString(name)] if !name.is_empty() => match env::var(&**name) {
+            Ok(value) => Ok(state.new_string(&value)),
+            Err(_) => Ok(Value::Nil),
+        },
+        _ => Err(NativeError::new("getenv() expects a variable name")),
+    }
+}
+
+/// call this like `setenv(name, value)`. Changes the environment of this process, and of anything it starts afterwards
+///
+/// Returns nil if the name or value can't be set, ie if the name contains '=' or either of them contains a NUL
+pub fn setenv(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxString(name), Value::LoxString(value)] => {
+            // set_var panics on names it can't set, so those are turned away first
+            if name.is_empty() || name.contains(['=', '\0']) || value.contains('\0') {
+                return Ok(Value::Nil);
+            }
+            env::set_var(&**name, &**value);
+            Ok(Value::Bool(true))
+        }
+        _ => Err(NativeError::new("setenv() expects a name and a value")),
+    }
+}
+
+/// The arguments passed to the script, ie everything after `--` on the command line
+pub fn args(vm: &VM, state: &mut VMState, _args: &[Value]) -> NativeResult {
+    let args = vm
+        .config
+        .script_args
+        .iter()
+        .map(|arg| state.new_string(arg))
+        .collect();
+    Ok(new_array(args))
+}
+
+/// The current working directory, or nil if it's gone or isn't valid UTF-8
+pub fn cwd(_vm: &VM, state: &mut VMState, _args: &[Value]) -> NativeResult {
+    match env::current_dir()
+        .ok()
+        .and_then(|dir| dir.to_str().map(String::from))
+    {
+        Some(dir) => Ok(state.new_string(&dir)),
+        None => Ok(Value::Nil),
+    }
+}
+
+/// The operating system, ie "linux", "macos" or "windows"
+pub fn platform(_vm: &VM, state: &mut VMState, _args: &[Value]) -> NativeResult {
+    Ok(state.new_string(env::consts::OS))
+}
+
+/// call this like `exec("ls", args)` with an array of string arguments. Waits for the program to finish and returns a map of its
+/// "status" (nil if it was killed by a signal), "stdout" and "stderr"
+///
+/// Returns nil if the program couldn't be started. It's an error to call this when VmConfig::allow_exec is off (ie with --sandbox)
+pub fn exec(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    if !vm.config.allow_exec {
+        return Err(NativeError::new("exec() is disabled in the sandbox"));
+    }
+    let (program, arguments) = match args {
+        [Value::LoxString(program)] => (program, Vec::new()),
+        [Value::LoxString(program), Value::LoxArray(arguments)] => {
+            let mut strings = Vec::new();
+            for arg in arguments.borrow().iter() {
+                match arg {
+                    Value::LoxString(arg) => strings.push(arg.to_string()),
+                    _ => {
+                        return Err(NativeError::with_value(
+                            "exec() arguments have to be strings",
+                            arg,
+                        ))
+                    }
+                }
+            }
+            (program, strings)
+        }
+        _ => {
+            return Err(NativeError::new(
+                "exec() expects a program and an array of arguments",
+            ))
+        }
+    };
+
+    let output = match Command::new(&**program).args(arguments).output() {
+        Ok(output) => output,
+        Err(_) => return Ok(Value::Nil),
+    };
+    let fields = [
+        (
+            "status",
+            output
+                .status
+                .code()
+                .map_or(Value::Nil, |code| Value::Int(code as i64)),
+        ),
+        (
+            "stdout",
+            state.new_string(&String::from_utf8_lossy(&output.stdout)),
+        ),
+        (
+            "stderr",
+            state.new_string(&String::from_utf8_lossy(&output.stderr)),
+        ),
+    ];
+    let mut result = LoxMap::default();
+    for (name, value) in fields {
+        result.insert(MapKey::String(state.intern(name)), value);
+    }
+    Ok(Value::LoxMap(Rc::new(RefCell::new(result))))
+}
+
+// The assertions fail with a runtime error, so a test script stops at the first one that doesn't hold and reports its line
+
+/// Strings are quoted so that "1" and 1 don't look the same in a failed assertion
+fn show(vm: &VM, state: &VMState, value: &Value) -> String {
+    match value {
+        Value::LoxString(s) => format!("\"{}\"", s),
+        value => value.to_string(vm, state),
+    }
+}
+
+/// Prefixes the optional message of an assertion to what went wrong
+fn assertion_failed(
+    vm: &VM,
+    state: &VMState,
+    message: Option<&Value>,
+    what: String,
+) -> NativeError {
+    match message {
+        Some(message) => NativeError::new(&format!("{}: {}", message.to_string(vm, state), what)),
+        None => NativeError::new(&format!("Assertion failed: {}", what)),
+    }
+}
+
+/// call this like `assertEq(expected, actual)` or `assertEq(expected, actual, message)`, comparing with == semantics
+pub fn assert_eq(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let (expected, actual) = (&args[0], &args[1]);
+    if values_equal((expected, actual)) {
+        return Ok(Value::Nil);
+    }
+    let what = format!(
+        "expected {} but got {}",
+        show(vm, state, expected),
+        show(vm, state, actual)
+    );
+    Err(assertion_failed(vm, state, args.get(2), what))
+}
+
+/// call this like `assertTrue(value)` or `assertTrue(value, message)`
+pub fn assert_true(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    if !is_falsey(&args[0]) {
+        return Ok(Value::Nil);
+    }
+    let what = format!(
+        "expected a truthy value but got {}",
+        show(vm, state, &args[0])
+    );
+    Err(assertion_failed(vm, state, args.get(1), what))
+}
+
+/// Fails unconditionally with the message, for the branches a test should never reach
+pub fn fail(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    Err(NativeError::new(&args[0].to_string(vm, state)))
+}
+
+/// Turns a number argument into an index, as long as it's a whole number that isn't negative
+fn as_index(value: &Value) -> Option<usize> {
+    match value {
+        Value::Int(i) => usize::try_from(*i).ok(),
+        Value::Double(i) if i.fract() == 0.0 && *i >= 0.0 => Some(*i as usize),
+        _ => None,
+    }
+}
+
+/// as_index for the natives that take an index argument, erroring on anything that isn't one
+fn index_arg(value: &Value) -> Result<usize, NativeError> {
+    as_index(value).ok_or_else(|| {
+        NativeError::with_value("Index must be a whole number that isn't negative", value)
+    })
+}
+
+/// call this like `push(arr, value)`, returns the new length
+pub fn push(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxArray(arr), value] => {
+            unfrozen(state, &args[0])?;
+            let mut elements = arr.borrow_mut();
+            elements.push(value.clone());
+            Ok(Value::Int(elements.len() as i64))
+        }
+        _ => Err(NativeError::new("push() expects an array and a value")),
+    }
+}
+
+/// Removes and returns the last element, or nil if the array is empty
+pub fn pop(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxArray(arr)] => {
+            unfrozen(state, &args[0])?;
+            Ok(arr.borrow_mut().pop().unwrap_or(Value::Nil))
+        }
+        _ => Err(NativeError::new("pop() expects an array")),
+    }
+}
+
+/// call this like `insert(arr, i, value)`, shifting everything from i onwards up by one. i can be at most the length of the array
+pub fn insert(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxArray(arr), i, value] => {
+            unfrozen(state, &args[0])?;
+            let mut elements = arr.borrow_mut();
+            match index_arg(i)? {
+                index if index <= elements.len() => {
+                    elements.insert(index, value.clone());
+                    Ok(Value::Int(elements.len() as i64))
+                }
+                _ => Err(NativeError::with_value("Array index out of range", i)),
+            }
+        }
+        _ => Err(NativeError::new(
+            "insert() expects an array, an index and a value",
+        )),
+    }
+}
+
+/// call this like `removeAt(arr, i)`, returns the removed element or nil if i is out of range
+pub fn remove_at(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxArray(arr), i] => {
+            unfrozen(state, &args[0])?;
+            let mut elements = arr.borrow_mut();
+            match index_arg(i)? {
+                i if i < elements.len() => Ok(elements.remove(i)),
+                _ => Ok(Value::Nil),
+            }
+        }
+        _ => Err(NativeError::new("removeAt() expects an array and an index")),
+    }
+}
+
+pub fn clear(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxArray(arr)] => {
+            unfrozen(state, &args[0])?;
+            arr.borrow_mut().clear();
+            Ok(Value::Nil)
+        }
+        _ => Err(NativeError::new("clear() expects an array")),
+    }
+}
+
+fn new_array(values: Vec<Value>) -> Value {
+    Value::LoxArray(Rc::new(RefCell::new(values)))
+}
+
+/// call this like `slice(arr, start, end)` for a new array of the elements from start up to but not including end. Both are clamped to the array
+pub fn slice(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxArray(arr), start, end] => {
+            let elements = arr.borrow();
+            let end = index_arg(end)?.min(elements.len());
+            let start = index_arg(start)?.min(end);
+            Ok(new_array(elements[start..end].to_vec()))
+        }
+        _ => Err(NativeError::new(
+            "slice() expects an array, a start and an end",
+        )),
+    }
+}
+
+/// A new array with the elements of a followed by the elements of b
+pub fn concat(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxArray(a), Value::LoxArray(b)] => {
+            let mut elements = a.borrow().clone();
+            elements.extend(b.borrow().iter().cloned());
+            Ok(new_array(elements))
+        }
+        _ => Err(NativeError::new("concat() expects two arrays")),
+    }
+}
+
+/// A reversed copy, the array itself is left alone
+pub fn reverse(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxArray(arr)] => Ok(new_array(arr.borrow().iter().rev().cloned().collect())),
+        _ => Err(NativeError::new("reverse() expects an array")),
+    }
+}
+
+/// call this like `contains(arr, value)`, comparing with == semantics
+pub fn contains(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxArray(arr), value] => Ok(Value::Bool(
+            arr.borrow().iter().any(|x| values_equal((x, value))),
+        )),
+        _ => Err(NativeError::new("contains() expects an array and a value")),
+    }
+}
+
+/// call this like `indexOf(arr, value)`, returns the index of the first element == value or -1 if there isn't one
+pub fn index_of(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxArray(arr), value] => {
+            match arr.borrow().iter().position(|x| values_equal((x, value))) {
+                Some(i) => Ok(Value::Int(i as i64)),
+                None => Ok(Value::Int(-1)),
+            }
+        }
+        _ => Err(NativeError::new("indexOf() expects an array and a value")),
+    }
+}
+
+/// call this like `join(arr, ", ")`, stringifying every element the way print would
+pub fn join(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxArray(arr), Value::LoxString(sep)] => {
+            let parts: Vec<String> = arr
+                .borrow()
+                .iter()
+                .map(|x| x.to_string(vm, state))
+                .collect();
+            Ok(state.new_string(&parts.join(sep)))
+        }
+        _ => Err(NativeError::new("join() expects an array and a separator")),
+    }
+}
+
+// The higher order natives copy the elements out before calling back into Lox, so the callback is free to change the array it's iterating over.
+// The arguments of a native aren't on the stack while it runs, so the copy and the result are rooted while the callbacks run, otherwise
+// a collection in the middle of one could free what they point at. The callee doesn't need it since it's on the stack for every call.
+// When a callback fails they stop calling it and return early, what they return is thrown away since the VM is already unwinding
+
+/// call this like `map(arr, fn)` for a new array of fn(x) for every element x
+pub fn map(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxArray(arr), callee] => {
+            let elements = arr.borrow().clone();
+            let result = Rc::new(RefCell::new(Vec::with_capacity(elements.len())));
+            state.push_root(new_array(elements.clone()));
+            state.push_root(Value::LoxArray(result.clone()));
+            for x in elements {
+                match vm.call_function(state, callee.clone(), &[x]) {
+                    Some(value) => result.borrow_mut().push(value),
+                    None => break,
+                }
+            }
+            state.pop_root();
+            state.pop_root();
+            Ok(Value::LoxArray(result))
+        }
+        _ => Err(NativeError::new("map() expects an array and a function")),
+    }
+}
+
+/// call this like `filter(arr, fn)` for a new array of the elements x where fn(x) is truthy
+pub fn filter(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxArray(arr), callee] => {
+            let elements = arr.borrow().clone();
+            let result = Rc::new(RefCell::new(Vec::new()));
+            state.push_root(new_array(elements.clone()));
+            for x in elements {
+                match vm.call_function(state, callee.clone(), std::slice::from_ref(&x)) {
+                    Some(keep) if !is_falsey(&keep) => result.borrow_mut().push(x),
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+            state.pop_root();
+            Ok(Value::LoxArray(result))
+        }
+        _ => Err(NativeError::new("filter() expects an array and a function")),
+    }
+}
+
+/// call this like `reduce(arr, fn, init)`, folding from the left with fn(accumulator, x)
+pub fn reduce(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxArray(arr), callee, init] => {
+            let elements = arr.borrow().clone();
+            state.push_root(new_array(elements.clone()));
+            let mut accumulator = init.clone();
+            for x in elements {
+                match vm.call_function(state, callee.clone(), &[accumulator, x]) {
+                    Some(value) => accumulator = value,
+                    None => {
+                        accumulator = Value::Nil;
+                        break;
+                    }
+                }
+            }
+            state.pop_root();
+            Ok(accumulator)
+        }
+        _ => Err(NativeError::new(
+            "reduce() expects an array, a function and an initial value",
+        )),
+    }
+}
+
+/// call this like `sort(arr)` or `sort(arr, cmp)` for a sorted copy. The sort is stable
+///
+/// cmp(a, b) returns a negative number if a goes before b, a positive one if it goes after, and 0 if it doesn't matter.
+/// Without cmp the elements have to be all numbers or all strings
+pub fn sort(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let sorted = match args {
+        [Value::LoxArray(arr)] => merge_sort(arr.borrow().clone(), &mut |a, b| match (a, b) {
+            (Value::Int(x), Value::Int(y)) => Ok(x.cmp(y)),
+            (x, y) if x.as_num().is_some() && y.as_num().is_some() => x
+                .as_num()
+                .partial_cmp(&y.as_num())
+                .ok_or_else(|| NativeError::new("sort() can't compare NaN")),
+            (Value::LoxString(x), Value::LoxString(y)) => Ok(x.cmp(y)),
+            _ => Err(NativeError::new(
+                "sort() without a compare function expects all numbers or all strings",
+            )),
+        }),
+        [Value::LoxArray(arr), callee] => {
+            let elements = arr.borrow().clone();
+            state.push_root(new_array(elements.clone()));
+            let sorted = merge_sort(elements, &mut |a, b| match vm.call_function(
+                state,
+                callee.clone(),
+                &[a.clone(), b.clone()],
+            ) {
+                Some(x) if x.as_num().is_some_and(|x| !x.is_nan()) => {
+                    Ok(x.as_num().unwrap().partial_cmp(&0.0).unwrap())
+                }
+                Some(other) => Err(NativeError::with_value(
+                    "sort() expects the compare function to return a number",
+                    &other,
+                )),
+                None => Err(NativeError::new("sort() compare function failed")),
+            });
+            state.pop_root();
+            sorted
+        }
+        _ => Err(NativeError::new(
+            "sort() expects an array and an optional compare function",
+        )),
+    };
+    Ok(new_array(sorted?))
+}
+
+/// Stable, and gives up as soon as a comparison fails since that might mean a callback errored
+fn merge_sort(
+    mut values: Vec<Value>,
+    cmp: &mut dyn FnMut(&Value, &Value) -> Result<Ordering, NativeError>,
+) -> Result<Vec<Value>, NativeError> {
+    if values.len() <= 1 {
+        return Ok(values);
+    }
+    let right = values.split_off(values.len() / 2);
+    let mut left = merge_sort(values, cmp)?.into_iter().peekable();
+    let mut right = merge_sort(right, cmp)?.into_iter().peekable();
+
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        // Only taking from the right when it's strictly smaller is what keeps equal elements in order
+        if cmp(b, a)? == Ordering::Less {
+            merged.push(right.next().unwrap());
+        } else {
+            merged.push(left.next().unwrap());
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
+
+// Maps are keyed by numbers or strings, anything else as a key is an error
+
+/// MapKey::from_value for the natives, erroring on values that can't be keys
+fn key_arg(value: &Value) -> Result<MapKey, NativeError> {
+    MapKey::from_value(value).ok_or_else(|| {
+        NativeError::with_value(
+            "Map keys and set members have to be numbers or strings",
+            value,
+        )
+    })
+}
+
+pub fn map_new(_vm: &VM, _state: &mut VMState, _args: &[Value]) -> NativeResult {
+    Ok(Value::LoxMap(Rc::new(RefCell::new(LoxMap::default()))))
+}
+
+/// call this like `mapGet(m, key)`, returns nil if the key isn't there
+pub fn map_get(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxMap(map), key] => Ok(map
+            .borrow()
+            .get(&key_arg(key)?)
+            .cloned()
+            .unwrap_or(Value::Nil)),
+        _ => Err(NativeError::new("mapGet() expects a map and a key")),
+    }
+}
+
+/// call this like `mapSet(m, key, value)`, returns the value
+pub fn map_set(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxMap(map), key, value] => {
+            unfrozen(state, &args[0])?;
+            map.borrow_mut().insert(key_arg(key)?, value.clone());
+            Ok(value.clone())
+        }
+        _ => Err(NativeError::new(
+            "mapSet() expects a map, a key and a value",
+        )),
+    }
+}
+
+pub fn map_has(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxMap(map), key] => Ok(Value::Bool(map.borrow().contains_key(&key_arg(key)?))),
+        _ => Err(NativeError::new("mapHas() expects a map and a key")),
+    }
+}
+
+/// call this like `mapRemove(m, key)`, returns the value that was removed or nil if the key wasn't there
+pub fn map_remove(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxMap(map), key] => {
+            unfrozen(state, &args[0])?;
+            Ok(map
+                .borrow_mut()
+                .remove(&key_arg(key)?)
+                .unwrap_or(Value::Nil))
+        }
+        _ => Err(NativeError::new("mapRemove() expects a map and a key")),
+    }
+}
+
+/// An array of the keys, in the order they were first set
+pub fn map_keys(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxMap(map)] => Ok(new_array(
+            map.borrow().iter().map(|(key, _)| key.to_value()).collect(),
+        )),
+        _ => Err(NativeError::new("mapKeys() expects a map")),
+    }
+}
+
+/// An array of the values, in the same order as mapKeys
+pub fn map_values(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxMap(map)] => Ok(new_array(
+            map.borrow()
+                .iter()
+                .map(|(_, value)| value.clone())
+                .collect(),
+        )),
+        _ => Err(NativeError::new("mapValues() expects a map")),
+    }
+}
+
+// Sets take the same values as map keys
+
+fn new_set(set: LoxSet) -> Value {
+    Value::LoxSet(Rc::new(RefCell::new(set)))
+}
+
+/// call this like `setNew()` for an empty set, or `setNew(arr)` for a set of the elements of arr
+pub fn set_new(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [] => Ok(new_set(LoxSet::default())),
+        [Value::LoxArray(arr)] => {
+            let mut set = LoxSet::default();
+            for x in arr.borrow().iter() {
+                set.insert(key_arg(x)?);
+            }
+            Ok(new_set(set))
+        }
+        _ => Err(NativeError::new("setNew() expects nothing or an array")),
+    }
+}
+
+/// call this like `add(s, value)`, returns false if it was already there
+pub fn add(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxSet(set), value] => {
+            unfrozen(state, &args[0])?;
+            Ok(Value::Bool(set.borrow_mut().insert(key_arg(value)?)))
+        }
+        _ => Err(NativeError::new("add() expects a set and a value")),
+    }
+}
+
+pub fn has(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxSet(set), value] => Ok(Value::Bool(set.borrow().contains(&key_arg(value)?))),
+        _ => Err(NativeError::new("has() expects a set and a value")),
+    }
+}
+
+/// call this like `remove(s, value)`, returns false if it wasn't there
+pub fn remove(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxSet(set), value] => {
+            unfrozen(state, &args[0])?;
+            Ok(Value::Bool(set.borrow_mut().remove(&key_arg(value)?)))
+        }
+        _ => Err(NativeError::new("remove() expects a set and a value")),
+    }
+}
+
+/// A new set of everything in a or b, with a's members first
+pub fn union(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxSet(a), Value::LoxSet(b)] => {
+            let mut set = LoxSet::default();
+            for member in a.borrow().iter().chain(b.borrow().iter()) {
+                set.insert(member.clone());
+            }
+            Ok(new_set(set))
+        }
+        _ => Err(NativeError::new("union() expects two sets")),
+    }
+}
+
+/// A new set of everything in both a and b, in a's order
+pub fn intersect(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxSet(a), Value::LoxSet(b)] => {
+            let mut set = LoxSet::default();
+            let b = b.borrow();
+            for member in a.borrow().iter().filter(|member| b.contains(member)) {
+                set.insert(member.clone());
+            }
+            Ok(new_set(set))
+        }
+        _ => Err(NativeError::new("intersect() expects two sets")),
+    }
+}
+
+// deepEqual and clone follow arrays, maps, sets, bytes and instances. Everything else (functions, classes, strings...) is compared
+// with == and shared by the clone. Both work through a list of what's left rather than recursing, so a long linked list doesn't
+// overflow the stack, and track what they've already visited so that cyclic structures don't loop forever
+
+/// What a deep operation has already visited, keyed by the address of the array/map/set/bytes or the pointer of the instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Identity {
+    Shared(usize),
+    Instance(usize),
+}
+
+fn identity(value: &Value) -> Option<Identity> {
+    match value {
+        Value::LoxArray(arr) => Some(Identity::Shared(Rc::as_ptr(arr) as *const () as usize)),
+        Value::LoxMap(map) => Some(Identity::Shared(Rc::as_ptr(map) as *const () as usize)),
+        Value::LoxSet(set) => Some(Identity::Shared(Rc::as_ptr(set) as *const () as usize)),
+        Value::LoxBytes(bytes) => Some(Identity::Shared(Rc::as_ptr(bytes) as *const () as usize)),
+        Value::LoxTuple(values) => Some(Identity::Shared(Rc::as_ptr(values) as *const () as usize)),
+        Value::LoxPointer(pointer) => Some(Identity::Instance(*pointer)),
+        _ => None,
+    }
+}
+
+/// For the natives that change their argument, which can't once it's been frozen
+fn unfrozen(state: &VMState, value: &Value) -> Result<(), NativeError> {
+    if state.is_frozen(value) {
+        Err(NativeError::new(&format!(
+            "Can't change a frozen {}",
+            value.type_name(state)
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// call this like `freeze(value)` on an array, map, set, bytes or instance, which can't be changed from then on. Returns the value.
+/// Only the value itself is frozen, not what's inside it
+pub fn freeze(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    if state.freeze(&args[0]) {
+        Ok(args[0].clone())
+    } else {
+        Err(NativeError::with_value(
+            "freeze() expects an array, map, set, bytes or instance",
+            &args[0],
+        ))
+    }
+}
+
+/// True for the values freeze() was called on, and for the ones that can't be changed anyway like numbers, strings and tuples
+pub fn is_frozen(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let mutable = match &args[0] {
+        Value::LoxArray(_) | Value::LoxMap(_) | Value::LoxSet(_) | Value::LoxBytes(_) => true,
+        value => state.instance(value).is_some(),
+    };
+    Ok(Value::Bool(!mutable || state.is_frozen(&args[0])))
+}
+
+/// call this like `deepEqual(a, b)`. Two arrays are equal if their elements are, two maps if they have the same keys with equal
+/// values (in any order), and two instances if they're of the same class with equal fields
+pub fn deep_equal(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let mut pending = vec![(args[0].clone(), args[1].clone())];
+    // A pair that's already been compared (or is being compared) is skipped, if it isn't equal that shows up the first time
+    let mut compared: HashSet<(Identity, Identity)> = HashSet::new();
+    while let Some((a, b)) = pending.pop() {
+        if values_equal((&a, &b)) {
+            continue;
+        }
+        if let (Some(x), Some(y)) = (identity(&a), identity(&b)) {
+            if !compared.insert((x, y)) {
+                continue;
+            }
+        }
+        let equal = match (&a, &b) {
+            (Value::LoxArray(x), Value::LoxArray(y)) => {
+                let (x, y) = (x.borrow(), y.borrow());
+                pending.extend(x.iter().cloned().zip(y.iter().cloned()));
+                x.len() == y.len()
+            }
+            (Value::LoxMap(x), Value::LoxMap(y)) => {
+                let (x, y) = (x.borrow(), y.borrow());
+                x.len() == y.len()
+                    && x.iter().all(|(key, x)| match y.get(key) {
+                        Some(y) => {
+                            pending.push((x.clone(), y.clone()));
+                            true
+                        }
+                        None => false,
+                    })
+            }
+            (Value::LoxSet(x), Value::LoxSet(y)) => {
+                let (x, y) = (x.borrow(), y.borrow());
+                x.len() == y.len() && x.iter().all(|member| y.contains(member))
+            }
+            (Value::LoxBytes(x), Value::LoxBytes(y)) => *x.borrow() == *y.borrow(),
+            (Value::LoxTuple(x), Value::LoxTuple(y)) => {
+                pending.extend(x.iter().cloned().zip(y.iter().cloned()));
+                x.len() == y.len()
+            }
+            (Value::LoxPointer(_), Value::LoxPointer(_)) => {
+                match (state.instance(&a), state.instance(&b)) {
+                    (Some(x), Some(y)) => {
+                        x.class == y.class
+                            && x.fields.len() == y.fields.len()
+                            && x.fields.iter().all(|(name, x)| match y.fields.get(name) {
+                                Some(y) => {
+                                    pending.push((x.clone(), y.clone()));
+                                    true
+                                }
+                                None => false,
+                            })
+                    }
+                    _ => false, // Closures are only equal to themselves
+                }
+            }
+            _ => false,
+        };
+        if !equal {
+            return Ok(Value::Bool(false));
+        }
+    }
+    Ok(Value::Bool(true))
+}
+
+/// A deep copy, where the copy has the same shape as the original. A value that's reachable twice is copied once, so the copy
+/// shares it the same way the original does
+pub fn clone(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    // The original isn't on the stack, and the instances that are copied aren't reachable from anywhere until clone returns
+    state.push_root(args[0].clone());
+    let mut cloner = Cloner {
+        copies: HashMap::new(),
+        pending: Vec::new(),
+        roots: 1,
+    };
+    let copy = cloner.copy_of(state, &args[0]);
+    while let Some((original, copy)) = cloner.pending.pop() {
+        cloner.fill(state, &original, &copy);
+    }
+    for _ in 0..cloner.roots {
+        state.pop_root();
+    }
+    Ok(copy)
+}
+
+struct Cloner {
+    copies: HashMap<Identity, Value>,
+    pending: Vec<(Value, Value)>, // Copies that still have to have the contents of their original copied in
+    roots: usize, // How many values have been rooted, popped off once the whole copy is done
+}
+
+impl Cloner {
+    /// The copy of value, which starts off empty if it hasn't been made yet. Its contents are filled in later on
+    fn copy_of(&mut self, state: &mut VMState, value: &Value) -> Value {
+        let identity = match identity(value) {
+            Some(identity) => identity,
+            None => return value.clone(),
+        };
+        if let Some(copy) = self.copies.get(&identity) {
+            return copy.clone();
+        }
+        let copy = match value {
+            Value::LoxArray(_) => new_array(Vec::new()),
+            Value::LoxMap(_) => Value::LoxMap(Rc::new(RefCell::new(LoxMap::default()))),
+            Value::LoxSet(set) => new_set(set.borrow().clone()), // Sets and bytes have nothing to follow, so they're copied right away
+            Value::LoxBytes(bytes) => new_bytes(bytes.borrow().clone()),
+            // A tuple can't be filled in later, but the copies of its elements can be, so its copy is made from them right away
+            Value::LoxTuple(values) => {
+                let copy: Rc<[Value]> = values.iter().map(|x| self.copy_of(state, x)).collect();
+                let copy = Value::LoxTuple(copy);
+                self.copies.insert(identity, copy.clone());
+                return copy;
+            }
+            Value::LoxPointer(_) => match state.instance(value) {
+                Some(instance) => {
+                    let class = instance.class;
+                    let copy = state.new_instance(class);
+                    state.push_root(copy.clone());
+                    self.roots += 1;
+                    copy
+                }
+                None => return value.clone(), // Closures are immutable from Lox, so there's nothing to copy
+            },
+            _ => return value.clone(),
+        };
+        self.copies.insert(identity, copy.clone());
+        self.pending.push((value.clone(), copy.clone()));
+        copy
+    }
+
+    fn fill(&mut self, state: &mut VMState, original: &Value, copy: &Value) {
+        match (original, copy) {
+            (Value::LoxArray(original), Value::LoxArray(copy)) => {
+                let elements = original.borrow().clone();
+                let copied: Vec<Value> = elements.iter().map(|x| self.copy_of(state, x)).collect();
+                *copy.borrow_mut() = copied;
+            }
+            (Value::LoxMap(original), Value::LoxMap(copy)) => {
+                let entries: Vec<(MapKey, Value)> = original
+                    .borrow()
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                for (key, value) in entries {
+                    let value = self.copy_of(state, &value);
+                    copy.borrow_mut().insert(key, value);
+                }
+            }
+            (Value::LoxPointer(_), Value::LoxPointer(_)) => {
+                let fields = state.instance(original).unwrap().fields.clone();
+                let mut copied = Fields::default();
+                for (name, field) in fields.iter() {
+                    copied.insert(*name, self.copy_of(state, field));
+                }
+                state.instance_mut(copy).unwrap().fields = copied;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// How deep inspect goes before it writes ... instead of the rest of the structure
+const MAX_INSPECT_DEPTH: usize = 64;
+
+/// A multi-line dump of nested arrays, maps, sets and instances, with the class name and fields of every instance. Strings are quoted,
+/// and a structure that contains itself shows <cycle> where it would repeat
+pub fn inspect(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let mut inspector = Inspector {
+        vm,
+        state,
+        out: String::new(),
+        path: Vec::new(),
+    };
+    inspector.write(&args[0], 0);
+    let out = inspector.out;
+    Ok(state.new_string(&out))
+}
+
+struct Inspector<'a> {
+    vm: &'a VM,
+    state: &'a VMState,
+    out: String,
+    path: Vec<Identity>, // The structures that contain the value being written, to spot cycles
+}
+
+impl Inspector<'_> {
+    fn write(&mut self, value: &Value, depth: usize) {
+        let identity = identity(value);
+        if let Some(identity) = identity {
+            if self.path.contains(&identity) {
+                self.out.push_str("<cycle>");
+                return;
+            }
+            if depth >= MAX_INSPECT_DEPTH {
+                self.out.push_str("...");
+                return;
+            }
+        }
+        let (open, close, entries) = match value {
+            Value::LoxArray(arr) => (
+                "[".to_string(),
+                "]",
+                arr.borrow().iter().map(|v| (None, v.clone())).collect(),
+            ),
+            Value::LoxTuple(values) => (
+                "(".to_string(),
+                ")",
+                values.iter().map(|v| (None, v.clone())).collect(),
+            ),
+            Value::LoxMap(map) => (
+                "map {".to_string(),
+                "}",
+                map.borrow()
+                    .iter()
+                    .map(|(key, v)| (Some(show(self.vm, self.state, &key.to_value())), v.clone()))
+                    .collect(),
+            ),
+            Value::LoxSet(set) => (
+                "set {".to_string(),
+                "}",
+                set.borrow()
+                    .iter()
+                    .map(|member| (None, member.to_value()))
+                    .collect(),
+            ),
+            Value::LoxBytes(bytes) => {
+                let bytes: Vec<String> = bytes.borrow().iter().map(u8::to_string).collect();
+                self.out.push_str(&format!("bytes [{}]", bytes.join(", ")));
+                return;
+            }
+            Value::LoxPointer(_) => match self.state.instance(value) {
+                Some(instance) => {
+                    let mut fields: Vec<(Option<String>, Value)> = instance
+                        .fields
+                        .iter()
+                        .map(|(name, v)| {
+                            (
+                                Some(self.state.property_name(self.vm, *name).to_string()),
+                                v.clone(),
+                            )
+                        })
+                        .collect();
+                    fields.sort_by(|a, b| a.0.cmp(&b.0));
+                    (
+                        format!("{} {{", self.vm.classes[instance.class].name),
+                        "}",
+                        fields,
+                    )
+                }
+                None => {
+                    self.out.push_str(&value.to_string(self.vm, self.state));
+                    return;
+                }
+            },
+            value => {
+                self.out.push_str(&show(self.vm, self.state, value));
+                return;
+            }
+        };
+        self.write_entries(identity.unwrap(), &open, close, entries, depth);
+    }
+
+    fn write_entries(
+        &mut self,
+        identity: Identity,
+        open: &str,
+        close: &str,
+        entries: Vec<(Option<String>, Value)>,
+        depth: usize,
+    ) {
+        self.out.push_str(open);
+        if entries.is_empty() {
+            self.out.push_str(close);
+            return;
+        }
+        self.path.push(identity);
+        let count = entries.len();
+        for (i, (label, value)) in entries.iter().enumerate() {
+            self.out.push('\n');
+            self.out.push_str(&"  ".repeat(depth + 1));
+            if let Some(label) = label {
+                self.out.push_str(label);
+                self.out.push_str(": ");
+            }
+            self.write(value, depth + 1);
+            if i + 1 < count {
+                self.out.push(',');
+            }
+        }
+        self.path.pop();
+        self.out.push('\n');
+        self.out.push_str(&"  ".repeat(depth));
+        self.out.push_str(close);
+    }
+}
+
+// Reflection on instances, where properties are named by strings. Like with `.`, a property is either a field or a method of the class
+
+/// The method of the instance's class, bound to the instance the same way `instance.name` would
+fn bound_method(vm: &VM, state: &VMState, instance: &Value, name: usize) -> Option<Value> {
+    let class = state.instance(instance)?.class;
+    let method = vm.find_method(state, class, name)?;
+    Some(Value::LoxBoundMethod(ObjBoundMethod {
+        method,
+        pointer: instance.as_pointer(),
+    }))
+}
+
+/// Private properties are only reachable by name from where `.` could reach them, see VM::check_access
+fn private(vm: &VM, state: &VMState, instance: &Value, name: &str) -> Result<(), NativeError> {
+    if !name.starts_with('_') {
+        return Ok(());
+    }
+    vm.check_private(state, instance, name)
+        .map_err(|msg| NativeError::new(&msg))
+}
+
+/// call this like `getattr(instance, "name")`, the same as `instance.name`
+pub fn getattr(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let (instance, name) = match args {
+        [instance @ Value::LoxPointer(_), Value::LoxString(name)]
+            if state.instance(instance).is_some() =>
+        {
+            (instance, name)
+        }
+        _ => {
+            return Err(NativeError::new(
+                "getattr() expects an instance and a property name",
+            ))
+        }
+    };
+    private(vm, state, instance, name)?;
+    let property = state.find_property(vm, name);
+    if let Some(value) = property.and_then(|index| state.instance(instance)?.fields.get(&index)) {
+        return Ok(value.clone());
+    }
+    match property.and_then(|index| bound_method(vm, state, instance, index)) {
+        Some(method) => Ok(method),
+        None => Err(NativeError::new(&format!("Undefined property '{}'", name))),
+    }
+}
+
+/// call this like `setattr(instance, "name", value)`, the same as `instance.name = value`. Returns the value
+pub fn setattr(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let (instance, name, value) = match args {
+        [instance @ Value::LoxPointer(_), Value::LoxString(name), value]
+            if state.instance(instance).is_some() =>
+        {
+            (instance, name, value)
+        }
+        _ => {
+            return Err(NativeError::new(
+                "setattr() expects an instance, a property name and a value",
+            ))
+        }
+    };
+    private(vm, state, instance, name)?;
+    unfrozen(state, instance)?;
+    let index = state.property_index(vm, name);
+    state
+        .instance_mut(instance)
+        .unwrap()
+        .fields
+        .insert(index, value.clone());
+    Ok(value.clone())
+}
+
+/// call this like `hasattr(instance, "name")`, true if getattr would find a field or a method
+pub fn hasattr(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let (instance, name) = match args {
+        [instance @ Value::LoxPointer(_), Value::LoxString(name)]
+            if state.instance(instance).is_some() =>
+        {
+            (instance, name)
+        }
+        _ => {
+            return Err(NativeError::new(
+                "hasattr() expects an instance and a property name",
+            ))
+        }
+    };
+    let found = match state.find_property(vm, name) {
+        Some(index) => {
+            state
+                .instance(instance)
+                .unwrap()
+                .fields
+                .contains_key(&index)
+                || bound_method(vm, state, instance, index).is_some()
+        }
+        None => false,
+    };
+    Ok(Value::Bool(found))
+}
+
+/// An array of the names of the instance's fields, sorted so that the order doesn't change from run to run. Methods aren't included
+pub fn fields(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let mut names: Vec<String> = match state.instance(&args[0]) {
+        Some(instance) => instance
+            .fields
+            .keys()
+            .map(|index| state.property_name(vm, *index).to_string())
+            .collect(),
+        None => {
+            return Err(NativeError::with_value(
+                "fields() expects an instance",
+                &args[0],
+            ))
+        }
+    };
+    names.sort();
+    Ok(new_array(
+        names.iter().map(|name| state.new_string(name)).collect(),
+    ))
+}
+
+// Reflection on classes, which are given either as the class or as one of its instances
+
+/// The class of an instance, or the class itself
+fn class_arg(state: &VMState, value: &Value) -> Option<usize> {
+    match value {
+        Value::LoxClass(class) => Some(*class),
+        value => state.instance(value).map(|instance| instance.class),
+    }
+}
+
+/// call this like `className(instance)` or `className(Class)`
+pub fn class_name(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match class_arg(state, &args[0]) {
+        Some(class) => Ok(state.new_string(&vm.classes[class].name)),
+        None => Err(NativeError::with_value(
+            "className() expects an instance or a class",
+            &args[0],
+        )),
+    }
+}
+
+/// The class the instance was made from
+pub fn class_of(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match state.instance(&args[0]) {
+        Some(instance) => Ok(Value::LoxClass(instance.class)),
+        None => Err(NativeError::with_value(
+            "classOf() expects an instance",
+            &args[0],
+        )),
+    }
+}
+
+/// The superclass of the class, nil if it doesn't inherit one. Call it again on the result to walk up the chain
+pub fn super_of(vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match &args[0] {
+        Value::LoxClass(class) => Ok(vm.classes[*class]
+            .superclass
+            .map_or(Value::Nil, Value::LoxClass)),
+        value => Err(NativeError::with_value("superOf() expects a class", value)),
+    }
+}
+
+/// A sorted array of the names of the methods of the class, including the inherited ones and the ones added by extend
+pub fn methods(vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let class = match class_arg(state, &args[0]) {
+        Some(class) => class,
+        None => {
+            return Err(NativeError::with_value(
+                "methods() expects an instance or a class",
+                &args[0],
+            ))
+        }
+    };
+    let mut names: Vec<&str> = vm
+        .method_names(state, class)
+        .into_iter()
+        .map(|name| vm.get_variable_name(name).as_str())
+        .collect();
+    names.sort();
+    Ok(new_array(
+        names.iter().map(|name| state.new_string(name)).collect(),
+    ))
+}
+
+/// Runs the garbage collector right away and returns the number of objects it freed
+pub fn gc_collect(_vm: &VM, state: &mut VMState, _args: &[Value]) -> NativeResult {
+    Ok(Value::Int(state.collect_garbage() as i64))
+}
+
+/// A map of objectsAlive, bytesAllocated and collections. Only instances and closures live on the GC heap, so strings, arrays and maps aren't counted
+pub fn memory_stats(_vm: &VM, state: &mut VMState, _args: &[Value]) -> NativeResult {
+    let gc = state.gc();
+    let stats = [
+        ("objectsAlive", gc.live_objects()),
+        ("bytesAllocated", gc.bytes_allocated()),
+        ("collections", gc.collections()),
+    ];
+    let mut result = LoxMap::default();
+    for (name, count) in stats {
+        result.insert(MapKey::String(state.intern(name)), Value::Int(count as i64));
+    }
+    Ok(Value::LoxMap(Rc::new(RefCell::new(result))))
+}
+
+/// Emitted by the compiler for `for (var x in value)`, which then loops over the array this returns
+///
+/// It's always a copy, so changing what's being looped over inside the loop doesn't affect the loop. Maps are looped over by key
+pub fn __iterate(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match &args[0] {
+        Value::LoxArray(arr) => Ok(new_array(arr.borrow().clone())),
+        Value::LoxSet(set) => Ok(new_array(
+            set.borrow().iter().map(MapKey::to_value).collect(),
+        )),
+        Value::LoxMap(map) => Ok(new_array(
+            map.borrow().iter().map(|(key, _)| key.to_value()).collect(),
+        )),
+        Value::LoxBytes(bytes) => Ok(new_array(
+            bytes
+                .borrow()
+                .iter()
+                .map(|byte| Value::Int(*byte as i64))
+                .collect(),
+        )),
+        Value::LoxTuple(values) => Ok(new_array(values.to_vec())),
+        value => Err(NativeError::with_value(
+            "Can only loop over arrays, maps, sets, bytes and tuples",
+            value,
+        )),
+    }
+}
+
+/// call this like `tupleGet(t, i)`, returns nil if i is out of range
+pub fn tuple_get(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxTuple(values), index] => {
+            Ok(values.get(index_arg(index)?).cloned().unwrap_or(Value::Nil))
+        }
+        _ => Err(NativeError::new("tupleGet() expects a tuple and an index")),
+    }
+}
+
+// Byte buffers hold numbers from 0 to 255. Indexing and slicing work the same way as for arrays
+
+fn new_bytes(bytes: Vec<u8>) -> Value {
+    Value::LoxBytes(Rc::new(RefCell::new(bytes)))
+}
+
+/// call this like `bytesNew(n)` for a buffer of n zeroes
+pub fn bytes_new(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    Ok(new_bytes(vec![0; index_arg(&args[0])?]))
+}
+
+/// call this like `bytesGet(b, i)`, returns nil if i is out of range
+pub fn bytes_get(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxBytes(bytes), index] => match bytes.borrow().get(index_arg(index)?) {
+            Some(byte) => Ok(Value::Int(*byte as i64)),
+            None => Ok(Value::Nil),
         },
+        _ => Err(NativeError::new("bytesGet() expects bytes and an index")),
     }
-}
\ No newline at end of file
+}
+
+/// call this like `bytesSet(b, i, byte)` where byte is a whole number from 0 to 255, returns the byte
+pub fn bytes_set(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxBytes(bytes), index, value] => {
+            unfrozen(state, &args[0])?;
+            let byte = match as_index(value).and_then(|byte| u8::try_from(byte).ok()) {
+                Some(byte) => byte,
+                None => {
+                    return Err(NativeError::with_value(
+                        "A byte has to be a whole number from 0 to 255",
+                        value,
+                    ))
+                }
+            };
+            let mut bytes = bytes.borrow_mut();
+            match bytes.get_mut(index_arg(index)?) {
+                Some(dest) => {
+                    *dest = byte;
+                    Ok(value.clone())
+                }
+                None => Err(NativeError::with_value("Bytes index out of range", index)),
+            }
+        }
+        _ => Err(NativeError::new(
+            "bytesSet() expects bytes, an index and a byte",
+        )),
+    }
+}
+
+/// call this like `bytesSlice(b, start, end)` for a new buffer, clamped the same way as slice
+pub fn bytes_slice(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxBytes(bytes), start, end] => {
+            let bytes = bytes.borrow();
+            let end = index_arg(end)?.min(bytes.len());
+            let start = index_arg(start)?.min(end);
+            Ok(new_bytes(bytes[start..end].to_vec()))
+        }
+        _ => Err(NativeError::new(
+            "bytesSlice() expects bytes, a start and an end",
+        )),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Encoding {
+    Utf8,
+    Latin1,
+    Hex,
+}
+
+/// The optional encoding argument of bytesFromString and bytesToString, which defaults to utf8
+fn encoding_arg(encoding: Option<&Value>) -> Result<Encoding, NativeError> {
+    match encoding {
+        None => Ok(Encoding::Utf8),
+        Some(Value::LoxString(name)) if &**name == "utf8" => Ok(Encoding::Utf8),
+        Some(Value::LoxString(name)) if &**name == "latin1" => Ok(Encoding::Latin1),
+        Some(Value::LoxString(name)) if &**name == "hex" => Ok(Encoding::Hex),
+        Some(other) => Err(NativeError::with_value(
+            "The encoding has to be \"utf8\", \"latin1\" or \"hex\"",
+            other,
+        )),
+    }
+}
+
+/// call this like `bytesFromString(s, encoding)`, where the encoding is "utf8" (the default), "latin1" or "hex"
+///
+/// Returns nil if s can't be encoded, ie if it has characters past U+00FF for latin1 or isn't pairs of hex digits
+pub fn bytes_from_string(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    let s = match &args[0] {
+        Value::LoxString(s) => s,
+        _ => return Err(NativeError::new("bytesFromString() expects a string")),
+    };
+    let bytes: Option<Vec<u8>> = match encoding_arg(args.get(1))? {
+        Encoding::Utf8 => Some(s.as_bytes().to_vec()),
+        Encoding::Latin1 => s.chars().map(|c| u8::try_from(c).ok()).collect(),
+        Encoding::Hex if s.len() % 2 == 0 => (0..s.len())
+            .step_by(2)
+            .map(|i| {
+                s.get(i..i + 2)
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            })
+            .collect(),
+        Encoding::Hex => None,
+    };
+    match bytes {
+        Some(bytes) => Ok(new_bytes(bytes)),
+        None => Ok(Value::Nil),
+    }
+}
+
+/// call this like `bytesToString(b, encoding)`, with the same encodings as bytesFromString. Returns nil if b isn't valid utf8
+pub fn bytes_to_string(_vm: &VM, state: &mut VMState, args: &[Value]) -> NativeResult {
+    let bytes = match &args[0] {
+        Value::LoxBytes(bytes) => bytes.borrow(),
+        _ => return Err(NativeError::new("bytesToString() expects bytes")),
+    };
+    let s = match encoding_arg(args.get(1))? {
+        Encoding::Utf8 => match std::str::from_utf8(&bytes) {
+            Ok(s) => s.to_string(),
+            Err(_) => return Ok(Value::Nil),
+        },
+        Encoding::Latin1 => bytes.iter().map(|byte| *byte as char).collect(),
+        Encoding::Hex => bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+    };
+    Ok(state.new_string(&s))
+}
+
+/// The whole file as a buffer, or nil if it can't be read
+pub fn read_bytes(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxString(path)] => match fs::read(&**path) {
+            Ok(bytes) => Ok(new_bytes(bytes)),
+            Err(_) => Ok(Value::Nil),
+        },
+        _ => Err(NativeError::new("readBytes() expects a path")),
+    }
+}
+
+/// call this like `writeBytes(path, b)`, replacing the file. Returns false if it couldn't be written
+pub fn write_bytes(_vm: &VM, _state: &mut VMState, args: &[Value]) -> NativeResult {
+    match args {
+        [Value::LoxString(path), Value::LoxBytes(bytes)] => {
+            Ok(Value::Bool(fs::write(&**path, &*bytes.borrow()).is_ok()))
+        }
+        _ => Err(NativeError::new("writeBytes() expects a path and bytes")),
+    }
+}
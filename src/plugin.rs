@@ -0,0 +1,261 @@
+//! Native extension modules, ie dynamic libraries loaded at runtime with `use "native:name";`
+//!
+//! A native module is a cdylib that exports two functions with C linkage:
+//!
+//! * `rlox_module_abi_version() -> u32`, which must return RLOX_ABI_VERSION
+//! * `rlox_register_module(registry: *mut c_void, register: RloxRegisterFn)`, which calls `register(registry, name, function)` once for every function it provides
+//!
+//! Registered functions are bound as `name::function` globals, where name is the file stem of the module path
+
+use crate::value::Value;
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+
+/// Bumped whenever the layout of RloxValue or the signatures below change
+pub const RLOX_ABI_VERSION: u32 = 1;
+
+pub const RLOX_NIL: u32 = 0;
+pub const RLOX_BOOL: u32 = 1;
+pub const RLOX_NUMBER: u32 = 2;
+pub const RLOX_STRING: u32 = 3;
+pub const RLOX_ERROR: u32 = 4; // Only returned, the string is the message of the runtime error raised at the call site
+
+/// Stable representation of a Value passed across the native module boundary, and by the C API in ffi.rs
+///
+/// Only nil, bools, numbers and strings can be passed. Strings are utf8 and not nul terminated.
+/// Argument strings are only valid for the duration of the call, returned strings are copied by the VM as soon as the function returns
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RloxValue {
+    pub tag: u32,
+    pub boolean: bool,
+    pub number: f64,
+    pub string: *const c_char,
+    pub string_len: usize,
+}
+
+impl RloxValue {
+    pub fn nil() -> RloxValue {
+        RloxValue {
+            tag: RLOX_NIL,
+            boolean: false,
+            number: 0.0,
+            string: std::ptr::null(),
+            string_len: 0,
+        }
+    }
+
+    pub fn bool(x: bool) -> RloxValue {
+        RloxValue {
+            tag: RLOX_BOOL,
+            boolean: x,
+            ..RloxValue::nil()
+        }
+    }
+
+    pub fn number(x: f64) -> RloxValue {
+        RloxValue {
+            tag: RLOX_NUMBER,
+            number: x,
+            ..RloxValue::nil()
+        }
+    }
+
+    /// The returned value borrows s, so s must outlive every use of it
+    pub fn string(s: &str) -> RloxValue {
+        RloxValue {
+            tag: RLOX_STRING,
+            string: s.as_ptr() as *const c_char,
+            string_len: s.len(),
+            ..RloxValue::nil()
+        }
+    }
+
+    pub fn error(message: &str) -> RloxValue {
+        RloxValue {
+            tag: RLOX_ERROR,
+            ..RloxValue::string(message)
+        }
+    }
+
+    /// None for the values that can't be passed, ie arrays, instances and functions
+    pub(crate) fn from_value(value: &Value) -> Option<RloxValue> {
+        match value {
+            Value::Nil => Some(RloxValue::nil()),
+            Value::Bool(x) => Some(RloxValue::bool(*x)),
+            Value::Double(x) => Some(RloxValue::number(*x)),
+            Value::Int(x) => Some(RloxValue::number(*x as f64)),
+            Value::LoxString(x) => Some(RloxValue::string(x)),
+            _ => None,
+        }
+    }
+
+    /// Converts a returned value back, with new_string making the Value out of a RLOX_STRING. Err is the message of the runtime error to raise
+    ///
+    /// # Safety
+    /// Strings have to describe a valid allocation, see as_str
+    pub(crate) unsafe fn to_value(
+        self,
+        new_string: impl FnOnce(&str) -> Value,
+    ) -> Result<Value, String> {
+        match self.tag {
+            RLOX_NIL => Ok(Value::Nil),
+            RLOX_BOOL => Ok(Value::Bool(self.boolean)),
+            RLOX_NUMBER => Ok(Value::Double(self.number)),
+            RLOX_STRING => match self.as_str() {
+                Some(s) => Ok(new_string(s)),
+                None => Err(String::from("Native function returned an invalid string")),
+            },
+            RLOX_ERROR => match self.as_str() {
+                Some(message) => Err(message.to_string()),
+                None => Err(String::from(
+                    "Native function returned an invalid error message",
+                )),
+            },
+            _ => Err(String::from("Native function returned an invalid value")),
+        }
+    }
+
+    /// Reads the string out of a RLOX_STRING or RLOX_ERROR value
+    ///
+    /// # Safety
+    /// string and string_len must describe a valid allocation
+    pub unsafe fn as_str(&self) -> Option<&str> {
+        if (self.tag != RLOX_STRING && self.tag != RLOX_ERROR) || self.string.is_null() {
+            return None;
+        }
+        let bytes = std::slice::from_raw_parts(self.string as *const u8, self.string_len);
+        std::str::from_utf8(bytes).ok()
+    }
+}
+
+pub type RloxForeignFn = extern "C" fn(arg_count: usize, args: *const RloxValue) -> RloxValue;
+pub type RloxRegisterFn =
+    extern "C" fn(registry: *mut c_void, name: *const c_char, function: RloxForeignFn);
+
+type AbiVersionFn = extern "C" fn() -> u32;
+type RegisterModuleFn = extern "C" fn(registry: *mut c_void, register: RloxRegisterFn);
+
+/// A loaded native module. The library stays loaded until this is dropped, so the VM must keep it around for as long as its functions can be called
+pub struct NativeLibrary {
+    #[cfg(unix)]
+    handle: *mut c_void,
+    pub functions: Vec<(String, RloxForeignFn)>,
+}
+
+extern "C" fn register(registry: *mut c_void, name: *const c_char, function: RloxForeignFn) {
+    let functions = unsafe { &mut *(registry as *mut Vec<(String, RloxForeignFn)>) };
+    let name = unsafe { CStr::from_ptr(name) };
+    functions.push((name.to_string_lossy().to_string(), function));
+}
+
+/// Turns the module path from `use "native:path";` into the platform's file name for the library, ie "dir/sqlite" => "dir/libsqlite.so" on linux
+fn library_path(path: &str) -> String {
+    let path = std::path::Path::new(path);
+    let file_name = match path.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => String::new(),
+    };
+    let file_name = format!(
+        "{}{}{}",
+        std::env::consts::DLL_PREFIX,
+        file_name,
+        std::env::consts::DLL_SUFFIX
+    );
+    let library = path.with_file_name(file_name);
+
+    // Without a slash, dlopen only searches the system library paths
+    if library.parent().is_none_or(|p| p.as_os_str().is_empty()) {
+        format!("./{}", library.display())
+    } else {
+        library.display().to_string()
+    }
+}
+
+#[cfg(unix)]
+impl NativeLibrary {
+    pub fn load(path: &str) -> Result<NativeLibrary, String> {
+        let path = library_path(path);
+        let c_path = std::ffi::CString::new(path.clone())
+            .map_err(|_| format!("Invalid native module path '{}'", path))?;
+
+        let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW) };
+        if handle.is_null() {
+            return Err(format!(
+                "Failed to load native module {}: {}",
+                path,
+                dl_error()
+            ));
+        }
+
+        // Construct it first so that the library gets closed on any of the early returns
+        let mut library = NativeLibrary {
+            handle,
+            functions: Vec::new(),
+        };
+
+        let version = library.symbol("rlox_module_abi_version")?;
+        let version: AbiVersionFn = unsafe { std::mem::transmute(version) };
+        if version() != RLOX_ABI_VERSION {
+            return Err(format!(
+                "Native module {} was built for ABI version {}, expected {}",
+                path,
+                version(),
+                RLOX_ABI_VERSION
+            ));
+        }
+
+        let register_module = library.symbol("rlox_register_module")?;
+        let register_module: RegisterModuleFn = unsafe { std::mem::transmute(register_module) };
+        let mut functions: Vec<(String, RloxForeignFn)> = Vec::new();
+        register_module(
+            &mut functions as *mut Vec<(String, RloxForeignFn)> as *mut c_void,
+            register,
+        );
+        library.functions = functions;
+
+        Ok(library)
+    }
+
+    fn symbol(&self, name: &str) -> Result<*mut c_void, String> {
+        let c_name = std::ffi::CString::new(name).unwrap();
+        let symbol = unsafe { libc::dlsym(self.handle, c_name.as_ptr()) };
+        if symbol.is_null() {
+            Err(format!("Native module does not export '{}'", name))
+        } else {
+            Ok(symbol)
+        }
+    }
+}
+
+#[cfg(unix)]
+fn dl_error() -> String {
+    let error = unsafe { libc::dlerror() };
+    if error.is_null() {
+        String::from("unknown error")
+    } else {
+        unsafe { CStr::from_ptr(error) }
+            .to_string_lossy()
+            .to_string()
+    }
+}
+
+#[cfg(unix)]
+impl Drop for NativeLibrary {
+    fn drop(&mut self) {
+        unsafe {
+            libc::dlclose(self.handle);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+impl NativeLibrary {
+    pub fn load(path: &str) -> Result<NativeLibrary, String> {
+        Err(format!(
+            "Failed to load native module {}: native modules are only supported on unix platforms",
+            library_path(path)
+        ))
+    }
+}
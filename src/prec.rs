@@ -207,6 +207,7 @@ pub fn get_rule(operator: TokenType) -> ParseRule {
         TokenType::TokenFalse => PARSE_RULE_FALSE,
         TokenType::TokenNil => PARSE_RULE_NIL,
         TokenType::TokenBang => PARSE_RULE_BANG,
+        TokenType::TokenAwait => PARSE_RULE_BANG, // Also a prefix operator
         TokenType::TokenBangEqual => PARSE_RULE_BE,
         TokenType::TokenEqualEqual => PARSE_RULE_EE,
         TokenType::TokenGreater => PARSE_RULE_G,
@@ -0,0 +1,105 @@
+use crate::vm::{Output, VM};
+
+use std::time::{Duration, Instant};
+
+// Per function profiler for `--profile`
+//
+// Instead of hooking every kind of call, the VM tells us the call depth before every instruction.
+// A call can only push one frame and a return can only pop one, so a change in depth is always exactly one function entering or exiting
+
+#[derive(Debug, Clone, Default)]
+struct FunctionStats {
+    calls: usize,
+    inclusive: Duration, // Time spent in the function and everything it called
+    exclusive: Duration, // Time spent in the function's own instructions
+}
+
+#[derive(Debug)]
+struct ActiveCall {
+    function: usize,
+    start: Instant,
+    children: Duration, // Inclusive time of the calls made from this one
+}
+
+pub struct Profiler {
+    stats: Vec<FunctionStats>, // Indexed the same as VM.functions
+    calls: Vec<ActiveCall>,
+}
+
+impl Profiler {
+    pub fn new(function_count: usize) -> Profiler {
+        Profiler {
+            stats: vec![FunctionStats::default(); function_count],
+            calls: Vec::new(),
+        }
+    }
+
+    /// For functions added by Vm::eval while the program is running
+    pub fn grow(&mut self, function_count: usize) {
+        self.stats.resize(function_count, FunctionStats::default());
+    }
+
+    /// Called before every instruction with the depth of the call stack and the function that is executing
+    pub fn on_instruction(&mut self, depth: usize, function: usize) {
+        if depth > self.calls.len() {
+            self.stats[function].calls += 1;
+            self.calls.push(ActiveCall {
+                function,
+                start: Instant::now(),
+                children: Duration::ZERO,
+            });
+        } else if depth < self.calls.len() {
+            self.exit();
+        }
+    }
+
+    fn exit(&mut self) {
+        let call = self.calls.pop().unwrap();
+        let elapsed = call.start.elapsed();
+
+        let stats = &mut self.stats[call.function];
+        stats.exclusive += elapsed.saturating_sub(call.children);
+        // Only the outermost call of a recursive function counts, otherwise the time would be counted once per level
+        if !self.calls.iter().any(|c| c.function == call.function) {
+            stats.inclusive += elapsed;
+        }
+
+        if let Some(parent) = self.calls.last_mut() {
+            parent.children += elapsed;
+        }
+    }
+
+    /// Closes off the calls that were still running when the program ended and prints the report to stderr, slowest functions first
+    pub fn report(mut self, vm: &VM, stderr: &Output) {
+        while !self.calls.is_empty() {
+            self.exit();
+        }
+
+        let mut rows: Vec<(usize, &FunctionStats)> = self
+            .stats
+            .iter()
+            .enumerate()
+            .filter(|(_, stats)| stats.calls > 0)
+            .collect();
+        rows.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.inclusive));
+
+        stderr.write_line("== Profile ==");
+        stderr.write_line(&format!(
+            "{:<24} {:>10} {:>16} {:>16}",
+            "function", "calls", "inclusive (ms)", "exclusive (ms)"
+        ));
+        for (function, stats) in rows {
+            let name = match &vm.functions[function].name {
+                Some(name) => name.as_str(),
+                None => "script",
+            };
+            stderr.write_line(&format!(
+                "{:<24} {:>10} {:>16.3} {:>16.3}",
+                name,
+                stats.calls,
+                stats.inclusive.as_secs_f64() * 1000.0,
+                stats.exclusive.as_secs_f64() * 1000.0
+            ));
+        }
+    }
+}
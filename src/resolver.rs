@@ -1,4 +1,5 @@
 use crate::chunk::FunctionType;
+use crate::scanner::Token;
 
 /// Manages the declaration and definition of local variables
 ///
@@ -34,11 +35,36 @@ impl Resolver {
     }
 
     delegate_to_latest!(begin_scope, ());
-    delegate_to_latest!(end_scope, usize);
+    delegate_to_latest!(end_scope, Vec<Local>);
     delegate_to_latest!(is_global, bool);
     delegate_to_latest!(mark_initialized, ());
     delegate_to_latest!(declare_variable, bool, String);
     delegate_to_latest!(resolve_local, Result<Option<usize>, ()>, &str);
+    delegate_to_latest!(last_local_slot, usize);
+    delegate_to_latest!(mark_read, (), usize);
+    delegate_to_latest!(set_declared_at, (), Token);
+
+    /// Whether declaring name as a local would hide a local of an enclosing scope or function
+    pub fn is_shadowing(&self, name: &str) -> bool {
+        let current = self.stack.last().unwrap();
+        let outer = current
+            .locals
+            .iter()
+            .filter(|local| local.depth.is_some_and(|depth| depth < current.scope_depth));
+        let enclosing = self.stack[..self.stack.len() - 1]
+            .iter()
+            .flat_map(|node| node.locals.iter());
+        outer.chain(enclosing).any(|local| local.name == name)
+    }
+
+    /// The local that name resolves to, searched for the same way resolve_local and resolve_upvalue do. None means it's a global
+    pub fn local_named(&self, name: &str) -> Option<&Local> {
+        let n = self.stack.len();
+        let enclosing = if n >= 3 { &self.stack[..n - 1] } else { &[] };
+        std::iter::once(&self.stack[n - 1])
+            .chain(enclosing.iter().rev())
+            .find_map(|node| node.locals.iter().find(|local| local.name == name))
+    }
 
     /// Calls Resolver::recursive_resolve to handle the flattening of upvalues
     ///
@@ -72,6 +98,7 @@ impl Resolver {
         }
 
         if let Some(index) = upval_index {
+            self.stack[child_index - 1].locals[index].read = true; // Capturing it counts as reading it, we can't tell what the closure does with it
             let child = self.stack.get_mut(child_index)?;
             return Some(child.add_upvalue(index, true));
         } else if let Some(index) = self.recursive_resolve(name, child_index - 1) {
@@ -89,10 +116,14 @@ impl Resolver {
             FunctionType::Method | FunctionType::Initializer => Local {
                 name: String::from("this"),
                 depth: Some(1),
+                read: false,
+                declared_at: None,
             }, // Fill the first slot with a magically initialized "this" which will contain the LoxPointer to itself
             _ => Local {
                 name: String::from(""),
                 depth: None,
+                read: false,
+                declared_at: None,
             }, // Fill the first slot with a blank to be filled with the closure
         };
         locals.push(first_local);
@@ -106,10 +137,10 @@ impl Resolver {
         self.stack.push(new);
     }
 
-    /// Remove the latest ResolverNode and return the UpValues resolved in that scope
-    pub fn pop(&mut self) -> Vec<UpValue> {
+    /// Remove the latest ResolverNode and return the UpValues resolved in that scope, along with the locals still in scope at the end of the function
+    pub fn pop(&mut self) -> (Vec<UpValue>, Vec<Local>) {
         let latest = self.stack.pop().unwrap(); // Fixme: make this not panic?
-        latest.upvalues
+        (latest.upvalues, latest.locals)
     }
 
     pub fn new() -> Resolver {
@@ -118,6 +149,8 @@ impl Resolver {
             // Placeholder local variable for VM use -> Will be filled by the corresponding LoxFunction for the CallFrame
             name: String::from(""),
             depth: None,
+            read: false,
+            declared_at: None,
         });
 
         let top = ResolverNode {
@@ -147,11 +180,11 @@ impl ResolverNode {
 
     /// MUST BE CALLED BY Compiler::end_scope()
     ///
-    /// Decrements the scope depth and pops off the values that went out of scope
+    /// Decrements the scope depth and pops off the values that went out of scope, returning them
     /// Todo:
     /// *  Make this less uggo
     /// *  Use a trait or something to limit the visibility somehow?
-    pub fn end_scope(&mut self) -> usize {
+    pub fn end_scope(&mut self) -> Vec<Local> {
         self.scope_depth -= 1;
         let mut pops = 0;
         for local in self.locals.iter().rev() {
@@ -163,10 +196,7 @@ impl ResolverNode {
                 }
             }
         }
-        for _ in 0..pops {
-            self.locals.pop();
-        }
-        pops
+        self.locals.split_off(self.locals.len() - pops)
     }
 
     pub fn is_global(&self) -> bool {
@@ -174,10 +204,30 @@ impl ResolverNode {
     }
 
     pub fn add_local(&mut self, name: String) {
-        let local = Local { name, depth: None };
+        let local = Local {
+            name,
+            depth: None,
+            read: false,
+            declared_at: None,
+        };
         self.locals.push(local);
     }
 
+    /// The slot of the most recently declared local, for locals the compiler declares itself and can't look up by name
+    pub fn last_local_slot(&mut self) -> usize {
+        self.locals.len() - 1
+    }
+
+    /// Notes that the local in slot was read, so it doesn't get warned about
+    pub fn mark_read(&mut self, slot: usize) {
+        self.locals[slot].read = true;
+    }
+
+    /// Remembers where the last local was declared, which only the locals named in the source have
+    pub fn set_declared_at(&mut self, token: Token) {
+        self.locals.last_mut().unwrap().declared_at = Some(token);
+    }
+
     /// Marks the last local variable as initialized by giving it a depth
     /// if the current scope is not global
     pub fn mark_initialized(&mut self) {
@@ -246,7 +296,8 @@ impl ResolverNode {
 
     fn add_upvalue(&mut self, index: usize, is_local: bool) -> usize {
         for (i, existing_upvalue) in self.upvalues.iter().enumerate() {
-            if existing_upvalue.index == index {
+            // A local of the enclosing function and an upvalue of it can have the same index, ie `this` captured from two functions out
+            if existing_upvalue.index == index && existing_upvalue.is_local == is_local {
                 return i;
             }
         }
@@ -260,6 +311,8 @@ impl ResolverNode {
 pub struct Local {
     pub name: String,
     pub depth: Option<usize>,
+    pub read: bool,
+    pub declared_at: Option<Token>, // The name it was declared with, None for the locals the compiler declares itself
 }
 
 /// Similar to local, but for upvalues
@@ -1,3 +1,6 @@
+use std::ops::Range;
+use unicode_xid::UnicodeXID;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenType {
     TokenLeftParen,    // (
@@ -44,32 +47,55 @@ pub enum TokenType {
     TokenWhile,
     TokenError,
     TokenAwait,
+    TokenAsync,
     TokenUse,
+    TokenExport,
+    TokenDelete,
+    TokenExtend,
+    TokenStatic,
     TokenEOF,
+
+    TokenComment, // From the // to the end of the line. Only scanned with Scanner::set_trivia
+    TokenWhitespace, // Spaces and tabs, up to and including a line break. ^
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Token {
     pub token_type: TokenType,
     pub line_num: usize,
-    pub lexemme: String,
+    pub column: usize, // Where the token starts on its line, counting characters from 1
+    pub span: Range<usize>, // Byte offsets of the token in the source
+    pub lexemme: String, // The source text of the token, quotes included for strings. For a TokenError it's the error message instead
 }
 
+/// Turns Lox source into tokens on demand, the same way the compiler reads it. Comments and whitespace are skipped, unless set_trivia asks for them
+///
+/// Iterating over a Scanner gives every token in order, ending with the TokenEOF. Scanning carries on past a TokenError
 #[derive(Debug, Clone, Copy)]
 pub struct Scanner<'a> {
     code: &'a str,
     cur_line: usize,
+    line_start: usize,          // Position of the first character of cur_line
+    line_continuations: usize, // How many bytes of cur_line before cur_pos were the 2nd to 4th bytes of a UTF-8 character
+    start_continuations: usize, // The same up to start_pos, which makes the column count characters instead of bytes
     start_pos: usize,
     cur_pos: usize,
+    finished: bool, // The TokenEOF has been handed out by next()
+    trivia: bool,   // Comments and whitespace are tokens as well
 }
 
 impl Scanner<'_> {
-    pub fn new(code: &str) -> Scanner {
+    pub fn new(code: &str) -> Scanner<'_> {
         Scanner {
             code,
             cur_line: 1,
+            line_start: 0,
+            line_continuations: 0,
+            start_continuations: 0,
             start_pos: 0,
             cur_pos: 0,
+            finished: false,
+            trivia: false,
         }
     }
 
@@ -77,6 +103,8 @@ impl Scanner<'_> {
         Token {
             token_type,
             line_num: self.cur_line,
+            column: self.column(),
+            span: self.start_pos..self.cur_pos,
             lexemme: self.code[self.start_pos..self.cur_pos].to_string(),
         }
     }
@@ -85,10 +113,38 @@ impl Scanner<'_> {
         Token {
             token_type: TokenType::TokenError,
             line_num: self.cur_line,
+            column: self.column(),
+            span: self.start_pos..self.cur_pos,
             lexemme: msg,
         }
     }
 
+    /// Makes the scanner return the comments and whitespace as TokenComment and TokenWhitespace, so that every byte of the source is in
+    /// exactly one token. The compiler can't read those, it's meant for tools like formatters
+    pub fn set_trivia(&mut self, trivia: bool) {
+        self.trivia = trivia;
+    }
+
+    /// The text of line n of the source, without its line break
+    pub fn line(&self, n: usize) -> &str {
+        self.code.lines().nth(n.saturating_sub(1)).unwrap_or("")
+    }
+
+    /// A string spanning lines starts before the line it's reported on, so it gets column 1
+    fn column(&self) -> usize {
+        if self.start_pos < self.line_start {
+            return 1;
+        }
+        self.start_pos - self.line_start - self.start_continuations + 1
+    }
+
+    /// Called right after consuming a \n
+    fn new_line(&mut self) {
+        self.cur_line += 1;
+        self.line_start = self.cur_pos;
+        self.line_continuations = 0;
+    }
+
     /// If this is true, then the current position is invalid and cannot be peeked
     fn is_at_end(&self) -> bool {
         self.cur_pos == self.code.len()
@@ -102,9 +158,21 @@ impl Scanner<'_> {
     fn advance(&mut self) -> u8 {
         let ret = self.peek();
         self.cur_pos += 1;
+        if is_continuation(ret) {
+            self.line_continuations += 1;
+        }
         ret
     }
 
+    /// Steps over the rest of the character starting at cur_pos - 1, and returns that character
+    fn advance_char(&mut self) -> char {
+        let c = self.code[self.cur_pos - 1..].chars().next().unwrap();
+        for _ in 1..c.len_utf8() {
+            self.advance();
+        }
+        c
+    }
+
     fn match_char(&mut self, expected: u8) -> bool {
         if self.is_at_end() {
             return false;
@@ -124,6 +192,32 @@ impl Scanner<'_> {
         self.code.as_bytes()[self.cur_pos + 1]
     }
 
+    /// A TokenComment or TokenWhitespace if there's one at cur_pos. Neither spans lines, so their line and column are where they start
+    fn scan_trivia(&mut self) -> Option<Token> {
+        if self.is_at_end() {
+            return None;
+        }
+        if self.peek() == b'/' && self.can_peek_next() && self.peek_next() == b'/' {
+            while !self.is_at_end() && self.peek() != b'\n' {
+                self.advance();
+            }
+            return Some(self.create_token(TokenType::TokenComment));
+        }
+
+        while !self.is_at_end() && matches!(self.peek(), b' ' | b'\t' | b'\r') {
+            self.advance();
+        }
+        let line_break = self.match_char(b'\n');
+        if self.cur_pos == self.start_pos {
+            return None;
+        }
+        let token = self.create_token(TokenType::TokenWhitespace);
+        if line_break {
+            self.new_line();
+        }
+        Some(token)
+    }
+
     /// This function is gross and it also messes up sometimes near the end of files
     fn skip_whitespace(&mut self) {
         while !self.is_at_end() {
@@ -132,7 +226,7 @@ impl Scanner<'_> {
                 self.advance();
             } else if next == b'\n' {
                 self.advance();
-                self.cur_line += 1;
+                self.new_line();
             } else if next == b'/' {
                 if self.can_peek_next() && self.peek_next() == b'/' {
                     while !self.is_at_end() && self.peek() != b'\n' {
@@ -141,7 +235,7 @@ impl Scanner<'_> {
 
                     if !self.is_at_end() {
                         self.advance(); // consume the \n
-                        self.cur_line += 1;
+                        self.new_line();
                     }
                 } else {
                     return; // Return on single slash
@@ -154,10 +248,9 @@ impl Scanner<'_> {
 
     fn create_string(&mut self) -> Token {
         while !self.is_at_end() && self.peek() != b'"' {
-            if self.peek() == b'\n' {
-                self.cur_line += 1
+            if self.advance() == b'\n' {
+                self.new_line();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
@@ -213,6 +306,7 @@ impl Scanner<'_> {
                     match self.code.as_bytes()[self.start_pos + 1] {
                         b'n' => self.check_for_keyword(2, 1, "d", TokenType::TokenAnd),
                         b'w' => self.check_for_keyword(2, 3, "ait", TokenType::TokenAwait),
+                        b's' => self.check_for_keyword(2, 3, "ync", TokenType::TokenAsync),
                         _ => TokenType::TokenIdentifier,
                     }
                 } else {
@@ -220,13 +314,42 @@ impl Scanner<'_> {
                 }
             }
             b'c' => self.check_for_keyword(1, 4, "lass", TokenType::TokenClass),
-            b'e' => self.check_for_keyword(1, 3, "lse", TokenType::TokenElse),
+            b'd' => self.check_for_keyword(1, 5, "elete", TokenType::TokenDelete),
+            b'e' => {
+                if self.cur_pos - self.start_pos > 1 {
+                    // more than 1 char in this maybe keyword
+                    match self.code.as_bytes()[self.start_pos + 1] {
+                        b'l' => self.check_for_keyword(2, 2, "se", TokenType::TokenElse),
+                        b'x' if self.cur_pos - self.start_pos > 2 => {
+                            match self.code.as_bytes()[self.start_pos + 2] {
+                                b'p' => self.check_for_keyword(3, 3, "ort", TokenType::TokenExport),
+                                b't' => self.check_for_keyword(3, 3, "end", TokenType::TokenExtend),
+                                _ => TokenType::TokenIdentifier,
+                            }
+                        }
+                        _ => TokenType::TokenIdentifier,
+                    }
+                } else {
+                    TokenType::TokenIdentifier
+                }
+            }
             b'i' => self.check_for_keyword(1, 1, "f", TokenType::TokenIf),
             b'n' => self.check_for_keyword(1, 2, "il", TokenType::TokenNil),
             b'o' => self.check_for_keyword(1, 1, "r", TokenType::TokenOr),
             b'p' => self.check_for_keyword(1, 4, "rint", TokenType::TokenPrint),
             b'r' => self.check_for_keyword(1, 5, "eturn", TokenType::TokenReturn),
-            b's' => self.check_for_keyword(1, 4, "uper", TokenType::TokenSuper),
+            b's' => {
+                if self.cur_pos - self.start_pos > 1 {
+                    // more than 1 char in this maybe keyword
+                    match self.code.as_bytes()[self.start_pos + 1] {
+                        b'u' => self.check_for_keyword(2, 3, "per", TokenType::TokenSuper),
+                        b't' => self.check_for_keyword(2, 4, "atic", TokenType::TokenStatic),
+                        _ => TokenType::TokenIdentifier,
+                    }
+                } else {
+                    TokenType::TokenIdentifier
+                }
+            }
             b'v' => self.check_for_keyword(1, 2, "ar", TokenType::TokenVar),
             b'w' => self.check_for_keyword(1, 4, "hile", TokenType::TokenWhile),
             b'f' => {
@@ -260,16 +383,34 @@ impl Scanner<'_> {
     }
 
     fn create_identifier(&mut self) -> Token {
-        while !self.is_at_end() && (is_alpha(self.peek()) || is_digit(self.peek())) {
-            self.advance();
+        while !self.is_at_end() {
+            let c = self.peek();
+            if is_alpha(c) || is_digit(c) {
+                self.advance();
+            } else if !c.is_ascii()
+                && self.code[self.cur_pos..].starts_with(|c: char| c.is_xid_continue())
+            {
+                self.advance();
+                self.advance_char();
+            } else {
+                break;
+            }
         }
         self.create_token(self.get_identifier_type())
     }
 
+    /// The next token, or another TokenEOF once the end has been reached
     pub fn scan_token(&mut self) -> Token {
         self.start_pos = self.cur_pos;
+        self.start_continuations = self.line_continuations;
+        if self.trivia {
+            if let Some(token) = self.scan_trivia() {
+                return token;
+            }
+        }
         self.skip_whitespace();
         self.start_pos = self.cur_pos; // reset any seeking we did while we were removing whitespace
+        self.start_continuations = self.line_continuations;
 
         if self.is_at_end() {
             return self.create_token(TokenType::TokenEOF);
@@ -281,6 +422,13 @@ impl Scanner<'_> {
             return self.create_number();
         } else if is_alpha(c) {
             return self.create_identifier();
+        } else if !c.is_ascii() {
+            // Identifiers can be written in any script, anything else is reported as a whole character instead of byte by byte
+            return if self.advance_char().is_xid_start() {
+                self.create_identifier()
+            } else {
+                self.error_token(String::from("Invalid character"))
+            };
         }
 
         // Punctuation and string literal tokens
@@ -342,6 +490,19 @@ impl Scanner<'_> {
     }
 }
 
+impl Iterator for Scanner<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        if self.finished {
+            return None;
+        }
+        let token = self.scan_token();
+        self.finished = token.token_type == TokenType::TokenEOF;
+        Some(token)
+    }
+}
+
 fn is_digit(c: u8) -> bool {
     c >= b'0' && c <= b'9'
 }
@@ -349,3 +510,8 @@ fn is_digit(c: u8) -> bool {
 fn is_alpha(c: u8) -> bool {
     (c >= b'a' && c <= b'z') || (c >= b'A' && c <= b'Z') || c == b'_'
 }
+
+/// The bytes after the first of a multi-byte UTF-8 character, which don't start a character of their own
+fn is_continuation(c: u8) -> bool {
+    c & 0b1100_0000 == 0b1000_0000
+}
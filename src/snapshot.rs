@@ -0,0 +1,381 @@
+use crate::bytecode::{Reader, Writer};
+use crate::value::{LoxMap, LoxSet, MapKey, Value};
+use crate::vm::{Global, VMState, VM};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Binary format for the globals saved by Vm::snapshot
+//
+// A 4 byte magic number and a 2 byte little endian format version, then the number of globals followed by a name and a value for each, using the
+// varints and strings of bytecode.rs. Arrays, maps, sets, bytes and instances are numbered in the order they're first written and only written out
+// once, every other time they show up is a reference to that number. So values shared between globals stay shared, and cycles end
+
+const MAGIC: &[u8; 4] = b"LOXS";
+const FORMAT_VERSION: u16 = 3;
+const HEADER_LEN: usize = MAGIC.len() + 2;
+const MAX_DEPTH: usize = 1000; // Anything nested deeper is refused, instead of overflowing the stack while it's read back
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NUMBER: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_ARRAY: u8 = 4;
+const TAG_MAP: u8 = 5;
+const TAG_SET: u8 = 6;
+const TAG_BYTES: u8 = 7;
+const TAG_INSTANCE: u8 = 8;
+const TAG_REFERENCE: u8 = 9;
+const TAG_INT: u8 = 10;
+const TAG_TUPLE: u8 = 11;
+
+/// The initialized globals of the program, except for the functions and classes since running the program defines those again
+///
+/// Returns a message describing the problem if a global holds something that can't be saved, ie a function inside an array or a future
+pub fn snapshot(vm: &VM, state: &VMState) -> Result<Vec<u8>, String> {
+    let mut writer = Writer { bytes: Vec::new() };
+    writer.bytes.extend_from_slice(MAGIC);
+    writer
+        .bytes
+        .extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+
+    let globals: Vec<(&String, &Value)> = vm
+        .identifiers
+        .iter()
+        .zip(state.globals())
+        .filter_map(|(name, global)| match global {
+            Global::Init(value) if !is_code(value, state) => Some((name, value)),
+            _ => None,
+        })
+        .collect();
+
+    let mut snapshot = Snapshot {
+        writer,
+        vm,
+        state,
+        ids: HashMap::new(),
+    };
+    snapshot.writer.usize(globals.len());
+    for (name, value) in globals {
+        snapshot.writer.string(name);
+        snapshot
+            .value(value, 0)
+            .map_err(|why| format!("Can't snapshot '{}': {}", name, why))?;
+    }
+    Ok(snapshot.writer.bytes)
+}
+
+/// Sets every global saved in bytes that the program uses. Nothing is set unless the whole snapshot could be read
+///
+/// Returns a message describing the problem if the bytes aren't a valid snapshot, or if one of its instances is of a class the program doesn't have
+pub fn restore(vm: &VM, state: &mut VMState, bytes: &[u8]) -> Result<(), String> {
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(String::from("Not a snapshot"));
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(String::from("Unexpected end of snapshot"));
+    }
+    let version = u16::from_le_bytes([bytes[MAGIC.len()], bytes[MAGIC.len() + 1]]);
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "Snapshot was saved in format version {}, but this rlox reads version {}",
+            version, FORMAT_VERSION
+        ));
+    }
+
+    let mut restore = Restore {
+        reader: Reader::new(bytes, HEADER_LEN),
+        values: Vec::new(),
+        rooted: 0,
+    };
+    let globals = restore.globals(vm, state);
+    for _ in 0..restore.rooted {
+        state.pop_root();
+    }
+
+    for (name, value) in globals? {
+        state.set_global(vm, &name, value);
+    }
+    Ok(())
+}
+
+/// Values the program makes again when it runs, so they're left out of snapshots
+fn is_code(value: &Value, state: &VMState) -> bool {
+    matches!(value.type_name(state), "function" | "class")
+}
+
+/// What a value that can be shared is told apart by, which is the address of its Rc or its index on the heap
+fn identity(value: &Value) -> Option<(u8, usize)> {
+    match value {
+        Value::LoxArray(x) => Some((TAG_ARRAY, Rc::as_ptr(x) as *const () as usize)),
+        Value::LoxMap(x) => Some((TAG_MAP, Rc::as_ptr(x) as *const () as usize)),
+        Value::LoxSet(x) => Some((TAG_SET, Rc::as_ptr(x) as *const () as usize)),
+        Value::LoxBytes(x) => Some((TAG_BYTES, Rc::as_ptr(x) as *const () as usize)),
+        Value::LoxPointer(x) => Some((TAG_INSTANCE, *x)),
+        _ => None,
+    }
+}
+
+struct Snapshot<'a> {
+    writer: Writer,
+    vm: &'a VM,
+    state: &'a VMState,
+    ids: HashMap<(u8, usize), usize>, // The number each shared value was written as
+}
+
+impl Snapshot<'_> {
+    fn value(&mut self, value: &Value, depth: usize) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err(String::from("it's nested too deeply"));
+        }
+        if let Some(identity) = identity(value) {
+            if let Some(id) = self.ids.get(&identity) {
+                self.writer.byte(TAG_REFERENCE);
+                self.writer.usize(*id);
+                return Ok(());
+            }
+            self.ids.insert(identity, self.ids.len());
+        }
+
+        match value {
+            Value::Nil => self.writer.byte(TAG_NIL),
+            Value::Bool(x) => {
+                self.writer.byte(TAG_BOOL);
+                self.writer.bool(*x);
+            }
+            Value::Double(x) => {
+                self.writer.byte(TAG_NUMBER);
+                self.writer.f64(*x);
+            }
+            Value::Int(x) => {
+                self.writer.byte(TAG_INT);
+                self.writer.i64(*x);
+            }
+            Value::LoxString(x) => {
+                self.writer.byte(TAG_STRING);
+                self.writer.string(x);
+            }
+            Value::LoxArray(values) => {
+                self.writer.byte(TAG_ARRAY);
+                let values = values.borrow();
+                self.writer.usize(values.len());
+                for value in values.iter() {
+                    self.value(value, depth + 1)?;
+                }
+            }
+            Value::LoxMap(map) => {
+                self.writer.byte(TAG_MAP);
+                let map = map.borrow();
+                self.writer.usize(map.len());
+                for (key, value) in map.iter() {
+                    self.key(key);
+                    self.value(value, depth + 1)?;
+                }
+            }
+            // Tuples can't be changed, so they're written out in full every time rather than shared
+            Value::LoxTuple(values) => {
+                self.writer.byte(TAG_TUPLE);
+                self.writer.usize(values.len());
+                for value in values.iter() {
+                    self.value(value, depth + 1)?;
+                }
+            }
+            Value::LoxSet(set) => {
+                self.writer.byte(TAG_SET);
+                let set = set.borrow();
+                self.writer.usize(set.len());
+                for member in set.iter() {
+                    self.key(member);
+                }
+            }
+            Value::LoxBytes(bytes) => {
+                self.writer.byte(TAG_BYTES);
+                let bytes = bytes.borrow();
+                self.writer.usize(bytes.len());
+                self.writer.bytes.extend_from_slice(&bytes);
+            }
+            Value::LoxPointer(_) if value.type_name(self.state) == "instance" => {
+                let instance = self.state.instance(value).unwrap();
+                self.writer.byte(TAG_INSTANCE);
+                self.writer.string(&self.vm.classes[instance.class].name);
+
+                // By name, since the indices depend on the order the program mentions them in
+                let mut fields: Vec<(&str, &Value)> = instance
+                    .fields
+                    .iter()
+                    .map(|(index, value)| (self.state.property_name(self.vm, *index), value))
+                    .collect();
+                fields.sort_by(|a, b| a.0.cmp(b.0));
+                self.writer.usize(fields.len());
+                for (name, value) in fields {
+                    self.writer.string(name);
+                    self.value(value, depth + 1)?;
+                }
+            }
+            _ => return Err(format!("it holds a {}", value.type_name(self.state))),
+        }
+        Ok(())
+    }
+
+    fn key(&mut self, key: &MapKey) {
+        match key {
+            MapKey::Number(bits) => {
+                self.writer.byte(TAG_NUMBER);
+                self.writer.f64(f64::from_bits(*bits));
+            }
+            MapKey::Int(x) => {
+                self.writer.byte(TAG_INT);
+                self.writer.i64(*x);
+            }
+            MapKey::String(s) => {
+                self.writer.byte(TAG_STRING);
+                self.writer.string(s);
+            }
+            MapKey::Tuple(keys) => {
+                self.writer.byte(TAG_TUPLE);
+                self.writer.usize(keys.len());
+                for key in keys.iter() {
+                    self.key(key);
+                }
+            }
+        }
+    }
+}
+
+struct Restore<'a> {
+    reader: Reader<'a>,
+    values: Vec<Value>, // The shared values by the number they were written as
+    rooted: usize, // How many instances were pushed onto the stack to keep them alive until the globals are set
+}
+
+impl Restore<'_> {
+    fn globals(&mut self, vm: &VM, state: &mut VMState) -> Result<Vec<(String, Value)>, String> {
+        let mut globals = Vec::new();
+        for _ in 0..self.reader.usize()? {
+            let name = self.reader.string()?;
+            let value = self.value(vm, state, 0)?;
+            globals.push((name, value));
+        }
+        if self.reader.pos != self.reader.bytes.len() {
+            return Err(String::from("Unexpected trailing bytes"));
+        }
+        Ok(globals)
+    }
+
+    fn value(&mut self, vm: &VM, state: &mut VMState, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err(String::from("Value is nested too deeply"));
+        }
+        match self.reader.byte()? {
+            TAG_NIL => Ok(Value::Nil),
+            TAG_BOOL => Ok(Value::Bool(self.reader.bool()?)),
+            TAG_NUMBER => Ok(Value::Double(self.reader.f64()?)),
+            TAG_INT => Ok(Value::Int(self.reader.i64()?)),
+            TAG_STRING => {
+                let s = self.reader.string()?;
+                Ok(state.new_string(&s))
+            }
+            // Shared values are numbered before their contents are read, so that they can contain themselves
+            TAG_ARRAY => {
+                let values = Rc::new(RefCell::new(Vec::new()));
+                self.values.push(Value::LoxArray(values.clone()));
+                for _ in 0..self.reader.usize()? {
+                    let value = self.value(vm, state, depth + 1)?;
+                    values.borrow_mut().push(value);
+                }
+                Ok(Value::LoxArray(values))
+            }
+            TAG_TUPLE => {
+                let mut values = Vec::new();
+                for _ in 0..self.reader.usize()? {
+                    values.push(self.value(vm, state, depth + 1)?);
+                }
+                Ok(Value::LoxTuple(values.into()))
+            }
+            TAG_MAP => {
+                let map = Rc::new(RefCell::new(LoxMap::default()));
+                self.values.push(Value::LoxMap(map.clone()));
+                for _ in 0..self.reader.usize()? {
+                    let key = self.key(state, depth + 1)?;
+                    let value = self.value(vm, state, depth + 1)?;
+                    map.borrow_mut().insert(key, value);
+                }
+                Ok(Value::LoxMap(map))
+            }
+            TAG_SET => {
+                let set = Rc::new(RefCell::new(LoxSet::default()));
+                self.values.push(Value::LoxSet(set.clone()));
+                for _ in 0..self.reader.usize()? {
+                    let member = self.key(state, depth + 1)?;
+                    set.borrow_mut().insert(member);
+                }
+                Ok(Value::LoxSet(set))
+            }
+            TAG_BYTES => {
+                let len = self.reader.usize()?;
+                if self.reader.bytes.len() - self.reader.pos < len {
+                    return Err(String::from("Unexpected end of snapshot"));
+                }
+                let bytes = self.reader.bytes[self.reader.pos..self.reader.pos + len].to_vec();
+                self.reader.pos += len;
+                let bytes = Value::LoxBytes(Rc::new(RefCell::new(bytes)));
+                self.values.push(bytes.clone());
+                Ok(bytes)
+            }
+            TAG_INSTANCE => {
+                let class_name = self.reader.string()?;
+                let class = match vm.classes.iter().position(|class| class.name == class_name) {
+                    Some(class) => class,
+                    None => return Err(format!("The program has no class '{}'", class_name)),
+                };
+                let instance = state.new_instance(class);
+                state.push_root(instance.clone());
+                self.rooted += 1;
+                self.values.push(instance.clone());
+
+                for _ in 0..self.reader.usize()? {
+                    let name = self.reader.string()?;
+                    let value = self.value(vm, state, depth + 1)?;
+                    let index = state.property_index(vm, &name);
+                    state
+                        .instance_mut(&instance)
+                        .unwrap()
+                        .fields
+                        .insert(index, value);
+                }
+                Ok(instance)
+            }
+            TAG_REFERENCE => {
+                let id = self.reader.usize()?;
+                match self.values.get(id) {
+                    Some(value) => Ok(value.clone()),
+                    None => Err(format!("Invalid reference {}", id)),
+                }
+            }
+            x => Err(format!("Invalid value tag {}", x)),
+        }
+    }
+
+    fn key(&mut self, state: &mut VMState, depth: usize) -> Result<MapKey, String> {
+        if depth > MAX_DEPTH {
+            return Err(String::from("Value is nested too deeply"));
+        }
+        match self.reader.byte()? {
+            TAG_NUMBER => Ok(MapKey::Number(self.reader.f64()?.to_bits())),
+            TAG_INT => Ok(MapKey::Int(self.reader.i64()?)),
+            TAG_STRING => {
+                let s = self.reader.string()?;
+                Ok(MapKey::String(state.intern(&s)))
+            }
+            TAG_TUPLE => {
+                let mut keys = Vec::new();
+                for _ in 0..self.reader.usize()? {
+                    keys.push(self.key(state, depth + 1)?);
+                }
+                Ok(MapKey::Tuple(keys.into()))
+            }
+            x => Err(format!("Invalid key tag {}", x)),
+        }
+    }
+}
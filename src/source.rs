@@ -0,0 +1,84 @@
+//! Where the compiler gets the modules named by `use` from. The CLI reads them from disk, embedders can hand the compiler their own
+use std::collections::HashMap;
+#[cfg(feature = "fs")]
+use std::fs;
+use std::rc::Rc;
+
+/// A module as a SourceProvider hands it to the compiler
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModuleSource {
+    Source(String),    // Lox source, compiled along with the importer
+    Compiled(Vec<u8>), // A program compiled with `rlox compile`, which then skips the compiler
+}
+
+/// Looks up the modules imported with `use "path";`. path is exactly what the script wrote, without an extension
+///
+/// Returns a message describing the problem if there's no such module, which becomes a compile error at the `use`
+pub trait SourceProvider {
+    fn load(&self, path: &str) -> Result<ModuleSource, String>;
+}
+
+/// Modules kept in memory, keyed by the path scripts import them with
+impl SourceProvider for HashMap<String, String> {
+    fn load(&self, path: &str) -> Result<ModuleSource, String> {
+        match self.get(path) {
+            Some(source) => Ok(ModuleSource::Source(source.clone())),
+            None => Err(format!("No module {}", path)),
+        }
+    }
+}
+
+/// Reads path.lox relative to the working directory, preferring a precompiled path.loxb as long as it isn't older than the source
+#[cfg(feature = "fs")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileSources;
+
+#[cfg(feature = "fs")]
+impl SourceProvider for FileSources {
+    fn load(&self, path: &str) -> Result<ModuleSource, String> {
+        let source_path = format!("{}.lox", path);
+        let compiled_path = format!("{}.loxb", path);
+
+        let modified = |p: &str| fs::metadata(p).and_then(|m| m.modified()).ok();
+        let use_compiled = match (modified(&compiled_path), modified(&source_path)) {
+            (Some(compiled), Some(source)) => compiled >= source,
+            (Some(_), None) => true,
+            _ => false,
+        };
+
+        if use_compiled {
+            fs::read(&compiled_path)
+                .map(ModuleSource::Compiled)
+                .map_err(|why| format!("Failed to load module {}: {}", compiled_path, why))
+        } else {
+            fs::read_to_string(&source_path)
+                .map(ModuleSource::Source)
+                .map_err(|why| format!("Failed to open module {}: {}", source_path, why))
+        }
+    }
+}
+
+/// Without the fs feature, ie in the browser, there's nowhere to load modules from unless the embedder provides them
+#[cfg(not(feature = "fs"))]
+struct NoSources;
+
+#[cfg(not(feature = "fs"))]
+impl SourceProvider for NoSources {
+    fn load(&self, path: &str) -> Result<ModuleSource, String> {
+        Err(format!(
+            "Can't import {}, this build of rlox has no filesystem access",
+            path
+        ))
+    }
+}
+
+/// What the compiler uses when it isn't given a SourceProvider
+#[cfg(feature = "fs")]
+pub(crate) fn default_sources() -> Rc<dyn SourceProvider> {
+    Rc::new(FileSources)
+}
+
+#[cfg(not(feature = "fs"))]
+pub(crate) fn default_sources() -> Rc<dyn SourceProvider> {
+    Rc::new(NoSources)
+}
@@ -0,0 +1,57 @@
+//! What the compiler saw declared and referenced in a script, for editors. It's only collected when asked for, see Compiler::symbols
+use crate::scanner::Token;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Class,
+    Method,
+    Function,
+    Variable,
+}
+
+/// A class, method, or a function or variable declared at the top level of the script
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub kind: SymbolKind,
+    pub name: Token,
+    pub container: Option<String>, // The class of a method
+}
+
+/// What a name in the source was resolved to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Local(Token), // The name the local or upvalue was declared with
+    Global(String),
+    Property(String), // After a '.', which names a method if any class has one by that name
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Symbols {
+    pub symbols: Vec<Symbol>, // In the order they appear in the source
+    pub references: Vec<(Token, Target)>,
+}
+
+impl Symbols {
+    /// The declaration of the name at the byte offset, which is the name itself if it's a declaration. None for names the script doesn't declare,
+    /// ie natives and the globals of imported modules
+    pub fn definition(&self, offset: usize) -> Option<&Token> {
+        let contains = |token: &Token| token.span.start <= offset && offset <= token.span.end;
+        if let Some(symbol) = self.symbols.iter().find(|symbol| contains(&symbol.name)) {
+            return Some(&symbol.name);
+        }
+        let (_, target) = self.references.iter().find(|(token, _)| contains(token))?;
+        match target {
+            Target::Local(declaration) => Some(declaration),
+            Target::Global(name) => self
+                .symbols
+                .iter()
+                .find(|symbol| symbol.kind != SymbolKind::Method && &symbol.name.lexemme == name)
+                .map(|symbol| &symbol.name),
+            Target::Property(name) => self
+                .symbols
+                .iter()
+                .find(|symbol| symbol.kind == SymbolKind::Method && &symbol.name.lexemme == name)
+                .map(|symbol| &symbol.name),
+        }
+    }
+}
@@ -1,63 +1,199 @@
-use crate::native::NativeFn;
+use crate::event_loop::AsyncNative;
+use crate::native::Native;
 use crate::vm::{VMState, VM};
 
+use std::any::Any;
+use std::cell::{Ref, RefCell, RefMut};
 use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Double(f64),
+    Int(i64), // Integer literals, and arithmetic on Ints while the result fits. Overflow and division give a Double instead
     Bool(bool),
     Nil,
-    LoxString(String),
+    LoxString(Rc<str>),              // Always interned, see Interner
     LoxFunction(usize), // Index of the function in the functions Vec in VM // Fixme: Is this even reachable? Can this be completely removed and the parameter put in OpClosure?
-    NativeFunction(NativeFn),
+    NativeFunction(&'static Native), // An entry of STD_LIB
     LoxClass(usize),
     LoxPointer(usize),
     LoxBoundMethod(ObjBoundMethod),
-    LoxArray(Vec<Value>),
+    LoxArray(Rc<RefCell<Vec<Value>>>), // Shared, so copying an array around the stack is cheap and every copy sees the changes made by natives like push
+    ForeignFunction(usize), // Index into the foreign_functions Vec in VMState, registered by a native module
+    HostFunction(usize), // Index into the host_natives Vec in VM, registered by the program embedding rlox
+    AsyncNativeFunction(&'static AsyncNative), // An entry of ASYNC_STD_LIB
+    LoxFuture(usize),    // Index into the futures Vec of the EventLoop
+    LoxChannel(usize),   // Index into the channels Vec in VMState
+    LoxMap(Rc<RefCell<LoxMap>>), // Shared the same way as LoxArray
+    LoxSet(Rc<RefCell<LoxSet>>),
+    LoxBytes(Rc<RefCell<Vec<u8>>>), // A mutable byte buffer, shared the same way as LoxArray
+    LoxTuple(Rc<[Value]>), // Immutable, so there's no RefCell and two tuples are equal when their elements are
+    LoxUserData(Rc<UserData>), // An object of the host, which Lox can only pass around. Equal only to itself
+}
+
+/// For the host to build values with, see Vm::set_global
+impl From<f64> for Value {
+    fn from(x: f64) -> Value {
+        Value::Double(x)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(x: i64) -> Value {
+        Value::Int(x)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(x: bool) -> Value {
+        Value::Bool(x)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::LoxString(Rc::from(s))
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::LoxString(Rc::from(s))
+    }
+}
+
+impl From<UserData> for Value {
+    fn from(data: UserData) -> Value {
+        Value::LoxUserData(Rc::new(data))
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(values: Vec<Value>) -> Value {
+        Value::LoxArray(Rc::new(RefCell::new(values)))
+    }
 }
 
 impl Value {
     /// Used for print statements, use {:?} debug formatting for trace and stack examining
     pub fn to_string(&self, vm: &VM, state: &VMState) -> String {
         match self {
+            Value::Double(x) if x.is_infinite() => match x.is_sign_positive() {
+                true => String::from("Infinity"), // The name of the global, rather than Rust's inf
+                false => String::from("-Infinity"),
+            },
             Value::Double(x) => format!("{}", x),
+            Value::Int(x) => format!("{}", x),
             Value::Bool(x) => format!("{}", x),
             Value::LoxString(x) => format!("{}", x),
             Value::Nil => String::from("nil"),
-            Value::LoxFunction(x) => format!(
-                "<fn {}>",
-                match &vm.functions.get(*x).unwrap().name{
-                    Some(n) => n.clone(),
-                    None => "None".to_string()
-                }
-            ),
-            Value::NativeFunction(_x) => format!("<native_fn>"),
-            Value::LoxClass(class) => format!("<class {}>", class),
+            Value::LoxFunction(x) => describe_function(vm, *x),
+            Value::NativeFunction(native) => format!("<native fn {}>", native.name),
+            Value::ForeignFunction(index) => format!("<native fn {}>", state.foreign_name(*index)),
+            Value::HostFunction(index) => format!("<native fn {}>", vm.host_natives[*index].name),
+            Value::AsyncNativeFunction(native) => format!("<native fn {}>", native.name),
+            Value::LoxFuture(_) => String::from("<future>"),
+            Value::LoxChannel(_) => String::from("<channel>"),
+            Value::LoxClass(class) => format!("<class {}>", vm.classes[*class].name),
+            Value::LoxPointer(pointer)
+                if state.deref(*pointer).obj_type == HeapObjType::LoxClosure =>
+            {
+                state.deref(*pointer).to_string(vm)
+            }
             Value::LoxPointer(pointer) => format!(
                 "<pointer {}> to {}",
                 pointer,
                 state.deref(*pointer).to_string(vm)
             ), // Suggestion: Don't reveal to the user the internals?
-            Value::LoxBoundMethod(method) => format!(
-                "<method {} from {}",
-                vm.functions
-                    .get(method.method)
-                    .unwrap()
-                    .name
-                    .as_ref()
-                    .unwrap(),
-                state.deref(method.pointer).to_string(vm)
-            ),
+            Value::LoxBoundMethod(method) => {
+                let function = &vm.functions[method.method];
+                let class = match &state.deref(method.pointer).obj {
+                    HeapObjVal::LoxInstance(instance) => vm.classes[instance.class].name.as_str(),
+                    _ => "?",
+                };
+                format!(
+                    "<method {}.{}/{}>",
+                    class,
+                    function.name.as_deref().unwrap_or(""),
+                    function.arity
+                )
+            }
             Value::LoxArray(_) => "<array>".to_string(),
+            Value::LoxMap(_) => "<map>".to_string(),
+            Value::LoxSet(_) => "<set>".to_string(),
+            Value::LoxBytes(_) => "<bytes>".to_string(),
+            Value::LoxUserData(data) => match data.tag {
+                Some(tag) => format!("<userdata {}>", tag),
+                None => String::from("<userdata>"),
+            },
+            Value::LoxTuple(values) => {
+                let elements: Vec<String> = values
+                    .iter()
+                    .map(|value| match value {
+                        Value::LoxString(s) => format!("\"{}\"", s),
+                        value => value.to_string(vm, state),
+                    })
+                    .collect();
+                match elements.len() {
+                    1 => format!("({},)", elements[0]),
+                    _ => format!("({})", elements.join(", ")),
+                }
+            }
+        }
+    }
+
+    /// The name type() returns for the value
+    pub fn type_name(&self, state: &VMState) -> &'static str {
+        match self {
+            Value::Double(_) | Value::Int(_) => "number",
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::LoxString(_) => "string",
+            Value::LoxFunction(_)
+            | Value::NativeFunction(_)
+            | Value::ForeignFunction(_)
+            | Value::HostFunction(_)
+            | Value::AsyncNativeFunction(_)
+            | Value::LoxBoundMethod(_) => "function",
+            Value::LoxClass(_) => "class",
+            Value::LoxPointer(pointer) => match state.deref(*pointer).obj_type {
+                HeapObjType::LoxInstance => "instance",
+                HeapObjType::LoxClosure => "function",
+                HeapObjType::HeapPlaceholder => {
+                    panic!("VM panic! Found a reference to a placeholder on the heap")
+                }
+            },
+            Value::LoxArray(_) => "array",
+            Value::LoxFuture(_) => "future",
+            Value::LoxChannel(_) => "channel",
+            Value::LoxMap(_) => "map",
+            Value::LoxSet(_) => "set",
+            Value::LoxBytes(_) => "bytes",
+            Value::LoxTuple(_) => "tuple",
+            Value::LoxUserData(data) => data.tag.unwrap_or("userdata"),
+        }
+    }
+
+    /// Roughly how many bytes the array, map, set, bytes or tuple owns, not counting what its elements own. 0 for everything else, see VmConfig::max_memory
+    pub(crate) fn container_size(&self) -> usize {
+        match self {
+            Value::LoxArray(values) => values.borrow().len() * size_of::<Value>(),
+            Value::LoxMap(map) => map.borrow().len() * MAP_ENTRY_SIZE,
+            Value::LoxSet(set) => set.borrow().len() * MAP_ENTRY_SIZE,
+            Value::LoxBytes(bytes) => bytes.borrow().len(),
+            Value::LoxTuple(values) => values.len() * size_of::<Value>(),
+            _ => 0,
         }
     }
 
+    /// Either kind of number as a double
     pub fn as_num(&self) -> Option<f64> {
-        if let Value::Double(val) = self {
-            Some(val.clone())
-        } else {
-            None
+        match self {
+            Value::Double(val) => Some(*val),
+            Value::Int(val) => Some(*val as f64),
+            _ => None,
         }
     }
 
@@ -72,6 +208,27 @@ impl Value {
             )
         }
     }
+
+    /// Hard cast to the index of a class. Panics if this value is not a LoxClass
+    pub fn as_class(&self) -> usize {
+        if let Value::LoxClass(class) = self {
+            *class
+        } else {
+            panic!(
+                "VM panic! Failed to cast value to a class. Found {:?} instead",
+                self
+            )
+        }
+    }
+}
+
+/// How print shows a function, by its name and arity like <fn add/2>
+fn describe_function(vm: &VM, index: usize) -> String {
+    let function = &vm.functions[index];
+    match &function.name {
+        Some(name) => format!("<fn {}/{}>", name, function.arity),
+        None => String::from("<script>"),
+    }
 }
 
 pub fn is_falsey(val: &Value) -> bool {
@@ -81,24 +238,242 @@ pub fn is_falsey(val: &Value) -> bool {
 pub fn values_equal(t: (&Value, &Value)) -> bool {
     match t {
         (Value::Double(x), Value::Double(y)) => x == y,
+        (Value::Int(x), Value::Int(y)) => x == y,
+        (Value::Int(x), Value::Double(y)) | (Value::Double(y), Value::Int(x)) => {
+            (*x as f64) == *y && y.fract() == 0.0 && *y as i64 == *x // Not just as f64, which is rounded past 2^53
+        }
         (Value::Bool(x), Value::Bool(y)) => x == y,
         (Value::Nil, Value::Nil) => true,
-        (Value::LoxString(x), Value::LoxString(y)) => x.eq(y),
+        (Value::LoxString(x), Value::LoxString(y)) => Rc::ptr_eq(x, y), // Both sides are interned, so equal strings share an allocation
         (Value::LoxPointer(x), Value::LoxPointer(y)) => x == y,
         (Value::LoxClass(x), Value::LoxClass(y)) => x == y,
         (Value::LoxFunction(x), Value::LoxFunction(y)) => x == y,
-        (Value::NativeFunction(x), Value::NativeFunction(y)) => x == y,
+        (Value::NativeFunction(x), Value::NativeFunction(y)) => std::ptr::eq(*x, *y),
         (Value::LoxBoundMethod(x), Value::LoxBoundMethod(y)) => x == y,
+        (Value::ForeignFunction(x), Value::ForeignFunction(y)) => x == y,
+        (Value::HostFunction(x), Value::HostFunction(y)) => x == y,
+        (Value::AsyncNativeFunction(x), Value::AsyncNativeFunction(y)) => std::ptr::eq(*x, *y),
+        (Value::LoxFuture(x), Value::LoxFuture(y)) => x == y,
+        (Value::LoxChannel(x), Value::LoxChannel(y)) => x == y,
+        (Value::LoxArray(x), Value::LoxArray(y)) => Rc::ptr_eq(x, y), // Same as instances, two arrays are only equal if they're the same array
+        (Value::LoxMap(x), Value::LoxMap(y)) => Rc::ptr_eq(x, y),
+        (Value::LoxSet(x), Value::LoxSet(y)) => Rc::ptr_eq(x, y),
+        (Value::LoxBytes(x), Value::LoxBytes(y)) => Rc::ptr_eq(x, y),
+        (Value::LoxUserData(x), Value::LoxUserData(y)) => Rc::ptr_eq(x, y),
+        (Value::LoxTuple(x), Value::LoxTuple(y)) => {
+            x.len() == y.len() && x.iter().zip(y.iter()).all(values_equal)
+        }
         _ => false,
     }
 }
 
+/// A handle to a Rust object that host functions give to scripts and get back in later calls, ie a database connection or an entity
+///
+/// The object is dropped along with the last copy of the value, which for a value stored in an instance is when the GC frees the instance.
+/// The finalizer, if there is one, runs just before that
+pub struct UserData {
+    pub tag: Option<&'static str>, // What type() returns and print shows for it, "userdata" when there's none
+    object: RefCell<Box<dyn Any>>,
+    finalizer: Option<Finalizer>,
+}
+
+type Finalizer = Box<dyn FnOnce(&mut dyn Any)>;
+
+impl UserData {
+    pub fn new(object: impl Any) -> UserData {
+        UserData {
+            tag: None,
+            object: RefCell::new(Box::new(object)),
+            finalizer: None,
+        }
+    }
+
+    pub fn with_tag(mut self, tag: &'static str) -> UserData {
+        self.tag = Some(tag);
+        self
+    }
+
+    /// Runs finalizer on the object once the value is gone. It has to take the same type the UserData was made with, or it never runs
+    pub fn with_finalizer<T: Any>(mut self, finalizer: impl FnOnce(&mut T) + 'static) -> UserData {
+        self.finalizer = Some(Box::new(move |object: &mut dyn Any| {
+            if let Some(object) = object.downcast_mut::<T>() {
+                finalizer(object)
+            }
+        }));
+        self
+    }
+
+    /// The object, if it's a T. Panics if it's already borrowed mutably, ie by a host function further up the stack
+    pub fn get<T: Any>(&self) -> Option<Ref<'_, T>> {
+        Ref::filter_map(self.object.borrow(), |object| object.downcast_ref::<T>()).ok()
+    }
+
+    pub fn get_mut<T: Any>(&self) -> Option<RefMut<'_, T>> {
+        RefMut::filter_map(self.object.borrow_mut(), |object| {
+            object.downcast_mut::<T>()
+        })
+        .ok()
+    }
+}
+
+impl Drop for UserData {
+    fn drop(&mut self) {
+        if let Some(finalizer) = self.finalizer.take() {
+            finalizer(self.object.get_mut().as_mut());
+        }
+    }
+}
+
+impl fmt::Debug for UserData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UserData({})", self.tag.unwrap_or("untagged"))
+    }
+}
+
+/// Only for Value's PartialEq, since a UserData is only ever compared by pointer
+impl PartialEq for UserData {
+    fn eq(&self, other: &UserData) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ObjBoundMethod {
     pub method: usize,  // Index into the functions vec for which function to call
     pub pointer: usize, // Pointer to the LoxInstance that this method is bound to
 }
 
+const MAP_ENTRY_SIZE: usize = size_of::<(MapKey, Value)>() + size_of::<(MapKey, usize)>(); // An entry plus its slot
+
+/// The values a LoxMap can be keyed by
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Number(u64), // The bits of the f64, with -0 folded into 0 so keys agree with ==. NaN can't be a key since it isn't == to itself
+    Int(i64), // Whole numbers, whether they were an Int or a Double, so that 1 and 1.0 are the same key the way they're ==
+    String(Rc<str>),
+    Tuple(Rc<[MapKey]>), // A tuple whose elements can all be keys themselves
+}
+
+impl MapKey {
+    pub fn from_value(value: &Value) -> Option<MapKey> {
+        match value {
+            Value::Double(x) if x.is_nan() => None,
+            Value::Double(x) if x.fract() == 0.0 && x.abs() < 9.2e18 => {
+                Some(MapKey::Int(*x as i64))
+            }
+            Value::Double(x) => Some(MapKey::Number((x + 0.0).to_bits())),
+            Value::Int(x) => Some(MapKey::Int(*x)),
+            Value::LoxString(s) => Some(MapKey::String(s.clone())),
+            Value::LoxTuple(values) => values
+                .iter()
+                .map(MapKey::from_value)
+                .collect::<Option<Rc<[MapKey]>>>()
+                .map(MapKey::Tuple),
+            _ => None,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            MapKey::Number(bits) => Value::Double(f64::from_bits(*bits)),
+            MapKey::Int(x) => Value::Int(*x),
+            MapKey::String(s) => Value::LoxString(s.clone()),
+            MapKey::Tuple(keys) => Value::LoxTuple(keys.iter().map(MapKey::to_value).collect()),
+        }
+    }
+}
+
+/// A hash map that remembers the order keys were first inserted in, so that mapKeys and mapValues don't change from run to run
+///
+/// Removing leaves a hole in entries instead of shifting everything down, the holes are compacted once they make up half of it
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LoxMap {
+    slots: HashMap<MapKey, usize>, // Index into entries
+    entries: Vec<Option<(MapKey, Value)>>,
+    removed: usize,
+}
+
+impl LoxMap {
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn get(&self, key: &MapKey) -> Option<&Value> {
+        let slot = self.slots.get(key)?;
+        self.entries[*slot].as_ref().map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, key: &MapKey) -> bool {
+        self.slots.contains_key(key)
+    }
+
+    /// Replaces the value in place if the key is already there, so it keeps its position
+    pub fn insert(&mut self, key: MapKey, value: Value) {
+        match self.slots.get(&key) {
+            Some(slot) => self.entries[*slot] = Some((key, value)),
+            None => {
+                self.slots.insert(key.clone(), self.entries.len());
+                self.entries.push(Some((key, value)));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &MapKey) -> Option<Value> {
+        let slot = self.slots.remove(key)?;
+        let (_, value) = self.entries[slot].take()?;
+        self.removed += 1;
+        if self.removed * 2 > self.entries.len() {
+            self.entries.retain(Option::is_some);
+            for (i, (key, _)) in self.entries.iter().flatten().enumerate() {
+                self.slots.insert(key.clone(), i);
+            }
+            self.removed = 0;
+        }
+        Some(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&MapKey, &Value)> {
+        self.entries
+            .iter()
+            .flatten()
+            .map(|(key, value)| (key, value))
+    }
+}
+
+/// A set of the same values a LoxMap can be keyed by, also kept in insertion order
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LoxSet {
+    members: LoxMap, // Every value is nil, only the keys matter
+}
+
+impl LoxSet {
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn contains(&self, member: &MapKey) -> bool {
+        self.members.contains_key(member)
+    }
+
+    /// Returns false if it was already in the set
+    pub fn insert(&mut self, member: MapKey) -> bool {
+        if self.contains(&member) {
+            return false;
+        }
+        self.members.insert(member, Value::Nil);
+        true
+    }
+
+    /// Returns false if it wasn't in the set
+    pub fn remove(&mut self, member: &MapKey) -> bool {
+        self.members.remove(member).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MapKey> {
+        self.members.iter().map(|(member, _)| member)
+    }
+}
+
 // End of stack/implicit copy objects
 
 // Heap Objects
@@ -159,16 +534,7 @@ pub enum HeapObjVal {
 impl HeapObjVal {
     fn to_string(&self, vm: &VM) -> String {
         match self {
-            HeapObjVal::LoxClosure(closure) => format!(
-                "<fn {} | {:?}>",
-                vm.functions
-                    .get(closure.function)
-                    .unwrap()
-                    .name
-                    .as_ref()
-                    .unwrap(),
-                closure
-            ),
+            HeapObjVal::LoxClosure(closure) => describe_function(vm, closure.function),
             HeapObjVal::LoxInstance(instance) => format!(
                 "<instance {}>",
                 vm.classes.get(instance.class).unwrap().name
@@ -213,17 +579,162 @@ impl HeapObjVal {
 }
 
 /// Runtime instantiation of class definitions
-#[derive(Debug, PartialEq)]
+#[derive(PartialEq)]
 pub struct ObjInstance {
-    pub class: usize,                  // Which class was this instance made from?
-    pub fields: HashMap<usize, Value>, // Stores the field values. FunctionChunks are stored in the ClassChunk, which is not ideal since it adds an extra vec lookup before getting to the function
+    pub class: usize,   // Which class was this instance made from?
+    pub fields: Fields, // Stores the field values. FunctionChunks are stored in the ClassChunk, which is not ideal since it adds an extra vec lookup before getting to the function
+    pub frozen: bool,   // Set by freeze(), after which its fields can't be set
+}
+
+/// The fields of an instance, keyed by the index of their name in the identifiers and kept in the order they were first set. The fields a
+/// class declares are set first, so each one is at the same slot in every instance, see OpGetField
+#[derive(Clone, Default)]
+pub struct Fields {
+    entries: Vec<(usize, Value)>,
+    index: Option<HashMap<usize, usize>>, // Name to slot, only built once there are too many entries to search through. None until then, since an empty HashMap still costs a RandomState per instance
+}
+
+const FIELDS_SEARCHED: usize = 8; // The most entries that are searched through instead of indexed
+
+impl Fields {
+    /// The fields of a new instance of a class that declares these, which start out as nil
+    pub fn declared(names: &[usize]) -> Fields {
+        let mut fields = Fields {
+            entries: names.iter().map(|name| (*name, Value::Nil)).collect(), // The compiler only lists each field once
+            index: None,
+        };
+        if fields.entries.len() > FIELDS_SEARCHED {
+            fields.reindex();
+        }
+        fields
+    }
+
+    fn slot(&self, name: &usize) -> Option<usize> {
+        match &self.index {
+            Some(index) => index.get(name).copied(),
+            None => self.entries.iter().position(|(key, _)| key == name),
+        }
+    }
+
+    pub fn get(&self, name: &usize) -> Option<&Value> {
+        self.slot(name).map(|slot| &self.entries[slot].1)
+    }
+
+    pub fn get_mut(&mut self, name: &usize) -> Option<&mut Value> {
+        self.slot(name).map(move |slot| &mut self.entries[slot].1)
+    }
+
+    /// The field in slot, if it's the one with that name
+    pub fn at(&self, slot: usize, name: usize) -> Option<&Value> {
+        match self.entries.get(slot) {
+            Some((key, value)) if *key == name => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn at_mut(&mut self, slot: usize, name: usize) -> Option<&mut Value> {
+        match self.entries.get_mut(slot) {
+            Some((key, value)) if *key == name => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn contains_key(&self, name: &usize) -> bool {
+        self.slot(name).is_some()
+    }
+
+    /// Returns the old value, like HashMap::insert. New fields go at the end
+    pub fn insert(&mut self, name: usize, value: Value) -> Option<Value> {
+        if let Some(slot) = self.slot(&name) {
+            return Some(std::mem::replace(&mut self.entries[slot].1, value));
+        }
+        self.entries.push((name, value));
+        if let Some(index) = &mut self.index {
+            index.insert(name, self.entries.len() - 1);
+        } else if self.entries.len() > FIELDS_SEARCHED {
+            self.reindex();
+        }
+        None
+    }
+
+    /// Moves the fields after it down a slot
+    pub fn remove(&mut self, name: &usize) -> Option<Value> {
+        let slot = self.slot(name)?;
+        let (_, value) = self.entries.remove(slot);
+        self.index = None;
+        if self.entries.len() > FIELDS_SEARCHED {
+            self.reindex();
+        }
+        Some(value)
+    }
+
+    fn reindex(&mut self) {
+        self.index = Some(
+            self.entries
+                .iter()
+                .enumerate()
+                .map(|(slot, (name, _))| (*name, slot))
+                .collect(),
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&usize, &Value)> {
+        self.entries.iter().map(|(name, value)| (name, value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &usize> {
+        self.entries.iter().map(|(name, _)| name)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.entries.iter().map(|(_, value)| value)
+    }
+}
+
+/// Like a HashMap, the order doesn't matter
+impl PartialEq for Fields {
+    fn eq(&self, other: &Fields) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(name, value)| other.get(name) == Some(value))
+    }
+}
+
+impl fmt::Debug for Fields {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// By hand so that frozen only shows up once it's set, since some runtime errors show the instance this way
+impl fmt::Debug for ObjInstance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = f.debug_struct("ObjInstance");
+        debug
+            .field("class", &self.class)
+            .field("fields", &self.fields);
+        if self.frozen {
+            debug.field("frozen", &true);
+        }
+        debug.finish()
+    }
 }
 
 impl ObjInstance {
     pub fn new(class: usize) -> ObjInstance {
         ObjInstance {
             class,
-            fields: HashMap::new(),
+            fields: Fields::default(),
+            frozen: false,
         }
     }
 }
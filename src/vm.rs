@@ -1,16 +1,40 @@
-use crate::chunk::{ClassChunk, FunctionChunk, Instr, ModuleChunk, OpCode};
-use crate::compiler::CompilationResult;
+use crate::chunk::{module_file, ClassChunk, FunctionChunk, Instr, ModuleChunk, OpCode, SourceMap};
+use crate::compiler::{CompilationResult, ErrorFormat};
+use crate::coverage::Coverage;
 use crate::debug::*;
+use crate::debugger::Debugger;
+use crate::event_loop::{
+    AsyncNative, AsyncOp, Channel, Completion, EventLoop, FutureState, ASYNC_STD_LIB,
+};
 use crate::gc::GC;
+use crate::interner::Interner;
 use crate::native::*;
+use crate::plugin::{NativeLibrary, RloxForeignFn, RloxValue};
+use crate::profiler::Profiler;
 use crate::resolver::UpValue;
 use crate::value::{
-    is_falsey, values_equal, HeapObj, HeapObjType, HeapObjVal, ObjBoundMethod, ObjClosure,
-    ObjInstance, Value,
+    is_falsey, values_equal, Fields, HeapObj, HeapObjType, HeapObjVal, LoxMap, LoxSet, MapKey,
+    ObjBoundMethod, ObjClosure, ObjInstance, Value,
 };
-use crate::InterpretResult;
-
-const FRAMES_MAX: usize = 64;
+use crate::{InterpretResult, StepResult};
+
+use regex::Regex;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_FRAMES: usize = 1024;
+const FIBER_SLICE: usize = 64; // How many loop back-edges and calls a task gets before it has to let the next ready task run
+const TIME_CHECK_INTERVAL: u64 = 1024; // Only look at the clock every this many instructions since Instant::now() isn't free
+const BACKTRACE_EDGE: usize = 10; // Backtraces longer than twice this only show this many frames from each end
+const MAX_CALLBACK_DEPTH: usize = 256; // Natives calling back into Lox recurse on the Rust stack, which runs out long before a large max_frames would
+const CALLBACK_WAIT_ERROR: &str = "Can't wait on another task inside a function called by a native";
 
 #[derive(Debug)]
 pub enum ExecutionMode {
@@ -18,6 +42,194 @@ pub enum ExecutionMode {
     Trace,
 }
 
+/// Options for running a program that don't change its meaning
+#[derive(Debug, Clone)]
+pub struct VmConfig {
+    pub gc_stress: bool, // Collect garbage on every allocation, useful for shaking out GC bugs
+    pub gc_log: bool,    // Print GC diagnostics to stderr
+    pub trace: bool, // Print every executed instruction along with the stack, like clox's DEBUG_TRACE_EXECUTION
+    pub profile: bool, // Count calls and time spent per function, and print a report once the program ends
+    pub coverage: Option<String>, // Write an lcov report of the executed lines to this path once the program ends
+    pub script_path: Option<String>, // Where the main script came from, used to name it in reports
+    pub warn_undefined_globals: bool, // Not a runtime option, but read by interpret_with_config when it sets up the compiler
+    pub strict: bool,                 // The same, making undefined globals compile errors
+    pub warnings: bool, // The same, for warning about unused locals, unreachable code and shadowed locals
+    pub deny_warnings: bool, // The same, failing the compilation if there are any warnings
+    pub error_format: ErrorFormat, // How the compile errors and warnings are printed
+    pub source_map: bool, // Compile with a source map, so runtime errors and the disassembler show columns as well
+    pub debugger: bool,   // Pause in the interactive debugger before the first instruction
+    pub max_frames: usize, // Call depth at which we report a stack overflow
+    pub max_instructions: Option<u64>, // Stop with InterpretBudgetExceeded after executing this many instructions
+    pub max_time: Option<Duration>, // Stop with InterpretBudgetExceeded after running for this long, including the time spent waiting on timers and io
+    pub max_memory: Option<usize>, // Stop with InterpretBudgetExceeded once the strings, arrays, maps, instances and closures alive take up more bytes than this
+    pub script_args: Vec<String>, // What args() returns, ie everything after `--` on the command line
+    pub entry: Option<String>, // A global function to call with the script args as strings once the top level code has run, see `rlox --entry`
+    pub allow_exec: bool, // Whether exec() can start other programs. Off unless the embedder trusts the scripts it runs
+    pub stdout: Output,   // Where print statements go
+    pub stderr: Output, // Where runtime errors and their backtraces go, and the --profile and --coverage reports. Diagnostics like --trace and --gc-log always go to stderr
+    pub hooks: Hooks,   // Callbacks for tools like profilers and debuggers that live in the host
+}
+
+/// Callbacks run by the dispatch loop. They slow every instruction down a little even when unset, so only the ones a tool needs should be set
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    pub on_call: Option<Hook<str>>, // With the name of the Lox function, before its first instruction. Natives don't count
+    pub on_return: Option<Hook<str>>, // With the name of the function returning, after its frame is gone. Frames unwound by an error don't return
+    pub on_line: Option<Hook<usize>>, // Before the first instruction of a line, whenever execution moves to a different line or function
+}
+
+/// Shared, so that clones of the config all call the same closure
+pub struct Hook<T: ?Sized>(Rc<dyn Fn(&T)>);
+
+impl<T: ?Sized> Hook<T> {
+    pub fn new(function: impl Fn(&T) + 'static) -> Hook<T> {
+        Hook(Rc::new(function))
+    }
+
+    pub(crate) fn call(&self, arg: &T) {
+        (self.0)(arg)
+    }
+}
+
+// Derived Clone would want T: Clone, which str isn't
+impl<T: ?Sized> Clone for Hook<T> {
+    fn clone(&self) -> Hook<T> {
+        Hook(self.0.clone())
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Hook<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hook")
+    }
+}
+
+/// A runtime error along with where it happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: usize, // Where the innermost frame was, 0 if there wasn't one
+    pub backtrace: Vec<BacktraceFrame>, // Innermost first
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BacktraceFrame {
+    pub function: Option<String>, // None for the top level of the script
+    pub line: usize,
+    pub file: Option<String>, // The module the function was written in, None for the script itself
+    pub column: Option<usize>, // Only known when the program was compiled with a source map, see VmConfig::source_map
+}
+
+/// The message followed by the backtrace, the same way the CLI prints it
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.message)?;
+        write_backtrace(f, &self.backtrace)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// What calling a native with the wrong number of arguments reports, the same for STD_LIB, ASYNC_STD_LIB and the host's natives
+fn arity_error(name: &str, arity: Arity, arg_count: usize) -> String {
+    format!(
+        "Expected {} arguments but got {} in call to '{}'",
+        arity, arg_count, name
+    )
+}
+
+/// A [line N] in function line for every frame. Deep recursion would otherwise print every single frame, so only both ends are shown
+fn write_backtrace(f: &mut impl fmt::Write, backtrace: &[BacktraceFrame]) -> fmt::Result {
+    let depth = backtrace.len();
+    for (i, frame) in backtrace.iter().enumerate() {
+        if depth > BACKTRACE_EDGE * 2 && i >= BACKTRACE_EDGE && i < depth - BACKTRACE_EDGE {
+            if i == BACKTRACE_EDGE {
+                writeln!(f, "... {} more frames ...", depth - BACKTRACE_EDGE * 2)?;
+            }
+            continue;
+        }
+        write!(
+            f,
+            "[line {}] in {}",
+            frame.line,
+            frame.function.as_deref().unwrap_or("script")
+        )?;
+        match (&frame.file, frame.column) {
+            (Some(file), Some(column)) => writeln!(f, " ({}, col {})", file, column)?,
+            (Some(file), None) => writeln!(f, " ({})", file)?,
+            (None, Some(column)) => writeln!(f, " (col {})", column)?,
+            (None, None) => writeln!(f)?,
+        }
+    }
+    Ok(())
+}
+
+/// Somewhere the program's output is written to. Clones write to the same place, so a host can keep one to read back what was captured
+#[derive(Clone)]
+pub struct Output(Rc<RefCell<dyn Write>>);
+
+impl Output {
+    pub fn new(writer: impl Write + 'static) -> Output {
+        Output(Rc::new(RefCell::new(writer)))
+    }
+
+    pub fn stdout() -> Output {
+        Output::new(io::stdout())
+    }
+
+    pub fn stderr() -> Output {
+        Output::new(io::stderr())
+    }
+
+    /// An output that collects everything into the returned buffer, ie for testing what a script prints
+    pub fn buffer() -> (Output, Rc<RefCell<Vec<u8>>>) {
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        (Output(buffer.clone()), buffer)
+    }
+
+    /// Failing to write (ie to a closed pipe) doesn't stop the program, the same way it wouldn't for a program that ignores its output
+    pub(crate) fn write_line(&self, line: &str) {
+        let mut writer = self.0.borrow_mut();
+        let _ = writeln!(writer, "{}", line);
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Output")
+    }
+}
+
+impl Default for VmConfig {
+    fn default() -> VmConfig {
+        VmConfig {
+            gc_stress: false,
+            gc_log: false,
+            trace: false,
+            debugger: false,
+            warn_undefined_globals: false,
+            strict: false,
+            warnings: false,
+            deny_warnings: false,
+            error_format: ErrorFormat::Human,
+            source_map: false,
+            profile: false,
+            coverage: None,
+            script_path: None,
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_instructions: None,
+            max_time: None,
+            max_memory: None,
+            script_args: Vec::new(),
+            entry: None,
+            allow_exec: false,
+            stdout: Output::stdout(),
+            stderr: Output::stderr(),
+            hooks: Hooks::default(),
+        }
+    }
+}
+
 /// This ended up not being very useful since we usually don't care what kind of deref error we get, they usually mean the same thing, that we tried to use a value in a way it wasn't supposed to be used
 #[derive(Debug, Clone, Copy)]
 pub enum DerefError {
@@ -32,13 +244,141 @@ struct CallFrame {
     frame_start: usize,
 }
 
+/// A suspended call stack, either waiting in the ready queue or on a future
+struct Task {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    current_frame: CallFrame,
+    future: Option<usize>, // The future this task resolves with its return value. None for the main script
+}
+
+/// What to do with the value of a future that a combinator is watching, once it resolves
+enum Watcher {
+    All(usize, usize), // The future of the all() and where the value goes in its results
+    Race(usize),       // The future of the race(), which the first value resolves
+}
+
+/// The results so far of an all() that's still waiting on some of its futures
+struct Join {
+    results: Vec<Value>,
+    left: usize,
+}
+
+/// Every kind of value that can be called, so the call opcodes, fibers and the callbacks of natives all go through Callable::call instead
+/// of each matching on the callee. A new kind of callable only needs a variant here
+#[derive(Debug, Clone, Copy)]
+enum Callable {
+    Function(usize, Option<usize>), // The function, and the instance a bound method puts in slot 0. Functions and closures stay in slot 0
+    Class(usize),                   // Calling a class constructs an instance of it
+    Native(&'static Native),
+    Foreign(usize),
+    Host(usize),
+    AsyncNative(&'static AsyncNative),
+}
+
+impl Callable {
+    /// What calling value would do, None if it can't be called
+    fn of(value: &Value, state: &VMState) -> Option<Callable> {
+        match value {
+            Value::LoxPointer(_) => match state.deref_into(value, HeapObjType::LoxClosure) {
+                Ok(closure) => Some(Callable::Function(closure.as_closure().function, None)),
+                Err(_) => None,
+            },
+            Value::LoxFunction(function) => Some(Callable::Function(*function, None)),
+            Value::LoxBoundMethod(method) => {
+                Some(Callable::Function(method.method, Some(method.pointer)))
+            }
+            Value::LoxClass(class) => Some(Callable::Class(*class)),
+            Value::NativeFunction(native) => Some(Callable::Native(native)),
+            Value::ForeignFunction(index) => Some(Callable::Foreign(*index)),
+            Value::HostFunction(index) => Some(Callable::Host(*index)),
+            Value::AsyncNativeFunction(native) => Some(Callable::AsyncNative(native)),
+            _ => None,
+        }
+    }
+
+    /// Calls it with the callee and its arguments on top of the stack
+    ///
+    /// Note: This must fufill the promise made in Resolver about what value sits in slot 0 of the local variables.
+    /// Whether that's 'this' or a placeholder
+    ///
+    /// Returns a String containing an error message or None
+    fn call(self, state: &mut VMState, arg_count: usize, vm: &VM) -> Option<String> {
+        let callee_slot = state.stack.len() - arg_count - 1;
+        match self {
+            Callable::Function(function, this) => {
+                if let Some(pointer) = this {
+                    state.stack[callee_slot] = Value::LoxPointer(pointer); // The "this" variable
+                }
+                state.call(function, arg_count, &vm.functions)
+            }
+            Callable::Class(class) => {
+                let class_def = &vm.classes[class];
+                let instance_obj = ObjInstance {
+                    class,
+                    fields: Fields::declared(&class_def.fields),
+                    frozen: false,
+                };
+                let ptr = state.alloc(HeapObj::new_instance(instance_obj));
+                state.stack[callee_slot] = ptr; // Replace the LoxClass with the pointer
+
+                // Call the initializer if it exists
+                // If the LoxClass was called with arguments the stack will look like this: LoxClass | arg1 | arg2
+                // So we want to call with the stack as: LoxPointer => LoxInstance | arg1 | arg2
+                // And we need the init() fn to return the LoxInstance
+                // The arity is checked here rather than by state.call, so that the error names the class instead of init
+                let (init, arity) = if class_def.has_init {
+                    let init_slot = vm.init_slot.expect(
+                        "VM panic! Attempted to call a custom initializer without it existing as a method identifier?",
+                    );
+                    let init = *class_def.methods.get(&init_slot).unwrap();
+                    (Some(init), vm.functions[init].arity)
+                } else {
+                    (None, 0)
+                };
+                if arg_count != arity {
+                    return Some(format!(
+                        "Expected {} arguments but got {} when constructing '{}'",
+                        arity, arg_count, class_def.name
+                    ));
+                }
+                init.and_then(|init| state.call(init, arg_count, &vm.functions))
+            }
+            Callable::Native(native) => state.call_native(native, arg_count, vm),
+            Callable::Foreign(index) => state.call_foreign(index, arg_count),
+            Callable::Host(index) => {
+                let native = &vm.host_natives[index];
+                state.call_native_with(
+                    &native.name,
+                    native.arity,
+                    arg_count,
+                    vm,
+                    |vm, state, args| {
+                        // Unlike STD_LIB, the host builds its strings without the interner, see Vm::set_global
+                        let value = (native.function)(&mut NativeContext::new(vm, state), args)?;
+                        Ok(state.adopt(value))
+                    },
+                )
+            }
+            Callable::AsyncNative(native) => {
+                let result = state.call_async_native(native, arg_count, &vm.functions);
+                // delay, send and recv wait on the event loop themselves
+                if state.timed_out {
+                    let result = vm.time_budget_exceeded(state);
+                    state.unwinding = Some(result);
+                }
+                result
+            }
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Global {
     Init(Value),
     Uninit,
 }
 
-
 // Is it good rust to split these into two very coupled but seperate structs or is there a way to keep them together while not angering the borrow checker?
 //
 // I think this setup worked quite well, but I'm sure there's a better way to do it
@@ -50,11 +390,129 @@ pub struct VMState {
     frames: Vec<CallFrame>,
     globals: Vec<Global>,
     gc: GC,
-    // Not implemented due to it destryoing my code => multiple upvalues pointing to the same original value in a function will NOT affect each other. This is a small enough edge case that I'm willing to just let it go
-    // upvalues: Vec<Value>,
+    max_frames: usize,
+    strings: Interner, // Strings created at runtime have to go through here so they can be compared by pointer
+    foreign_functions: Vec<(String, RloxForeignFn)>, // Functions registered by native modules along with their names, indexed by Value::ForeignFunction
+    native_libraries: Vec<NativeLibrary>, // Kept around so the libraries don't get unloaded while their functions are still reachable
+    rng: Rng,                             // Shared by the random natives, seeded by seedRandom
+    regexes: HashMap<Rc<str>, Regex>, // Compiled patterns by their source, so a regex native in a loop only compiles its pattern once
+    native_args: Vec<Value>, // Reused for the arguments of every native call, see call_native
+    property_indices: HashMap<String, usize>, // Name to index for ObjInstance::fields, filled from VM::identifiers the first time a native needs it
+    extra_properties: Vec<String>, // Names set by setattr that never appear in the program, indexed from the end of VM::identifiers
+    frozen: HashMap<usize, Weak<dyn Any>>, // The arrays, maps, sets and bytes passed to freeze(), by address. The Weak keeps the address from being reused
+    frozen_prune_at: usize, // How many there can be before the ones that have been dropped are forgotten
+    extensions: HashMap<(usize, usize), usize>, // The methods added by extend, keyed by class and name. See VM::find_method
+    statics: HashMap<(usize, usize), Value>, // The static fields of classes, keyed the same way. See VM::find_static
+
+    // The stack, frames and current_frame above belong to the running task, every other task is parked in ready or waiting
+    event_loop: EventLoop,
+    current_future: Option<usize>, // See Task.future
+    ready: VecDeque<Task>,
+    waiting: HashMap<usize, Vec<Task>>, // Keyed by the future each task is awaiting
+    delayed: HashMap<usize, Task>, // Tasks from setTimeout that haven't started yet, keyed by the timer that starts them
+    watchers: HashMap<usize, Vec<Watcher>>, // Keyed by the future each combinator is watching
+    joins: HashMap<usize, Join>,   // Keyed by the future of each unfinished all()
+    channels: Vec<Channel>,        // Indexed by Value::LoxChannel
+    slice_left: usize, // Back-edges and calls left before the running task gets preempted
+
+    // Kept here rather than in VM::resume so that the loop can return in the middle of a program and pick up where it left off, and so that callbacks from natives go through them too
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    debugger: Option<Debugger>,
+    deadline: Option<Instant>, // For VmConfig::max_time, which counts the time between slices and spent waiting on the event loop too. Only read the clock when there's a limit, since wasm32 doesn't have one
+    timed_out: bool, // Set when switch_to_next_task gave up waiting on the event loop because the deadline passed
+    instruction_count: u64, // For VmConfig::max_instructions, counted across every slice
+    memory_used: usize, // For VmConfig::max_memory. Grows with every allocation and is only brought back down by measure_memory
+    track_memory: bool, // Whether natives need to count what they allocate into memory_used, only when there's a limit to check it against
+    callback_depth: usize, // How many natives are currently calling back into Lox, see VM::call_function
+    unwinding: Option<InterpretResult>, // Set when a callback ended the program, so the loops of the natives' callers stop too
+    pub(crate) error: Option<RuntimeError>, // The last runtime error, see VM::runtime_error
+    hooks: Hooks, // A copy of VmConfig::hooks, since calls are made from here without the VM
+    last_line: Option<(usize, usize)>, // The function and line Hooks::on_line was last run with
+
+                  // Not implemented due to it destryoing my code => multiple upvalues pointing to the same original value in a function will NOT affect each other. This is a small enough edge case that I'm willing to just let it go
+                  // upvalues: Vec<Value>,
+}
+
+/// Every value alive outside of the heap and the globals: the running stack, the stacks of suspended tasks, the values held by the event loop and the channels,
+/// and the static fields of classes
+/// A macro instead of a method so that the borrow stays disjoint from the mutable borrow of the GC
+macro_rules! heap_roots {
+    ($state:expr) => {
+        $state
+            .stack
+            .iter()
+            .chain($state.ready.iter().flat_map(|task| task.stack.iter()))
+            .chain(
+                $state
+                    .waiting
+                    .values()
+                    .flatten()
+                    .flat_map(|task| task.stack.iter()),
+            )
+            .chain($state.delayed.values().flat_map(|task| task.stack.iter()))
+            .chain($state.joins.values().flat_map(|join| join.results.iter()))
+            .chain($state.event_loop.resolved_values())
+            .chain($state.channels.iter().flat_map(|channel| channel.values()))
+            .chain($state.statics.values())
+    };
 }
 
 impl VMState {
+    pub(crate) fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    pub(crate) fn globals(&self) -> &[Global] {
+        &self.globals
+    }
+
+    /// The names set by setattr that the program doesn't have, see extra_properties
+    pub(crate) fn extra_properties(&self) -> &[String] {
+        &self.extra_properties
+    }
+
+    /// Drops whatever frames a runtime error left behind, so the next call from the host starts on an empty stack the same as after a program
+    /// that ended normally
+    pub(crate) fn clear_frames(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.unwinding = None;
+        self.current_frame.ip = usize::MAX; // Run off its end, so it's left out of backtraces
+    }
+
+    /// Interns s, which every string created at runtime has to go through
+    pub(crate) fn new_string(&mut self, s: &str) -> Value {
+        Value::LoxString(self.intern(s))
+    }
+
+    pub(crate) fn intern(&mut self, s: &str) -> Rc<str> {
+        self.memory_used += s.len(); // Even if it's already interned, measure_memory sorts it out
+        self.strings.intern(s)
+    }
+
+    pub(crate) fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    /// The compiled pattern, or None if it isn't a valid regex
+    pub(crate) fn regex(&mut self, pattern: &Rc<str>) -> Option<&Regex> {
+        if !self.regexes.contains_key(pattern) {
+            let regex = Regex::new(pattern).ok()?;
+            self.regexes.insert(pattern.clone(), regex);
+        }
+        self.regexes.get(pattern)
+    }
+
+    /// The index of the executing function, its instruction pointer, and where its stack window starts
+    pub(crate) fn current_frame(&self) -> (usize, usize, usize) {
+        (
+            self.current_frame.function,
+            self.current_frame.ip,
+            self.current_frame.frame_start,
+        )
+    }
+
     fn pop(&mut self) -> Value {
         match self.stack.pop() {
             Some(x) => x,
@@ -75,7 +533,400 @@ impl VMState {
     }
 
     fn alloc(&mut self, val: HeapObj) -> Value {
-        self.gc.alloc(val, &self.stack, &self.globals)
+        if self.track_memory {
+            self.memory_used += size_of::<HeapObj>();
+            if let HeapObjVal::LoxClosure(closure) = &val.obj {
+                self.memory_used += closure.values.len() * size_of::<Value>();
+            }
+        }
+        if self.gc.collection_due() {
+            let roots = heap_roots!(self); // Only gathered when they're needed, since it goes through every suspended task
+            self.gc.collect(roots, &self.globals);
+        }
+        self.gc.alloc(val)
+    }
+
+    /// Runs the GC now, for gcCollect(). Returns the number of objects freed
+    pub(crate) fn collect_garbage(&mut self) -> usize {
+        let roots = heap_roots!(self);
+        self.gc.collect(roots, &self.globals)
+    }
+
+    /// Collects garbage and resets memory_used to what is actually still alive
+    fn measure_memory(&mut self) -> usize {
+        self.collect_garbage();
+        let roots = heap_roots!(self);
+        self.memory_used =
+            self.gc.memory_in_use(roots, &self.globals) + self.strings.bytes_in_use();
+        self.memory_used
+    }
+
+    pub(crate) fn gc(&self) -> &GC {
+        &self.gc
+    }
+
+    /// Parks the running task until future resolves. The caller has to switch to another task afterwards
+    fn suspend(&mut self, future: usize) {
+        let task = Task {
+            stack: std::mem::take(&mut self.stack),
+            frames: std::mem::take(&mut self.frames),
+            current_frame: self.current_frame.clone(),
+            future: self.current_future,
+        };
+        self.waiting.entry(future).or_default().push(task);
+    }
+
+    /// Resolves the future and wakes every task awaiting it, with the value pushed as the result of their OpAwait
+    fn resolve(&mut self, future: usize, value: Value) {
+        self.event_loop.futures[future] = FutureState::Resolved(value.clone());
+        if let Some(task) = self.delayed.remove(&future) {
+            self.ready.push_back(task); // It hasn't run yet, so there's no OpAwait to give the value to
+        }
+        for mut task in self.waiting.remove(&future).unwrap_or_default() {
+            task.stack.push(value.clone());
+            self.ready.push_back(task);
+        }
+        for watcher in self.watchers.remove(&future).unwrap_or_default() {
+            match watcher {
+                Watcher::All(all, i) => {
+                    let join = self.joins.get_mut(&all).unwrap();
+                    join.results[i] = value.clone();
+                    join.left -= 1;
+                    if join.left == 0 {
+                        let join = self.joins.remove(&all).unwrap();
+                        self.resolve(all, Value::from(join.results));
+                    }
+                }
+                Watcher::Race(race) => {
+                    if self.event_loop.futures[race] == FutureState::Pending {
+                        self.resolve(race, value.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    /// What the value resolved to if it's a future that already has, or the value itself if it isn't a future. Err with the future if it's
+    /// still pending
+    fn settled(&self, value: Value) -> Result<Value, usize> {
+        match value {
+            Value::LoxFuture(future) => match &self.event_loop.futures[future] {
+                FutureState::Resolved(value) => Ok(value.clone()),
+                FutureState::Pending => Err(future),
+            },
+            value => Ok(value),
+        }
+    }
+
+    /// The future for all(), resolved by an array of the values once every future has resolved
+    fn all(&mut self, futures: Vec<Value>) -> usize {
+        let all = self.event_loop.new_future();
+        let mut join = Join {
+            results: vec![Value::Nil; futures.len()],
+            left: 0,
+        };
+        for (i, future) in futures.into_iter().enumerate() {
+            match self.settled(future) {
+                Ok(value) => join.results[i] = value,
+                Err(future) => {
+                    join.left += 1;
+                    self.watchers
+                        .entry(future)
+                        .or_default()
+                        .push(Watcher::All(all, i));
+                }
+            }
+        }
+        if join.left == 0 {
+            self.resolve(all, Value::from(join.results));
+        } else {
+            self.joins.insert(all, join);
+        }
+        all
+    }
+
+    /// The future for race(), resolved by the first of the futures to resolve. The watchers of the others are left to find it already resolved
+    fn race(&mut self, futures: Vec<Value>) -> usize {
+        let race = self.event_loop.new_future();
+        let mut pending = Vec::new();
+        for future in futures {
+            match self.settled(future) {
+                Ok(value) => {
+                    self.resolve(race, value);
+                    return race;
+                }
+                Err(future) => pending.push(future),
+            }
+        }
+        for future in pending {
+            self.watchers
+                .entry(future)
+                .or_default()
+                .push(Watcher::Race(race));
+        }
+        race
+    }
+
+    /// The future for then(). The fiber that calls callee waits on the future as if it had awaited it, so the value ends up as its argument
+    fn then(
+        &mut self,
+        future: Value,
+        callee: Value,
+        function_defs: &[FunctionChunk],
+    ) -> Result<usize, String> {
+        let mut task = self.fiber(callee, function_defs, "then", 1)?;
+        let result = task.future.unwrap();
+        match self.settled(future) {
+            Ok(value) => {
+                task.stack.push(value);
+                self.ready.push_back(task);
+            }
+            Err(future) => self.waiting.entry(future).or_default().push(task),
+        }
+        Ok(result)
+    }
+
+    /// Makes the next ready task the running one, blocking on the event loop if every task is waiting
+    ///
+    /// Returns false if there is nothing left that could ever run, or if max_time ran out while waiting, in which case timed_out is set
+    fn switch_to_next_task(&mut self) -> bool {
+        loop {
+            if let Some(task) = self.ready.pop_front() {
+                self.stack = task.stack;
+                self.frames = task.frames;
+                self.current_frame = task.current_frame;
+                self.current_future = task.future;
+                return true;
+            }
+
+            if self.event_loop.is_idle() {
+                return false;
+            }
+            if self.out_of_time() {
+                self.timed_out = true;
+                return false;
+            }
+            for (future, completion) in self.event_loop.wait(self.deadline) {
+                let value = match completion {
+                    Completion::Nil => Value::Nil,
+                    Completion::Bool(b) => Value::Bool(b),
+                    Completion::String(s) => Value::LoxString(self.intern(&s)),
+                };
+                self.resolve(future, value);
+            }
+        }
+    }
+
+    /// Moves the callee and its arguments off the stack into a new task and replaces them with the future for its result
+    fn spawn(&mut self, fn_index: usize, arg_count: usize) {
+        let stack = self.stack.split_off(self.stack.len() - arg_count - 1);
+        let future = self.event_loop.new_future();
+        self.ready.push_back(Task {
+            stack,
+            frames: Vec::new(),
+            current_frame: CallFrame {
+                function: fn_index,
+                ip: 0,
+                frame_start: 0,
+            },
+            future: Some(future),
+        });
+        self.stack.push(Value::LoxFuture(future));
+    }
+
+    /// Calls a native that starts an async operation, leaving the future for it on the stack
+    ///
+    /// Channel operations are the exception, they leave their result on the stack directly or block the running task until they can
+    fn call_async_native(
+        &mut self,
+        native: &'static AsyncNative,
+        arg_count: usize,
+        function_defs: &[FunctionChunk],
+    ) -> Option<String> {
+        if !native.arity.accepts(arg_count) {
+            return Some(arity_error(native.name, native.arity, arg_count));
+        }
+        let callee_slot = self.stack.len() - arg_count - 1;
+        let args: Vec<Value> = self.stack.drain(callee_slot + 1..).collect();
+        self.pop(); // Pop off the Value::AsyncNativeFunction
+        let future = match (native.function)(&args) {
+            Ok(AsyncOp::Spawn(callee)) => {
+                match self.spawn_fiber(callee, function_defs, "spawn", None) {
+                    Ok(future) => future,
+                    Err(msg) => return Some(msg),
+                }
+            }
+            Ok(AsyncOp::Timeout(callee, duration)) => {
+                match self.spawn_fiber(callee, function_defs, "setTimeout", Some(duration)) {
+                    Ok(future) => future,
+                    Err(msg) => return Some(msg),
+                }
+            }
+            Ok(AsyncOp::All(futures)) => self.all(futures),
+            Ok(AsyncOp::Race(futures)) => self.race(futures),
+            Ok(AsyncOp::Then(future, callee)) => match self.then(future, callee, function_defs) {
+                Ok(future) => future,
+                Err(msg) => return Some(msg),
+            },
+            Ok(AsyncOp::Delay(duration)) => {
+                let timer = self.event_loop.start(AsyncOp::Timer(duration));
+                return self.block_on(timer);
+            }
+            Ok(AsyncOp::Channel(capacity)) => {
+                self.channels.push(Channel::new(capacity));
+                self.stack.push(Value::LoxChannel(self.channels.len() - 1));
+                return None;
+            }
+            Ok(AsyncOp::Send(channel, value)) => return self.channel_send(channel, value),
+            Ok(AsyncOp::Recv(channel)) => return self.channel_recv(channel),
+            Ok(op) => self.event_loop.start(op),
+            Err(msg) => return Some(msg),
+        };
+        self.stack.push(Value::LoxFuture(future));
+        None
+    }
+
+    /// Parks the running task until future resolves, as if it had awaited it, and switches to the next one
+    fn block_on(&mut self, future: usize) -> Option<String> {
+        if self.callback_depth > 0 {
+            return Some(String::from(CALLBACK_WAIT_ERROR));
+        }
+        self.suspend(future);
+        if self.switch_to_next_task() || self.timed_out {
+            None // Callable::call reports running out of time
+        } else {
+            Some(String::from(
+                "Every task is blocked on a channel, none of them can continue",
+            ))
+        }
+    }
+
+    fn channel_send(&mut self, channel: usize, value: Value) -> Option<String> {
+        let ch = &mut self.channels[channel];
+        if let Some(receiver) = ch.receivers.pop_front() {
+            self.resolve(receiver, value);
+        } else if ch.buffer.len() < ch.capacity {
+            ch.buffer.push_back(value);
+        } else {
+            let future = self.event_loop.new_future();
+            self.channels[channel].senders.push_back((future, value));
+            return self.block_on(future);
+        }
+        self.stack.push(Value::Nil);
+        None
+    }
+
+    fn channel_recv(&mut self, channel: usize) -> Option<String> {
+        let ch = &mut self.channels[channel];
+        if let Some(value) = ch.buffer.pop_front() {
+            // That made room, so the first blocked sender can go
+            if let Some((sender, sent)) = ch.senders.pop_front() {
+                ch.buffer.push_back(sent);
+                self.resolve(sender, Value::Nil);
+            }
+            self.stack.push(value);
+        } else if let Some((sender, sent)) = ch.senders.pop_front() {
+            // Unbuffered, so the value goes straight from the sender to us
+            self.resolve(sender, Value::Nil);
+            self.stack.push(sent);
+        } else {
+            let future = self.event_loop.new_future();
+            self.channels[channel].receivers.push_back(future);
+            return self.block_on(future);
+        }
+        None
+    }
+
+    /// Queues a new task that calls callee with no arguments, returning the future for its result. With a delay the task is only queued once
+    /// that much time has passed
+    fn spawn_fiber(
+        &mut self,
+        callee: Value,
+        function_defs: &[FunctionChunk],
+        native: &str,
+        delay: Option<Duration>,
+    ) -> Result<usize, String> {
+        let task = self.fiber(callee, function_defs, native, 0)?;
+        let future = task.future.unwrap();
+        match delay {
+            Some(duration) => {
+                let timer = self.event_loop.start(AsyncOp::Timer(duration));
+                self.delayed.insert(timer, task);
+            }
+            None => self.ready.push_back(task),
+        }
+        Ok(future)
+    }
+
+    /// A task that calls callee, with a new future for its result. Its arguments still have to be pushed onto its stack
+    fn fiber(
+        &mut self,
+        callee: Value,
+        function_defs: &[FunctionChunk],
+        native: &str,
+        arity: usize,
+    ) -> Result<Task, String> {
+        // Same stack layout as a normal call, with the closure or "this" in slot 0
+        let (function, slot_zero) = match Callable::of(&callee, self) {
+            Some(Callable::Function(function, this)) => {
+                (function, this.map_or(callee, Value::LoxPointer))
+            }
+            _ => return Err(format!("{}() expects a function", native)),
+        };
+        if function_defs[function].arity != arity {
+            let takes = if arity == 0 {
+                "no arguments"
+            } else {
+                "one argument"
+            };
+            return Err(format!(
+                "{}() expects a function that takes {}",
+                native, takes
+            ));
+        }
+
+        Ok(Task {
+            stack: vec![slot_zero],
+            frames: Vec::new(),
+            current_frame: CallFrame {
+                function,
+                ip: 0,
+                frame_start: 0,
+            },
+            future: Some(self.event_loop.new_future()),
+        })
+    }
+
+    /// Counts down the running task's time slice, and once it runs out moves it to the back of the ready queue in favour of the next task
+    ///
+    /// Returns true if a different task is now running
+    /// Whether VmConfig::max_time has run out
+    fn out_of_time(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    fn preempt(&mut self) -> bool {
+        if self.callback_depth > 0 {
+            return false; // The native that called back is still on the Rust stack, so this task has to stay put until the callback returns
+        }
+        self.slice_left -= 1;
+        if self.slice_left > 0 {
+            return false;
+        }
+        self.slice_left = FIBER_SLICE;
+        if self.ready.is_empty() {
+            return false;
+        }
+
+        let task = Task {
+            stack: std::mem::take(&mut self.stack),
+            frames: std::mem::take(&mut self.frames),
+            current_frame: self.current_frame.clone(),
+            future: self.current_future,
+        };
+        self.ready.push_back(task);
+        self.switch_to_next_task()
     }
 
     // Fixme: Figure out how to not copy paste this code for mut and immut
@@ -188,142 +1039,378 @@ impl VMState {
         closure.values[index] = val;
     }
 
-    /// Checks if the targetted Value is callable {LoxPointer to a LoxClosure, NativeFn, LoxClass, LoxBoundMethod}, passes it to call() to continue attempting the call if necessary.
-    ///
-    /// Note: This function or call() must fufill the promise made in Resolver about what value sits in slot 0 of the local variables.
-    /// Whether that's 'this' or a placeholder
+    /// Calls the value sitting below the arguments on the stack, see Callable::call
     ///
     /// Returns a String containing an error message or None
-    fn call_value(
+    fn call_value(&mut self, arg_count: usize, vm: &VM) -> Option<String> {
+        match Callable::of(self.peek_at(arg_count), self) {
+            Some(callable) => callable.call(self, arg_count, vm),
+            None => Some(String::from("Can only call functions and classes")),
+        }
+    }
+
+    /// Attempts to call a function with the values on the stack, with the given # of arguments
+    fn call(
         &mut self,
+        fn_index: usize,
         arg_count: usize,
         function_defs: &Vec<FunctionChunk>,
-        class_defs: &Vec<ClassChunk>,
-        init_slot: &Option<usize>,
     ) -> Option<String> {
-        let callee = self.peek_at(arg_count);
-        if let Value::LoxPointer(_) = callee {
-            match self.deref_into(callee, HeapObjType::LoxClosure) {
-                Ok(closure) => {
-                    let closure = closure.as_closure();
-                    let fn_index = closure.function;
-                    self.call(fn_index, arg_count, function_defs)
-                }
-                Err(_) => Some(String::from("Can only call functions and classes")),
-            }
-        } else if let Value::LoxFunction(fn_index) = callee {
-            let index = *fn_index;
-            self.call(index, arg_count, function_defs)
-        } else if let Value::LoxBoundMethod(method) = callee {
-            let fn_index = method.method;
-            let index = self.stack.len() - arg_count - 1; // Index to put the LoxPointer to represent the "this" variable
-            self.stack[index] = Value::LoxPointer(method.pointer);
-            self.call(fn_index, arg_count, function_defs)
-        } else if let Value::LoxClass(class) = callee {
-            let instance_obj = ObjInstance::new(*class);
-            let class_def = &class_defs[*class];
-            let ptr = self.alloc(HeapObj::new_instance(instance_obj));
-            let index = self.stack.len() - arg_count - 1;
-            self.stack[index] = ptr; // Replace the LoxClass with the pointer
-
-            // Call the initializer if it exists
-            // If the LoxClass was called with arguments the stack will look like this: LoxClass | arg1 | arg2
-            // So we want to call with the stack as: LoxPointer => LoxInstance | arg1 | arg2
-            // And we need the init() fn to return the LoxInstance
-            if class_def.has_init {
-                if init_slot == &None {
-                    panic!("VM panic! Attempted to call a custom initializer without it existing as a method identifier?");
-                }
-                self.call(
-                    *class_def.methods.get(&init_slot.unwrap()).unwrap(),
-                    arg_count,
-                    function_defs,
-                )
-            } else if arg_count != 0 {
-                Some(format!(
-                    "Expected 0 arguments but got {} instead",
-                    arg_count
-                ))
-            } else {
+        let target_fn = function_defs.get(fn_index).unwrap();
+        if arg_count != target_fn.arity {
+            return Some(format!(
+                "Expected {} arguments but got {} in call to '{}'",
+                target_fn.arity,
+                arg_count,
+                target_fn.name.as_deref().unwrap_or("script")
+            ));
+        }
+        if self.frames.len() + 1 >= self.max_frames {
+            return Some(String::from("Stack overflow"));
+        }
+
+        if target_fn.is_async {
+            self.spawn(fn_index, arg_count);
+            return None;
+        }
+
+        let mut frame = CallFrame {
+            function: fn_index,
+            ip: 0,
+            frame_start: self.stack.len() - arg_count - 1,
+        };
+
+        // Swap on the new call frame for the old one
+        std::mem::swap(&mut self.current_frame, &mut frame);
+
+        // Put the old one onto the stack
+        self.frames.push(frame);
+        if let Some(hook) = self.hooks.on_call.as_ref() {
+            hook.call(target_fn.name.as_deref().unwrap_or("script"));
+        }
+        return None;
+    }
+
+    /// Attempts to call a native (rust) function, returning the error message if it was called the wrong way
+    fn call_native(
+        &mut self,
+        native: &'static Native,
+        arg_count: usize,
+        vm: &VM,
+    ) -> Option<String> {
+        self.call_native_with(native.name, native.arity, arg_count, vm, native.function)
+    }
+
+    /// Shared by the natives of STD_LIB and the ones registered by the host
+    fn call_native_with(
+        &mut self,
+        name: &str,
+        arity: Arity,
+        arg_count: usize,
+        vm: &VM,
+        function: impl FnOnce(&VM, &mut VMState, &[Value]) -> NativeResult,
+    ) -> Option<String> {
+        if !arity.accepts(arg_count) {
+            return Some(arity_error(name, arity, arg_count));
+        }
+
+        // The arguments are moved off the stack into a buffer that's kept between calls, so calling a native doesn't allocate.
+        // A native that calls one from a callback finds it taken and gets a fresh one, the outer buffer is put back once the outer call returns.
+        // Since they're off the stack while the native runs, a native that calls back into Lox has to root whatever it still needs, see push_root
+        let callee_slot = self.stack.len() - arg_count - 1;
+        let mut args = std::mem::take(&mut self.native_args);
+        args.extend(self.stack.drain(callee_slot + 1..));
+        self.pop(); // Pop off the Value::NativeFunction
+
+        // Natives build their arrays and maps without going through the VMState, so whatever they made or grew is counted here
+        let arg_sizes: usize = if self.track_memory {
+            args.iter().map(Value::container_size).sum()
+        } else {
+            0
+        };
+        let result = function(vm, self, &args);
+        if self.track_memory {
+            let grown: usize = args.iter().map(Value::container_size).sum();
+            self.memory_used += grown.saturating_sub(arg_sizes);
+            if let Ok(value) = &result {
+                if !args.iter().any(|arg| values_equal((arg, value))) {
+                    self.memory_used += value.container_size();
+                }
+            }
+        }
+        args.clear();
+        self.native_args = args;
+
+        match result {
+            Ok(value) => {
+                self.stack.push(value);
                 None
             }
-        } else if let Value::NativeFunction(native_fn) = callee {
-            let native_fn = native_fn.clone();
-            self.call_native(&native_fn, arg_count);
-            None
-        } else {
-            Some(String::from("Can only call functions and classes"))
+            // A callback that failed has already been reported, whatever the native made of it would only be a second error for the same thing
+            Err(_) if self.unwinding.is_some() => {
+                self.stack.push(Value::Nil);
+                None
+            }
+            Err(error) => Some(error.describe(vm, self)),
+        }
+    }
+
+    /// Keeps value alive across callbacks from a native, until the matching pop_root
+    pub(crate) fn push_root(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    pub(crate) fn pop_root(&mut self) {
+        self.pop();
+    }
+
+    /// The instance value points at, or None if it isn't one
+    pub(crate) fn instance(&self, value: &Value) -> Option<&ObjInstance> {
+        match self.deref_into(value, HeapObjType::LoxInstance) {
+            Ok(instance) => Some(instance.as_instance()),
+            Err(_) => None,
+        }
+    }
+
+    /// Marks an array, map, set, bytes or instance as frozen, so that changing it is a runtime error from then on. Returns false for anything else
+    pub(crate) fn freeze(&mut self, value: &Value) -> bool {
+        let weak: Weak<dyn Any> = match value {
+            Value::LoxArray(x) => Rc::downgrade(x) as Weak<dyn Any>,
+            Value::LoxMap(x) => Rc::downgrade(x) as Weak<dyn Any>,
+            Value::LoxSet(x) => Rc::downgrade(x) as Weak<dyn Any>,
+            Value::LoxBytes(x) => Rc::downgrade(x) as Weak<dyn Any>,
+            _ => match self.instance_mut(value) {
+                Some(instance) => {
+                    instance.frozen = true;
+                    return true;
+                }
+                None => return false,
+            },
+        };
+        if self.frozen.len() >= self.frozen_prune_at {
+            self.frozen.retain(|_, weak| weak.strong_count() > 0);
+            self.frozen_prune_at = (self.frozen.len() * 2).max(64);
+        }
+        self.frozen
+            .insert(weak.as_ptr() as *const () as usize, weak);
+        true
+    }
+
+    pub(crate) fn is_frozen(&self, value: &Value) -> bool {
+        let address = match value {
+            Value::LoxArray(x) => Rc::as_ptr(x) as *const () as usize,
+            Value::LoxMap(x) => Rc::as_ptr(x) as *const () as usize,
+            Value::LoxSet(x) => Rc::as_ptr(x) as *const () as usize,
+            Value::LoxBytes(x) => Rc::as_ptr(x) as *const () as usize,
+            _ => return self.instance(value).is_some_and(|instance| instance.frozen),
+        };
+        self.frozen.contains_key(&address)
+    }
+
+    pub(crate) fn instance_mut(&mut self, value: &Value) -> Option<&mut ObjInstance> {
+        match self.deref_into_mut(value, HeapObjType::LoxInstance) {
+            Ok(instance) => Some(instance.as_instance_mut()),
+            Err(_) => None,
+        }
+    }
+
+    /// The index ObjInstance::fields stores the property under, or None if no instance could have it
+    pub(crate) fn find_property(&mut self, vm: &VM, name: &str) -> Option<usize> {
+        if self.property_indices.is_empty() {
+            for (index, identifier) in vm.identifiers.iter().enumerate() {
+                self.property_indices
+                    .entry(identifier.clone())
+                    .or_insert(index);
+            }
+        }
+        self.property_indices.get(name).copied()
+    }
+
+    /// Same as find_property, but gives names that aren't in the program an index of their own
+    pub(crate) fn property_index(&mut self, vm: &VM, name: &str) -> usize {
+        if let Some(index) = self.find_property(vm, name) {
+            return index;
+        }
+        let index = vm.identifiers.len() + self.extra_properties.len();
+        self.extra_properties.push(name.to_string());
+        self.property_indices.insert(name.to_string(), index);
+        index
+    }
+
+    pub(crate) fn property_name<'a>(&'a self, vm: &'a VM, index: usize) -> &'a str {
+        match vm.identifiers.get(index) {
+            Some(name) => name,
+            None => &self.extra_properties[index - vm.identifiers.len()],
+        }
+    }
+
+    /// The value of the global with that name, or None if it isn't defined
+    pub(crate) fn global(&self, vm: &VM, name: &str) -> Option<Value> {
+        let index = vm.identifiers.iter().position(|x| x == name)?;
+        match &self.globals[index] {
+            Global::Init(value) => Some(value.clone()),
+            Global::Uninit => None,
+        }
+    }
+
+    /// Defines the global if the program uses the name anywhere, otherwise no code could read it anyway
+    pub(crate) fn set_global(&mut self, vm: &VM, name: &str, value: Value) {
+        if let Some(index) = vm.identifiers.iter().position(|x| x == name) {
+            let value = self.adopt(value);
+            self.globals[index] = Global::Init(value);
+        }
+    }
+
+    /// Interns the strings of a value made outside of the program, ie by the host, since string equality relies on them being interned
+    pub(crate) fn adopt(&mut self, value: Value) -> Value {
+        let mut visited = HashSet::new();
+        let mut pending = Vec::new();
+        let value = self.adopt_element(value, &mut pending);
+        while let Some(container) = pending.pop() {
+            match container {
+                Value::LoxArray(arr) if visited.insert(Rc::as_ptr(&arr) as *const () as usize) => {
+                    let elements = arr.borrow().clone();
+                    let adopted = elements
+                        .into_iter()
+                        .map(|element| self.adopt_element(element, &mut pending))
+                        .collect();
+                    *arr.borrow_mut() = adopted;
+                }
+                Value::LoxMap(map) if visited.insert(Rc::as_ptr(&map) as *const () as usize) => {
+                    let entries: Vec<(MapKey, Value)> = map
+                        .borrow()
+                        .iter()
+                        .map(|(key, value)| (key.clone(), value.clone()))
+                        .collect();
+                    let mut adopted = LoxMap::default();
+                    for (key, value) in entries {
+                        let key = self.adopt_key(key);
+                        let value = self.adopt_element(value, &mut pending);
+                        adopted.insert(key, value);
+                    }
+                    *map.borrow_mut() = adopted;
+                }
+                Value::LoxSet(set) if visited.insert(Rc::as_ptr(&set) as *const () as usize) => {
+                    let members: Vec<MapKey> = set.borrow().iter().cloned().collect();
+                    let mut adopted = LoxSet::default();
+                    for member in members {
+                        adopted.insert(self.adopt_key(member));
+                    }
+                    *set.borrow_mut() = adopted;
+                }
+                _ => {}
+            }
         }
+        value
     }
 
-    /// Attempts to call a function with the values on the stack, with the given # of arguments
-    fn call(
-        &mut self,
-        fn_index: usize,
-        arg_count: usize,
-        function_defs: &Vec<FunctionChunk>,
-    ) -> Option<String> {
-        let target_fn = function_defs.get(fn_index).unwrap();
-        if arg_count != target_fn.arity {
-            return Some(format!(
-                "Expected {} arguments but got {} instead",
-                target_fn.arity, arg_count
-            ));
+    /// Interns a string, and the strings in a tuple since it can't be changed in place. Arrays, maps and sets are left in pending for adopt
+    fn adopt_element(&mut self, value: Value, pending: &mut Vec<Value>) -> Value {
+        match value {
+            Value::LoxString(s) => Value::LoxString(self.intern(&s)),
+            Value::LoxTuple(values) => Value::LoxTuple(
+                values
+                    .iter()
+                    .map(|value| self.adopt_element(value.clone(), pending))
+                    .collect(),
+            ),
+            value => {
+                pending.push(value.clone());
+                value
+            }
         }
-        if self.frames.len() == FRAMES_MAX {
-            return Some(String::from("Stack overflow"));
+    }
+
+    fn adopt_key(&mut self, key: MapKey) -> MapKey {
+        match key {
+            MapKey::String(s) => MapKey::String(self.intern(&s)),
+            MapKey::Tuple(keys) => {
+                MapKey::Tuple(keys.iter().map(|key| self.adopt_key(key.clone())).collect())
+            }
+            key => key,
         }
+    }
 
-        let mut frame = CallFrame {
-            function: fn_index,
-            ip: 0,
-            frame_start: self.stack.len() - arg_count - 1,
+    /// Allocates an instance with no fields set. This can run the GC, so a native has to root anything it's holding onto first
+    pub(crate) fn new_instance(&mut self, class: usize) -> Value {
+        self.alloc(HeapObj::new_instance(ObjInstance::new(class)))
+    }
+
+    /// Calls a function registered by a native module, converting the arguments and the result across the RloxValue boundary
+    fn call_foreign(&mut self, index: usize, arg_count: usize) -> Option<String> {
+        let function = self.foreign_functions[index].1;
+        let args_start = self.stack.len() - arg_count;
+
+        let mut args = Vec::with_capacity(arg_count);
+        for value in self.stack[args_start..].iter() {
+            match RloxValue::from_value(value) {
+                Some(arg) => args.push(arg),
+                None => {
+                    return Some(String::from(
+                        "Native module functions only accept nil, bools, numbers and strings",
+                    ))
+                }
+            }
+        }
+
+        let result = function(arg_count, args.as_ptr());
+        let result = match unsafe { result.to_value(|s| self.new_string(s)) } {
+            Ok(result) => result,
+            Err(msg) => return Some(msg),
         };
 
-        // Swap on the new call frame for the old one
-        std::mem::swap(&mut self.current_frame, &mut frame);
+        self.stack.truncate(args_start - 1); // Remove the arguments and the Value::ForeignFunction
+        self.stack.push(result);
+        None
+    }
 
-        // Put the old one onto the stack
-        self.frames.push(frame);
-        return None;
+    /// The name the native module gave the function, for printing it
+    pub(crate) fn foreign_name(&self, index: usize) -> &str {
+        &self.foreign_functions[index].0
     }
 
-    /// Attempts to call a native (rust) function
-    fn call_native(&mut self, native_fn: &NativeFn, arg_count: usize) {
-        let mut args: Vec<Value> = Vec::new();
-        for _ in 0..arg_count {
-            args.push(self.pop());
+    /// Loads the native module at path and binds each of its functions to the `module::function` global, if the program uses it
+    fn load_native_module(&mut self, path: &str, identifiers: &[String]) -> Result<(), String> {
+        let library = NativeLibrary::load(path)?;
+        let module_name = match Path::new(path).file_stem() {
+            Some(stem) => stem.to_string_lossy().to_string(),
+            None => path.to_string(),
+        };
+
+        for (name, function) in library.functions.iter() {
+            self.foreign_functions.push((name.clone(), *function));
+            let global = format!("{}::{}", module_name, name);
+            if let Some(index) = identifiers.iter().position(|x| x == &global) {
+                self.globals[index] =
+                    Global::Init(Value::ForeignFunction(self.foreign_functions.len() - 1));
+            }
         }
-        self.pop(); // Pop off the Value::NativeFunction
-        let result = native_fn(arg_count, args);
-        self.stack.push(result);
+
+        self.native_libraries.push(library);
+        Ok(())
     }
 
     /// Defines all native functions
     ///
     /// Searches for references to native functions and adds them in if they're used in the program
     /// Todo: make the compiler/vm reject using these strings as anything else other than to call global with
-    fn define_std_lib(&mut self, identifiers: &Vec<String>) {
-        if let Some(index) = identifiers.iter().position(|x| x == "clock") {
-            self.globals[index] = Global::Init(Value::NativeFunction(clock));
-        }
-        if let Some(index) = identifiers.iter().position(|x| x == "sin") {
-            self.globals[index] = Global::Init(Value::NativeFunction(sin));
-        }
-        if let Some(index) = identifiers.iter().position(|x| x == "radians") {
-            self.globals[index] = Global::Init(Value::NativeFunction(radians));
-        }
-        if let Some(index) = identifiers.iter().position(|x| x == "__array") {
-            self.globals[index] = Global::Init(Value::NativeFunction(__array));
-        }
-        if let Some(index) = identifiers.iter().position(|x| x == "__array_index_get") {
-            self.globals[index] = Global::Init(Value::NativeFunction(__array_index_get));
+    /// Only looks at the identifiers from start on, since the ones before it were already bound when the program started or by an earlier Vm::eval
+    fn define_std_lib(&mut self, identifiers: &[String], start: usize) {
+        start_clock();
+        for native in STD_LIB.iter() {
+            if let Some(index) = identifiers[start..].iter().position(|x| x == native.name) {
+                self.globals[start + index] = Global::Init(Value::NativeFunction(native));
+            }
         }
-        if let Some(index) = identifiers.iter().position(|x| x == "__array_index_set") {
-            self.globals[index] = Global::Init(Value::NativeFunction(__array_index_set));
+        for (name, value) in STD_CONSTANTS.iter() {
+            if let Some(index) = identifiers[start..].iter().position(|x| x == name) {
+                self.globals[start + index] = Global::Init(Value::Double(*value));
+            }
         }
-        if let Some(index) = identifiers.iter().position(|x| x == "len") {
-            self.globals[index] = Global::Init(Value::NativeFunction(len));
+        for native in ASYNC_STD_LIB.iter() {
+            if let Some(index) = identifiers[start..].iter().position(|x| x == native.name) {
+                self.globals[start + index] = Global::Init(Value::AsyncNativeFunction(native));
+            }
         }
     }
 
@@ -332,7 +1419,7 @@ impl VMState {
     /// - A CallFrame for function #0
     /// - Defined global variables for the native functions
     /// - A Value::LoxFunction for function #0 pushed onto the stack => Satisfies the resolver assumption that the first locals slot is filled with something
-    fn new(identifiers: &Vec<String>) -> VMState {
+    fn new(identifiers: &Vec<String>, strings: Interner, config: &VmConfig) -> VMState {
         let first_fn = CallFrame {
             function: 0,
             ip: 0,
@@ -348,10 +1435,45 @@ impl VMState {
             stack,
             frames: Vec::new(),
             globals: vec![Global::Uninit; identifiers.len()],
-            gc: GC::new(),
+            gc: GC::new(config.gc_stress, config.gc_log),
+            max_frames: config.max_frames,
+            strings,
+            foreign_functions: Vec::new(),
+            native_libraries: Vec::new(),
+            event_loop: EventLoop::new(),
+            current_future: None,
+            ready: VecDeque::new(),
+            waiting: HashMap::new(),
+            delayed: HashMap::new(),
+            watchers: HashMap::new(),
+            joins: HashMap::new(),
+            channels: Vec::new(),
+            rng: Rng::new(),
+            regexes: HashMap::new(),
+            native_args: Vec::new(),
+            property_indices: HashMap::new(),
+            extra_properties: Vec::new(),
+            frozen: HashMap::new(),
+            frozen_prune_at: 64,
+            extensions: HashMap::new(),
+            statics: HashMap::new(),
+            slice_left: FIBER_SLICE,
+            profiler: None,
+            coverage: None,
+            debugger: None,
+            deadline: config.max_time.map(|max| Instant::now() + max),
+            timed_out: false,
+            instruction_count: 0,
+            memory_used: 0,
+            track_memory: config.max_memory.is_some(),
+            callback_depth: 0,
+            unwinding: None,
+            error: None,
+            hooks: config.hooks.clone(),
+            last_line: None,
         };
 
-        state.define_std_lib(identifiers);
+        state.define_std_lib(identifiers, 0);
         return state;
     }
 }
@@ -361,50 +1483,204 @@ impl VMState {
 pub struct VM {
     quiet_mode: bool,
     mode: ExecutionMode,
+    pub(crate) config: VmConfig,
+    strings: Interner, // Taken by the VMState when the program starts running
     pub functions: Vec<FunctionChunk>,
     pub classes: Vec<ClassChunk>,
     pub constants: Vec<Value>,
     pub identifiers: Vec<String>,
     pub modules: Vec<ModuleChunk>,
+    pub module_functions: Vec<(String, Range<usize>)>, // See CompilationResult
+    pub source_map: Option<SourceMap>,
     init_slot: Option<usize>,
+    pub(crate) host_natives: Vec<HostNative>, // Set by Vm::start, indexed by Value::HostFunction
 }
 
 impl VM {
-    pub fn new(mode: ExecutionMode, result: CompilationResult, quiet: bool) -> VM {
+    pub fn new(
+        mode: ExecutionMode,
+        result: CompilationResult,
+        quiet: bool,
+        config: VmConfig,
+    ) -> VM {
         let functions = result.functions;
         let init_slot = result.identifier_constants.iter().position(|x| x == "init");
         VM {
             quiet_mode: quiet,
             mode,
+            config,
             functions,
             classes: result.classes,
             constants: result.constants,
             identifiers: result.identifier_constants,
+            strings: result.strings,
             modules: Vec::new(),
+            module_functions: result.module_functions,
+            source_map: result.source_map,
             init_slot,
+            host_natives: Vec::new(),
         }
     }
 
-    fn runtime_error(&self, msg: &str, state: &VMState) {
-        if self.quiet_mode {
-            return;
+    /// Reports the error, and keeps it in the state for Vm to hand to the host
+    fn runtime_error(&self, msg: &str, state: &mut VMState) {
+        let backtrace = self.backtrace(state);
+        let error = RuntimeError {
+            message: msg.to_string(),
+            line: backtrace.first().map_or(0, |frame| frame.line),
+            backtrace,
+        };
+        if !self.quiet_mode {
+            self.config.stderr.write_line(error.to_string().trim_end());
         }
+        state.error = Some(error);
+    }
+
+    fn time_budget_exceeded(&self, state: &mut VMState) -> InterpretResult {
+        self.runtime_error("Time budget exceeded", state);
+        InterpretResult::InterpretBudgetExceeded
+    }
 
-        eprintln!("{}", msg);
+    /// Every call frame, innermost first
+    fn backtrace(&self, state: &VMState) -> Vec<BacktraceFrame> {
+        let mut backtrace = Vec::new();
         for call_frame in [state.current_frame.clone()]
             .iter()
             .chain(state.frames.iter().rev())
         {
             let function = self.functions.get(call_frame.function).unwrap();
-            eprint!(
-                "[line {}] in ",
-                function.chunk.code.get(call_frame.ip).unwrap().line_num
-            );
-            match &function.name {
-                Some(name) => eprintln!("{}", name),
-                None => eprintln!("script"),
+            // The script has already run off its end when the host calls into a program that finished, see Vm::call_function
+            if call_frame.ip >= function.chunk.code.len() {
+                continue;
+            }
+            // ip is already past the instruction the frame is running, ie the call or the instruction that failed
+            let ip = call_frame.ip.saturating_sub(1);
+            backtrace.push(BacktraceFrame {
+                function: function.name.clone(),
+                line: function.chunk.code[ip].line_num,
+                file: self.file_of(call_frame.function).map(String::from),
+                column: self
+                    .source_map
+                    .as_ref()
+                    .and_then(|source_map| source_map.column(call_frame.function, ip)),
+            });
+        }
+        backtrace
+    }
+
+    /// The module the function was written in, None if it's part of the script itself
+    pub(crate) fn file_of(&self, function: usize) -> Option<&str> {
+        module_file(&self.module_functions, function)
+    }
+
+    /// Prints a [line N] in function line for every call frame, innermost first
+    pub(crate) fn print_backtrace(&self, state: &VMState) {
+        let mut out = String::new();
+        write_backtrace(&mut out, &self.backtrace(state)).unwrap();
+        self.config.stderr.write_line(out.trim_end());
+    }
+
+    /// The method the class has by that name. A method added by extend wins over the class's own, and a method added to a superclass only
+    /// reaches the subclasses that didn't override it, which are told apart by having a different method than their superclass
+    pub(crate) fn find_method(&self, state: &VMState, class: usize, name: usize) -> Option<usize> {
+        if state.extensions.is_empty() {
+            return self.classes[class].methods.get(&name).copied();
+        }
+        let mut next = Some(class);
+        while let Some(class) = next {
+            if let Some(method) = state.extensions.get(&(class, name)) {
+                return Some(*method);
+            }
+            let class_chunk = &self.classes[class];
+            let method = class_chunk.methods.get(&name);
+            let inherited = class_chunk
+                .superclass
+                .and_then(|superclass| self.classes[superclass].methods.get(&name));
+            if method.is_some() && method != inherited {
+                return method.copied();
+            }
+            next = class_chunk.superclass;
+        }
+        None
+    }
+
+    /// The static field of the class, or of the nearest superclass that has one by that name. Setting one always sets it on the class itself
+    pub(crate) fn find_static<'a>(
+        &self,
+        state: &'a VMState,
+        class: usize,
+        name: usize,
+    ) -> Option<&'a Value> {
+        let mut next = Some(class);
+        while let Some(class) = next {
+            if let Some(value) = state.statics.get(&(class, name)) {
+                return Some(value);
+            }
+            next = self.classes[class].superclass;
+        }
+        None
+    }
+
+    /// Properties whose names start with '_' are private. On an instance they can only be used by the methods of its class and of its
+    /// superclasses, and the functions nested in those. The same goes for the static fields of a class
+    fn check_access(
+        &self,
+        state: &VMState,
+        receiver: &Value,
+        name_index: usize,
+    ) -> Result<(), String> {
+        let name = self.get_variable_name(name_index);
+        if !name.starts_with('_') {
+            return Ok(());
+        }
+        self.check_private(state, receiver, name)
+    }
+
+    /// The check_access for a property that's known to be private, which natives can call with names that aren't in VM::identifiers
+    pub(crate) fn check_private(
+        &self,
+        state: &VMState,
+        receiver: &Value,
+        name: &str,
+    ) -> Result<(), String> {
+        let class = match receiver {
+            Value::LoxClass(class) => *class,
+            receiver => match state.instance(receiver) {
+                Some(instance) => instance.class,
+                None => return Ok(()), // Not something with properties, which is reported as usual
+            },
+        };
+        let accessor = self.functions[state.current_frame.function].class;
+        let mut next = Some(class);
+        while let Some(class) = next {
+            if Some(class) == accessor {
+                return Ok(());
             }
+            next = self.classes[class].superclass;
+        }
+        Err(format!(
+            "Can't access private property '{}' from outside of class {}",
+            name, self.classes[class].name
+        ))
+    }
+
+    /// The names of every method find_method finds for the class, sorted by index
+    pub(crate) fn method_names(&self, state: &VMState, class: usize) -> Vec<usize> {
+        let mut names: Vec<usize> = self.classes[class].methods.keys().copied().collect(); // Which already has the inherited ones
+        let mut next = Some(class);
+        while let Some(class) = next {
+            names.extend(
+                state
+                    .extensions
+                    .keys()
+                    .filter(|(extended, _)| *extended == class)
+                    .map(|(_, name)| *name),
+            );
+            next = self.classes[class].superclass;
         }
+        names.sort_unstable();
+        names.dedup();
+        names
     }
 
     /// Should only be used for getting debugging and error reporting
@@ -412,7 +1688,7 @@ impl VM {
     /// * For the global instructions, just the index should suffice
     /// * For instance properties and fields, the hashmaps are keyed on the usize corresponding to the identifier string
     /// * Local variable names are erased completely by the resolver at compile time
-    fn get_variable_name(&self, index: usize) -> &String {
+    pub(crate) fn get_variable_name(&self, index: usize) -> &String {
         let name_val = self.identifiers.get(index);
         if let Some(var_name) = name_val {
             return var_name;
@@ -431,38 +1707,391 @@ impl VM {
     }
 
     pub fn run(&self) -> InterpretResult {
+        let mut state = self.start();
+        let mut result = match self.resume(&mut state, None) {
+            StepResult::Done(result) => result,
+            StepResult::Yielded => unreachable!("VM panic! Yielded without an instruction limit"),
+        };
+        if let (InterpretResult::InterpretOK, Some(entry)) = (result, &self.config.entry) {
+            result = self.run_entry(&mut state, entry);
+        }
+        self.finish(&mut state);
+        result
+    }
+
+    /// Calls the function in the global name with the script arguments as strings, once the top level code has run. See VmConfig::entry
+    fn run_entry(&self, state: &mut VMState, name: &str) -> InterpretResult {
+        let Some(callee) = state.global(self, name) else {
+            self.runtime_error(&format!("Undefined entry point '{}'", name), state);
+            return InterpretResult::InterpretRuntimeError;
+        };
+        let args: Vec<Value> = self
+            .config
+            .script_args
+            .iter()
+            .map(|arg| Value::LoxString(state.intern(arg)))
+            .collect();
+        if let Err(result) = self.call_from_host(state, callee, &args) {
+            return result;
+        }
+        // The tasks it started still have to finish, just like the ones started by the top level code
+        if !state.switch_to_next_task() {
+            if state.timed_out {
+                return self.time_budget_exceeded(state);
+            }
+            return InterpretResult::InterpretOK;
+        }
+        match self.resume(state, None) {
+            StepResult::Done(result) => result,
+            StepResult::Yielded => unreachable!("VM panic! Yielded without an instruction limit"),
+        }
+    }
+
+    /// Sets up everything needed to run the program from the start, without executing anything yet
+    pub(crate) fn start(&self) -> VMState {
         if let ExecutionMode::Trace = self.mode {
             eprintln!("== Starting execution | Mode: {:?} ==", self.mode);
             debug_print_constants(&self);
         }
 
-        let mut state = VMState::new(&self.identifiers);
+        let mut state = VMState::new(&self.identifiers, self.strings.clone(), &self.config);
+        for (i, native) in self.host_natives.iter().enumerate() {
+            if let Some(index) = self.identifiers.iter().position(|x| x == &native.name) {
+                state.globals[index] = Global::Init(Value::HostFunction(i));
+            }
+        }
+        if self.config.profile {
+            state.profiler = Some(Profiler::new(self.functions.len()));
+        }
+        state.coverage = self.config.coverage.as_ref().map(|_| Coverage::new(self));
+        if self.config.debugger {
+            state.debugger = Some(Debugger::new());
+        }
+        state
+    }
+
+    /// Appends what Compiler::continuing compiled on top of the program, for Vm::eval. Returns the index of the function holding its top level code
+    pub(crate) fn extend(&mut self, result: CompilationResult, state: &mut VMState) -> usize {
+        let script = self.functions.len();
+        self.functions
+            .extend(result.functions.into_iter().skip(script));
+        let classes = self.classes.len();
+        self.classes
+            .extend(result.classes.into_iter().skip(classes));
+        // New string constants were interned by the compiler, and have to be shared with the strings the program made at runtime
+        let constants = self.constants.len();
+        for constant in result.constants.into_iter().skip(constants) {
+            let constant = state.adopt(constant);
+            self.constants.push(constant);
+        }
+        self.module_functions.extend(result.module_functions);
+        if let (Some(source_map), Some(new)) = (&mut self.source_map, result.source_map) {
+            source_map.columns.resize(script, Vec::new());
+            source_map
+                .columns
+                .extend(new.columns.into_iter().skip(script));
+        }
+
+        // The properties set by setattr were passed in at the end of the identifiers, so their indices are the same as before
+        let identifiers = self.identifiers.len() + state.extra_properties.len();
+        self.identifiers = result.identifier_constants;
+        self.init_slot = self.identifiers.iter().position(|x| x == "init");
+        state.extra_properties.clear();
+        state.property_indices.clear();
+
+        state.globals.resize(self.identifiers.len(), Global::Uninit);
+        state.define_std_lib(&self.identifiers[..], identifiers);
+        for (i, native) in self.host_natives.iter().enumerate() {
+            if let Some(index) = self.identifiers[identifiers..]
+                .iter()
+                .position(|x| x == &native.name)
+            {
+                state.globals[identifiers + index] = Global::Init(Value::HostFunction(i));
+            }
+        }
+        if let Some(profiler) = state.profiler.as_mut() {
+            profiler.grow(self.functions.len());
+        }
+        if let Some(coverage) = state.coverage.as_mut() {
+            coverage.grow(self);
+        }
+        script
+    }
+
+    /// Prints the reports that were asked for in the config once the program has ended
+    pub(crate) fn finish(&self, state: &mut VMState) {
+        if let Some(profiler) = state.profiler.take() {
+            profiler.report(self, &self.config.stderr);
+        }
+        if let (Some(coverage), Some(output)) = (state.coverage.take(), &self.config.coverage) {
+            let script_path = self.config.script_path.as_deref().unwrap_or("script");
+            coverage.report(self, script_path, output, &self.config.stderr);
+        }
+    }
+
+    /// Continues executing from wherever the execution stopped, until the program ends or max_steps instructions have run
+    pub(crate) fn resume(&self, state: &mut VMState, max_steps: Option<u64>) -> StepResult {
+        self.dispatch(state, max_steps, None)
+    }
+
+    /// Calls callee with args from inside a native and runs it to completion, returning what it returned
+    ///
+    /// Returns None if the call failed or the program ended inside it, in which case the error has already been reported and the native should return straight away
+    pub(crate) fn call_function(
+        &self,
+        state: &mut VMState,
+        callee: Value,
+        args: &[Value],
+    ) -> Option<Value> {
+        if state.unwinding.is_some() {
+            return None;
+        }
+        if state.callback_depth >= MAX_CALLBACK_DEPTH {
+            self.runtime_error("Stack overflow", state);
+            state.unwinding = Some(InterpretResult::InterpretRuntimeError);
+            return None;
+        }
+
+        let depth = state.frames.len();
+        state.stack.push(callee);
+        state.stack.extend(args.iter().cloned());
+        if let Some(msg) = state.call_value(args.len(), self) {
+            self.runtime_error(&msg[..], state);
+            state.unwinding = Some(InterpretResult::InterpretRuntimeError);
+            return None;
+        }
+
+        // Natives and classes without an init finish inside call_value, anything else pushed a frame that has to run until it returns
+        if state.frames.len() > depth {
+            state.callback_depth += 1;
+            let result = self.dispatch(state, None, Some(depth));
+            state.callback_depth -= 1;
+            match result {
+                StepResult::Done(InterpretResult::InterpretOK) if state.frames.len() == depth => {
+                    // No instruction runs in the caller between two callbacks, so the profiler has to be told this one returned
+                    let (function, _, _) = state.current_frame();
+                    if let Some(profiler) = state.profiler.as_mut() {
+                        profiler.on_instruction(depth + 1, function);
+                    }
+                }
+                StepResult::Done(result) => {
+                    state.unwinding.get_or_insert(result);
+                    return None;
+                }
+                StepResult::Yielded => {
+                    unreachable!("VM panic! Yielded without an instruction limit")
+                }
+            }
+        }
+        Some(state.pop())
+    }
+
+    /// call_function for the host, which unlike a native goes on using the state after a call that failed, so the frames and values the failed
+    /// call left behind are dropped and it won't stop the next call either
+    pub(crate) fn call_from_host(
+        &self,
+        state: &mut VMState,
+        callee: Value,
+        args: &[Value],
+    ) -> Result<Value, InterpretResult> {
+        let stack_len = state.stack.len();
+        let depth = state.frames.len();
+        let current_frame = state.current_frame.clone();
+        match self.call_function(state, callee, args) {
+            Some(value) => Ok(value),
+            None => {
+                state.stack.truncate(stack_len);
+                state.frames.truncate(depth);
+                state.current_frame = current_frame;
+                Err(state
+                    .unwinding
+                    .take()
+                    .unwrap_or(InterpretResult::InterpretRuntimeError))
+            }
+        }
+    }
+
+    /// Whether dispatch has to do anything besides running instructions
+    fn instrumented(&self, state: &VMState, max_steps: Option<u64>) -> bool {
+        max_steps.is_some()
+            || self.config.max_instructions.is_some()
+            || self.config.max_time.is_some()
+            || self.config.max_memory.is_some()
+            || self.config.trace
+            || matches!(self.mode, ExecutionMode::Trace)
+            || state.hooks.on_line.is_some()
+            || state.profiler.is_some()
+            || state.coverage.is_some()
+            || state.debugger.is_some()
+    }
+
+    /// Checked before every instruction of an instrumented run. Returns what to stop with once a slice or budget runs out
+    fn check_limits(
+        &self,
+        state: &mut VMState,
+        max_steps: Option<u64>,
+        steps: &mut u64,
+    ) -> Option<StepResult> {
+        if let Some(max) = max_steps {
+            if *steps == max {
+                return Some(StepResult::Yielded);
+            }
+            *steps += 1;
+        }
+
+        state.instruction_count += 1;
+        if let Some(max) = self.config.max_instructions {
+            if state.instruction_count > max {
+                self.runtime_error("Instruction budget exceeded", state);
+                return Some(StepResult::Done(InterpretResult::InterpretBudgetExceeded));
+            }
+        }
+        if state.instruction_count.is_multiple_of(TIME_CHECK_INTERVAL) && state.out_of_time() {
+            return Some(StepResult::Done(self.time_budget_exceeded(state)));
+        }
+        if let Some(max) = self.config.max_memory {
+            // Only measured once the estimate says we're over, since measuring means a full collection
+            if state.memory_used > max && state.measure_memory() > max {
+                self.runtime_error("Memory limit exceeded", state);
+                return Some(StepResult::Done(InterpretResult::InterpretBudgetExceeded));
+            }
+        }
+        None
+    }
+
+    /// Tells the tools watching an instrumented run about the instruction that's about to run. Returns false if the user quit from the debugger
+    fn observe(&self, instr: &Instr, state: &mut VMState) -> bool {
+        if let ExecutionMode::Trace = self.mode {
+            debug_trace(self, instr, state);
+        } else if self.config.trace {
+            trace_execution(self, instr, state);
+        }
+
+        let (function, ip, _) = state.current_frame();
+        if let Some(hook) = state.hooks.on_line.as_ref() {
+            if state.last_line != Some((function, instr.line_num)) {
+                state.last_line = Some((function, instr.line_num));
+                hook.call(&instr.line_num);
+            }
+        }
+        let depth = state.frames.len() + 1;
+        if let Some(profiler) = state.profiler.as_mut() {
+            profiler.on_instruction(depth, function);
+        }
+        if let Some(coverage) = state.coverage.as_mut() {
+            coverage.hit(function, ip - 1);
+        }
+
+        // Taken out while it runs since it needs to look at the rest of the state
+        if let Some(mut debugger) = state.debugger.take() {
+            let carry_on = debugger.on_instruction(self, instr, state);
+            state.debugger = Some(debugger);
+            return carry_on;
+        }
+        true
+    }
+
+    /// The main loop. With a return_depth it returns as soon as the function called at that depth returns, leaving its result on the stack
+    ///
+    /// Budgets, hooks, the profiler, coverage, the debugger and tracing all need a look at every instruction, so there are two copies of the
+    /// loop and a plain run gets the one that doesn't check for any of them
+    fn dispatch(
+        &self,
+        state: &mut VMState,
+        max_steps: Option<u64>,
+        return_depth: Option<usize>,
+    ) -> StepResult {
+        if self.instrumented(state, max_steps) {
+            self.dispatch_loop::<true>(state, max_steps, return_depth)
+        } else {
+            self.dispatch_loop::<false>(state, max_steps, return_depth)
+        }
+    }
 
+    fn dispatch_loop<const INSTRUMENTED: bool>(
+        &self,
+        state: &mut VMState,
+        max_steps: Option<u64>,
+        return_depth: Option<usize>,
+    ) -> StepResult {
         // Makes getting new instructions faster
         // Update this vec whenever
-        let mut current_code = &self.get_current_code(&state)[..];
+        let mut current_code = &self.get_current_code(state)[..];
 
         // Move this into a match arm that matches all the binary ops, and then matches on the individual opcodes?
         macro_rules! op_binary {
             ($val_type: path, $oper: tt) => {
                 {
                     //if let ($val_type(a), $val_type(b)) = (self.pop(), self.pop()) {
-                    if let (Value::Double(a), Value::Double(b)) = (state.pop(), state.pop()) {
+                    let (a, b) = (state.pop(), state.pop());
+                    if let (Some(a), Some(b)) = (a.as_num(), b.as_num()) {
                         state.stack.push($val_type(b $oper a))
                     } else {
-                        self.runtime_error("Operands must be numbers", &state);
-                        return InterpretResult::InterpretRuntimeError;
+                        self.runtime_error("Operands must be numbers", state);
+                        return StepResult::Done(InterpretResult::InterpretRuntimeError);
                     }
                 }
             }
         }
 
+        // Private properties, see VM::check_access
+        macro_rules! check_access {
+            ($receiver: expr, $name_index: expr) => {
+                if let Err(msg) = self.check_access(state, $receiver, $name_index) {
+                    self.runtime_error(msg.as_str(), state);
+                    return StepResult::Done(InterpretResult::InterpretRuntimeError);
+                }
+            };
+        }
+
+        // Comparing two Ints as doubles would round them past 2^53
+        macro_rules! op_compare {
+            ($oper: tt) => {
+                match (state.pop(), state.pop()) {
+                    (Value::Int(a), Value::Int(b)) => state.stack.push(Value::Bool(b $oper a)),
+                    (a, b) => match (a.as_num(), b.as_num()) {
+                        (Some(a), Some(b)) => state.stack.push(Value::Bool(b $oper a)),
+                        _ => {
+                            self.runtime_error("Operands must be numbers", state);
+                            return StepResult::Done(InterpretResult::InterpretRuntimeError);
+                        }
+                    },
+                }
+            };
+        }
+
+        // Two Ints give an Int as long as the result fits, everything else is done in doubles
+        macro_rules! op_arithmetic {
+            ($checked: ident, $oper: tt) => {
+                match (state.pop(), state.pop()) {
+                    (Value::Int(a), Value::Int(b)) => state.stack.push(match b.$checked(a) {
+                        Some(x) => Value::Int(x),
+                        None => Value::Double(b as f64 $oper a as f64),
+                    }),
+                    (a, b) => match (a.as_num(), b.as_num()) {
+                        (Some(a), Some(b)) => state.stack.push(Value::Double(b $oper a)),
+                        _ => {
+                            self.runtime_error("Operands must be numbers", state);
+                            return StepResult::Done(InterpretResult::InterpretRuntimeError);
+                        }
+                    },
+                }
+            };
+        }
+
+        let mut steps: u64 = 0;
         loop {
+            if INSTRUMENTED {
+                if let Some(result) = self.check_limits(state, max_steps, &mut steps) {
+                    return result;
+                }
+            }
+
             let instr = &current_code[state.current_frame.ip];
             state.increment_ip(); // Preincrement the ip so OpLoops to 0 are possible
 
-            if let ExecutionMode::Trace = self.mode {
-                debug_trace(&self, &instr, &state);
+            if INSTRUMENTED && !self.observe(instr, state) {
+                return StepResult::Done(InterpretResult::InterpretOK); // The user quit from the debugger
             }
 
             match instr.op_code {
@@ -474,11 +2103,33 @@ impl VM {
                     }
 
                     if state.frames.is_empty() {
-                        return InterpretResult::InterpretOK;
+                        // This task is done, the program only ends once every other task is too
+                        if let Some(future) = state.current_future {
+                            state.resolve(future, result);
+                        }
+                        if !state.switch_to_next_task() {
+                            if state.timed_out {
+                                return StepResult::Done(self.time_budget_exceeded(state));
+                            }
+                            return StepResult::Done(InterpretResult::InterpretOK);
+                        }
+                        current_code = &self.get_current_code(state)[..];
                     } else {
+                        let returning = state.current_frame.function;
                         state.current_frame = state.frames.pop().unwrap(); // Update the current frame
-                        current_code = &self.get_current_code(&state)[..]; // Update the current code
+                        if let Some(hook) = state.hooks.on_return.as_ref() {
+                            hook.call(
+                                self.functions[returning]
+                                    .name
+                                    .as_deref()
+                                    .unwrap_or("script"),
+                            );
+                        }
+                        current_code = &self.get_current_code(state)[..]; // Update the current code
                         state.stack.push(result); // Push the result back
+                        if return_depth == Some(state.frames.len()) {
+                            return StepResult::Done(InterpretResult::InterpretOK);
+                        }
                     }
                 }
                 OpCode::OpPop => {
@@ -495,25 +2146,26 @@ impl VM {
                             let new = x.clone();
                             let index = state.stack.len() - arity;
                             state.stack.insert(index, new);
-                            let result = state.call_value(
-                                arity,
-                                &self.functions,
-                                &self.classes,
-                                &self.init_slot,
-                            );
-                            current_code = &self.get_current_code(&state)[..]; // Update the current code
+                            let result = state.call_value(arity, self);
+                            current_code = &self.get_current_code(state)[..]; // Update the current code
                             if let Some(msg) = result {
-                                self.runtime_error(&msg[..], &state);
-                                return InterpretResult::InterpretRuntimeError;
+                                self.runtime_error(&msg[..], state);
+                                return StepResult::Done(InterpretResult::InterpretRuntimeError);
+                            }
+                            if let Some(result) = state.unwinding.take() {
+                                return StepResult::Done(result);
+                            }
+                            if state.preempt() {
+                                current_code = &self.get_current_code(state)[..];
                             }
                         }
                         _ => {
                             self.runtime_error(
                                 format!("Undefined variable '{}'", self.get_variable_name(index))
                                     .as_str(),
-                                &state,
+                                state,
                             );
-                            return InterpretResult::InterpretRuntimeError;
+                            return StepResult::Done(InterpretResult::InterpretRuntimeError);
                         }
                     }
                 }
@@ -528,9 +2180,9 @@ impl VM {
                             self.runtime_error(
                                 format!("Undefined variable '{}'", self.get_variable_name(index))
                                     .as_str(),
-                                &state,
+                                state,
                             );
-                            return InterpretResult::InterpretRuntimeError;
+                            return StepResult::Done(InterpretResult::InterpretRuntimeError);
                         }
                     }
                 }
@@ -544,9 +2196,9 @@ impl VM {
                             self.runtime_error(
                                 format!("Undefined variable '{}'", self.get_variable_name(index))
                                     .as_str(),
-                                &state,
+                                state,
                             );
-                            return InterpretResult::InterpretRuntimeError;
+                            return StepResult::Done(InterpretResult::InterpretRuntimeError);
                         }
                     }
                 }
@@ -559,29 +2211,24 @@ impl VM {
                 }
 
                 OpCode::OpInvoke(name_index, arg_count) => {
+                    check_access!(state.peek_at(arg_count), name_index);
                     let pointer_val = state.peek_at(arg_count);
 
                     let result = match state.deref_into(pointer_val, HeapObjType::LoxInstance) {
                         Ok(instance) => {
                             let instance = instance.as_instance();
-                            let class_def = &self.classes[instance.class];
+                            let method = self.find_method(state, instance.class, name_index);
                             if instance.fields.contains_key(&name_index) {
                                 // Guard against the weird edge case where instance.thing() is actually calling a closure instance.thing, not a method invocation
                                 let value = instance.fields.get(&name_index).unwrap().clone();
                                 let index = state.stack.len() - 1 - arg_count;
                                 state.stack[index] = value; // Remove the instance and replace with the value
-                                state.call_value(
-                                    arg_count,
-                                    &self.functions,
-                                    &self.classes,
-                                    &self.init_slot,
-                                )
+                                state.call_value(arg_count, self)
                             // Perform the call
-                            } else if class_def.methods.contains_key(&name_index) {
+                            } else if let Some(fn_index) = method {
                                 // We know that the top of the stack is LoxPointer | arg1 | arg2
                                 // So we can go ahead and call
-                                let fn_index = class_def.methods.get(&name_index).unwrap();
-                                state.call(*fn_index, arg_count, &self.functions)
+                                Callable::Function(fn_index, None).call(state, arg_count, self)
                             } else {
                                 Some(format!(
                                     "Undefined property '{}' in {:?}",
@@ -590,16 +2237,91 @@ impl VM {
                                 ))
                             }
                         }
+                        Err(_) if matches!(pointer_val, Value::LoxClass(_)) => {
+                            // Calling a static field that holds something callable
+                            let class = pointer_val.as_class();
+                            match self.find_static(state, class, name_index).cloned() {
+                                Some(value) => {
+                                    let index = state.stack.len() - 1 - arg_count;
+                                    state.stack[index] = value;
+                                    state.call_value(arg_count, self)
+                                }
+                                None => Some(format!(
+                                    "Undefined property '{}' in class {}",
+                                    self.get_variable_name(name_index),
+                                    self.classes[class].name
+                                )),
+                            }
+                        }
                         Err(_) => Some(String::from("Can only invoke methods on class instances")),
                     };
 
                     if let Some(error) = result {
-                        self.runtime_error(error.as_str(), &state);
-                        return InterpretResult::InterpretRuntimeError;
+                        self.runtime_error(error.as_str(), state);
+                        return StepResult::Done(InterpretResult::InterpretRuntimeError);
+                    }
+                    if let Some(result) = state.unwinding.take() {
+                        return StepResult::Done(result);
+                    }
+                    current_code = &self.get_current_code(state)[..]; // Update the current code
+                    if state.preempt() {
+                        current_code = &self.get_current_code(state)[..];
+                    }
+                }
+                // The static fields, see Compiler::static_field. There are no methods to look for, since those are only bound to instances
+                OpCode::OpGetProperty(name_index) if matches!(state.peek(), Value::LoxClass(_)) => {
+                    check_access!(state.peek(), name_index);
+                    let class = state.peek().as_class();
+                    match self.find_static(state, class, name_index) {
+                        Some(value) => {
+                            let value = value.clone();
+                            state.pop(); // Remove the class
+                            state.stack.push(value);
+                        }
+                        None => {
+                            let msg = format!(
+                                "Undefined property '{}' in class {}",
+                                self.get_variable_name(name_index),
+                                self.classes[class].name
+                            );
+                            self.runtime_error(msg.as_str(), state);
+                            return StepResult::Done(InterpretResult::InterpretRuntimeError);
+                        }
                     }
-                    current_code = &self.get_current_code(&state)[..]; // Update the current code
                 }
-                OpCode::OpGetProperty(name_index) => {
+                OpCode::OpSetProperty(name_index)
+                    if matches!(state.peek_at(1), Value::LoxClass(_)) =>
+                {
+                    check_access!(state.peek_at(1), name_index);
+                    let val = state.pop();
+                    let class = state.pop().as_class();
+                    state.statics.insert((class, name_index), val.clone());
+                    state.stack.push(val);
+                }
+                // The slot is only a guess, see Compiler::this_field_slot. Private fields don't need checking since it's always this
+                OpCode::OpGetField(name_index, slot)
+                    if state
+                        .instance(state.peek())
+                        .is_some_and(|instance| instance.fields.at(slot, name_index).is_some()) =>
+                {
+                    let instance = state.instance(state.peek()).unwrap();
+                    let value = instance.fields.at(slot, name_index).unwrap().clone();
+                    state.pop(); // Remove the instance
+                    state.stack.push(value);
+                }
+                OpCode::OpSetField(name_index, slot)
+                    if state.instance(state.peek_at(1)).is_some_and(|instance| {
+                        !instance.frozen && instance.fields.at(slot, name_index).is_some()
+                    }) =>
+                {
+                    let val = state.pop();
+                    let pointer_val = state.pop();
+                    let instance = state.instance_mut(&pointer_val).unwrap();
+                    *instance.fields.at_mut(slot, name_index).unwrap() = val.clone();
+                    state.stack.push(val);
+                }
+                OpCode::OpGetProperty(name_index) | OpCode::OpGetField(name_index, _) => {
+                    check_access!(state.peek(), name_index);
                     let pointer_val = state.peek();
 
                     // Todo: Combine this and SetProperty into a macro so it doesn't hurt me everytime i have to read this
@@ -612,10 +2334,10 @@ impl VM {
                                 state.pop(); // Remove the instance
                                 state.stack.push(value); // Replace with the value
                             } else {
-                                let class_chunk = &self.classes[instance.class]; // if not a field, then we must be getting a function. Create a LoxBoundMethod for it
-                                if class_chunk.methods.contains_key(&name_index) {
+                                let method = self.find_method(state, instance.class, name_index); // if not a field, then we must be getting a function. Create a LoxBoundMethod for it
+                                if let Some(method) = method {
                                     let bound_value = ObjBoundMethod {
-                                        method: *class_chunk.methods.get(&name_index).unwrap(),
+                                        method,
                                         pointer: pointer_val.as_pointer(),
                                     };
                                     state.pop(); // Remove the instance
@@ -629,33 +2351,42 @@ impl VM {
                                             instance
                                         )
                                         .as_str(),
-                                        &state,
+                                        state,
+                                    );
+                                    return StepResult::Done(
+                                        InterpretResult::InterpretRuntimeError,
                                     );
-                                    return InterpretResult::InterpretRuntimeError;
                                 }
                             }
                         }
                         Err(_) => {
-                            let msg = format!("Only class instances can access properties with '.' Found {} instead", pointer_val.to_string(&self, &state));
-                            self.runtime_error(msg.as_str(), &state);
-                            return InterpretResult::InterpretRuntimeError;
+                            let msg = format!("Only class instances can access properties with '.' Found {} instead", pointer_val.to_string(&self, state));
+                            self.runtime_error(msg.as_str(), state);
+                            return StepResult::Done(InterpretResult::InterpretRuntimeError);
                         }
                     }
                 }
-                OpCode::OpSetProperty(name_index) => {
+                OpCode::OpSetProperty(name_index) | OpCode::OpSetField(name_index, _) => {
                     // Fixme: this is nearly identical to OpGetProperty, is there any way to combine them nicely?
+                    check_access!(state.peek_at(1), name_index);
                     let val = state.pop();
                     let pointer_val = state.peek().clone();
 
                     match state.deref_into_mut(&pointer_val, HeapObjType::LoxInstance) {
+                        Ok(instance) if instance.as_instance().frozen => {
+                            self.runtime_error("Can't set a property of a frozen instance", state);
+                            return StepResult::Done(InterpretResult::InterpretRuntimeError);
+                        }
                         Ok(instance) => {
                             let instance = instance.as_instance_mut();
-                            instance.fields.insert(name_index, val.clone());
+                            if instance.fields.insert(name_index, val.clone()).is_none() {
+                                state.memory_used += size_of::<(usize, Value)>();
+                            }
                         }
                         Err(_) => {
-                            let msg = format!("Only class instances can access properties with '.' Found {} instead", pointer_val.to_string(&self, &state));
-                            self.runtime_error(msg.as_str(), &state);
-                            return InterpretResult::InterpretRuntimeError;
+                            let msg = format!("Only class instances can access properties with '.' Found {} instead", pointer_val.to_string(&self, state));
+                            self.runtime_error(msg.as_str(), state);
+                            return StepResult::Done(InterpretResult::InterpretRuntimeError);
                         }
                     }
 
@@ -663,6 +2394,28 @@ impl VM {
                     state.pop(); // Instance
                     state.stack.push(val); // Return the value to the stack
                 }
+                OpCode::OpDeleteProperty(name_index) => {
+                    check_access!(state.peek(), name_index);
+                    let pointer_val = state.pop();
+                    match state.deref_into_mut(&pointer_val, HeapObjType::LoxInstance) {
+                        Ok(instance) if instance.as_instance().frozen => {
+                            self.runtime_error(
+                                "Can't delete a property of a frozen instance",
+                                state,
+                            );
+                            return StepResult::Done(InterpretResult::InterpretRuntimeError);
+                        }
+                        Ok(instance) => {
+                            instance.as_instance_mut().fields.remove(&name_index);
+                            // Deleting a field that isn't there does nothing
+                        }
+                        Err(_) => {
+                            let msg = format!("Only class instances can have properties deleted. Found {} instead", pointer_val.to_string(self, state));
+                            self.runtime_error(msg.as_str(), state);
+                            return StepResult::Done(InterpretResult::InterpretRuntimeError);
+                        }
+                    }
+                }
                 // This is almost identical to OpGetProperty, but it goes one extra jump to get the method from the superclass, and binds it to itself
                 OpCode::OpGetSuper(name_index) => {
                     let pointer_val = state.peek();
@@ -672,16 +2425,16 @@ impl VM {
                         match state.deref_into(pointer_val, HeapObjType::LoxInstance) {
                             Ok(instance) => {
                                 let instance = instance.as_instance();
-                                let superclass_chunk = &self.classes[*superclass];
-                                if superclass_chunk.methods.contains_key(&name_index) {
+                                let method = self.find_method(state, *superclass, name_index);
+                                if let Some(method) = method {
                                     let bound_value = ObjBoundMethod {
-                                        method: *superclass_chunk.methods.get(&name_index).unwrap(),
+                                        method,
                                         pointer: pointer_val.as_pointer(),
                                     };
                                     // println!("Superclass get method found method {:?} ", bound_value);
-                                    // println!("Superclass methods {:?}", superclass_chunk.methods);
                                     // println!("Superclass for {:?} is {:?}", instance, class_chunk.superclass);
                                     state.pop(); // Remove the instance
+                                    state.pop(); // And the superclass under it
                                     state.stack.push(Value::LoxBoundMethod(bound_value));
                                 // Replace with bound method
                                 } else {
@@ -692,17 +2445,22 @@ impl VM {
                                             self.classes.get(instance.class).unwrap().name,
                                         )
                                         .as_str(),
-                                        &state,
+                                        state,
+                                    );
+                                    return StepResult::Done(
+                                        InterpretResult::InterpretRuntimeError,
                                     );
-                                    return InterpretResult::InterpretRuntimeError;
                                 }
                             }
+                            // Neither happens in code from the compiler, only in a corrupt .loxb
                             Err(_) => {
-                                panic!("VM panic! Failed to obtain instance LoxPointer for super");
+                                self.runtime_error("Can only use 'super' on an instance", state);
+                                return StepResult::Done(InterpretResult::InterpretRuntimeError);
                             }
                         }
                     } else {
-                        panic!("VM panic! Failed to obtain superclass index for super, got {:?} instead", superclass_val);
+                        self.runtime_error("Can only look up 'super' methods in a class", state);
+                        return StepResult::Done(InterpretResult::InterpretRuntimeError);
                     }
                 }
 
@@ -735,19 +2493,44 @@ impl VM {
                         state.jump(offset);
                     }
                 }
-                OpCode::OpLoop(neg_offset) => state.jump_back(neg_offset),
+                OpCode::OpLoop(neg_offset) => {
+                    state.jump_back(neg_offset);
+                    if state.preempt() {
+                        current_code = &self.get_current_code(state)[..];
+                    }
+                }
 
                 OpCode::OpCall(arity) => {
-                    let result =
-                        state.call_value(arity, &self.functions, &self.classes, &self.init_slot);
-                    current_code = &self.get_current_code(&state)[..]; // Update the current code
+                    let result = state.call_value(arity, self);
+                    current_code = &self.get_current_code(state)[..]; // Update the current code
                     if let Some(msg) = result {
-                        self.runtime_error(&msg[..], &state);
-                        return InterpretResult::InterpretRuntimeError;
+                        self.runtime_error(&msg[..], state);
+                        return StepResult::Done(InterpretResult::InterpretRuntimeError);
+                    }
+                    if let Some(result) = state.unwinding.take() {
+                        return StepResult::Done(result);
+                    }
+                    if state.preempt() {
+                        current_code = &self.get_current_code(state)[..];
                     }
                 }
 
                 OpCode::OpClass(index) => state.stack.push(Value::LoxClass(index)),
+                OpCode::OpExtend(index) => match state.pop() {
+                    Value::LoxClass(class) => {
+                        for (name, method) in self.classes[index].methods.iter() {
+                            state.extensions.insert((class, *name), *method);
+                        }
+                    }
+                    value => {
+                        let msg = format!(
+                            "Only classes can be extended. Found {} instead",
+                            value.to_string(self, state)
+                        );
+                        self.runtime_error(msg.as_str(), state);
+                        return StepResult::Done(InterpretResult::InterpretRuntimeError);
+                    }
+                },
 
                 OpCode::OpConstant(index) => state.stack.push(self.constants[index].clone()),
                 OpCode::OpTrue => state.stack.push(Value::Bool(true)),
@@ -757,22 +2540,29 @@ impl VM {
                 OpCode::OpAdd => {
                     let t = (state.pop(), state.pop());
                     if let (Value::LoxString(a), Value::LoxString(b)) = t {
-                        state.stack.push(Value::LoxString(format!("{}{}", b, a)))
-                    } else if let (Value::Double(a), Value::Double(b)) = t {
-                        state.stack.push(Value::Double(a + b))
+                        let result = state.intern(&format!("{}{}", b, a));
+                        state.stack.push(Value::LoxString(result))
+                    } else if let (Value::Int(a), Value::Int(b)) = t {
+                        state.stack.push(match b.checked_add(a) {
+                            Some(x) => Value::Int(x),
+                            None => Value::Double(b as f64 + a as f64),
+                        })
+                    } else if let (Some(a), Some(b)) = (t.0.as_num(), t.1.as_num()) {
+                        state.stack.push(Value::Double(b + a))
                     } else if let (val1, val2) = t {
-                        
-                          state.stack.push(Value::LoxString(
-                            val2.to_string(self, &state) + val1.to_string(self, &state).as_str(),
-                            ))  
-                        
+                        let result =
+                            val2.to_string(self, state) + val1.to_string(self, state).as_str();
+                        let result = state.intern(&result);
+                        state.stack.push(Value::LoxString(result))
                     }
                 }
                 OpCode::OpDivide => op_binary!(Value::Double, /),
-                OpCode::OpSubtract => op_binary!(Value::Double, -),
-                OpCode::OpMultiply => op_binary!(Value::Double, *),
-                OpCode::OpGreater => op_binary!(Value::Bool, >),
-                OpCode::OpLess => op_binary!(Value::Bool, <),
+                OpCode::OpSubtract => op_arithmetic!(checked_sub, -),
+                OpCode::OpMultiply => op_arithmetic!(checked_mul, *),
+                OpCode::OpGreater => op_compare!(>),
+                OpCode::OpLess => op_compare!(<),
+                OpCode::OpGreaterEqual => op_compare!(>=),
+                OpCode::OpLessEqual => op_compare!(<=),
                 OpCode::OpEqual => {
                     let t = (&state.pop(), &state.pop());
                     state.stack.push(Value::Bool(values_equal(t)));
@@ -783,22 +2573,90 @@ impl VM {
                     state.stack.push(val);
                 }
                 OpCode::OpNegate => {
-                    let value = state.pop().as_num();
-                    match value {
-                        Some(x) => state.stack.push(Value::Double(x * -1.0)),
-                        None => {
-                            self.runtime_error("Attempted to negate a non-number value", &state);
-                            return InterpretResult::InterpretRuntimeError;
+                    let value = state.pop();
+                    match (&value, value.as_num()) {
+                        (Value::Int(x), _) => state.stack.push(match x.checked_neg() {
+                            Some(x) => Value::Int(x),
+                            None => Value::Double(-(*x as f64)),
+                        }),
+                        (_, Some(x)) => state.stack.push(Value::Double(x * -1.0)),
+                        (_, None) => {
+                            self.runtime_error("Attempted to negate a non-number value", state);
+                            return StepResult::Done(InterpretResult::InterpretRuntimeError);
                         }
                     }
                 }
 
                 OpCode::OpPrint => {
-                    println!("{}", state.pop().to_string(&self, &state));
+                    let line = state.pop().to_string(&self, state);
+                    self.config.stdout.write_line(&line);
+                }
+
+                OpCode::OpTuple(count) => {
+                    let start = state.stack.len() - count;
+                    let values: Rc<[Value]> = state.stack.drain(start..).collect();
+                    state.memory_used += count * size_of::<Value>();
+                    state.stack.push(Value::LoxTuple(values));
                 }
+                OpCode::OpUnpack(count) => match state.pop() {
+                    Value::LoxTuple(values) if values.len() == count => {
+                        state.stack.extend(values.iter().cloned());
+                    }
+                    value => {
+                        let msg = match &value {
+                            Value::LoxTuple(values) => format!(
+                                "Expected a tuple of {} elements to unpack, found {}",
+                                count,
+                                values.len()
+                            ),
+                            _ => format!(
+                                "Expected a tuple to unpack, found a {}",
+                                value.type_name(state)
+                            ),
+                        };
+                        self.runtime_error(&msg, state);
+                        return StepResult::Done(InterpretResult::InterpretRuntimeError);
+                    }
+                },
 
                 OpCode::OpAwait => {
-                    unimplemented!();
+                    // Awaiting anything that isn't a future just gives the value back
+                    if let Value::LoxFuture(future) = state.peek() {
+                        let future = *future;
+                        if let FutureState::Resolved(value) = &state.event_loop.futures[future] {
+                            let value = value.clone();
+                            state.pop();
+                            state.stack.push(value);
+                        } else if state.callback_depth > 0 {
+                            self.runtime_error(CALLBACK_WAIT_ERROR, state);
+                            return StepResult::Done(InterpretResult::InterpretRuntimeError);
+                        } else {
+                            state.pop();
+                            state.suspend(future);
+                            if !state.switch_to_next_task() {
+                                if state.timed_out {
+                                    return StepResult::Done(self.time_budget_exceeded(state));
+                                }
+                                self.runtime_error(
+                                    "Awaited a future that can never resolve",
+                                    state,
+                                );
+                                return StepResult::Done(InterpretResult::InterpretRuntimeError);
+                            }
+                            current_code = &self.get_current_code(state)[..];
+                        }
+                    }
+                }
+
+                OpCode::OpLoadNative(index) => {
+                    let path = match &self.constants[index] {
+                        Value::LoxString(path) => path,
+                        x => panic!("VM panic! Found a non LoxString native module path {:?}", x),
+                    };
+                    if let Err(msg) = state.load_native_module(path, &self.identifiers) {
+                        self.runtime_error(msg.as_str(), state);
+                        return StepResult::Done(InterpretResult::InterpretRuntimeError);
+                    }
                 }
             }
         }
@@ -842,6 +2700,22 @@ fn debug_trace(vm: &VM, instr: &Instr, state: &VMState) {
     eprintln!("---\n");
 }
 
+/// Prints the stack followed by the instruction that is about to run, in the same layout as the disassembler
+fn trace_execution(vm: &VM, instr: &Instr, state: &VMState) {
+    eprint!("          ");
+    for value in state.stack.iter() {
+        eprint!("[ {} ]", value.to_string(vm, state));
+    }
+    eprintln!();
+    eprint!("{:04}\t{}", state.current_frame.ip - 1, instr.line_num);
+    disassemble_instruction(
+        instr,
+        state.current_frame.ip - 1,
+        &vm.constants,
+        &vm.identifiers,
+    );
+}
+
 fn debug_print_constants(vm: &VM) {
     eprintln!("---");
     eprintln!("> Constants");
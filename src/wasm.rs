@@ -0,0 +1,70 @@
+//! JavaScript bindings for running scripts in the browser, ie for a playground. Only built for wasm32 with the wasm feature, see Cargo.toml
+use crate::{Output, RloxError, Vm, VmConfig};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use wasm_bindgen::prelude::*;
+
+// A script can't be interrupted once it's running, so without these an infinite loop would freeze the page for good
+const MAX_INSTRUCTIONS: u64 = 100_000_000;
+const MAX_MEMORY: usize = 256 << 20;
+
+/// Everything a script printed, with stdout and stderr kept apart so the page can show errors differently
+#[wasm_bindgen]
+pub struct RunOutput {
+    stdout: String,
+    stderr: String, // Compile errors, warnings and runtime errors along with their backtraces
+    ok: bool,
+}
+
+#[wasm_bindgen]
+impl RunOutput {
+    #[wasm_bindgen(getter)]
+    pub fn stdout(&self) -> String {
+        self.stdout.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn stderr(&self) -> String {
+        self.stderr.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn ok(&self) -> bool {
+        self.ok
+    }
+}
+
+/// Checks source without running it, the diagnostics end up in stderr
+#[wasm_bindgen]
+pub fn compile(source: &str) -> RunOutput {
+    capture(|vm| vm.compile(source).map(|_| ()))
+}
+
+#[wasm_bindgen]
+pub fn run(source: &str) -> RunOutput {
+    capture(|vm| vm.compile(source).and_then(|program| vm.run(program)))
+}
+
+/// Runs f on a fresh Vm that prints into buffers instead of the console
+fn capture(f: impl FnOnce(&mut Vm) -> Result<(), RloxError>) -> RunOutput {
+    let (stdout, printed) = Output::buffer();
+    let (stderr, errors) = Output::buffer();
+    let mut vm = Vm::new(VmConfig {
+        stdout,
+        stderr,
+        max_instructions: Some(MAX_INSTRUCTIONS),
+        max_memory: Some(MAX_MEMORY),
+        ..VmConfig::default()
+    });
+    let ok = f(&mut vm).is_ok();
+
+    let text =
+        |buffer: &Rc<RefCell<Vec<u8>>>| String::from_utf8_lossy(&buffer.borrow()).into_owned();
+    RunOutput {
+        stdout: text(&printed),
+        stderr: text(&errors),
+        ok,
+    }
+}
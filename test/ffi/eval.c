@@ -0,0 +1,50 @@
+/*
+ * Checks that every rlox_eval on a handle adds to the same program. Build the library as include/rlox.h says, then from the repository root
+ *
+ *     cc test/ffi/eval.c -Iinclude -Ltarget/release -lrlox -o target/ffi_eval && LD_LIBRARY_PATH=target/release target/ffi_eval
+ *
+ * which prints what the programs print, and exits with 1 after printing what went wrong if a check fails
+ */
+#include <stdio.h>
+#include <string.h>
+
+#include "rlox.h"
+
+static int failures = 0;
+
+static void expect(RloxVm *vm, const char *source, int expected) {
+    int result = rlox_eval(vm, source);
+    if (result != expected) {
+        const char *error = rlox_last_error(vm);
+        fprintf(stderr, "FAIL %s: got %d instead of %d (%s)\n", source, result, expected, error ? error : "no error");
+        failures++;
+    }
+}
+
+static RloxValue twice(void *userdata, size_t arg_count, const RloxValue *args) {
+    (void)userdata;
+    (void)arg_count;
+    return rlox_value_number(rlox_value_as_number(args[0]) * 2);
+}
+
+int main(void) {
+    RloxVm *vm = rlox_vm_new();
+
+    expect(vm, "var count = 1; fun bump() { count = count + 1; return count; }", RLOX_RESULT_OK);
+    expect(vm, "print bump(); // 2", RLOX_RESULT_OK);
+    expect(vm, "class Pair { init(a, b) { this.a = a; this.b = b; } }", RLOX_RESULT_OK);
+    expect(vm, "var pair = Pair(bump(), 4); print pair.a + pair.b; // 7", RLOX_RESULT_OK);
+
+    /* What ran before an error is kept, and what didn't compile leaves the program alone */
+    expect(vm, "var before = bump(); missing();", RLOX_RESULT_RUNTIME_ERROR);
+    expect(vm, "print before; // 4", RLOX_RESULT_OK);
+    expect(vm, "var broken = ;", RLOX_RESULT_COMPILE_ERROR);
+    expect(vm, "print count; // 4", RLOX_RESULT_OK);
+
+    /* Natives registered after the first call are bound in the later ones */
+    rlox_register_native(vm, "twice", 1, twice, NULL);
+    expect(vm, "print twice(count); // 8", RLOX_RESULT_OK);
+
+    rlox_vm_free(vm);
+    return failures == 0 ? 0 : 1;
+}
@@ -12,7 +12,8 @@ final _expectedOutputPattern = RegExp(r"// expect: ?(.*)");
 final _expectedErrorPattern = RegExp(r"// (Error.*)");
 final _errorLinePattern = RegExp(r"// \[((java|c) )?line (\d+)\] (Error.*)");
 final _expectedRuntimeErrorPattern = RegExp(r"// expect runtime error: (.+)");
-final _syntaxErrorPattern = RegExp(r"\[.*Line (\d+)\] (Error at .*)");
+final _syntaxErrorPattern = RegExp(r"\[.*Line (\d+)(, col \d+)?\] (Error.*)");
+final _sourceSnippetPattern = RegExp(r"^ *\d* \| ");
 final _stackTracePattern = RegExp(r"\[line (\d+)\]");
 final _nonTestPattern = RegExp(r"// nontest");
 
@@ -309,7 +310,7 @@ class Test {
     for (var line in error_lines) {
       var match = _syntaxErrorPattern.firstMatch(line);
       if (match != null) {
-        var error = "[${match[1]}] ${match[2]}";
+        var error = "[${match[1]}] ${match[3]}";
         if (_expectedErrors.contains(error)) {
           foundErrors.add(error);
         } else {
@@ -319,7 +320,7 @@ class Test {
           }
           unexpectedCount++;
         }
-      } else if (line != "") {
+      } else if (line != "" && !_sourceSnippetPattern.hasMatch(line)) {
         if (unexpectedCount < 10) {
           fail("Unexpected output on stderr:");
           fail(line);